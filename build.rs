@@ -0,0 +1,52 @@
+use std::process::Command;
+
+// Libraries whose versions are recorded with each session.
+const LIBRARIES: [&str; 4] = ["lordserial", "postgres", "serialport", "tokio-postgres"];
+
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|out| out.trim().to_string())
+}
+
+// "postgres=0.19.1,serialport=4.0.1" for the LIBRARIES found in Cargo.lock.
+fn library_versions() -> String {
+    let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let mut versions = Vec::new();
+    let mut lines = lock.lines();
+    while let Some(line) = lines.next() {
+        let name = match line.strip_prefix("name = ") {
+            Some(name) => name.trim_matches('"'),
+            None => continue,
+        };
+        let version = lines.next().and_then(|l| l.strip_prefix("version = "));
+        if let (true, Some(version)) = (LIBRARIES.contains(&name), version) {
+            versions.push(format!("{}={}", name, version.trim_matches('"')));
+        }
+    }
+    versions.join(",")
+}
+
+// Puts `git describe` in LORDLOGGER_GIT_VERSION for the sessions table, or
+// leaves it unset when building outside a checkout, along with the compiler
+// and library versions.
+fn main() {
+    if let Some(version) = run("git", &["describe", "--always", "--dirty", "--tags"]) {
+        println!("cargo:rustc-env=LORDLOGGER_GIT_VERSION={}", version);
+    }
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Some(version) = run(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=LORDLOGGER_RUSTC_VERSION={}", version);
+    }
+    println!(
+        "cargo:rustc-env=LORDLOGGER_LIBRARY_VERSIONS={}",
+        library_versions()
+    );
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+}
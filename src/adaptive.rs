@@ -0,0 +1,163 @@
+// GNSS logged faster while the fix is in question and slower while it's
+// settled, trading storage for detail where it helps diagnose. With
+// LORDLOGGER_GNSS_ADAPTIVE=1 the GNSS fields' decimation follows the fix:
+//
+//   fast    the fix type changed in the last 30s, or HDOP is above
+//           LORDLOGGER_GNSS_HIGH_DOP (2.0): a quarter of the configured
+//           decimation, so four times the rate, down to every sample
+//   slow    RTK fixed for LORDLOGGER_GNSS_STEADY_SECS (120) straight: four
+//           times the decimation, a quarter of the rate
+//   normal  anything else, as configured
+//
+// The device is sent the new format when the rate changes, at most once every
+// 10s so a fix flickering at the edge doesn't flood it with commands, and each
+// change is recorded as a gnss_rate event.
+use crate::config;
+use crate::descriptors::{self, DataDescriptor, GnssField};
+use crate::types::{Dop, FixInfo};
+use lordserial::Packet;
+use std::time::{Duration, Instant};
+
+pub const ADAPTIVE_ENV: &str = "LORDLOGGER_GNSS_ADAPTIVE";
+pub const HIGH_DOP_ENV: &str = "LORDLOGGER_GNSS_HIGH_DOP";
+pub const STEADY_SECS_ENV: &str = "LORDLOGGER_GNSS_STEADY_SECS";
+
+const HIGH_DOP: f32 = 2.0;
+const STEADY: Duration = Duration::from_secs(120);
+// How long after a fix type change the rate stays up.
+const CHANGE_HOLD: Duration = Duration::from_secs(30);
+const MIN_SWITCH: Duration = Duration::from_secs(10);
+const FACTOR: u16 = 4;
+const RTK_FIXED: u8 = 0x06;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rate {
+    Fast,
+    Normal,
+    Slow,
+}
+
+impl Rate {
+    pub fn name(self) -> &'static str {
+        match self {
+            Rate::Fast => "fast",
+            Rate::Normal => "normal",
+            Rate::Slow => "slow",
+        }
+    }
+
+    // The configured GNSS fields at this rate.
+    pub fn format(self, fields: &[(u8, u16)]) -> Vec<(u8, u16)> {
+        fields
+            .iter()
+            .map(|&(field, decimation)| {
+                let decimation = match self {
+                    Rate::Fast => (decimation / FACTOR).max(1),
+                    Rate::Normal => decimation,
+                    Rate::Slow => decimation.saturating_mul(FACTOR),
+                };
+                (field, decimation)
+            })
+            .collect()
+    }
+}
+
+pub struct AdaptiveGnss {
+    high_dop: f32,
+    steady_after: Duration,
+    fix_type: Option<u8>,
+    changed: Option<Instant>,
+    rtk_fixed_since: Option<Instant>,
+    hdop: f32,
+    rate: Rate,
+    switched: Option<Instant>,
+}
+
+impl AdaptiveGnss {
+    // None unless it's enabled.
+    pub fn from_settings(adaptive: &config::Adaptive) -> Option<Self> {
+        if adaptive.enabled != Some(true) {
+            return None;
+        }
+        Some(AdaptiveGnss {
+            high_dop: adaptive.high_dop.unwrap_or(HIGH_DOP),
+            steady_after: adaptive.steady_secs.map_or(STEADY, Duration::from_secs),
+            fix_type: None,
+            changed: None,
+            rtk_fixed_since: None,
+            hdop: 0.0,
+            rate: Rate::Normal,
+            switched: None,
+        })
+    }
+
+    pub fn rate(&self) -> Rate {
+        self.rate
+    }
+
+    pub fn packet(&mut self, packet: &Packet) {
+        if descriptors::data_set(packet) != Some(DataDescriptor::Gnss) {
+            return;
+        }
+        let field = |f: GnssField| packet.payload.get_field(f.into());
+        if let Some(dop) = field(GnssField::Dop).and_then(|f| Dop::extract(f).ok()) {
+            self.hdop = dop.hdop;
+        }
+        let fix = match field(GnssField::FixInfo).and_then(|f| FixInfo::extract(f).ok()) {
+            Some(fix) => fix,
+            None => return,
+        };
+        if self.fix_type.is_some_and(|t| t != fix.fix_type) {
+            self.changed = Some(Instant::now());
+        }
+        self.fix_type = Some(fix.fix_type);
+        match fix.fix_type {
+            RTK_FIXED => {
+                self.rtk_fixed_since.get_or_insert_with(Instant::now);
+            }
+            _ => self.rtk_fixed_since = None,
+        }
+    }
+
+    fn wanted(&self) -> Rate {
+        if self.changed.is_some_and(|t| t.elapsed() < CHANGE_HOLD) || self.hdop > self.high_dop {
+            Rate::Fast
+        } else if self
+            .rtk_fixed_since
+            .is_some_and(|t| t.elapsed() >= self.steady_after)
+        {
+            Rate::Slow
+        } else {
+            Rate::Normal
+        }
+    }
+
+    // The rate to switch the device to, when it's time for a change. Counts
+    // as switched.
+    pub fn due(&mut self) -> Option<Rate> {
+        let wanted = self.wanted();
+        if wanted == self.rate || self.switched.is_some_and(|t| t.elapsed() < MIN_SWITCH) {
+            return None;
+        }
+        self.rate = wanted;
+        self.switched = Some(Instant::now());
+        Some(wanted)
+    }
+
+    // What made the current rate, for its event.
+    pub fn reason(&self) -> String {
+        match self.rate {
+            Rate::Fast if self.hdop > self.high_dop => format!("hdop {:.1}", self.hdop),
+            Rate::Fast => "fix type changed".to_string(),
+            Rate::Slow => "rtk fixed".to_string(),
+            Rate::Normal => "fix settled".to_string(),
+        }
+    }
+}
+
+// The GNSS fields the device should have now.
+pub fn format(adaptive: Option<&AdaptiveGnss>, fields: &[(u8, u16)]) -> Vec<(u8, u16)> {
+    adaptive
+        .map_or(Rate::Normal, AdaptiveGnss::rate)
+        .format(fields)
+}
@@ -0,0 +1,160 @@
+use crate::descriptors::{self, DataDescriptor, GnssField};
+use crate::preflight;
+use crate::types::FixInfo;
+use lordserial::Packet;
+use std::fs;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+// Any sysfs file that takes "1"/"0", e.g. /sys/class/gpio/gpio17/value for a
+// buzzer or /sys/class/leds/led0/brightness for an LED.
+pub const ALERT_PATH_ENV: &str = "LORDLOGGER_ALERT_PATH";
+
+const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Ok = 0,
+    NoFix = 1,
+    DataStall = 2,
+    DiskFull = 3,
+}
+
+impl Condition {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => Condition::NoFix,
+            2 => Condition::DataStall,
+            3 => Condition::DiskFull,
+            _ => Condition::Ok,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Condition::Ok => "ok",
+            Condition::NoFix => "no_fix",
+            Condition::DataStall => "data_stall",
+            Condition::DiskFull => "disk_full",
+        }
+    }
+
+    // (on, off) durations of the output pattern; no-fix blinks slowly,
+    // a data stall blinks fast and a full disk holds the output on.
+    fn pattern(self) -> Option<(Duration, Duration)> {
+        match self {
+            Condition::Ok => None,
+            Condition::NoFix => Some((Duration::from_millis(500), Duration::from_millis(1500))),
+            Condition::DataStall => Some((Duration::from_millis(125), Duration::from_millis(125))),
+            Condition::DiskFull => Some((Duration::from_secs(1), Duration::from_secs(0))),
+        }
+    }
+}
+
+fn drive(path: String, condition: Arc<AtomicU8>) {
+    let set = |on: bool| {
+        if let Err(e) = fs::write(&path, if on { "1" } else { "0" }) {
+            error!("Failed to drive alert output {}. Error: {}", path, e);
+        }
+    };
+
+    loop {
+        match Condition::from_u8(condition.load(Ordering::Relaxed)).pattern() {
+            None => {
+                set(false);
+                thread::sleep(Duration::from_millis(250));
+            }
+            Some((on, off)) => {
+                set(true);
+                thread::sleep(on);
+                if off > Duration::from_secs(0) {
+                    set(false);
+                    thread::sleep(off);
+                }
+            }
+        }
+    }
+}
+
+pub struct Alerts {
+    output: Option<Arc<AtomicU8>>,
+    current: Condition,
+    last_packet: Instant,
+    has_fix: bool,
+    disk_full: bool,
+    last_disk_check: Option<Instant>,
+}
+
+impl Alerts {
+    // Drives the sysfs file at `path`, if there is one.
+    pub fn new(path: Option<&str>) -> Self {
+        let output = path.map(|path| {
+            let path = path.to_string();
+            let condition = Arc::new(AtomicU8::new(Condition::Ok as u8));
+            let shared = condition.clone();
+            thread::spawn(move || drive(path, shared));
+            condition
+        });
+
+        Alerts {
+            output,
+            current: Condition::Ok,
+            last_packet: Instant::now(),
+            has_fix: false,
+            disk_full: false,
+            last_disk_check: None,
+        }
+    }
+
+    pub fn packet_received(&mut self, packet: &Packet) {
+        self.last_packet = Instant::now();
+
+        if descriptors::data_set(packet) == Some(DataDescriptor::Gnss) {
+            if let Some(fix) = packet
+                .payload
+                .get_field(GnssField::FixInfo.into())
+                .and_then(|f| FixInfo::extract(f).ok())
+            {
+                self.has_fix = fix.has_fix();
+            }
+        }
+    }
+
+    // Returns the new condition whenever it changes.
+    pub fn poll(&mut self) -> Option<Condition> {
+        let check_due = self
+            .last_disk_check
+            .is_none_or(|t| t.elapsed() >= DISK_CHECK_INTERVAL);
+        if check_due {
+            self.disk_full = preflight::free_bytes(".")
+                .map(|free| free < preflight::MIN_FREE_BYTES)
+                .unwrap_or(false);
+            self.last_disk_check = Some(Instant::now());
+        }
+
+        let condition = if self.disk_full {
+            Condition::DiskFull
+        } else if self.last_packet.elapsed() >= STALL_TIMEOUT {
+            Condition::DataStall
+        } else if !self.has_fix {
+            Condition::NoFix
+        } else {
+            Condition::Ok
+        };
+
+        if condition == self.current {
+            return None;
+        }
+
+        self.current = condition;
+        if let Some(output) = &self.output {
+            output.store(condition as u8, Ordering::Relaxed);
+        }
+
+        Some(condition)
+    }
+}
@@ -0,0 +1,564 @@
+// Self-contained session bundles: a zstd compressed tarball holding a
+// manifest, the session's events and one CSV per data table. Rows move with
+// COPY in both directions so values round-trip exactly.
+use crate::clock::ClockSources;
+use crate::pipeline::Settings;
+use crate::rates::RateGroup;
+use crate::schema::SchemaMode;
+use crate::session::{Window, GPS_TIME_SQL};
+use crate::timescale::Timescale;
+use crate::vehicle::Vehicle;
+use crate::{jsonb, measurements, Error};
+use postgres::{Client, GenericClient, Transaction};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+// Format 2 added per-file SHA-256 hashes and row counts to the manifest.
+const FORMAT_VERSION: u64 = 2;
+const MANIFEST: &str = "manifest.json";
+const EVENTS: &str = "events.csv";
+const TABLES_DIR: &str = "tables/";
+
+const EVENT_COLUMNS: &str = "created_at, tow, week, kind, message";
+
+struct Table {
+    name: String,
+    // Expression giving each row's time, to select the session's rows.
+    time: &'static str,
+    columns: Vec<String>,
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn table_exists(c: &mut impl GenericClient, name: &str) -> Result<bool, Error> {
+    let row = c.query_one("SELECT to_regclass($1) IS NOT NULL", &[&name])?;
+    Ok(row.get(0))
+}
+
+// Every column but the serial id and session id, which are reassigned on
+// import, and generated columns, which Postgres recomputes.
+fn data_columns(c: &mut Client, name: &str) -> Result<Vec<String>, Error> {
+    let rows = c.query(
+        "SELECT column_name::text FROM information_schema.columns
+         WHERE table_schema = current_schema() AND table_name = $1
+           AND column_name NOT IN ('id', 'session_id') AND is_generated = 'NEVER'
+         ORDER BY ordinal_position",
+        &[&name],
+    )?;
+    Ok(rows.iter().map(|r| r.get(0)).collect())
+}
+
+fn data_tables(c: &mut Client, groups: &[RateGroup]) -> Result<Vec<Table>, Error> {
+    let mut names: Vec<String> = [
+        "imu_data",
+        "gnss_data",
+        "clock_bias",
+        "ekf_data",
+        "measurements",
+        "packets",
+        "gpsd_position",
+    ]
+    .iter()
+    .map(|n| n.to_string())
+    .collect();
+    names.extend(groups.iter().map(|g| g.table.clone()));
+
+    let mut tables = Vec::new();
+    for name in names {
+        if !table_exists(c, &name)? {
+            continue;
+        }
+        let columns = data_columns(c, &name)?;
+        // A timed table's `time` may come from a host clock and is missing on
+        // rows from before it had one, so those go by their tow and week.
+        let has = |column: &str| columns.iter().any(|c| c == column);
+        let time = if has("time") && !has("gps_time") {
+            "time"
+        } else {
+            GPS_TIME_SQL
+        };
+        tables.push(Table {
+            name,
+            time,
+            columns,
+        });
+    }
+
+    Ok(tables)
+}
+
+fn copy_to_file(c: &mut Client, query: &str, path: &Path) -> Result<(), Error> {
+    let mut reader =
+        c.copy_out(format!("COPY ({}) TO STDOUT WITH (FORMAT csv, HEADER)", query).as_str())?;
+    io::copy(&mut reader, &mut File::create(path)?)?;
+    Ok(())
+}
+
+struct FileDigest {
+    rows: u64,
+    sha256: String,
+}
+
+// Hashes a CSV file and counts its records, not counting the header. Quoted
+// values can span lines, so only newlines outside quotes end a record.
+fn digest(mut reader: impl Read) -> Result<FileDigest, Error> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut in_quotes = false;
+    let mut records: u64 = 0;
+
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        for &b in &buf[..n] {
+            match b {
+                b'"' => in_quotes = !in_quotes,
+                b'\n' if !in_quotes => records += 1,
+                _ => (),
+            }
+        }
+    }
+
+    Ok(FileDigest {
+        rows: records.saturating_sub(1),
+        sha256: hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    })
+}
+
+// Payload of the session's first event of this kind, parsed as JSON.
+fn event_json(c: &mut Client, session: i32, kind: &str) -> Result<Value, Error> {
+    let row = c.query_opt(
+        "SELECT message FROM events WHERE session_id = $1 AND kind = $2 ORDER BY id LIMIT 1",
+        &[&session, &kind],
+    )?;
+    Ok(match row {
+        Some(row) => serde_json::from_str(row.get(0)).unwrap_or(Value::Null),
+        None => Value::Null,
+    })
+}
+
+// Rows each data table holds for the session, leaving out empty tables.
+pub fn row_counts(
+    c: &mut Client,
+    window: &Window,
+    groups: &[RateGroup],
+) -> Result<Vec<(String, i64)>, Error> {
+    let mut counts = Vec::new();
+    for table in data_tables(c, groups)? {
+        let count: i64 = c
+            .query_one(
+                format!(
+                    "SELECT count(*) FROM {} WHERE {}",
+                    table.name,
+                    window.filter(table.time)
+                )
+                .as_str(),
+                &[],
+            )?
+            .get(0);
+        if count > 0 {
+            counts.push((table.name, count));
+        }
+    }
+    Ok(counts)
+}
+
+// Writes the bundle and returns the number of data rows in it.
+pub fn export(
+    c: &mut Client,
+    session: i32,
+    out: &Path,
+    groups: &[RateGroup],
+) -> Result<u64, Error> {
+    let window = Window::load(c, session)?;
+
+    let staging = std::env::temp_dir().join(format!("lordlogger-archive-{}", std::process::id()));
+    fs::create_dir_all(&staging)?;
+    let result = export_staged(c, session, &window, &staging, out, groups);
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+fn export_staged(
+    c: &mut Client,
+    session: i32,
+    window: &Window,
+    staging: &Path,
+    out: &Path,
+    groups: &[RateGroup],
+) -> Result<u64, Error> {
+    let mut files: Vec<(String, PathBuf)> = Vec::new();
+
+    let events = staging.join(EVENTS);
+    copy_to_file(
+        c,
+        &format!(
+            "SELECT {} FROM events WHERE session_id = {} ORDER BY id",
+            EVENT_COLUMNS, session
+        ),
+        &events,
+    )?;
+    let events_digest = digest(File::open(&events)?)?;
+    files.push((EVENTS.to_string(), events));
+
+    let mut total = 0;
+    let mut table_manifest = Vec::new();
+    for table in data_tables(c, groups)? {
+        let filter = window.filter(table.time);
+
+        let file = format!("{}{}.csv", TABLES_DIR, table.name);
+        let path = staging.join(format!("{}.csv", table.name));
+        copy_to_file(
+            c,
+            &format!(
+                "SELECT {} FROM {} WHERE {}",
+                table.columns.join(", "),
+                table.name,
+                filter
+            ),
+            &path,
+        )?;
+
+        let table_digest = digest(File::open(&path)?)?;
+        if table_digest.rows == 0 {
+            continue;
+        }
+
+        total += table_digest.rows;
+        table_manifest.push(json!({
+            "name": table.name,
+            "file": file,
+            "rows": table_digest.rows,
+            "sha256": table_digest.sha256,
+        }));
+        files.push((file, path));
+    }
+
+    let manifest = json!({
+        "format": FORMAT_VERSION,
+        "lordlogger_version": env!("CARGO_PKG_VERSION"),
+        "session": {
+            "id": session,
+            "started_at": window.started_at,
+            "ended_at": window.ended_at,
+            "vehicle_id": window.vehicle_id,
+            "port": window.port,
+            "device_serial": window.device_serial,
+            "config_hash": window.config_hash,
+            "version": window.version,
+            "environment": window.environment,
+            "session_key": window.session_key,
+        },
+        "config": event_json(c, session, "config")?,
+        "device": event_json(c, session, "device")?,
+        "events": {
+            "file": EVENTS,
+            "rows": events_digest.rows,
+            "sha256": events_digest.sha256,
+        },
+        "tables": table_manifest,
+    });
+
+    let encoder = zstd::Encoder::new(File::create(out)?, 0)?.auto_finish();
+    let mut bundle = tar::Builder::new(encoder);
+
+    // The manifest goes first so import knows the session before any rows.
+    let manifest = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    bundle.append_data(&mut header, MANIFEST, manifest.as_slice())?;
+
+    for (name, path) in files {
+        bundle.append_path_with_name(path, name)?;
+    }
+    bundle.into_inner()?;
+
+    Ok(total)
+}
+
+fn check_format(manifest: &Value) -> Result<u64, Error> {
+    match manifest["format"].as_u64() {
+        Some(format) if (1..=FORMAT_VERSION).contains(&format) => Ok(format),
+        _ => Err(format!("unsupported bundle format {}", manifest["format"]).into()),
+    }
+}
+
+// Merging into a session that already has rows leaves out the ones it has.
+fn import_events(
+    tx: &mut Transaction,
+    session: i32,
+    entry: impl Read,
+    merging: bool,
+) -> Result<(), Error> {
+    tx.batch_execute(
+        "CREATE TEMP TABLE import_events (
+            created_at timestamptz, tow double precision, week smallint, kind text, message text
+        ) ON COMMIT DROP",
+    )?;
+    copy_csv(tx, "import_events", entry)?;
+    let mut sql = format!(
+        "INSERT INTO events (session_id, {cols}) SELECT $1, {cols} FROM import_events",
+        cols = EVENT_COLUMNS
+    );
+    if merging {
+        sql.push_str(&format!(
+            " EXCEPT SELECT session_id, {} FROM events WHERE session_id = $1",
+            EVENT_COLUMNS
+        ));
+    }
+    tx.execute(sql.as_str(), &[&session])?;
+    Ok(())
+}
+
+// Rows go in through a staging table so they can be given the new session.
+fn import_rows(
+    tx: &mut Transaction,
+    session: i32,
+    table: &str,
+    entry: impl Read,
+    merging: bool,
+) -> Result<(), Error> {
+    let staging = format!("import_{}", table);
+    tx.batch_execute(&format!(
+        "CREATE TEMP TABLE {} ON COMMIT DROP AS SELECT * FROM {} WITH NO DATA",
+        staging, table
+    ))?;
+    let columns = copy_csv(tx, &staging, entry)?;
+    let mut sql = format!(
+        "INSERT INTO {} ({cols}, session_id) SELECT {cols}, $1 FROM {}",
+        table,
+        staging,
+        cols = columns
+    );
+    if merging {
+        sql.push_str(&format!(
+            " EXCEPT SELECT {}, session_id FROM {} WHERE session_id = $1",
+            columns, table
+        ));
+    }
+    tx.execute(sql.as_str(), &[&session])?;
+    Ok(())
+}
+
+// For tables without a session, which a merge would otherwise repeat.
+fn import_new_rows(tx: &mut Transaction, table: &str, entry: impl Read) -> Result<(), Error> {
+    let staging = format!("import_{}", table);
+    tx.batch_execute(&format!(
+        "CREATE TEMP TABLE {} ON COMMIT DROP AS SELECT * FROM {} WITH NO DATA",
+        staging, table
+    ))?;
+    let columns = copy_csv(tx, &staging, entry)?;
+    tx.batch_execute(&format!(
+        "INSERT INTO {table} ({cols}) SELECT {cols} FROM {staging}
+         EXCEPT SELECT {cols} FROM {table}",
+        table = table,
+        staging = staging,
+        cols = columns
+    ))?;
+    Ok(())
+}
+
+fn has_column(tx: &mut Transaction, table: &str, column: &str) -> Result<bool, Error> {
+    let row = tx.query_one(
+        "SELECT EXISTS (SELECT 1 FROM information_schema.columns
+         WHERE table_schema = current_schema() AND table_name = $1 AND column_name = $2)",
+        &[&table, &column],
+    )?;
+    Ok(row.get(0))
+}
+
+// The CSV header names the columns, in whatever order they were exported.
+// Returns them as written in the header.
+fn copy_csv(tx: &mut Transaction, table: &str, entry: impl Read) -> Result<String, Error> {
+    let mut reader = BufReader::new(entry);
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    let columns = header.trim_end().to_string();
+    if !columns.split(',').all(is_identifier) {
+        return Err(format!("invalid CSV header for {}", table).into());
+    }
+
+    let mut writer =
+        tx.copy_in(format!("COPY {} ({}) FROM STDIN WITH (FORMAT csv)", table, columns).as_str())?;
+    io::copy(&mut reader, &mut writer)?;
+    writer.finish()?;
+    Ok(columns)
+}
+
+fn open(path: &Path) -> Result<tar::Archive<impl Read>, Error> {
+    Ok(tar::Archive::new(zstd::Decoder::new(File::open(path)?)?))
+}
+
+// Loads a bundle as a new session and returns its id, and whether it was
+// merged instead into the session that has its key, with only the rows that
+// session lacks. Importing a bundle twice adds nothing the second time.
+// Everything happens in one transaction, so a bad bundle leaves nothing behind.
+pub fn import(c: &mut Client, path: &Path, settings: &Settings) -> Result<(i32, bool), Error> {
+    crate::sinks::setup_psql(
+        c,
+        SchemaMode::Wide,
+        &[],
+        &ClockSources::from_settings(&settings.data)?,
+        &Vehicle::from_settings(&settings.vehicle)?,
+        Timescale::from_settings(&settings.timescale)?.as_ref(),
+    )?;
+
+    let mut bundle = open(path)?;
+
+    let mut tx = c.transaction()?;
+    let mut session = None;
+    let mut merging = false;
+
+    for entry in bundle.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+
+        if name == MANIFEST {
+            let mut manifest = String::new();
+            entry.read_to_string(&mut manifest)?;
+            let manifest: Value = serde_json::from_str(&manifest)?;
+            check_format(&manifest)?;
+
+            let run = &manifest["session"];
+            let key = run["session_key"].as_str();
+            if let Some(existing) =
+                tx.query_opt("SELECT id FROM sessions WHERE session_key = $1", &[&key])?
+            {
+                session = Some(existing.get::<_, i32>(0));
+                merging = true;
+                continue;
+            }
+            let row = tx.query_one(
+                "INSERT INTO sessions (
+                    started_at, ended_at, vehicle_id, port, device_serial, config_hash, version,
+                    environment, session_key
+                 )
+                 VALUES ($1::text::timestamptz, $2::text::timestamptz, $3, $4, $5, $6, $7, $8, $9)
+                 RETURNING id",
+                &[
+                    &run["started_at"].as_str(),
+                    &run["ended_at"].as_str(),
+                    &run["vehicle_id"].as_str(),
+                    &run["port"].as_str(),
+                    &run["device_serial"].as_str(),
+                    &run["config_hash"].as_str(),
+                    &run["version"].as_str(),
+                    &Some(&run["environment"]).filter(|e| !e.is_null()),
+                    &key,
+                ],
+            )?;
+            session = Some(row.get::<_, i32>(0));
+            continue;
+        }
+
+        let session = session.ok_or("bundle does not start with a manifest")?;
+
+        if name == EVENTS {
+            import_events(&mut tx, session, entry, merging)?;
+        } else if let Some(file) = name.strip_prefix(TABLES_DIR) {
+            let table = file.trim_end_matches(".csv");
+            if !is_identifier(table) {
+                return Err(format!("invalid table name `{}` in bundle", table).into());
+            }
+
+            match table {
+                "measurements" => tx.batch_execute(measurements::CREATE_SQL)?,
+                "packets" => tx.batch_execute(jsonb::CREATE_SQL)?,
+                _ => (),
+            }
+            if !table_exists(&mut tx, table)? {
+                return Err(format!(
+                    "table {} does not exist, run the logger with the same rate groups first",
+                    table
+                )
+                .into());
+            }
+
+            if has_column(&mut tx, table, "session_id")? {
+                import_rows(&mut tx, session, table, entry, merging)?;
+            } else if merging {
+                import_new_rows(&mut tx, table, entry)?;
+            } else {
+                copy_csv(&mut tx, table, entry)?;
+            }
+        }
+    }
+
+    let session = session.ok_or("bundle has no manifest")?;
+    tx.commit()?;
+
+    Ok((session, merging))
+}
+
+// Checks every file in the bundle against the hash and row count in its
+// manifest, and that nothing listed is missing. Returns the manifest.
+pub fn verify(path: &Path) -> Result<Value, Error> {
+    let mut bundle = open(path)?;
+    let mut manifest: Option<Value> = None;
+    let mut expected: Vec<Value> = Vec::new();
+    let mut problems = Vec::new();
+
+    for entry in bundle.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+
+        if name == MANIFEST {
+            let mut text = String::new();
+            entry.read_to_string(&mut text)?;
+            let parsed: Value = serde_json::from_str(&text)?;
+            if check_format(&parsed)? < 2 {
+                return Err("bundle predates manifest hashes and cannot be verified".into());
+            }
+            expected.push(parsed["events"].clone());
+            expected.extend(parsed["tables"].as_array().cloned().unwrap_or_default());
+            manifest = Some(parsed);
+            continue;
+        }
+
+        if manifest.is_none() {
+            return Err("bundle does not start with a manifest".into());
+        }
+
+        let actual = digest(entry)?;
+        match expected.iter().position(|e| e["file"] == name.as_str()) {
+            Some(i) => {
+                let listed = expected.remove(i);
+                if listed["sha256"] != actual.sha256.as_str() {
+                    problems.push(format!("{}: SHA-256 mismatch", name));
+                }
+                if listed["rows"].as_u64() != Some(actual.rows) {
+                    problems.push(format!(
+                        "{}: {} rows, manifest lists {}",
+                        name, actual.rows, listed["rows"]
+                    ));
+                }
+            }
+            None => problems.push(format!("{}: not listed in the manifest", name)),
+        }
+    }
+
+    for missing in expected {
+        problems.push(format!("{}: missing from the bundle", missing["file"]));
+    }
+
+    let manifest = manifest.ok_or("bundle has no manifest")?;
+    if !problems.is_empty() {
+        return Err(problems.join("\n").into());
+    }
+
+    Ok(manifest)
+}
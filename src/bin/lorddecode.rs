@@ -0,0 +1,82 @@
+// Raw MIP bytes on stdin to rows on stdout, with the logger's decoding:
+//
+//   cat dump.bin | lorddecode --table imu_data > imu.csv
+//   socat /dev/ttyACM0,raw - | lorddecode --format jsonl | jq .accel_x
+//
+// CSV is one table's rows under its header, imu_data unless --table says
+// otherwise. JSON Lines is every table, or those given with --table. The
+// LORDLOGGER_* settings that shape the rows apply as they do to the logger.
+use clap::Parser;
+use lordlogger::builder::LoggerBuilder;
+use lordlogger::config::{self, ConfigFile};
+use lordlogger::failure::{Context, Failure, FailureKind};
+use lordlogger::fanout::{Tables, TargetConfig};
+use lordlogger::packet_source;
+use lordlogger::{csv, framing, jsonl, logging, pipeline};
+use tracing::info;
+
+#[derive(Debug, Parser)]
+#[command(about = "Decodes raw MIP bytes on stdin to CSV or JSON Lines on stdout")]
+struct Cli {
+    #[arg(long, default_value = "csv", value_parser = ["csv", "jsonl"])]
+    format: String,
+    #[arg(
+        long,
+        help = "Table to write; repeatable for jsonl [default: imu_data for csv, every table for jsonl]"
+    )]
+    table: Vec<String>,
+}
+
+fn decode(cli: &Cli) -> Result<(), Failure> {
+    let tables = match (cli.format.as_str(), &cli.table[..]) {
+        ("csv", []) => Tables::Only(["imu_data".to_string()].into()),
+        ("csv", [table]) => Tables::Only([table.clone()].into()),
+        ("csv", _) => {
+            return Err(Failure::new(
+                FailureKind::Config,
+                "CSV takes a single --table",
+            ))
+        }
+        (_, []) => Tables::All,
+        (_, tables) => Tables::Only(tables.iter().cloned().collect()),
+    };
+    let url = match cli.format.as_str() {
+        "csv" => format!("{}{}", csv::SCHEME, csv::STDOUT),
+        _ => format!("{}{}", jsonl::SCHEME, jsonl::STDOUT),
+    };
+
+    let mut file = ConfigFile::default();
+    config::apply_env(&mut file).or_fail(FailureKind::Config)?;
+    let mut settings = LoggerBuilder::new()
+        .device(packet_source::STDIN)
+        .data(file.data)
+        .vehicle(file.vehicle)
+        .writes(file.writes)
+        .layout(file.layout)
+        .build()
+        .or_fail(FailureKind::Config)?;
+    settings.sinks = vec![TargetConfig { url, tables }];
+    settings.framing = file
+        .device
+        .framing
+        .as_deref()
+        .map(framing::from_spec)
+        .transpose()
+        .or_fail(FailureKind::Config)?;
+    let stats = pipeline::decode(&settings)?;
+    info!(
+        "Decoded {} packets ({} dropped)",
+        stats.packets, stats.decode_errors
+    );
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = logging::init() {
+        Failure::new(FailureKind::Config, e.to_string()).exit();
+    }
+    if let Err(failure) = decode(&cli) {
+        failure.exit();
+    }
+}
@@ -0,0 +1,128 @@
+// What happens during a run, for a program embedding the logger to react to
+// without patching the acquisition loop. Subscriptions are by event type,
+// either a callback or a channel:
+//
+//   bus::subscribe(|fix: &FixChange| println!("fix {:?}", fix.name));
+//   let gaps = bus::stream::<GapDetected>();
+//   thread::spawn(move || for gap in gaps { alarm(gap.idle) });
+//
+// Callbacks run on whichever thread published the event, the acquisition
+// thread, a decode worker or a target's writer, so anything slow should be
+// handed off; a stream is that hand-off, unbounded, and unsubscribes when its
+// receiver is dropped. Subscriptions are for the whole process and last
+// across runs. Nothing is built for an event type nobody subscribed to.
+use lordserial::Packet;
+use std::any::{Any, TypeId};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+pub trait BusEvent: Any + Clone + Send {}
+
+// A packet decoded into rows without error. With decode workers these come
+// from the workers, not necessarily in the order the packets arrived.
+#[derive(Debug, Clone)]
+pub struct PacketDecoded {
+    pub device: String,
+    pub packet: Packet,
+}
+
+// The device's stream picked up again after going quiet for `idle`, the gap
+// in the data.
+#[derive(Debug, Clone)]
+pub struct GapDetected {
+    pub device: String,
+    pub idle: Duration,
+    pub from_standby: bool,
+}
+
+// A target or custom sink failed to write `rows` rows. `lost` is a lost
+// connection or a passing failure such as a cancelled statement or a
+// deadlock, whose rows are tried again or spooled, rather than rows rejected
+// and dropped.
+#[derive(Debug, Clone)]
+pub struct SinkError {
+    pub sink: String,
+    pub error: String,
+    pub rows: usize,
+    pub lost: bool,
+}
+
+// The GNSS fix type changed, with the fix_type_name labels, `from` None for
+// the first fix of the run.
+#[derive(Debug, Clone)]
+pub struct FixChange {
+    pub from: Option<u8>,
+    pub to: u8,
+    pub name: Option<&'static str>,
+    pub svs: u8,
+}
+
+impl BusEvent for PacketDecoded {}
+impl BusEvent for GapDetected {}
+impl BusEvent for SinkError {}
+impl BusEvent for FixChange {}
+
+// Returns whether to stay subscribed.
+type Handler = Arc<dyn Fn(&dyn Any) -> bool + Send + Sync>;
+
+struct Subscriber {
+    event: TypeId,
+    handler: Handler,
+}
+
+static SUBSCRIBERS: RwLock<Vec<Subscriber>> = RwLock::new(Vec::new());
+static ANY: AtomicBool = AtomicBool::new(false);
+
+fn add<E: BusEvent>(handler: impl Fn(&E) -> bool + Send + Sync + 'static) {
+    SUBSCRIBERS.write().unwrap().push(Subscriber {
+        event: TypeId::of::<E>(),
+        handler: Arc::new(move |event| event.downcast_ref().is_none_or(&handler)),
+    });
+    ANY.store(true, Ordering::Relaxed);
+}
+
+pub fn subscribe<E: BusEvent>(callback: impl Fn(&E) + Send + Sync + 'static) {
+    add(move |event: &E| {
+        callback(event);
+        true
+    });
+}
+
+pub fn stream<E: BusEvent>() -> Receiver<E> {
+    let (send, events) = mpsc::channel();
+    add(move |event: &E| send.send(event.clone()).is_ok());
+    events
+}
+
+// Whether anything subscribed to E, for events costly to build.
+pub fn listening<E: BusEvent>() -> bool {
+    ANY.load(Ordering::Relaxed)
+        && SUBSCRIBERS
+            .read()
+            .unwrap()
+            .iter()
+            .any(|s| s.event == TypeId::of::<E>())
+}
+
+// The handlers are called outside the lock, so one can subscribe in turn.
+pub fn publish<E: BusEvent>(event: E) {
+    if !ANY.load(Ordering::Relaxed) {
+        return;
+    }
+    let handlers: Vec<Handler> = SUBSCRIBERS
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|s| s.event == TypeId::of::<E>())
+        .map(|s| s.handler.clone())
+        .collect();
+    let ended: Vec<Handler> = handlers.into_iter().filter(|h| !h(&event)).collect();
+    if !ended.is_empty() {
+        SUBSCRIBERS
+            .write()
+            .unwrap()
+            .retain(|s| !ended.iter().any(|h| Arc::ptr_eq(h, &s.handler)));
+    }
+}
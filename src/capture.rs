@@ -0,0 +1,180 @@
+// Raw packet captures, a lossless archive of what the device sent that doesn't
+// depend on extraction or the schema being right. `--record <file>` appends
+// every packet to the file as it arrives, before anything decodes it:
+//
+//   LORDCAP1                      8 bytes, once at the start
+//   time     u64 big-endian       host arrival, ns since the Unix epoch
+//   length   u16 big-endian       of the packet that follows
+//   packet                        MIP bytes, sync to checksum
+//
+// lordserial only hands over packets it has framed, so each one is put back
+// together from its fields with a fresh checksum. Packets it threw away for a
+// bad checksum aren't in the capture. A run that knows its device starts
+// what it records with the device's information reply, which a replay keys
+// its session on.
+use crate::dump;
+use crate::mip::{self, DeviceInfo};
+use crate::Error;
+use lordserial::Packet;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+pub const MAGIC: &[u8; 8] = b"LORDCAP1";
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// A packet's bytes as the device sent them.
+pub fn frame(packet: &Packet) -> Vec<u8> {
+    let mut bytes = mip::SYNC.to_vec();
+    bytes.push(packet.header.descriptor);
+    bytes.push(0);
+    for field in &packet.payload.fields {
+        let data = dump::bytes(field);
+        bytes.push(data.len() as u8 + 2);
+        bytes.push(field.descriptor);
+        bytes.extend_from_slice(&data);
+    }
+    bytes[3] = (bytes.len() - 4) as u8;
+    let sum = mip::checksum(&bytes);
+    bytes.extend_from_slice(&sum);
+    bytes
+}
+
+// Records nothing when made without a file. A file that stops taking writes,
+// say a full disk, ends the recording rather than the run.
+pub struct Recorder {
+    out: Option<(PathBuf, BufWriter<File>)>,
+    packets: u64,
+    flushed: Instant,
+}
+
+impl Recorder {
+    // Appends to a capture that's already there, so a restarted run keeps
+    // adding to the same file.
+    pub fn create(path: Option<&Path>) -> Result<Self, Error> {
+        let out = match path {
+            Some(path) => {
+                let mut file = OpenOptions::new()
+                    .read(true)
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                if file.metadata()?.len() == 0 {
+                    file.write_all(MAGIC)?;
+                } else {
+                    let mut magic = [0; 8];
+                    file.read_exact(&mut magic)?;
+                    if &magic != MAGIC {
+                        return Err(format!("{} is not a capture file", path.display()).into());
+                    }
+                }
+                info!("Recording raw packets to {}", path.display());
+                Some((path.to_path_buf(), BufWriter::new(file)))
+            }
+            None => None,
+        };
+        Ok(Recorder {
+            out,
+            packets: 0,
+            flushed: Instant::now(),
+        })
+    }
+
+    pub fn packet(&mut self, packet: &Packet, at: SystemTime) {
+        if self.recording() {
+            self.write(&frame(packet), at);
+        }
+    }
+
+    pub fn device_info(&mut self, info: &DeviceInfo, at: SystemTime) {
+        self.write(&info.frame(), at);
+    }
+
+    fn write(&mut self, bytes: &[u8], at: SystemTime) {
+        let (path, out) = match &mut self.out {
+            Some(out) => out,
+            None => return,
+        };
+        let time = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let mut result = out
+            .write_all(&time.to_be_bytes())
+            .and_then(|_| out.write_all(&(bytes.len() as u16).to_be_bytes()))
+            .and_then(|_| out.write_all(bytes));
+        if result.is_ok() && self.flushed.elapsed() >= FLUSH_INTERVAL {
+            self.flushed = Instant::now();
+            result = out.flush();
+        }
+        match result {
+            Ok(()) => self.packets += 1,
+            Err(e) => {
+                warn!(
+                    "Stopped recording to {} after {} packets. Error: {}",
+                    path.display(),
+                    self.packets,
+                    e
+                );
+                self.out = None;
+            }
+        }
+    }
+
+    pub fn recording(&self) -> bool {
+        self.out.is_some()
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some((path, out)) = &mut self.out {
+            match out.flush() {
+                Ok(()) => info!("Recorded {} packets to {}", self.packets, path.display()),
+                Err(e) => error!(
+                    "Failed to finish recording to {}. Error: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+// Reads a capture back a packet at a time.
+pub struct Reader {
+    file: BufReader<File>,
+}
+
+impl Reader {
+    // None when the file doesn't start like a capture.
+    pub fn open(path: &Path) -> Result<Option<Self>, Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        match file.read_exact(&mut magic) {
+            Ok(()) if &magic == MAGIC => Ok(Some(Reader { file })),
+            Ok(()) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // The next packet and when it arrived, None at the end. A packet cut
+    // short by a crash while recording counts as the end.
+    pub fn next_packet(&mut self) -> Result<Option<(SystemTime, Vec<u8>)>, Error> {
+        let mut header = [0; 10];
+        match self.file.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let time = u64::from_be_bytes(header[..8].try_into().unwrap());
+        let mut packet = vec![0; u16::from_be_bytes([header[8], header[9]]) as usize];
+        match self.file.read_exact(&mut packet) {
+            Ok(()) => Ok(Some((UNIX_EPOCH + Duration::from_nanos(time), packet))),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
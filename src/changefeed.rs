@@ -0,0 +1,214 @@
+// Change stream over the data tables using a logical replication slot.
+//
+// The slot uses the built-in test_decoding plugin and is read through the
+// SQL interface, so no replication connection or server extension is needed.
+// A publication is created alongside it for consumers that prefer pgoutput.
+#![allow(dead_code)]
+
+use crate::Error;
+use postgres::Client;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+pub const SLOT: &str = "lordlogger_changes";
+pub const PUBLICATION: &str = "lordlogger_publication";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Insert,
+    Update,
+    Delete,
+}
+
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    pub sql_type: String,
+    // None for SQL NULL
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub lsn: String,
+    pub table: String,
+    pub operation: Operation,
+    pub columns: Vec<Column>,
+}
+
+impl Change {
+    pub fn get<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.columns
+            .iter()
+            .find(|c| c.name == name)
+            .and_then(|c| c.value.as_ref())
+            .and_then(|v| v.parse().ok())
+    }
+
+    // Parses one test_decoding line, e.g.
+    // table public.imu_data: INSERT: id[integer]:5 baro[real]:1013.2 quat[quaternion]:'(1,0,0,0)'
+    fn parse(lsn: String, data: &str) -> Option<Self> {
+        let rest = data.strip_prefix("table ")?;
+        let (table, rest) = rest.split_at(rest.find(": ")?);
+        let rest = &rest[2..];
+        let (operation, rest) = rest.split_at(rest.find(':')?);
+        let operation = match operation {
+            "INSERT" => Operation::Insert,
+            "UPDATE" => Operation::Update,
+            "DELETE" => Operation::Delete,
+            _ => return None,
+        };
+
+        Some(Change {
+            lsn,
+            table: table.rsplit('.').next()?.to_string(),
+            operation,
+            columns: parse_columns(rest[1..].trim_start())?,
+        })
+    }
+}
+
+fn parse_columns(mut s: &str) -> Option<Vec<Column>> {
+    let mut columns = Vec::new();
+
+    while !s.is_empty() {
+        let open = s.find('[')?;
+        let close = s.find("]:")?;
+        let name = s[..open].to_string();
+        let sql_type = s[open + 1..close].to_string();
+        s = &s[close + 2..];
+
+        let value = if let Some(quoted) = s.strip_prefix('\'') {
+            // Quoted literal, '' escapes a quote.
+            let mut value = String::new();
+            let mut chars = quoted.char_indices().peekable();
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                if c == '\'' {
+                    if chars.peek().map(|&(_, n)| n) == Some('\'') {
+                        value.push('\'');
+                        chars.next();
+                    } else {
+                        end = Some(i + 1);
+                        break;
+                    }
+                } else {
+                    value.push(c);
+                }
+            }
+            s = &quoted[end?..];
+            Some(value)
+        } else {
+            let end = s.find(' ').unwrap_or(s.len());
+            let raw = &s[..end];
+            s = &s[end..];
+            if raw == "null" {
+                None
+            } else {
+                Some(raw.to_string())
+            }
+        };
+
+        columns.push(Column {
+            name,
+            sql_type,
+            value,
+        });
+        s = s.trim_start();
+    }
+
+    Some(columns)
+}
+
+pub fn setup(c: &mut Client, tables: &[&str]) -> Result<(), Error> {
+    let exists = c
+        .query_opt(
+            "SELECT 1 FROM pg_publication WHERE pubname = $1",
+            &[&PUBLICATION],
+        )?
+        .is_some();
+    if !exists {
+        c.batch_execute(&format!(
+            "CREATE PUBLICATION {} FOR TABLE {}",
+            PUBLICATION,
+            tables.join(", ")
+        ))?;
+    }
+
+    let exists = c
+        .query_opt(
+            "SELECT 1 FROM pg_replication_slots WHERE slot_name = $1",
+            &[&SLOT],
+        )?
+        .is_some();
+    if !exists {
+        c.execute(
+            "SELECT pg_create_logical_replication_slot($1, 'test_decoding')",
+            &[&SLOT],
+        )?;
+    }
+
+    Ok(())
+}
+
+// Dropping the slot matters: an abandoned slot makes the server keep WAL forever.
+pub fn teardown(c: &mut Client) -> Result<(), Error> {
+    c.execute(
+        "SELECT pg_drop_replication_slot(slot_name) FROM pg_replication_slots WHERE slot_name = $1",
+        &[&SLOT],
+    )?;
+    c.batch_execute(&format!("DROP PUBLICATION IF EXISTS {}", PUBLICATION))?;
+    Ok(())
+}
+
+// Blocking iterator of changes to the given tables. Changes are consumed from
+// the slot as they are read, so each one is delivered once.
+pub struct ChangeStream<'a> {
+    client: &'a mut Client,
+    tables: Vec<String>,
+    pending: std::vec::IntoIter<Change>,
+}
+
+impl<'a> ChangeStream<'a> {
+    pub fn new(client: &'a mut Client, tables: &[&str]) -> Self {
+        ChangeStream {
+            client,
+            tables: tables.iter().map(|t| t.to_string()).collect(),
+            pending: Vec::new().into_iter(),
+        }
+    }
+
+    fn fetch(&mut self) -> Result<Vec<Change>, Error> {
+        let rows = self.client.query(
+            "SELECT lsn::text, data FROM pg_logical_slot_get_changes($1, NULL, NULL)",
+            &[&SLOT],
+        )?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| Change::parse(row.get(0), row.get(1)))
+            .filter(|change| self.tables.contains(&change.table))
+            .collect())
+    }
+}
+
+impl<'a> Iterator for ChangeStream<'a> {
+    type Item = Result<Change, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(change) = self.pending.next() {
+                return Some(Ok(change));
+            }
+
+            match self.fetch() {
+                Ok(changes) if changes.is_empty() => thread::sleep(POLL_INTERVAL),
+                Ok(changes) => self.pending = changes.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
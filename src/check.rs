@@ -0,0 +1,120 @@
+use crate::descriptors;
+use crate::Error;
+use lordserial::Packet;
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Comma separated `set=hz` base rates the decimations divide, e.g.
+// `80=1000,81=4,82=500`. Sets not listed keep their default.
+pub const BASE_RATES_ENV: &str = "LORDLOGGER_BASE_RATES";
+
+const DEFAULT_BASE_RATES: [(u8, f64); 3] = [(0x80, 1000.0), (0x81, 4.0), (0x82, 500.0)];
+
+// How far a measured rate may stray from the expected one and still pass.
+const TOLERANCE: f64 = 0.1;
+
+// The defaults with `set=hz` entries over them.
+pub fn base_rates(entries: &[String]) -> Result<HashMap<u8, f64>, Error> {
+    let mut rates: HashMap<u8, f64> = DEFAULT_BASE_RATES.iter().copied().collect();
+    for entry in entries.iter().map(|e| e.trim()) {
+        let (set, hz) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("base rate `{}` is not set=hz", entry))?;
+        let set = u8::from_str_radix(set.trim().trim_start_matches("0x"), 16)?;
+        rates.insert(set, hz.trim().parse()?);
+    }
+    Ok(rates)
+}
+
+// "30s", "2m", "6h", "1d", "500ms" or plain seconds.
+pub fn parse_duration(text: &str) -> Result<Duration, Error> {
+    let text = text.trim();
+    let (number, scale) = if let Some(ms) = text.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(s) = text.strip_suffix('s') {
+        (s, 1.0)
+    } else if let Some(m) = text.strip_suffix('m') {
+        (m, 60.0)
+    } else if let Some(h) = text.strip_suffix('h') {
+        (h, 3600.0)
+    } else if let Some(d) = text.strip_suffix('d') {
+        (d, 86_400.0)
+    } else {
+        (text, 1.0)
+    };
+    let seconds = number.trim().parse::<f64>()? * scale;
+    if !(seconds.is_finite() && seconds > 0.0) {
+        return Err(format!("`{}` is not a positive duration", text).into());
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+// The (set, fields) requested from the device.
+pub type Formats = Vec<(u8, Vec<(u8, u16)>)>;
+
+struct Expected {
+    set: u8,
+    field: u8,
+    hz: f64,
+    seen: u64,
+}
+
+// Counts how often every requested field arrives, to hold against the rate
+// its decimation asks for.
+pub struct StreamCheck {
+    expected: Vec<Expected>,
+}
+
+impl StreamCheck {
+    pub fn new(formats: &Formats, base_rates: &HashMap<u8, f64>) -> Result<Self, Error> {
+        let mut expected = Vec::new();
+        for (set, fields) in formats {
+            let base = base_rates
+                .get(set)
+                .ok_or_else(|| format!("no base rate for {}", descriptors::describe_set(*set)))?;
+            for (field, decimation) in fields {
+                expected.push(Expected {
+                    set: *set,
+                    field: *field,
+                    hz: base / f64::from((*decimation).max(1)),
+                    seen: 0,
+                });
+            }
+        }
+        Ok(StreamCheck { expected })
+    }
+
+    pub fn packet(&mut self, packet: &Packet) {
+        let set = packet.header.descriptor;
+        for e in self.expected.iter_mut().filter(|e| e.set == set) {
+            if packet.payload.get_field(e.field).is_some() {
+                e.seen += 1;
+            }
+        }
+    }
+
+    // The pass/fail table and whether every field passed.
+    pub fn report(&self, elapsed: Duration) -> (String, bool) {
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let mut lines = vec![format!(
+            "{:<50} {:>10} {:>10}  {}",
+            "field", "expected", "measured", "result"
+        )];
+        let mut passed = true;
+
+        for e in &self.expected {
+            let measured = e.seen as f64 / seconds;
+            let ok = (measured - e.hz).abs() <= e.hz * TOLERANCE;
+            passed &= ok;
+            lines.push(format!(
+                "{:<50} {:>8.2}Hz {:>8.2}Hz  {}",
+                descriptors::describe_field(e.set, e.field),
+                e.hz,
+                measured,
+                if ok { "PASS" } else { "FAIL" }
+            ));
+        }
+
+        (lines.join("\n"), passed)
+    }
+}
@@ -0,0 +1,273 @@
+use crate::config::Data;
+use crate::fanout::Param;
+use crate::registry;
+use crate::session::GpsTime;
+use crate::Error;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How far, in milliseconds, the short-term offset may drift from the
+// long-term average before the device's time solution counts as wandering.
+pub const CLOCK_WANDER_ENV: &str = "LORDLOGGER_CLOCK_WANDER_MS";
+// The clock that fills each data table's `time` column: "gps", "host" or
+// "monotonic", optionally per table, e.g. "gps,gnss_data=host".
+pub const CLOCK_SOURCE_ENV: &str = "LORDLOGGER_CLOCK_SOURCE";
+
+const DEFAULT_WANDER_MS: f64 = 250.0;
+const FAST_ALPHA: f64 = 0.2;
+const SLOW_ALPHA: f64 = 0.01;
+const WARMUP_SAMPLES: u64 = 30;
+
+// 0x81/0x09 time flags: bit 0 is TOW valid, bit 1 is week number valid.
+pub const TIME_VALID: u16 = 0x03;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Wandering { drift: f64 },
+    Settled { drift: f64 },
+}
+
+impl Change {
+    pub fn message(self) -> String {
+        match self {
+            Change::Wandering { drift } => format!(
+                "device GPS time wandering {:+.1} ms against the host clock",
+                drift * 1000.0
+            ),
+            Change::Settled { drift } => format!(
+                "device GPS time settled at {:+.1} ms against the host clock",
+                drift * 1000.0
+            ),
+        }
+    }
+}
+
+// Tracks the offset between the host clock, assumed NTP-disciplined, and the
+// device's GPS time. Serial and USB latency add a roughly constant delay, so
+// what matters is the offset moving, not its absolute value: a fast average
+// is compared against a slow one.
+#[derive(Debug)]
+pub struct ClockMonitor {
+    threshold: f64,
+    fast: f64,
+    slow: f64,
+    samples: u64,
+    wandering: bool,
+}
+
+// Host minus device, in seconds.
+pub fn offset(time: GpsTime, received: SystemTime) -> f64 {
+    let host = received
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    host - time.unix_seconds()
+}
+
+impl ClockMonitor {
+    pub fn from_settings(data: &Data) -> Self {
+        ClockMonitor {
+            threshold: data.clock_wander_ms.unwrap_or(DEFAULT_WANDER_MS) / 1000.0,
+            fast: 0.0,
+            slow: 0.0,
+            samples: 0,
+            wandering: false,
+        }
+    }
+
+    pub fn update(&mut self, offset: f64) -> Option<Change> {
+        if self.samples == 0 {
+            self.fast = offset;
+            self.slow = offset;
+        } else {
+            self.fast += FAST_ALPHA * (offset - self.fast);
+            self.slow += SLOW_ALPHA * (offset - self.slow);
+        }
+        self.samples += 1;
+
+        if self.samples < WARMUP_SAMPLES {
+            return None;
+        }
+
+        let drift = self.fast - self.slow;
+        let wandering = drift.abs() > self.threshold;
+        if wandering == self.wandering {
+            return None;
+        }
+
+        self.wandering = wandering;
+        Some(if wandering {
+            Change::Wandering { drift }
+        } else {
+            Change::Settled { drift }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    Gps,
+    Host,
+    Monotonic,
+}
+
+impl ClockSource {
+    fn parse(name: &str) -> Result<Self, Error> {
+        match name {
+            "gps" => Ok(ClockSource::Gps),
+            "host" => Ok(ClockSource::Host),
+            "monotonic" => Ok(ClockSource::Monotonic),
+            other => Err(format!("unknown clock source {}", other).into()),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ClockSource::Gps => "gps",
+            ClockSource::Host => "host",
+            ClockSource::Monotonic => "monotonic",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClockSources {
+    default: ClockSource,
+    tables: HashMap<String, ClockSource>,
+}
+
+impl ClockSources {
+    // Also starts the monotonic clock, so call it when the logger starts.
+    pub fn from_settings(data: &Data) -> Result<Self, Error> {
+        started();
+        let mut sources = ClockSources {
+            default: ClockSource::Gps,
+            tables: HashMap::new(),
+        };
+
+        for entry in data.clock_source.iter().flatten().map(|e| e.trim()) {
+            match entry.split_once('=') {
+                Some((table, source)) => {
+                    sources
+                        .tables
+                        .insert(table.trim().to_string(), ClockSource::parse(source.trim())?);
+                }
+                None => sources.default = ClockSource::parse(entry)?,
+            }
+        }
+        Ok(sources)
+    }
+
+    pub fn source(&self, table: &str) -> ClockSource {
+        self.tables.get(table).copied().unwrap_or(self.default)
+    }
+}
+
+// Every timed data table carries all three clocks, with `time` a copy of the
+// configured one so queries can order by it whichever that is. The long and
+// JSON layouts keep their own GPS `time` column.
+pub const TIME_COLUMNS: [&str; 4] = ["time", "gps_time", "host_time", "monotonic_ns"];
+
+pub fn create_sql(table: &str) -> String {
+    format!(
+        "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS time timestamptz;
+         ALTER TABLE {table} ADD COLUMN IF NOT EXISTS gps_time timestamptz;
+         ALTER TABLE {table} ADD COLUMN IF NOT EXISTS host_time timestamptz;
+         ALTER TABLE {table} ADD COLUMN IF NOT EXISTS monotonic_ns bigint;
+         CREATE INDEX IF NOT EXISTS {table}_time_idx ON {table} (time);",
+        table = table
+    )
+}
+
+pub fn comments(table: &str, source: ClockSource) -> Vec<String> {
+    vec![
+        registry::comment_sql(
+            table,
+            "time",
+            &format!("row ordering time, from the {} clock", source.name()),
+        ),
+        registry::comment_sql(table, "gps_time", "device GPS time, leap seconds removed"),
+        registry::comment_sql(
+            table,
+            "host_time",
+            "host wall clock when the packet was read from the device",
+        ),
+        registry::comment_sql(
+            table,
+            "monotonic_ns",
+            "ns, host monotonic clock since the logger started, when the packet was read",
+        ),
+    ]
+}
+
+// The host monotonic clock reads as a time by anchoring it to the wall clock
+// once, so it never steps when NTP does.
+fn started() -> (Instant, SystemTime) {
+    static STARTED: OnceLock<(Instant, SystemTime)> = OnceLock::new();
+    *STARTED.get_or_init(|| (Instant::now(), SystemTime::now()))
+}
+
+thread_local! {
+    static RECEIVED: Cell<Option<Stamp>> = const { Cell::new(None) };
+}
+
+// Runs `decode` with the rows it makes stamped as received at `stamp`, so a
+// packet decoded later on a worker keeps the time it came off the port.
+pub fn received<T>(stamp: Stamp, decode: impl FnOnce() -> T) -> T {
+    // Put back even when the decode panics.
+    struct Restore(Option<Stamp>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            RECEIVED.with(|r| r.set(self.0));
+        }
+    }
+
+    let _restore = Restore(RECEIVED.with(|r| r.replace(Some(stamp))));
+    decode()
+}
+
+// The host clocks, read when a packet is received.
+#[derive(Debug, Clone, Copy)]
+pub struct Stamp {
+    host: SystemTime,
+    monotonic: Duration,
+}
+
+impl Stamp {
+    pub fn now() -> Self {
+        let (instant, _) = started();
+        Stamp {
+            host: SystemTime::now(),
+            monotonic: instant.elapsed(),
+        }
+    }
+
+    // The stamp of the packet being decoded, or now outside of one.
+    pub fn received() -> Self {
+        RECEIVED.with(Cell::get).unwrap_or_else(Stamp::now)
+    }
+
+    pub fn host(&self) -> SystemTime {
+        self.host
+    }
+
+    fn time(&self, source: ClockSource, gps: GpsTime) -> SystemTime {
+        match source {
+            ClockSource::Gps => gps.to_system_time(),
+            ClockSource::Host => self.host,
+            ClockSource::Monotonic => started().1 + self.monotonic,
+        }
+    }
+
+    // Parameters in `TIME_COLUMNS` order.
+    pub fn params(&self, source: ClockSource, gps: GpsTime) -> Vec<Param> {
+        vec![
+            Box::new(self.time(source, gps)),
+            Box::new(gps.to_system_time()),
+            Box::new(self.host),
+            Box::new(self.monotonic.as_nanos() as i64),
+        ]
+    }
+}
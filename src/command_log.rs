@@ -0,0 +1,112 @@
+// Every control action taken during a session, in the order it was taken, so
+// what was done to the device and the logger can be audited afterwards or sent
+// again to another run. Commands are stored as the control socket's own lines,
+// which `Command::parse` reads back.
+use crate::control::{self, Command};
+use crate::session::Session;
+use crate::Error;
+use postgres::Client;
+use std::path::Path;
+
+pub const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS command_log (
+        id BIGSERIAL PRIMARY KEY,
+        session_id integer NOT NULL REFERENCES sessions(id),
+        seq integer NOT NULL,
+        issued_at timestamptz NOT NULL DEFAULT now(),
+        tow double precision,
+        week smallint,
+        source text NOT NULL,
+        command text NOT NULL,
+        outcome text NOT NULL,
+        UNIQUE (session_id, seq)
+    );
+
+    COMMENT ON TABLE command_log IS 'Control actions taken during each session, in order';
+    COMMENT ON COLUMN command_log.seq IS 'order within the session, from 1';
+    COMMENT ON COLUMN command_log.source IS
+        'control for the control socket, scheduler for scheduled device commands';
+    COMMENT ON COLUMN command_log.command IS 'the command as a control socket line';
+    COMMENT ON COLUMN command_log.outcome IS 'ok, or why it failed';
+";
+
+// Where a command came from.
+pub const CONTROL: &str = "control";
+pub const SCHEDULER: &str = "scheduler";
+
+#[derive(Debug)]
+pub struct Entry {
+    pub seq: i32,
+    // UTC, e.g. 2024-05-01 12:00:00.000
+    pub issued_at: String,
+    pub source: String,
+    pub command: String,
+    pub outcome: String,
+}
+
+fn outcome(result: &Result<(), Error>) -> String {
+    match result {
+        Ok(()) => "ok".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+pub fn record(
+    c: &mut Client,
+    session: &Session,
+    source: &str,
+    command: &str,
+    result: &Result<(), Error>,
+) -> Result<(), Error> {
+    let tow = session.gps_time.map(|t| t.tow);
+    let week = session.gps_time.map(|t| t.week);
+    c.execute(
+        "INSERT INTO command_log (session_id, seq, tow, week, source, command, outcome)
+         SELECT $1, coalesce(max(seq), 0) + 1, $2, $3, $4, $5, $6
+         FROM command_log WHERE session_id = $1",
+        &[
+            &session.id,
+            &tow,
+            &week,
+            &source,
+            &command.trim_end(),
+            &outcome(result),
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn load(c: &mut Client, session: i32) -> Result<Vec<Entry>, Error> {
+    let rows = c.query(
+        "SELECT seq, to_char(issued_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS.MS'),
+                source, command, outcome
+         FROM command_log WHERE session_id = $1 ORDER BY seq",
+        &[&session],
+    )?;
+    Ok(rows
+        .iter()
+        .map(|row| Entry {
+            seq: row.get(0),
+            issued_at: row.get(1),
+            source: row.get(2),
+            command: row.get(3),
+            outcome: row.get(4),
+        })
+        .collect())
+}
+
+// Sends a session's control commands that succeeded to the running logger, in
+// their original order. Returns how many were sent.
+pub fn replay(entries: &[Entry], socket: &Path) -> Result<usize, Error> {
+    let mut sent = 0;
+    for entry in entries
+        .iter()
+        .filter(|e| e.source == CONTROL && e.outcome == "ok")
+    {
+        let command = Command::parse(&entry.command)
+            .map_err(|e| format!("command {} `{}`: {}", entry.seq, entry.command, e))?;
+        control::send(socket, &command)?;
+        sent += 1;
+    }
+    Ok(sent)
+}
@@ -0,0 +1,142 @@
+use crate::Error;
+use std::fs::{self, Permissions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use tracing::warn;
+
+// Where the control socket is, by default lordlogger.sock in
+// $XDG_RUNTIME_DIR, or in /run/lordlogger for a logger run as a service.
+pub const CONTROL_SOCKET_ENV: &str = "LORDLOGGER_CONTROL_SOCKET";
+const SYSTEM_DIR: &str = "/run/lordlogger";
+const SOCKET_NAME: &str = "lordlogger.sock";
+
+// `configured` when there is one.
+pub fn socket_path(configured: Option<&str>) -> PathBuf {
+    if let Some(path) = configured.filter(|p| !p.is_empty()) {
+        return PathBuf::from(path);
+    }
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(SYSTEM_DIR));
+    dir.join(SOCKET_NAME)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Annotate(String),
+    ResetFilter,
+    // degrees true
+    SetHeading(f32),
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let line = line.trim();
+        let (verb, rest) = match line.find(' ') {
+            Some(i) => (&line[..i], line[i + 1..].trim()),
+            None => (line, ""),
+        };
+
+        match verb {
+            "annotate" if !rest.is_empty() => Ok(Command::Annotate(rest.to_string())),
+            "annotate" => Err("annotate requires a note".into()),
+            "reset-filter" => Ok(Command::ResetFilter),
+            "heading" => match rest.parse::<f32>() {
+                Ok(degrees) if degrees.is_finite() => Ok(Command::SetHeading(degrees)),
+                _ => Err(format!("heading needs degrees, got `{}`", rest).into()),
+            },
+            _ => Err(format!("unknown command `{}`", verb).into()),
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        match self {
+            Command::Annotate(note) => format!("annotate {}\n", note.replace('\n', " ")),
+            Command::ResetFilter => "reset-filter\n".to_string(),
+            Command::SetHeading(degrees) => format!("heading {}\n", degrees),
+        }
+    }
+}
+
+fn serve(stream: UnixStream, commands: &Sender<Command>) -> Result<(), Error> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        let reply = match Command::parse(&line) {
+            Ok(command) => {
+                commands.send(command)?;
+                "ok".to_string()
+            }
+            Err(e) => format!("error: {}", e),
+        };
+        writeln!(writer, "{}", reply)?;
+        line.clear();
+    }
+
+    Ok(())
+}
+
+pub fn listen(path: &Path) -> Result<Receiver<Command>, Error> {
+    if let Some(dir) = path.parent().filter(|d| !d.exists()) {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .map_err(|e| format!("creating {}: {}", dir.display(), e))?;
+    }
+    // A socket left behind by a logger that didn't shut down cleanly would
+    // otherwise make bind fail with AddrInUse. Only a socket nothing answers
+    // on is removed; anything else at the path is left for bind to refuse.
+    let is_socket = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    if is_socket {
+        match UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(format!("another lordlogger is listening on {}", path.display()).into())
+            }
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+            Err(_) => (),
+        }
+    }
+
+    let listener =
+        UnixListener::bind(path).map_err(|e| format!("binding {}: {}", path.display(), e))?;
+    // Commands annotate the session and reset the filter, so only the
+    // logger's own user may send them.
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(Error::from)
+                .and_then(|stream| serve(stream, &tx));
+
+            if let Err(e) = result {
+                warn!("Control connection failed. Error: {}", e);
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+pub fn send(path: &Path, command: &Command) -> Result<(), Error> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| format!("is lordlogger running? ({}: {})", path.display(), e))?;
+    stream.write_all(command.to_line().as_bytes())?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+
+    match reply.trim() {
+        "ok" => Ok(()),
+        other => Err(other.to_string().into()),
+    }
+}
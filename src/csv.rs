@@ -0,0 +1,251 @@
+// Flat files for people who'd rather load the data into MATLAB or pandas than
+// run a database: a target whose URL is `csv:<dir>` writes each table it's sent
+// to `<dir>/<table>.csv`, one line per row, and `csv:-` writes to stdout.
+//
+// Columns are named after the table's, with composite ones split out, e.g.
+// accel_x, accel_y, accel_z and quat_q0 .. quat_q3. Times are seconds since the
+// Unix epoch, booleans 1 and 0, and missing values empty.
+use crate::fanout::{self, FlatSink, Tables, TargetConfig};
+use crate::jsonl;
+use crate::spool::Encoded;
+use crate::Error;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub const SCHEME: &str = "csv:";
+pub const STDOUT: &str = "-";
+
+// The directory a `csv:` URL names, None for any other URL.
+pub fn dir(url: &str) -> Option<&Path> {
+    url.strip_prefix(SCHEME).map(Path::new)
+}
+
+// `csv:-`, the rows written to stdout instead, a header before each run of a
+// table's rows. Best sent a single table.
+pub fn is_stdout(url: &str) -> bool {
+    url.strip_prefix(SCHEME) == Some(STDOUT)
+}
+
+// The target `--csv-dir` adds.
+pub fn target(dir: &Path) -> TargetConfig {
+    TargetConfig {
+        url: format!("{}{}", SCHEME, dir.display()),
+        tables: Tables::Only(
+            ["imu_data", "gnss_data"]
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+        ),
+    }
+}
+
+// Each CSV column and the parameter it's read from, 0-based.
+pub type Layout = Vec<(String, usize)>;
+
+fn suffixes(width: usize) -> Vec<String> {
+    match width {
+        3 => ["x", "y", "z"].iter().map(|s| s.to_string()).collect(),
+        4 => ["q0", "q1", "q2", "q3"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+        _ => (0..width).map(|i| i.to_string()).collect(),
+    }
+}
+
+// Splits at the commas outside parentheses.
+fn split_top(list: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in list.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(list[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(list[start..].trim());
+    parts
+}
+
+// The columns of an `INSERT INTO t (...) VALUES (...)`, or None for a
+// statement of another shape. A value built from several parameters, like
+// ROW($1, $2, $3), becomes a column for each.
+pub fn layout(sql: &str) -> Option<Layout> {
+    let (head, tuple) = fanout::split_values(sql)?;
+    let head = head[..head.len() - "VALUES".len()].trim_end();
+    let columns = &head[head.find('(')? + 1..head.rfind(')')?];
+    let values = split_top(&tuple[1..tuple.len() - 1]);
+    let columns = split_top(columns);
+    if columns.len() != values.len() {
+        return None;
+    }
+
+    let mut layout = Vec::new();
+    for (column, value) in columns.iter().zip(values) {
+        let params = RefCell::new(Vec::new());
+        fanout::replace_placeholders(value, |n| {
+            if !params.borrow().contains(&n) {
+                params.borrow_mut().push(n);
+            }
+            String::new()
+        });
+        let params = params.into_inner();
+        match params.len() {
+            0 => (),
+            1 => layout.push((column.to_string(), params[0] - 1)),
+            width => {
+                for (suffix, n) in suffixes(width).into_iter().zip(params) {
+                    layout.push((format!("{}_{}", column, suffix), n - 1));
+                }
+            }
+        }
+    }
+    Some(layout)
+}
+
+fn quote(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+pub fn field(value: &Encoded) -> String {
+    match value {
+        Encoded::Null => String::new(),
+        Encoded::Bool(v) => (*v as u8).to_string(),
+        Encoded::I16(v) => v.to_string(),
+        Encoded::I32(v) => v.to_string(),
+        Encoded::I64(v) => v.to_string(),
+        Encoded::F32(v) => f32::from_bits(*v).to_string(),
+        Encoded::F64(v) => f64::from_bits(*v).to_string(),
+        Encoded::Text(v) => quote(v),
+        Encoded::Time(secs, nanos) => format!("{}.{:09}", secs, nanos),
+        Encoded::Json(v) => quote(&v.to_string()),
+        Encoded::TextArray(v) => quote(&v.join(";")),
+        Encoded::F64Array(v) => {
+            let values: Vec<String> = v.iter().map(|v| f64::from_bits(*v).to_string()).collect();
+            quote(&values.join(";"))
+        }
+        Encoded::Bytes(v) => v.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+// The open files of one directory, one per table and header. A file left by
+// an earlier run is appended to when its header matches, otherwise the next
+// free `<table>.<n>.csv` is used.
+pub struct CsvFiles {
+    dir: PathBuf,
+    files: HashMap<String, BufWriter<File>>,
+}
+
+fn first_line(path: &Path) -> Result<Option<String>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut line = String::new();
+    BufReader::new(File::open(path)?).read_line(&mut line)?;
+    Ok(Some(line.trim_end().to_string()).filter(|l| !l.is_empty()))
+}
+
+impl CsvFiles {
+    pub fn new(dir: &Path) -> Result<Self, Error> {
+        fs::create_dir_all(dir)?;
+        Ok(CsvFiles {
+            dir: dir.to_path_buf(),
+            files: HashMap::new(),
+        })
+    }
+
+    fn open(&self, table: &str, header: &str) -> Result<BufWriter<File>, Error> {
+        for n in 0.. {
+            let name = match n {
+                0 => format!("{}.csv", table),
+                n => format!("{}.{}.csv", table, n),
+            };
+            let path = self.dir.join(name);
+            let existing = first_line(&path)?;
+            if existing.as_deref().is_some_and(|line| line != header) {
+                continue;
+            }
+            let mut file =
+                BufWriter::new(OpenOptions::new().create(true).append(true).open(&path)?);
+            if existing.is_none() {
+                writeln!(file, "{}", header)?;
+            }
+            return Ok(file);
+        }
+        unreachable!()
+    }
+}
+
+impl FlatSink for CsvFiles {
+    fn write(&mut self, table: &str, layout: &Layout, params: &[Encoded]) -> Result<(), Error> {
+        let header: Vec<&str> = layout.iter().map(|(column, _)| column.as_str()).collect();
+        let key = format!("{}\n{}", table, header.join(","));
+        if !self.files.contains_key(&key) {
+            let file = self.open(table, &header.join(","))?;
+            self.files.insert(key.clone(), file);
+        }
+
+        let line: Vec<String> = layout
+            .iter()
+            .map(|(_, i)| params.get(*i).map_or(String::new(), field))
+            .collect();
+        writeln!(self.files.get_mut(&key).unwrap(), "{}", line.join(","))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        for file in self.files.values_mut() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+pub struct CsvStdout {
+    out: BufWriter<File>,
+    header: String,
+}
+
+impl CsvStdout {
+    pub fn new() -> Result<Self, Error> {
+        Ok(CsvStdout {
+            out: BufWriter::new(jsonl::stdout()?),
+            header: String::new(),
+        })
+    }
+}
+
+impl FlatSink for CsvStdout {
+    fn write(&mut self, _: &str, layout: &Layout, params: &[Encoded]) -> Result<(), Error> {
+        let header: Vec<&str> = layout.iter().map(|(column, _)| column.as_str()).collect();
+        let header = header.join(",");
+        if header != self.header {
+            writeln!(self.out, "{}", header)?;
+            self.header = header;
+        }
+        let line: Vec<String> = layout
+            .iter()
+            .map(|(_, i)| params.get(*i).map_or(String::new(), field))
+            .collect();
+        writeln!(self.out, "{}", line.join(","))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
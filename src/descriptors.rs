@@ -0,0 +1,208 @@
+use crate::shared;
+use crate::Error;
+use lordserial::Packet;
+use std::convert::TryFrom;
+
+// A field-less `#[repr(u8)]` enum with its display name, convertible to its
+// wire value and back from it.
+macro_rules! descriptor_enum {
+    ($name:ident, $what:expr, { $($variant:ident = $value:expr => $text:expr,)* }) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[repr(u8)]
+        pub enum $name {
+            $($variant = $value,)*
+        }
+
+        impl $name {
+            pub fn name(self) -> &'static str {
+                match self {
+                    $($name::$variant => $text,)*
+                }
+            }
+        }
+
+        impl From<$name> for u8 {
+            fn from(descriptor: $name) -> u8 {
+                descriptor as u8
+            }
+        }
+
+        impl TryFrom<u8> for $name {
+            type Error = Error;
+
+            fn try_from(value: u8) -> Result<Self, Error> {
+                match value {
+                    $(v if v == $value => Ok($name::$variant),)*
+                    _ => Err(format!("unknown {} descriptor 0x{:02X}", $what, value).into()),
+                }
+            }
+        }
+    };
+}
+
+descriptor_enum!(CommandDescriptor, "command set", {
+    Base = 0x01 => "Base Command",
+    ThreeDm = 0x0C => "3DM Command",
+    Filter = 0x0D => "Filter Command",
+});
+
+descriptor_enum!(DataDescriptor, "data set", {
+    Imu = 0x80 => "IMU",
+    Gnss = 0x81 => "GNSS",
+    Filter = 0x82 => "Filter",
+});
+
+descriptor_enum!(ImuField, "IMU field", {
+    RawAccel = 0x01 => "Raw Accelerometer",
+    RawGyro = 0x02 => "Raw Gyro",
+    RawMag = 0x03 => "Raw Magnetometer",
+    ScaledAccel = 0x04 => "Scaled Accelerometer",
+    ScaledGyro = 0x05 => "Scaled Gyro",
+    ScaledMag = 0x06 => "Scaled Magnetometer",
+    DeltaTheta = 0x07 => "Delta Theta",
+    DeltaVelocity = 0x08 => "Delta Velocity",
+    OrientationMatrix = 0x09 => "Orientation Matrix",
+    Quaternion = 0x0A => "Quaternion",
+    EulerAngles = 0x0C => "Euler Angles",
+    InternalTimestamp = 0x0E => "Internal Timestamp",
+    StabilizedMag = 0x10 => "Stabilized Mag Vector",
+    StabilizedAccel = 0x11 => "Stabilized Accel Vector",
+    GpsTimestamp = 0x12 => "GPS Timestamp",
+    RawPressure = 0x16 => "Raw Pressure",
+    ScaledPressure = 0x17 => "Scaled Pressure",
+});
+
+descriptor_enum!(GnssField, "GNSS field", {
+    LlhPosition = 0x03 => "LLH Position",
+    EcefPosition = 0x04 => "ECEF Position",
+    NedVelocity = 0x05 => "NED Velocity",
+    EcefVelocity = 0x06 => "ECEF Velocity",
+    Dop = 0x07 => "DOP",
+    UtcTime = 0x08 => "UTC Time",
+    GpsTime = 0x09 => "GPS Time",
+    ClockInfo = 0x0A => "Clock Info",
+    FixInfo = 0x0B => "Fix Info",
+    SpaceVehicleInfo = 0x0C => "Space Vehicle Info",
+    HardwareStatus = 0x0D => "Hardware Status",
+});
+
+descriptor_enum!(FilterField, "filter field", {
+    LlhPosition = 0x01 => "LLH Position",
+    NedVelocity = 0x02 => "NED Velocity",
+    OrientationQuaternion = 0x03 => "Orientation Quaternion",
+    OrientationEuler = 0x05 => "Orientation Euler",
+    LlhUncertainty = 0x08 => "LLH Uncertainty",
+    NedVelocityUncertainty = 0x09 => "NED Velocity Uncertainty",
+    EulerUncertainty = 0x0A => "Euler Uncertainty",
+    FilterStatus = 0x10 => "Filter Status",
+    GpsTimestamp = 0x11 => "GPS Timestamp",
+    OdometerScaleFactorError = 0x47 => "Odometer Scale Factor Error",
+    OdometerScaleFactorUncertainty = 0x48 => "Odometer Scale Factor Uncertainty",
+});
+
+descriptor_enum!(SharedField, "shared field", {
+    EventSource = shared::EVENT_SOURCE => "Event Source",
+    Ticks = shared::TICKS => "Ticks",
+    DeltaTicks = shared::DELTA_TICKS => "Delta Ticks",
+    GpsTimestamp = shared::GPS_TIMESTAMP => "Shared GPS Timestamp",
+    DeltaTime = shared::DELTA_TIME => "Delta Time",
+    ReferenceTimestamp = shared::REFERENCE_TIMESTAMP => "Reference Timestamp",
+    DeltaReferenceTime = shared::DELTA_REFERENCE_TIME => "Delta Reference Time",
+});
+
+pub const ACK_NACK: u8 = 0xF1;
+
+// A field of a data set. The same byte means different things in each set,
+// so the set picks the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FieldDescriptor {
+    Imu(ImuField),
+    Gnss(GnssField),
+    Filter(FilterField),
+    Shared(DataDescriptor, SharedField),
+}
+
+impl FieldDescriptor {
+    pub fn set(self) -> DataDescriptor {
+        match self {
+            FieldDescriptor::Imu(_) => DataDescriptor::Imu,
+            FieldDescriptor::Gnss(_) => DataDescriptor::Gnss,
+            FieldDescriptor::Filter(_) => DataDescriptor::Filter,
+            FieldDescriptor::Shared(set, _) => set,
+        }
+    }
+
+    pub fn field(self) -> u8 {
+        match self {
+            FieldDescriptor::Imu(f) => f.into(),
+            FieldDescriptor::Gnss(f) => f.into(),
+            FieldDescriptor::Filter(f) => f.into(),
+            FieldDescriptor::Shared(_, f) => f.into(),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FieldDescriptor::Imu(f) => f.name(),
+            FieldDescriptor::Gnss(f) => f.name(),
+            FieldDescriptor::Filter(f) => f.name(),
+            FieldDescriptor::Shared(_, f) => f.name(),
+        }
+    }
+}
+
+impl TryFrom<(u8, u8)> for FieldDescriptor {
+    type Error = Error;
+
+    fn try_from((set, field): (u8, u8)) -> Result<Self, Error> {
+        let set = DataDescriptor::try_from(set)?;
+        if let Ok(shared) = SharedField::try_from(field) {
+            return Ok(FieldDescriptor::Shared(set, shared));
+        }
+
+        Ok(match set {
+            DataDescriptor::Imu => FieldDescriptor::Imu(ImuField::try_from(field)?),
+            DataDescriptor::Gnss => FieldDescriptor::Gnss(GnssField::try_from(field)?),
+            DataDescriptor::Filter => FieldDescriptor::Filter(FilterField::try_from(field)?),
+        })
+    }
+}
+
+// The data set a packet belongs to, None for command replies and sets this
+// logger doesn't know.
+pub fn data_set(packet: &Packet) -> Option<DataDescriptor> {
+    DataDescriptor::try_from(packet.header.descriptor).ok()
+}
+
+pub fn set_name(set: u8) -> Option<&'static str> {
+    DataDescriptor::try_from(set)
+        .map(DataDescriptor::name)
+        .or_else(|_| CommandDescriptor::try_from(set).map(CommandDescriptor::name))
+        .ok()
+}
+
+pub fn field_name(set: u8, field: u8) -> Option<&'static str> {
+    if field == ACK_NACK {
+        return Some("ACK/NACK");
+    }
+
+    FieldDescriptor::try_from((set, field))
+        .map(FieldDescriptor::name)
+        .ok()
+}
+
+// "GNSS (0x81)"
+pub fn describe_set(set: u8) -> String {
+    format!("{} (0x{:02X})", set_name(set).unwrap_or("Unknown"), set)
+}
+
+// "GNSS LLH Position (0x81/0x03)"
+pub fn describe_field(set: u8, field: u8) -> String {
+    format!(
+        "{} {} (0x{:02X}/0x{:02X})",
+        set_name(set).unwrap_or("Unknown"),
+        field_name(set, field).unwrap_or("Unknown Field"),
+        set,
+        field
+    )
+}
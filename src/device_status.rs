@@ -0,0 +1,219 @@
+// The sensor's own diagnostics, polled every so often with 3DM Device Status
+// (0x0C/0x64) and logged to device_status, for telling a bad dataset's cause
+// apart from the data afterwards.
+//
+// The command names the model it's meant for and the device NACKs any other,
+// so polling is off until the model number is set.
+use crate::clock::Stamp;
+use crate::config::Status;
+use crate::descriptors::CommandDescriptor;
+use crate::fanout::{FanOut, Row};
+use crate::mip::CommandPort;
+use crate::registry::Read;
+use crate::Error;
+use lordserial::{Field, Packet};
+use std::time::{Duration, Instant};
+
+// Model number the device reports, e.g. 6237 for the 3DM-GX4-45.
+pub const STATUS_MODEL_ENV: &str = "LORDLOGGER_STATUS_MODEL";
+// Seconds between polls, 10 by default.
+pub const STATUS_INTERVAL_ENV: &str = "LORDLOGGER_STATUS_INTERVAL_S";
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+const DEVICE_STATUS: u8 = 0x64;
+const DIAGNOSTIC: u8 = 0x02;
+// Reply field carrying the status
+const STATUS_REPLY: u8 = 0x90;
+
+// Base command set (0x01) built-in test and its reply, a u32 of failure bits.
+const BUILT_IN_TEST: u8 = 0x05;
+const BIT_REPLY: u8 = 0x83;
+
+pub const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS device_status (
+        id BIGSERIAL PRIMARY KEY,
+        session_id integer,
+        received_at timestamptz NOT NULL,
+        model smallint NOT NULL,
+        status_flags bigint NOT NULL,
+        system_state smallint NOT NULL,
+        uptime_ms bigint NOT NULL,
+        gps_power_on boolean NOT NULL,
+        pps_count bigint NOT NULL,
+        imu_dropped bigint NOT NULL,
+        gnss_dropped bigint NOT NULL,
+        filter_dropped bigint NOT NULL,
+        com_bytes_written bigint NOT NULL,
+        com_bytes_read bigint NOT NULL,
+        com_write_overruns bigint NOT NULL,
+        com_read_overruns bigint NOT NULL,
+        imu_parser_errors bigint NOT NULL,
+        gnss_parser_errors bigint NOT NULL,
+        raw bytea NOT NULL
+    );
+
+    COMMENT ON TABLE device_status IS
+        'Polled diagnostics. Source: 3DM Device Status (0x0C/0x64), diagnostic selector';
+    COMMENT ON COLUMN device_status.session_id IS 'sessions.id of the primary database';
+    COMMENT ON COLUMN device_status.uptime_ms IS 'ms since the device powered on';
+    COMMENT ON COLUMN device_status.com_write_overruns IS
+        'output bytes the device dropped because the port was too slow for the data rate';
+    COMMENT ON COLUMN device_status.raw IS
+        'whole reply, for fields past the common layout that only some models send';
+";
+
+// Diagnostic reply offsets common to the GX4 and GX5 families.
+const MODEL_AT: usize = 0;
+const FLAGS_AT: usize = 3;
+const STATE_AT: usize = 7;
+const TIMER_AT: usize = 9;
+const GPS_POWER_AT: usize = 13;
+const PPS_COUNT_AT: usize = 14;
+const DROPPED_AT: usize = 25;
+const COM_AT: usize = 37;
+const IMU_PARSER_ERRORS_AT: usize = 53;
+const GNSS_PARSER_ERRORS_AT: usize = 65;
+
+#[derive(Debug, Clone)]
+pub struct DeviceStatus {
+    pub model: u16,
+    pub flags: u32,
+    pub system_state: u16,
+    pub uptime_ms: u32,
+    pub gps_power_on: bool,
+    pub pps_count: u32,
+    pub dropped: [u32; 3],
+    pub com: [u32; 4],
+    pub imu_parser_errors: u32,
+    pub gnss_parser_errors: u32,
+    pub raw: Vec<u8>,
+}
+
+impl DeviceStatus {
+    pub fn extract(field: &Field) -> Result<Self, Error> {
+        let counts = |offset: usize| -> Result<[u32; 4], Error> {
+            Ok([
+                field.read(offset)?,
+                field.read(offset + 4)?,
+                field.read(offset + 8)?,
+                field.read(offset + 12)?,
+            ])
+        };
+        let dropped = counts(DROPPED_AT)?;
+        Ok(DeviceStatus {
+            model: field.read(MODEL_AT)?,
+            flags: field.read(FLAGS_AT)?,
+            system_state: field.read(STATE_AT)?,
+            uptime_ms: field.read(TIMER_AT)?,
+            gps_power_on: field.read::<u8>(GPS_POWER_AT)? != 0,
+            pps_count: field.read(PPS_COUNT_AT)?,
+            dropped: [dropped[0], dropped[1], dropped[2]],
+            com: counts(COM_AT)?,
+            imu_parser_errors: field.read(IMU_PARSER_ERRORS_AT)?,
+            gnss_parser_errors: field.read(GNSS_PARSER_ERRORS_AT)?,
+            raw: (0..).map_while(|i| field.read::<u8>(i).ok()).collect(),
+        })
+    }
+
+    pub fn from_packet(packet: &Packet) -> Result<Option<Self>, Error> {
+        if packet.header.descriptor != u8::from(CommandDescriptor::ThreeDm) {
+            return Ok(None);
+        }
+        packet
+            .payload
+            .get_field(STATUS_REPLY)
+            .map(DeviceStatus::extract)
+            .transpose()
+    }
+
+    pub fn insert(&self, out: &FanOut, session: i32) {
+        let count = |n: u32| Box::new(n as i64);
+        out.send(Row::new(
+            "INSERT INTO device_status (
+                session_id, received_at, model, status_flags, system_state, uptime_ms,
+                gps_power_on, pps_count, imu_dropped, gnss_dropped, filter_dropped,
+                com_bytes_written, com_bytes_read, com_write_overruns, com_read_overruns,
+                imu_parser_errors, gnss_parser_errors, raw
+            ) VALUES (
+                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18
+            )",
+            vec![
+                Box::new(session),
+                Box::new(Stamp::received().host()),
+                Box::new(self.model as i16),
+                count(self.flags),
+                Box::new(self.system_state as i16),
+                count(self.uptime_ms),
+                Box::new(self.gps_power_on),
+                count(self.pps_count),
+                count(self.dropped[0]),
+                count(self.dropped[1]),
+                count(self.dropped[2]),
+                count(self.com[0]),
+                count(self.com[1]),
+                count(self.com[2]),
+                count(self.com[3]),
+                count(self.imu_parser_errors),
+                count(self.gnss_parser_errors),
+                Box::new(self.raw.clone()),
+            ],
+        ));
+    }
+}
+
+pub struct StatusPoll {
+    model: u16,
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl StatusPoll {
+    pub fn from_settings(status: &Status) -> Result<Option<Self>, Error> {
+        let model = match status.model {
+            Some(model) => model,
+            None => return Ok(None),
+        };
+        let interval = match status.interval_s {
+            Some(secs) => Duration::try_from_secs_f64(secs)?,
+            None => DEFAULT_INTERVAL,
+        };
+        if interval.is_zero() {
+            return Err("the status interval must be more than 0".into());
+        }
+        Ok(Some(StatusPoll {
+            model,
+            interval,
+            last: None,
+        }))
+    }
+
+    // Asks for the status when the interval is up. The reply comes back on
+    // the data stream.
+    pub fn poll(&mut self, port: &mut CommandPort) -> Result<(), Error> {
+        if self.last.is_some_and(|t| t.elapsed() < self.interval) {
+            return Ok(());
+        }
+        self.last = Some(Instant::now());
+        let mut data = self.model.to_be_bytes().to_vec();
+        data.push(DIAGNOSTIC);
+        port.send(CommandDescriptor::ThreeDm, DEVICE_STATUS, &data)
+    }
+}
+
+// Starts the device's built-in test. The result comes back on the data stream.
+pub fn built_in_test(port: &mut CommandPort) -> Result<(), Error> {
+    port.send(CommandDescriptor::Base, BUILT_IN_TEST, &[])
+}
+
+// The failure bits of a built-in test reply, 0 when it passed.
+pub fn bit_result(packet: &Packet) -> Result<Option<u32>, Error> {
+    if packet.header.descriptor != u8::from(CommandDescriptor::Base) {
+        return Ok(None);
+    }
+    packet
+        .payload
+        .get_field(BIT_REPLY)
+        .map(|field| field.read::<u32>(0))
+        .transpose()
+}
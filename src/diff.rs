@@ -0,0 +1,118 @@
+// Side-by-side comparison of two recordings, e.g. two IMUs on the same rig,
+// for unit acceptance. Both sessions' IMU rows are averaged into bins of one
+// step on their time, and the bins both have are compared channel by channel:
+//   bias         mean of b - a
+//   scale        least-squares slope of b against a, 1 when they agree
+//   rms          root mean square of b - a
+//   correlation  of b with a
+// Recordings made at different times can be lined up by shifting b.
+use crate::session::GPS_TIME_SQL;
+use crate::Error;
+use postgres::Client;
+use std::time::Duration;
+
+pub const DEFAULT_STEP: &str = "10ms";
+
+// Name and SQL expression of each compared channel.
+const CHANNELS: [(&str, &str); 10] = [
+    ("accel_x", "(accel).x"),
+    ("accel_y", "(accel).y"),
+    ("accel_z", "(accel).z"),
+    ("gyro_x", "(gyro).x"),
+    ("gyro_y", "(gyro).y"),
+    ("gyro_z", "(gyro).z"),
+    ("mag_x", "(mag).x"),
+    ("mag_y", "(mag).y"),
+    ("mag_z", "(mag).z"),
+    ("baro", "baro"),
+];
+
+#[derive(Debug, Clone)]
+pub struct ChannelDiff {
+    pub channel: &'static str,
+    pub samples: i64,
+    pub bias: Option<f64>,
+    pub scale: Option<f64>,
+    pub rms: Option<f64>,
+    pub correlation: Option<f64>,
+}
+
+// One session's channels averaged per bin, b's times moved by `shift`.
+fn binned(session: i32, step: f64, shift: f64) -> String {
+    let averages: Vec<String> = CHANNELS
+        .iter()
+        .map(|(name, expr)| format!("avg(({})::float8) AS {}", expr, name))
+        .collect();
+    format!(
+        "SELECT floor((extract(epoch FROM coalesce(time, {gps})) + {shift}) / {step}) AS bin, {averages}
+         FROM imu_data WHERE session_id = {session} GROUP BY bin",
+        gps = GPS_TIME_SQL,
+        shift = shift,
+        step = step,
+        averages = averages.join(", "),
+        session = session
+    )
+}
+
+pub fn compare(
+    c: &mut Client,
+    a: i32,
+    b: i32,
+    step: Duration,
+    shift: f64,
+) -> Result<Vec<ChannelDiff>, Error> {
+    for session in &[a, b] {
+        let rows: i64 = c
+            .query_one(
+                "SELECT count(*) FROM imu_data WHERE session_id = $1",
+                &[session],
+            )?
+            .get(0);
+        if rows == 0 {
+            return Err(format!("session {} has no IMU data", session).into());
+        }
+    }
+
+    let stats: Vec<String> = CHANNELS
+        .iter()
+        .map(|(name, _)| {
+            format!(
+                "regr_count(b.{c}, a.{c}), avg(b.{c} - a.{c}), regr_slope(b.{c}, a.{c}),
+                 sqrt(avg((b.{c} - a.{c}) ^ 2)), corr(b.{c}, a.{c})",
+                c = name
+            )
+        })
+        .collect();
+    let step = step.as_secs_f64();
+    let row = c.query_one(
+        format!(
+            "SELECT {stats} FROM ({a}) a JOIN ({b}) b USING (bin)",
+            stats = stats.join(", "),
+            a = binned(a, step, 0.0),
+            b = binned(b, step, shift)
+        )
+        .as_str(),
+        &[],
+    )?;
+
+    let diffs: Vec<ChannelDiff> = CHANNELS
+        .iter()
+        .enumerate()
+        .map(|(i, (channel, _))| ChannelDiff {
+            channel,
+            samples: row.get(i * 5),
+            bias: row.get(i * 5 + 1),
+            scale: row.get(i * 5 + 2),
+            rms: row.get(i * 5 + 3),
+            correlation: row.get(i * 5 + 4),
+        })
+        .collect();
+    if diffs.iter().all(|d| d.samples == 0) {
+        return Err(format!(
+            "sessions {} and {} don't overlap in time; shift b to line them up",
+            a, b
+        )
+        .into());
+    }
+    Ok(diffs)
+}
@@ -0,0 +1,116 @@
+// Hex dumps of chosen fields as they arrive, at most so often each, for
+// checking offsets and scaling on a live system without a full capture.
+use crate::descriptors;
+use crate::Error;
+use lordserial::{Field, Packet};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct DumpSpec {
+    pub set: u8,
+    pub field: u8,
+    pub interval: Duration,
+}
+
+fn byte(text: &str) -> Result<u8, Error> {
+    let text = text.trim();
+    Ok(match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16)?,
+        None => text.parse()?,
+    })
+}
+
+// "0x81:0x03@1Hz", "0x80:0x04@0.2hz" or "0x81:0x03" for once a second.
+pub fn parse(text: &str) -> Result<DumpSpec, Error> {
+    let (field, rate) = match text.split_once('@') {
+        Some((field, rate)) => (field, Some(rate)),
+        None => (text, None),
+    };
+    let (set, field) = field
+        .split_once(':')
+        .ok_or_else(|| format!("`{}` is not set:field[@rate]", text))?;
+
+    let interval = match rate {
+        Some(rate) => {
+            let rate = rate.trim();
+            let hz: f64 = rate
+                .strip_suffix("Hz")
+                .or(rate.strip_suffix("hz"))
+                .unwrap_or(rate)
+                .trim()
+                .parse()?;
+            if !(hz.is_finite() && hz > 0.0) {
+                return Err(format!("`{}` is not a positive rate", rate).into());
+            }
+            Duration::from_secs_f64(1.0 / hz)
+        }
+        None => DEFAULT_INTERVAL,
+    };
+
+    Ok(DumpSpec {
+        set: byte(set)?,
+        field: byte(field)?,
+        interval,
+    })
+}
+
+// The field's bytes, read one at a time so nothing depends on how lordserial
+// stores them.
+pub fn bytes(field: &Field) -> Vec<u8> {
+    (0..).map_while(|i| field.extract::<u8>(i).ok()).collect()
+}
+
+// Sixteen bytes a line, each line led by its offset.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, line)| {
+            let words: Vec<String> = line
+                .chunks(4)
+                .map(|word| {
+                    word.iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect();
+            format!("  {:04x}  {}", i * 16, words.join("  "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub struct RawDump {
+    specs: Vec<(DumpSpec, Option<Instant>)>,
+}
+
+impl RawDump {
+    pub fn new(specs: &[DumpSpec]) -> Self {
+        RawDump {
+            specs: specs.iter().map(|spec| (*spec, None)).collect(),
+        }
+    }
+
+    pub fn packet(&mut self, packet: &Packet) {
+        let set = packet.header.descriptor;
+        for (spec, last) in self.specs.iter_mut().filter(|(s, _)| s.set == set) {
+            if last.is_some_and(|t| t.elapsed() < spec.interval) {
+                continue;
+            }
+            if let Some(field) = packet.payload.get_field(spec.field) {
+                *last = Some(Instant::now());
+                let bytes = bytes(field);
+                info!(
+                    "Raw {} ({} bytes)\n{}",
+                    descriptors::describe_field(spec.set, spec.field),
+                    bytes.len(),
+                    hex(&bytes)
+                );
+            }
+        }
+    }
+}
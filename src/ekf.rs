@@ -0,0 +1,146 @@
+// The filter's navigation solution (0x82): where the device thinks it is,
+// how fast it's going and which way it points, blended from IMU and GNSS.
+use crate::descriptors::{self, DataDescriptor, FilterField};
+use crate::fanout::{FanOut, Row};
+use crate::registry::Read;
+use crate::session::GpsTime;
+use crate::types::{Quaternion, Vector3f};
+use crate::Error;
+use lordserial::{Field, Packet};
+
+pub const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS ekf_data (
+        id BIGSERIAL PRIMARY KEY,
+        tow double precision NOT NULL,
+        week smallint NOT NULL,
+        latitude double precision,
+        longitude double precision,
+        height double precision,
+        velocity_north real,
+        velocity_east real,
+        velocity_down real,
+        quat quaternion,
+        euler_angles real3d
+    );
+
+    COMMENT ON TABLE ekf_data IS 'Filter solution, NULL where the filter flagged a field invalid';
+    COMMENT ON COLUMN ekf_data.latitude IS 'deg. Source: Filter LLH Position (0x82/0x01)';
+    COMMENT ON COLUMN ekf_data.longitude IS 'deg. Source: Filter LLH Position (0x82/0x01)';
+    COMMENT ON COLUMN ekf_data.height IS
+        'm above the WGS84 ellipsoid. Source: Filter LLH Position (0x82/0x01)';
+    COMMENT ON COLUMN ekf_data.velocity_north IS
+        'm/s, NED frame. Source: Filter NED Velocity (0x82/0x02)';
+    COMMENT ON COLUMN ekf_data.quat IS
+        'NED to body. Source: Filter Orientation Quaternion (0x82/0x03)';
+    COMMENT ON COLUMN ekf_data.euler_angles IS
+        'rad, roll pitch yaw. Source: Filter Orientation Euler (0x82/0x05)';
+";
+
+// The fields that make up a row, logged at the filter rate.
+pub const FIELDS: [FilterField; 4] = [
+    FilterField::LlhPosition,
+    FilterField::NedVelocity,
+    FilterField::OrientationQuaternion,
+    FilterField::OrientationEuler,
+];
+
+#[derive(Debug)]
+pub struct EkfData {
+    pub llh: Option<[f64; 3]>,
+    pub ned_velocity: Option<Vector3f>,
+    pub quat: Option<Quaternion>,
+    pub euler_angles: Option<Vector3f>,
+}
+
+// Each field ends in a valid flag after its values.
+fn valid(field: &Field, offset: usize) -> Result<bool, Error> {
+    Ok(field.read::<u16>(offset)? & 0x01 == 0x01)
+}
+
+fn llh(field: &Field) -> Result<Option<[f64; 3]>, Error> {
+    if !valid(field, 24)? {
+        return Ok(None);
+    }
+    Ok(Some([field.read(0)?, field.read(8)?, field.read(16)?]))
+}
+
+impl EkfData {
+    // None unless the packet is filter data carrying at least one of FIELDS.
+    pub fn from_packet(packet: &Packet) -> Result<Option<Self>, Error> {
+        if descriptors::data_set(packet) != Some(DataDescriptor::Filter) {
+            return Ok(None);
+        }
+        let get = |f: FilterField| packet.payload.get_field(f.into());
+        if FIELDS.iter().all(|f| get(*f).is_none()) {
+            return Ok(None);
+        }
+
+        let vector = |field: Option<&Field>, valid_at| -> Result<Option<Vector3f>, Error> {
+            match field {
+                Some(field) if valid(field, valid_at)? => Ok(Some(Vector3f::extract(field)?)),
+                _ => Ok(None),
+            }
+        };
+        let quat = match get(FilterField::OrientationQuaternion) {
+            Some(field) if valid(field, 16)? => Some(Quaternion::extract(field)?),
+            _ => None,
+        };
+
+        Ok(Some(EkfData {
+            llh: get(FilterField::LlhPosition)
+                .map(llh)
+                .transpose()?
+                .flatten(),
+            ned_velocity: vector(get(FilterField::NedVelocity), 12)?,
+            quat,
+            euler_angles: vector(get(FilterField::OrientationEuler), 12)?,
+        }))
+    }
+}
+
+// Writes the packet's filter solution, if it has one.
+pub fn insert(out: &FanOut, packet: &Packet) -> Result<bool, Error> {
+    let (data, time) = match (EkfData::from_packet(packet)?, GpsTime::from_packet(packet)?) {
+        (Some(data), Some(time)) => (data, time),
+        _ => return Ok(false),
+    };
+    let llh = |i: usize| data.llh.map(|v| v[i]);
+    let velocity = data.ned_velocity.as_ref();
+    let quat = data.quat.as_ref();
+    let euler = data.euler_angles.as_ref();
+
+    out.send(Row::timed(
+        "INSERT INTO ekf_data (
+            tow, week,
+            latitude, longitude, height,
+            velocity_north, velocity_east, velocity_down,
+            quat, euler_angles
+        ) VALUES (
+            $1, $2,
+            $3, $4, $5,
+            $6, $7, $8,
+            CASE WHEN $9::real IS NULL THEN NULL ELSE ROW($9, $10, $11, $12)::quaternion END,
+            CASE WHEN $13::real IS NULL THEN NULL ELSE ROW($13, $14, $15)::real3d END
+        )",
+        vec![
+            Box::new(time.tow),
+            Box::new(time.week),
+            Box::new(llh(0)),
+            Box::new(llh(1)),
+            Box::new(llh(2)),
+            Box::new(velocity.map(|v| v.x)),
+            Box::new(velocity.map(|v| v.y)),
+            Box::new(velocity.map(|v| v.z)),
+            Box::new(quat.map(|q| q.q0)),
+            Box::new(quat.map(|q| q.q1)),
+            Box::new(quat.map(|q| q.q2)),
+            Box::new(quat.map(|q| q.q3)),
+            Box::new(euler.map(|v| v.x)),
+            Box::new(euler.map(|v| v.y)),
+            Box::new(euler.map(|v| v.z)),
+        ],
+        time,
+    ));
+
+    Ok(true)
+}
@@ -0,0 +1,95 @@
+// What a run was built with and running on, kept with its session so data
+// logged months apart by different builds and hosts can be told apart.
+use crate::session;
+use serde_json::{json, Map, Value};
+use std::ffi::CStr;
+use std::fs;
+use std::path::Path;
+
+fn uname() -> Option<libc::utsname> {
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } != 0 {
+        return None;
+    }
+    Some(name)
+}
+
+fn text(field: &[libc::c_char]) -> String {
+    unsafe { CStr::from_ptr(field.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+// PRETTY_NAME from os-release, e.g. "Ubuntu 22.04.3 LTS".
+fn os_release() -> Option<String> {
+    let release = fs::read_to_string("/etc/os-release").ok()?;
+    release
+        .lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|name| name.trim_matches('"').to_string())
+}
+
+// The kernel driver bound to a tty, e.g. "cdc_acm" or "ftdi_sio", following
+// symlinks such as /dev/lord0 to the device itself.
+fn serial_driver(port: &str) -> Option<String> {
+    let device = fs::canonicalize(port).ok()?;
+    let name = device.file_name()?.to_str()?;
+    let driver =
+        fs::read_link(Path::new("/sys/class/tty").join(name).join("device/driver")).ok()?;
+    Some(driver.file_name()?.to_string_lossy().into_owned())
+}
+
+fn libraries() -> Map<String, Value> {
+    option_env!("LORDLOGGER_LIBRARY_VERSIONS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, version)| (name.to_string(), version.into()))
+        .collect()
+}
+
+pub fn capture(port: &str) -> Value {
+    let uname = uname();
+    json!({
+        "version": session::version(),
+        "package_version": env!("CARGO_PKG_VERSION"),
+        "git": option_env!("LORDLOGGER_GIT_VERSION"),
+        "rustc": option_env!("LORDLOGGER_RUSTC_VERSION"),
+        "libraries": libraries(),
+        "os": os_release(),
+        "kernel": uname.as_ref().map(|u| format!("{} {}", text(&u.sysname), text(&u.release))),
+        "arch": std::env::consts::ARCH,
+        "hostname": uname.as_ref().map(|u| text(&u.nodename)),
+        "serial_driver": serial_driver(port),
+    })
+}
+
+// The environment as printed when logging starts, a line per entry.
+pub fn banner(environment: &Value) -> String {
+    let get = |key: &str| match environment.get(key) {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Null) | None => "unknown".to_string(),
+        Some(other) => other.to_string(),
+    };
+    let libraries = environment["libraries"]
+        .as_object()
+        .map(|libraries| {
+            libraries
+                .iter()
+                .map(|(name, version)| format!("{} {}", name, version.as_str().unwrap_or("?")))
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+
+    [
+        format!("lordlogger {}", get("version")),
+        format!("  built with  {}", get("rustc")),
+        format!("  libraries   {}", libraries),
+        format!("  host        {} ({})", get("hostname"), get("arch")),
+        format!("  os          {}", get("os")),
+        format!("  kernel      {}", get("kernel")),
+        format!("  driver      {}", get("serial_driver")),
+    ]
+    .join("\n")
+}
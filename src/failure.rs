@@ -0,0 +1,110 @@
+use crate::Error;
+use serde_json::json;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+pub const REPORT_ENV: &str = "LORDLOGGER_ERROR_REPORT";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    Other,
+    Config,
+    Serial,
+    Database,
+    DeviceNack,
+    Locked,
+    MissingData,
+}
+
+impl FailureKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            FailureKind::Other => 1,
+            FailureKind::Config => 2,
+            FailureKind::Serial => 3,
+            FailureKind::Database => 4,
+            FailureKind::DeviceNack => 5,
+            FailureKind::Locked => 6,
+            FailureKind::MissingData => 7,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FailureKind::Other => "other",
+            FailureKind::Config => "config",
+            FailureKind::Serial => "serial",
+            FailureKind::Database => "database",
+            FailureKind::DeviceNack => "device_nack",
+            FailureKind::Locked => "locked",
+            FailureKind::MissingData => "missing_data",
+        }
+    }
+
+    // Whether a supervisor can reasonably expect a restart to succeed without
+    // someone changing the configuration or the hardware first.
+    pub fn retryable(self) -> bool {
+        matches!(self, FailureKind::Serial | FailureKind::Database)
+    }
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub error: Error,
+}
+
+impl Failure {
+    pub fn new<E: Into<Error>>(kind: FailureKind, error: E) -> Self {
+        Failure {
+            kind,
+            error: error.into(),
+        }
+    }
+
+    pub fn write_report(&self, path: &str) -> std::io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let report = json!({
+            "kind": self.kind.name(),
+            "exit_code": self.kind.exit_code(),
+            "retryable": self.kind.retryable(),
+            "message": self.error.to_string(),
+            "time": time,
+        });
+
+        std::fs::write(path, report.to_string())
+    }
+
+    pub fn exit(self) -> ! {
+        error!("Fatal {} error: {}", self.kind.name(), self.error);
+
+        if let Ok(path) = std::env::var(REPORT_ENV) {
+            if let Err(e) = self.write_report(&path) {
+                error!("Failed to write error report to {}. Error: {}", path, e);
+            }
+        }
+
+        std::process::exit(self.kind.exit_code())
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} error: {}", self.kind.name(), self.error)
+    }
+}
+
+pub trait Context<T> {
+    fn or_fail(self, kind: FailureKind) -> Result<T, Failure>;
+}
+
+impl<T, E: Into<Error>> Context<T> for Result<T, E> {
+    fn or_fail(self, kind: FailureKind) -> Result<T, Failure> {
+        self.map_err(|e| Failure::new(kind, e))
+    }
+}
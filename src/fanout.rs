@@ -0,0 +1,1805 @@
+// Data-table writes fanned out to every configured Postgres target.
+//
+// Each target has its own connection, queue and writer task, so a slow or
+// unreachable remote only fills its own queue and never holds up the device
+// loop or the local database.
+use crate::bus::{self, SinkError};
+use crate::clock::{self, ClockSources, Stamp};
+use crate::config::Writes;
+use crate::csv::{self, CsvFiles, CsvStdout, Layout};
+use crate::influx::{self, Influx};
+use crate::jsonl::{self, JsonLines};
+use crate::mqtt::{self, Mqtt};
+use crate::parquet_files::{self, ParquetFiles};
+use crate::quota::{Exceeded, Quotas};
+use crate::runtime;
+use crate::session::GpsTime;
+use crate::spool::{Ack, Encoded, Spool, Spooled, Value, ACK_SQL};
+use crate::sqlite::{self, SqliteFile};
+use crate::tail::{self, TailSink};
+use crate::telemetry::{self, Span};
+use crate::vehicle;
+use crate::Error;
+use postgres::types::ToSql;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Notify;
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::{Client, Config, NoTls, Statement, Transaction};
+use tracing::{debug_span, error, info, warn, Instrument};
+
+// A target URL with this in front is a mirror, e.g. a staging database sent a
+// copy of everything to try schema changes on live data. It's sent every table
+// and never spools, and one that can't be opened is left out rather than
+// stopping the run.
+pub const MIRROR: &str = "mirror:";
+// Comma separated Postgres URLs written to in addition to the primary database.
+pub const EXTRA_DB_URLS_ENV: &str = "LORDLOGGER_EXTRA_DB_URLS";
+// A target's batch is written once it holds this many rows or its first row
+// has waited this long, whichever comes first.
+pub const BATCH_ROWS_ENV: &str = "LORDLOGGER_BATCH_ROWS";
+pub const BATCH_MS_ENV: &str = "LORDLOGGER_BATCH_MS";
+// "insert" (default) or "copy", which loads the high rate tables with binary
+// COPY instead of multi-row INSERTs.
+pub const INGEST_ENV: &str = "LORDLOGGER_INGEST";
+// How long a target may take to connect or to write one batch before the
+// attempt counts as failed and the connection is dropped, so a hung server
+// can't stall its writer forever.
+pub const SINK_TIMEOUT_ENV: &str = "LORDLOGGER_SINK_TIMEOUT_MS";
+// Rows each target holds in memory while its database is away, replayed in
+// order once it reconnects. Rows beyond this are dropped and counted.
+pub const QUEUE_ROWS_ENV: &str = "LORDLOGGER_QUEUE_ROWS";
+// Comma separated high-rate tables thinned out when a target falls behind,
+// leaving the rest of its queue to the low-rate tables. Default imu_data.
+pub const SHED_TABLES_ENV: &str = "LORDLOGGER_SHED_TABLES";
+// "auto" (default) writes each row of a table arriving slower than
+// LORDLOGGER_ROW_BELOW_HZ by itself as soon as it comes, over a second
+// connection, so GNSS fixes and the like don't wait behind IMU batches.
+// "batch" batches every table.
+pub const INSERT_MODE_ENV: &str = "LORDLOGGER_INSERT_MODE";
+pub const ROW_BELOW_HZ_ENV: &str = "LORDLOGGER_ROW_BELOW_HZ";
+// Comma separated tables always written a row at a time, in either mode.
+pub const ROW_TABLES_ENV: &str = "LORDLOGGER_ROW_TABLES";
+// Seconds stopping waits for the targets to write what they hold, 30 by
+// default. Whatever's left after goes to each target's spool, to be written
+// on the next run, or is dropped and counted for a target without one. A write
+// or connect already under way finishes or times out first, see
+// LORDLOGGER_SINK_TIMEOUT_MS.
+pub const FLUSH_DEADLINE_ENV: &str = "LORDLOGGER_FLUSH_DEADLINE_S";
+
+const QUEUE_ROWS: usize = 50_000;
+// A target's queue this full, as a fraction, starts shedding and half of it
+// stops shedding again. While shedding one row in SHED_KEEP_EVERY is kept.
+const SHED_AT: f64 = 0.75;
+const SHED_KEEP_EVERY: u64 = 10;
+const ROW_BELOW_HZ: f64 = 10.0;
+// How often each table's rate is measured again.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+const BATCH_ROWS: usize = 500;
+const BATCH_INTERVAL: Duration = Duration::from_millis(500);
+const SINK_TIMEOUT: Duration = Duration::from_secs(10);
+// Rows per multi-row INSERT, capped so the parameters stay under Postgres'
+// limit of 65535 per statement.
+const MULTI_ROW_MAX: usize = 64;
+const MAX_PARAMS: usize = 65_535;
+// Tables loaded with COPY in copy mode.
+const COPY_TABLES: &[&str] = &["imu_data", "gnss_data"];
+const RETRY_MIN: Duration = Duration::from_millis(500);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+const FLUSH_DEADLINE: Duration = Duration::from_secs(30);
+const FLUSH_PROGRESS: Duration = Duration::from_secs(1);
+const FLUSH_POLL: Duration = Duration::from_millis(50);
+
+pub type Param = Box<dyn Value>;
+
+// Run on every (re)connect before the first batch is written, over a blocking
+// connection of its own since the schema code is shared with the primary's.
+pub type Setup = Arc<dyn Fn(&mut postgres::Client) -> Result<(), Error> + Send + Sync>;
+
+pub struct Row {
+    sql: String,
+    table: String,
+    params: Vec<Param>,
+    time: Option<(GpsTime, Stamp)>,
+    // When the packet behind a timed row arrived.
+    received: Option<SystemTime>,
+}
+
+// The table an `INSERT INTO table ...` writes to.
+fn insert_table(sql: &str) -> String {
+    let mut words = sql.split_whitespace();
+    while let Some(word) = words.next() {
+        if word.eq_ignore_ascii_case("INTO") {
+            return words
+                .next()
+                .unwrap_or("")
+                .split('(')
+                .next()
+                .unwrap_or("")
+                .to_string();
+        }
+    }
+    String::new()
+}
+
+impl Row {
+    pub fn new<S: Into<String>>(sql: S, params: Vec<Param>) -> Self {
+        let sql = sql.into();
+        Row {
+            table: insert_table(&sql),
+            sql,
+            params,
+            time: None,
+            received: None,
+        }
+    }
+
+    // A row of a timed data table, sent with the `clock::TIME_COLUMNS`.
+    pub fn timed<S: Into<String>>(sql: S, params: Vec<Param>, time: GpsTime) -> Self {
+        let stamp = Stamp::received();
+        Row {
+            time: Some((time, stamp)),
+            received: Some(stamp.host()),
+            ..Row::new(sql, params)
+        }
+    }
+
+    // Appends the time columns, the session and the vehicle id when rows carry
+    // one to the INSERT, or leaves a statement that can't take them alone.
+    fn with_times(
+        mut self,
+        clocks: &ClockSources,
+        session: Option<i32>,
+        vehicle: Option<&str>,
+    ) -> Self {
+        let (gps, stamp) = match self.time.take() {
+            Some(time) => time,
+            None => return self,
+        };
+        let (head, tuple) = match split_values(&self.sql) {
+            Some(parts) => parts,
+            None => return self,
+        };
+        let columns = match head[..head.len() - "VALUES".len()]
+            .trim_end()
+            .strip_suffix(')')
+        {
+            Some(columns) => columns,
+            None => return self,
+        };
+
+        let mut added = clock::TIME_COLUMNS.to_vec();
+        self.params
+            .extend(stamp.params(clocks.source(&self.table), gps));
+        added.push("session_id");
+        self.params.push(Box::new(session));
+        if let Some(vehicle) = vehicle {
+            added.push(vehicle::COLUMN);
+            self.params.push(Box::new(vehicle.to_string()));
+        }
+
+        let first = self.params.len() - added.len() + 1;
+        let placeholders: Vec<String> = (first..first + added.len())
+            .map(|i| format!("${}", i))
+            .collect();
+        self.sql = format!(
+            "{}, {}) VALUES {}, {})",
+            columns,
+            added.join(", "),
+            &tuple[..tuple.len() - 1],
+            placeholders.join(", ")
+        );
+        self
+    }
+
+    fn params(&self) -> impl Iterator<Item = &(dyn ToSql + Sync)> {
+        self.params.iter().map(|p| p.as_sql())
+    }
+
+    fn spooled(&self) -> Spooled {
+        Spooled {
+            sql: self.sql.clone(),
+            params: self.params.iter().map(|p| p.encode()).collect(),
+            received: self.received,
+        }
+    }
+
+    fn from_spooled(spooled: Spooled) -> Self {
+        let params = spooled
+            .params
+            .into_iter()
+            .map(|p| Box::new(p) as Param)
+            .collect();
+        Row {
+            received: spooled.received,
+            ..Row::new(spooled.sql, params)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ingest {
+    Insert,
+    Copy,
+}
+
+impl Ingest {
+    fn from_settings(writes: &Writes) -> Result<Self, Error> {
+        match writes.ingest.as_deref() {
+            None | Some("insert") => Ok(Ingest::Insert),
+            Some("copy") => Ok(Ingest::Copy),
+            Some(other) => Err(format!("ingest must be insert or copy, not {}", other).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Batching {
+    pub rows: usize,
+    pub interval: Duration,
+    pub ingest: Ingest,
+    pub timeout: Duration,
+    pub queue: usize,
+    pub flush_deadline: Duration,
+}
+
+impl Batching {
+    pub fn from_settings(writes: &Writes) -> Result<Self, Error> {
+        let rows = writes.batch_rows.unwrap_or(BATCH_ROWS);
+        if rows == 0 {
+            return Err("batches must be at least 1 row".into());
+        }
+        let interval = writes
+            .batch_ms
+            .map_or(BATCH_INTERVAL, Duration::from_millis);
+
+        let timeout = writes
+            .sink_timeout_ms
+            .map_or(SINK_TIMEOUT, Duration::from_millis);
+        if timeout.is_zero() {
+            return Err("the sink timeout must be at least 1 ms".into());
+        }
+
+        let queue = writes.queue_rows.unwrap_or(QUEUE_ROWS);
+        if queue == 0 {
+            return Err("the queue must hold at least 1 row".into());
+        }
+
+        let flush_deadline = writes
+            .flush_deadline_s
+            .map_or(FLUSH_DEADLINE, Duration::from_secs);
+
+        Ok(Batching {
+            rows,
+            interval,
+            ingest: Ingest::from_settings(writes)?,
+            timeout,
+            queue,
+            flush_deadline,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Shedding {
+    tables: HashSet<String>,
+}
+
+impl Shedding {
+    pub fn from_settings(writes: &Writes) -> Self {
+        let tables = match &writes.shed_tables {
+            Some(tables) => tables.iter().map(|t| t.trim().to_string()).collect(),
+            None => std::iter::once("imu_data".to_string()).collect(),
+        };
+        Shedding { tables }
+    }
+
+    fn sheds(&self, table: &str) -> bool {
+        self.tables.contains(table)
+    }
+
+    fn names(&self) -> String {
+        let mut names: Vec<&str> = self.tables.iter().map(String::as_str).collect();
+        names.sort_unstable();
+        names.join(", ")
+    }
+}
+
+// A table's rows counted over the current window, and whether it was slow
+// enough for rows of their own over the last one. Every table starts batched.
+struct Meter {
+    since: Instant,
+    rows: u32,
+    per_row: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InsertMode {
+    Auto { below_hz: f64 },
+    Batch,
+}
+
+pub struct Inserts {
+    mode: InsertMode,
+    tables: HashSet<String>,
+    meters: Mutex<HashMap<String, Meter>>,
+}
+
+impl Inserts {
+    pub fn from_settings(writes: &Writes) -> Result<Self, Error> {
+        let mode = match writes.insert_mode.as_deref() {
+            None | Some("auto") => InsertMode::Auto {
+                below_hz: writes.row_below_hz.unwrap_or(ROW_BELOW_HZ),
+            },
+            Some("batch") => InsertMode::Batch,
+            Some(other) => {
+                return Err(format!("the insert mode must be auto or batch, not {}", other).into())
+            }
+        };
+        let tables = writes
+            .row_tables
+            .iter()
+            .flatten()
+            .map(|t| t.trim().to_string())
+            .collect();
+        Ok(Inserts {
+            mode,
+            tables,
+            meters: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Whether any rows may be written by themselves, which needs a second
+    // writer per target.
+    fn any(&self) -> bool {
+        self.mode != InsertMode::Batch || !self.tables.is_empty()
+    }
+
+    // Counts the row and says whether it's written by itself.
+    fn per_row(&self, table: &str) -> bool {
+        if self.tables.contains(table) {
+            return true;
+        }
+        let below_hz = match self.mode {
+            InsertMode::Auto { below_hz } => below_hz,
+            InsertMode::Batch => return false,
+        };
+
+        let mut meters = self.meters.lock().unwrap();
+        let meter = meters.entry(table.to_string()).or_insert_with(|| Meter {
+            since: Instant::now(),
+            rows: 0,
+            per_row: false,
+        });
+        meter.rows += 1;
+        let elapsed = meter.since.elapsed();
+        if elapsed >= RATE_WINDOW {
+            meter.per_row = (meter.rows as f64 / elapsed.as_secs_f64()) < below_hz;
+            meter.since = Instant::now();
+            meter.rows = 0;
+        }
+        meter.per_row
+    }
+}
+
+// `INSERT ... VALUES (tuple)` split at its single values tuple, or None for
+// statements that can't be extended to several rows.
+pub fn split_values(sql: &str) -> Option<(&str, &str)> {
+    let at = sql.to_ascii_uppercase().rfind("VALUES")? + "VALUES".len();
+    let (head, tuple) = sql.split_at(at);
+    let tuple = tuple.trim().trim_end_matches(';').trim_end();
+    if !tuple.starts_with('(') {
+        return None;
+    }
+
+    let mut depth = 0;
+    for (i, c) in tuple.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => (),
+        }
+        if depth == 0 {
+            return if i == tuple.len() - 1 {
+                Some((head, tuple))
+            } else {
+                None
+            };
+        }
+    }
+    None
+}
+
+// Renumbers every `$n` placeholder to `$n+offset`.
+fn shift_placeholders(text: &str, offset: usize) -> String {
+    replace_placeholders(text, |n| format!("${}", n + offset))
+}
+
+pub fn replace_placeholders(text: &str, replace: impl Fn(usize) -> String) -> String {
+    let mut out = String::with_capacity(text.len() + 8);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+        let mut digits = String::new();
+        while let Some(d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+            digits.push(*d);
+            chars.next();
+        }
+        match digits.parse::<usize>() {
+            Ok(n) => out.push_str(&replace(n)),
+            Err(_) => {
+                out.push(c);
+                out.push_str(&digits);
+            }
+        }
+    }
+    out
+}
+
+// The row's statement extended to `rows` rows, each with its own parameters.
+fn multi_row_sql(sql: &str, params: usize, rows: usize) -> Option<String> {
+    let (head, tuple) = split_values(sql)?;
+    let tuples: Vec<String> = (0..rows)
+        .map(|k| shift_placeholders(tuple, k * params))
+        .collect();
+    Some(format!("{} {}", head, tuples.join(", ")))
+}
+
+// A temporary table COPY loads a row's parameters into, one column per
+// parameter, and the INSERT that moves them on through the row's own
+// expressions.
+struct Staging {
+    table: String,
+    columns: String,
+    insert: String,
+}
+
+impl Staging {
+    fn new(sql: &str, table: &str, n: usize) -> Option<Self> {
+        let (head, tuple) = split_values(sql)?;
+        let head = head[..head.len() - "VALUES".len()].trim_end();
+        let select = &tuple[1..tuple.len() - 1];
+        let table = format!("copy_{}_{}", table, n);
+
+        Some(Staging {
+            insert: format!(
+                "{} SELECT {} FROM {}",
+                head,
+                replace_placeholders(select, |i| format!("p{}", i)),
+                table
+            ),
+            columns: String::new(),
+            table,
+        })
+    }
+
+    // Creates the table for the statement's parameter types.
+    async fn create(&mut self, tx: &Transaction<'_>, statement: &Statement) -> Result<(), Error> {
+        let columns: Vec<String> = (1..=statement.params().len())
+            .map(|i| format!("p{}", i))
+            .collect();
+        let definitions: Vec<String> = statement
+            .params()
+            .iter()
+            .zip(&columns)
+            .map(|(ty, column)| format!("{} {}.{}", column, ty.schema(), ty.name()))
+            .collect();
+        tx.batch_execute(&format!(
+            "CREATE TEMP TABLE IF NOT EXISTS {} ({})",
+            self.table,
+            definitions.join(", ")
+        ))
+        .await?;
+        self.columns = columns.join(", ");
+        Ok(())
+    }
+
+    async fn copy(
+        &self,
+        tx: &Transaction<'_>,
+        statement: &Statement,
+        rows: &[Arc<Row>],
+    ) -> Result<(), Error> {
+        let sink = tx
+            .copy_in(&format!(
+                "COPY {} ({}) FROM STDIN (FORMAT binary)",
+                self.table, self.columns
+            ))
+            .await?;
+        let mut writer = pin!(BinaryCopyInWriter::new(sink, statement.params()));
+        for row in rows {
+            let values: Vec<&(dyn ToSql + Sync)> = row.params().collect();
+            writer.as_mut().write(&values).await?;
+        }
+        writer.finish().await?;
+
+        tx.execute(self.insert.as_str(), &[]).await?;
+        tx.batch_execute(&format!("TRUNCATE {}", self.table))
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Health {
+    pub connected: AtomicBool,
+    pub written: AtomicU64,
+    pub dropped: AtomicU64,
+    pub failures: AtomicU64,
+    pub shed: AtomicU64,
+    // Rows sent to the writer and not yet taken off the queue, and those taken
+    // off it and not yet written, spooled or dropped.
+    queued: AtomicUsize,
+    held: AtomicUsize,
+    // Woken as the writer takes rows off the queue, for a sender waiting
+    // for it to catch up.
+    taken: Notify,
+    shedding: AtomicBool,
+    // High-rate rows offered while shedding, for keeping one in so many.
+    offered: AtomicU64,
+    // Compactions asked for so far. Each of the target's writers compacts its
+    // spool before its next batch when this has gone up.
+    compact: AtomicU64,
+    // Held while setting the schema up, which the per-row writer does only
+    // when the batch writer hasn't yet.
+    setting_up: Mutex<()>,
+    ready: AtomicBool,
+    // Set by close(). A writer retrying a full batch doesn't read the queue,
+    // so it wouldn't see the stop there.
+    stopping: AtomicBool,
+    // Set by close() once the flush deadline has passed.
+    spill: AtomicBool,
+    // Arrival of the newest packet sent to the target, and of the newest one
+    // it has committed, in ms since the epoch.
+    newest: AtomicU64,
+    committed: AtomicU64,
+    // From arrival to commit for the newest row committed, in ms.
+    latency: AtomicU64,
+}
+
+fn epoch_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+impl Health {
+    // How far the committed rows trail the newest packet sent, 0 once caught
+    // up.
+    pub fn lag(&self) -> Duration {
+        let newest = self.newest.load(Ordering::Relaxed);
+        let committed = self.committed.load(Ordering::Relaxed);
+        Duration::from_millis(newest.saturating_sub(committed))
+    }
+
+    fn remaining(&self) -> usize {
+        self.queued.load(Ordering::Relaxed) + self.held.load(Ordering::Relaxed)
+    }
+
+    // Rows taken off the queue, by the writer or dropped.
+    fn take(&self, rows: usize) {
+        self.queued.fetch_sub(rows, Ordering::Relaxed);
+        self.taken.notify_waiters();
+    }
+
+    pub fn latency(&self) -> Duration {
+        Duration::from_millis(self.latency.load(Ordering::Relaxed))
+    }
+
+    fn committed(&self, rows: &[Arc<Row>]) {
+        if let Some(received) = rows.iter().filter_map(|r| r.received).max() {
+            self.committed
+                .fetch_max(epoch_ms(received), Ordering::Relaxed);
+            self.latency.store(
+                epoch_ms(SystemTime::now()).saturating_sub(epoch_ms(received)),
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
+
+// Which data tables a target is sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tables {
+    All,
+    Only(HashSet<String>),
+    Except(HashSet<String>),
+}
+
+impl Tables {
+    fn wants(&self, table: &str) -> bool {
+        match self {
+            Tables::All => true,
+            Tables::Only(tables) => tables.contains(table),
+            Tables::Except(tables) => !tables.contains(table),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TargetConfig {
+    pub url: String,
+    pub tables: Tables,
+}
+
+#[derive(Clone)]
+struct Target {
+    name: String,
+    tables: Tables,
+    // None asks the writer to write what it has and stop.
+    queue: Sender<Option<Arc<Row>>>,
+    // The writer of rows written by themselves, for Postgres targets when
+    // there can be any.
+    per_row: Option<Sender<Option<Arc<Row>>>>,
+    health: Arc<Health>,
+    // Queue length that starts shedding.
+    shed_at: usize,
+}
+
+impl Target {
+    // More queued than the target can take without shedding.
+    fn is_behind(&self) -> bool {
+        self.health.queued.load(Ordering::Relaxed) >= self.shed_at / 2
+    }
+
+    // Whether to leave this row out to keep room for the low-rate tables,
+    // logging when shedding starts and stops.
+    fn sheds(&self, table: &str, shedding: &Shedding) -> bool {
+        let queued = self.health.queued.load(Ordering::Relaxed);
+        if queued < self.shed_at / 2 {
+            if self.health.shedding.swap(false, Ordering::Relaxed) {
+                info!(
+                    "Database {} caught up, stopped shedding ({} rows shed so far)",
+                    self.name,
+                    self.health.shed.load(Ordering::Relaxed)
+                );
+            }
+            return false;
+        }
+        if queued >= self.shed_at && !self.health.shedding.swap(true, Ordering::Relaxed) {
+            info!(
+                "Database {} is {} rows behind, keeping 1 in {} rows of {} until it catches up",
+                self.name,
+                queued,
+                SHED_KEEP_EVERY,
+                shedding.names()
+            );
+        }
+
+        self.health.shedding.load(Ordering::Relaxed)
+            && shedding.sheds(table)
+            && !self
+                .health
+                .offered
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(SHED_KEEP_EVERY)
+    }
+}
+
+#[derive(Clone)]
+pub struct FanOut {
+    targets: Vec<Target>,
+    clocks: Arc<ClockSources>,
+    vehicle: Option<Arc<str>>,
+    // The primary's sessions.id for the run, 0 before one has started.
+    session: Arc<AtomicI32>,
+    writers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    shedding: Arc<Shedding>,
+    inserts: Arc<Inserts>,
+    quotas: Arc<Quotas>,
+    flush_deadline: Duration,
+}
+
+impl FanOut {
+    pub fn new(
+        targets: &[TargetConfig],
+        setup: Setup,
+        writes: &Writes,
+        clocks: ClockSources,
+        vehicle: Option<&str>,
+    ) -> Result<Self, Error> {
+        let batching = Batching::from_settings(writes)?;
+        let shedding = Shedding::from_settings(writes);
+        let inserts = Inserts::from_settings(writes)?;
+        let mut writers = Vec::new();
+        let targets = targets
+            .iter()
+            .map(|config| {
+                let (url, mirror) = mirrored(&config.url);
+                let name = display_name(&config.url);
+                let (queue, rows) = mpsc::channel(batching.queue);
+                let health = Arc::new(Health::default());
+                // Nothing committed yet counts as behind since starting.
+                health
+                    .committed
+                    .store(epoch_ms(SystemTime::now()), Ordering::Relaxed);
+
+                let flat = match flat_sink(url, writes) {
+                    Err(e) if mirror => {
+                        warn!("Left out mirror {}. Error: {}", name, e);
+                        return Ok(None);
+                    }
+                    flat => flat?,
+                };
+                let spool = |name: &str| match (&writes.spool_dir, mirror) {
+                    (Some(dir), false) => Spool::open(Path::new(dir), name).map(Some),
+                    _ => Ok(None),
+                };
+
+                let mut per_row = None;
+                if let Some(sink) = flat {
+                    let health = health.clone();
+                    let name = name.clone();
+                    writers.push(
+                        runtime::get()
+                            .spawn_blocking(move || write_flat(sink, &name, &health, rows)),
+                    );
+                } else {
+                    let writer = Writer {
+                        url: url.to_string(),
+                        name: name.clone(),
+                        setup: setup.clone(),
+                        per_row: false,
+                        health: health.clone(),
+                        batching,
+                        spool: spool(&name)?,
+                        compacted: 0,
+                    };
+                    writers.push(runtime::get().spawn(writer.run(rows)));
+
+                    // Spools apart from the batch writer.
+                    if inserts.any() {
+                        let (queue, rows) = mpsc::channel(batching.queue);
+                        let writer = Writer {
+                            url: url.to_string(),
+                            name: name.clone(),
+                            setup: setup.clone(),
+                            per_row: true,
+                            health: health.clone(),
+                            batching: Batching {
+                                rows: 1,
+                                ..batching
+                            },
+                            spool: spool(&format!("{}.rows", name))?,
+                            compacted: 0,
+                        };
+                        writers.push(runtime::get().spawn(writer.run(rows)));
+                        per_row = Some(queue);
+                    }
+                }
+
+                Ok(Some(Target {
+                    name,
+                    tables: match mirror {
+                        true => Tables::All,
+                        false => config.tables.clone(),
+                    },
+                    queue,
+                    per_row,
+                    health,
+                    shed_at: (batching.queue as f64 * SHED_AT) as usize,
+                }))
+            })
+            .collect::<Result<Vec<Option<Target>>, Error>>()?;
+        let targets: Vec<Target> = targets.into_iter().flatten().collect();
+
+        for (name, read) in [
+            (
+                "lordlogger.sink_lag_seconds",
+                Health::lag as fn(&Health) -> Duration,
+            ),
+            ("lordlogger.sink_latency_seconds", Health::latency),
+        ] {
+            let healths: Vec<(String, Arc<Health>)> = targets
+                .iter()
+                .map(|t| (t.name.clone(), t.health.clone()))
+                .collect();
+            telemetry::gauge(name, move || {
+                healths
+                    .iter()
+                    .map(|(target, health)| {
+                        (
+                            vec![("target", target.as_str().into())],
+                            read(health).as_secs_f64(),
+                        )
+                    })
+                    .collect()
+            });
+        }
+
+        Ok(FanOut {
+            targets,
+            clocks: Arc::new(clocks),
+            vehicle: vehicle.map(Arc::from),
+            session: Arc::new(AtomicI32::new(0)),
+            writers: Arc::new(Mutex::new(writers)),
+            shedding: Arc::new(shedding),
+            inserts: Arc::new(inserts),
+            quotas: Arc::new(Quotas::default()),
+            flush_deadline: batching.flush_deadline,
+        })
+    }
+
+    // Holds tables to their daily quotas, see quota.rs.
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = Arc::new(quotas);
+        self
+    }
+
+    // The tables gone over their quotas since the last call.
+    pub fn quotas_exceeded(&self) -> Vec<Exceeded> {
+        self.quotas.exceeded()
+    }
+
+    // Whether any target has more queued than it can take without shedding,
+    // for senders that can wait.
+    pub fn is_behind(&self) -> bool {
+        self.targets.iter().any(Target::is_behind)
+    }
+
+    // Returns once no target is behind, see is_behind.
+    pub async fn caught_up(&self) {
+        while let Some(target) = self.targets.iter().find(|t| t.is_behind()) {
+            // Listening before looking again, so a take in between isn't
+            // missed.
+            let mut taken = pin!(target.health.taken.notified());
+            taken.as_mut().enable();
+            if target.is_behind() {
+                taken.await;
+            }
+        }
+    }
+
+    // Each target's writer compacts its spool when it next wakes.
+    pub fn compact_spools(&self) {
+        for target in &self.targets {
+            target.health.compact.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_session(&self, id: i32) {
+        self.session.store(id, Ordering::Relaxed);
+    }
+
+    // Never blocks. A target falling behind sheds high-rate rows first; one
+    // whose queue is full drops the row and counts it.
+    pub fn send(&self, row: Row) {
+        if !self.quotas.keeps(&row.table, &row.params) {
+            return;
+        }
+        let session = Some(self.session.load(Ordering::Relaxed)).filter(|&id| id != 0);
+        let row = Arc::new(row.with_times(&self.clocks, session, self.vehicle.as_deref()));
+        let per_row = self.inserts.per_row(&row.table);
+        for target in self.targets.iter().filter(|t| t.tables.wants(&row.table)) {
+            // Closed, see close().
+            if target.health.stopping.load(Ordering::Relaxed) {
+                continue;
+            }
+            if let Some(received) = row.received {
+                target
+                    .health
+                    .newest
+                    .fetch_max(epoch_ms(received), Ordering::Relaxed);
+            }
+            if target.sheds(&row.table, &self.shedding) {
+                target.health.shed.fetch_add(1, Ordering::Relaxed);
+                telemetry::add(
+                    "lordlogger.rows_shed",
+                    vec![("target", target.name.as_str().into())],
+                    1,
+                );
+                continue;
+            }
+
+            let queue = match &target.per_row {
+                Some(queue) if per_row => queue,
+                _ => &target.queue,
+            };
+            target.health.queued.fetch_add(1, Ordering::Relaxed);
+            if queue.try_send(Some(row.clone())).is_err() {
+                target.health.queued.fetch_sub(1, Ordering::Relaxed);
+                target.health.dropped.fetch_add(1, Ordering::Relaxed);
+                telemetry::add(
+                    "lordlogger.rows_dropped",
+                    vec![("target", target.name.as_str().into())],
+                    1,
+                );
+            }
+        }
+    }
+
+    // Waits for every target to write the rows already queued, or spool them
+    // when its database is away, reporting what's left every second. Past the
+    // flush deadline the rest is spilled to the spools. Rows sent after this
+    // are dropped.
+    pub fn close(&self) {
+        // The deadline counts from here, and a queue too full to take the
+        // end marker yet is tried again as its writer works through it.
+        let started = Instant::now();
+        let mut unsent: Vec<&Sender<Option<Arc<Row>>>> = Vec::new();
+        for target in &self.targets {
+            target.health.stopping.store(true, Ordering::Relaxed);
+            unsent.push(&target.queue);
+            unsent.extend(&target.per_row);
+        }
+        let writers: Vec<JoinHandle<()>> = self.writers.lock().unwrap().drain(..).collect();
+        let mut reported = started;
+        let mut spilled = false;
+        loop {
+            unsent.retain(|queue| matches!(queue.try_send(None), Err(TrySendError::Full(_))));
+            if writers.iter().all(|w| w.is_finished()) {
+                break;
+            }
+            if !spilled && started.elapsed() >= self.flush_deadline {
+                warn!(
+                    rows = self.remaining(),
+                    "Flush deadline of {}s passed, spilling what's left to the spools",
+                    self.flush_deadline.as_secs()
+                );
+                for target in &self.targets {
+                    target.health.spill.store(true, Ordering::Relaxed);
+                }
+                spilled = true;
+            }
+            if reported.elapsed() >= FLUSH_PROGRESS {
+                reported = Instant::now();
+                let left: Vec<String> = self
+                    .targets
+                    .iter()
+                    .map(|t| (t, t.health.remaining()))
+                    .filter(|(_, rows)| *rows > 0)
+                    .map(|(t, rows)| format!("{} {}", t.name, rows))
+                    .collect();
+                if spilled {
+                    info!(
+                        rows = self.remaining(),
+                        "Spilling once the writes under way end: {}",
+                        left.join(", ")
+                    );
+                } else {
+                    info!(
+                        rows = self.remaining(),
+                        "Flushing, {:.0}s left: {}",
+                        self.flush_deadline
+                            .saturating_sub(started.elapsed())
+                            .as_secs_f64(),
+                        left.join(", ")
+                    );
+                }
+            }
+            thread::sleep(FLUSH_POLL);
+        }
+        for writer in writers {
+            let _ = runtime::get().block_on(writer);
+        }
+    }
+
+    // Rows the targets have yet to write.
+    pub fn remaining(&self) -> usize {
+        self.targets.iter().map(|t| t.health.remaining()).sum()
+    }
+
+    pub fn report(&self) -> String {
+        let targets: Vec<String> = self
+            .targets
+            .iter()
+            .map(|t| {
+                format!(
+                    "{} {}: {} written, {} shed, {} dropped, {} failures, {:.1}s behind",
+                    t.name,
+                    if t.health.connected.load(Ordering::Relaxed) {
+                        "up"
+                    } else {
+                        "down"
+                    },
+                    t.health.written.load(Ordering::Relaxed),
+                    t.health.shed.load(Ordering::Relaxed),
+                    t.health.dropped.load(Ordering::Relaxed),
+                    t.health.failures.load(Ordering::Relaxed),
+                    t.health.lag().as_secs_f64()
+                )
+            })
+            .collect();
+
+        targets.join("; ")
+    }
+}
+
+// A live connection and the statements prepared on it, keyed by their SQL
+// and how many rows each writes. Each distinct insert is parsed once per
+// connection instead of once per row. Staging tables are keyed by SQL too.
+struct Connection {
+    client: Client,
+    statements: HashMap<(String, usize), Statement>,
+    staging: HashMap<String, Staging>,
+    // Whether the spool has been moved past what this database acknowledged.
+    resumed: bool,
+}
+
+async fn prepare(
+    statements: &mut HashMap<(String, usize), Statement>,
+    tx: &Transaction<'_>,
+    sql: &str,
+    params: usize,
+    rows: usize,
+) -> Result<Statement, Error> {
+    let key = (sql.to_string(), rows);
+    if let Some(statement) = statements.get(&key) {
+        return Ok(statement.clone());
+    }
+
+    let statement = match rows {
+        1 => tx.prepare(sql).await?,
+        _ => {
+            let multi =
+                multi_row_sql(sql, params, rows).ok_or_else(|| format!("can't batch `{}`", sql))?;
+            tx.prepare(&multi).await?
+        }
+    };
+    statements.insert(key, statement.clone());
+    Ok(statement)
+}
+
+// How many of `run` rows sharing a statement to write with the next INSERT:
+// the largest power of two that fits, so each statement is prepared in only a
+// few sizes.
+fn chunk_rows(sql: &str, params: usize, run: usize) -> usize {
+    let most = MULTI_ROW_MAX.min(MAX_PARAMS / params.max(1)).min(run);
+    if most <= 1 || split_values(sql).is_none() {
+        return 1;
+    }
+    1 << (usize::BITS - 1 - most.leading_zeros())
+}
+
+// Whether the server refused the rows themselves: a data exception (22xxx) or
+// an integrity constraint violation (23xxx). A client-side error with no
+// SQLSTATE, such as a value that wouldn't encode, counts too.
+fn rejected(err: &Error) -> bool {
+    match err
+        .downcast_ref::<tokio_postgres::Error>()
+        .and_then(|e| e.code())
+    {
+        Some(state) => matches!(&state.code()[..2], "22" | "23"),
+        None => true,
+    }
+}
+
+struct Writer {
+    url: String,
+    name: String,
+    setup: Setup,
+    // Writes rows by themselves, after the batch writer's.
+    per_row: bool,
+    health: Arc<Health>,
+    batching: Batching,
+    spool: Option<Spool>,
+    // The compaction requests this writer has seen to.
+    compacted: u64,
+}
+
+impl Writer {
+    fn count(&self, counter: &'static str, value: usize) {
+        telemetry::add(
+            counter,
+            vec![("target", self.name.as_str().into())],
+            value as u64,
+        );
+    }
+
+    // A row off the queue into the batch.
+    fn take(&self, batch: &mut Vec<Arc<Row>>, row: Arc<Row>) {
+        self.health.take(1);
+        self.health.held.fetch_add(1, Ordering::Relaxed);
+        batch.push(row);
+    }
+
+    // The batch done with, written or not.
+    fn release(&self, batch: &mut Vec<Arc<Row>>) {
+        self.health.held.fetch_sub(batch.len(), Ordering::Relaxed);
+        batch.clear();
+    }
+
+    // Past the flush deadline, the batch and the rest of the queue go to the
+    // spool for the next run, or are dropped without one.
+    fn spill(
+        &self,
+        spool: Option<&mut Spool>,
+        mut batch: Vec<Arc<Row>>,
+        rows: &mut Receiver<Option<Arc<Row>>>,
+    ) {
+        while let Ok(Some(row)) = rows.try_recv() {
+            self.take(&mut batch, row);
+        }
+        if batch.is_empty() {
+            return;
+        }
+        let spilled = match spool {
+            Some(spool) => match spool.append(batch.iter().map(|r| r.spooled())) {
+                Ok(n) => {
+                    self.count("lordlogger.rows_spooled", n);
+                    info!(
+                        "Spilled {} rows for database {} to {}, to be written on the next run",
+                        n,
+                        self.name,
+                        spool.path().display()
+                    );
+                    true
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to spill {} rows for database {} to {}. Error: {}",
+                        batch.len(),
+                        self.name,
+                        spool.path().display(),
+                        e
+                    );
+                    false
+                }
+            },
+            None => {
+                warn!(
+                    "Database {} has no spool, dropped its last {} rows at the flush deadline",
+                    self.name,
+                    batch.len()
+                );
+                false
+            }
+        };
+        if !spilled {
+            self.health
+                .dropped
+                .fetch_add(batch.len() as u64, Ordering::Relaxed);
+            self.count("lordlogger.rows_dropped", batch.len());
+        }
+        self.release(&mut batch);
+    }
+
+    fn copies(&self, table: &str) -> bool {
+        self.batching.ingest == Ingest::Copy && COPY_TABLES.contains(&table)
+    }
+
+    fn written(&self, rows: &[Arc<Row>]) {
+        self.health
+            .written
+            .fetch_add(rows.len() as u64, Ordering::Relaxed);
+        self.health.committed(rows);
+        self.count("lordlogger.rows_written", rows.len());
+    }
+
+    // Records a failed write and returns whether the rows are worth trying
+    // again, on a new connection.
+    fn failed(&self, conn: &mut Option<Connection>, err: &Error, rows: usize) -> bool {
+        self.health.failures.fetch_add(1, Ordering::Relaxed);
+        self.count("lordlogger.write_failures", 1);
+
+        // Only rows the server rejected as data, or that couldn't be encoded
+        // for it, are dropped, since retrying them would fail the same way
+        // forever. Anything else, a lost connection, a statement cancelled
+        // by its timeout, a serialization failure or a deadlock, is tried
+        // again or spooled.
+        let closed = conn.as_ref().is_none_or(|c| c.client.is_closed());
+        let lost = closed || !rejected(err);
+        bus::publish(SinkError {
+            sink: self.name.clone(),
+            error: err.to_string(),
+            rows,
+            lost,
+        });
+        if lost {
+            if !closed {
+                warn!(
+                    "Database {} failed a batch of {} rows, trying again. Error: {}",
+                    self.name, rows, err
+                );
+            } else if self.health.connected.swap(false, Ordering::Relaxed) {
+                warn!("Lost database {}. Error: {}", self.name, err);
+            }
+            // Either way the rows go round again on a fresh connection.
+            *conn = None;
+            return true;
+        }
+
+        error!(
+            sink = %self.name,
+            rows,
+            "Database rejected a batch of rows. Error: {}",
+            err
+        );
+        self.health
+            .dropped
+            .fetch_add(rows as u64, Ordering::Relaxed);
+        self.count("lordlogger.rows_dropped", rows);
+        // The rejection may come from a table that changed under a
+        // prepared statement, so prepare afresh next time.
+        if let Some(conn) = conn {
+            conn.statements.clear();
+            conn.staging.clear();
+        }
+        false
+    }
+
+    // Moves the spool past rows the database committed before a crash kept
+    // the spool from hearing of it. Asked once per connection.
+    async fn resume(&self, conn: &mut Option<Connection>, spool: &mut Spool) -> Result<(), Error> {
+        let generation = match spool.generation() {
+            Some(generation) => generation,
+            None => return Ok(()),
+        };
+        let current = self.connect(conn).await?;
+        if current.resumed {
+            return Ok(());
+        }
+        let params: &[&(dyn ToSql + Sync)] = &[&generation];
+        let query = current
+            .client
+            .query_opt("SELECT seq FROM spool_acks WHERE generation = $1", params);
+        let acked = match time::timeout(self.batching.timeout, query).await {
+            Ok(acked) => acked?,
+            Err(_) => {
+                *conn = None;
+                return Err(self.timed_out());
+            }
+        };
+        if let Some(row) = acked {
+            let skipped = spool.skip_acked(row.get(0))?;
+            if skipped > 0 {
+                info!(
+                    "Skipped {} spooled rows database {} already has",
+                    skipped, self.name
+                );
+            }
+        }
+        if let Some(conn) = conn {
+            conn.resumed = true;
+        }
+        Ok(())
+    }
+
+    // Writes spooled rows, oldest first, for up to one batch interval so the
+    // queue keeps moving. Each batch commits with its ack, so the spool and
+    // the database never disagree on what's written. Returns whether the
+    // spool is now empty.
+    async fn drain(&self, conn: &mut Option<Connection>, spool: &mut Spool) -> Result<bool, Error> {
+        if let Err(e) = self.resume(conn, spool).await {
+            self.failed(conn, &e, 0);
+            return Err(e);
+        }
+        let deadline = Instant::now() + self.batching.interval;
+        while Instant::now() < deadline {
+            let (spooled, next) = spool.peek(self.batching.rows)?;
+            if spooled.is_empty() {
+                spool.commit(next)?;
+                break;
+            }
+            let rows: Vec<Arc<Row>> = spooled
+                .into_iter()
+                .map(|s| Arc::new(Row::from_spooled(s)))
+                .collect();
+            match self.write_within(conn, &rows, spool.ack(next)).await {
+                Ok(()) => self.written(&rows),
+                Err(e) if self.failed(conn, &e, rows.len()) => return Err(e),
+                Err(_) => (),
+            }
+            spool.commit(next)?;
+        }
+        spool.is_empty()
+    }
+
+    async fn run(mut self, mut rows: Receiver<Option<Arc<Row>>>) {
+        let mut conn: Option<Connection> = None;
+        let mut batch: Vec<Arc<Row>> = Vec::new();
+        let mut backoff = RETRY_MIN;
+        let mut spool = self.spool.take();
+        // Once rows are on disk every new batch follows them there, so rows
+        // reach the database in the order they were made.
+        let mut spooling = match &spool {
+            Some(spool) => !spool.is_empty().unwrap_or(false),
+            None => false,
+        };
+        let mut retry_at: Option<Instant> = None;
+        let mut closing = false;
+
+        loop {
+            if self.health.spill.load(Ordering::Relaxed) {
+                return self.spill(spool.as_mut(), batch, &mut rows);
+            }
+            // A batch that failed to write is kept and topped up for the retry.
+            if batch.is_empty() {
+                if closing {
+                    return;
+                }
+                match rows.recv().await {
+                    Some(Some(row)) => self.take(&mut batch, row),
+                    Some(None) | None => return,
+                }
+            }
+
+            let deadline = Instant::now() + self.batching.interval;
+            while batch.len() < self.batching.rows && !closing {
+                let left = deadline.saturating_duration_since(Instant::now());
+                match time::timeout(left, rows.recv()).await {
+                    Ok(Some(Some(row))) => self.take(&mut batch, row),
+                    Ok(Some(None)) => closing = true,
+                    Ok(None) | Err(_) => break,
+                }
+            }
+
+            let requested = self.health.compact.load(Ordering::Relaxed);
+            if let Some(spool) = spool.as_mut().filter(|_| requested > self.compacted) {
+                self.compacted = requested;
+                match spool.compact() {
+                    Ok(0) => (),
+                    Ok(freed) => info!(
+                        "Compacted the spool for database {}, freed {} bytes",
+                        self.name, freed
+                    ),
+                    Err(e) => error!(
+                        "Failed to compact the spool for database {}. Error: {}",
+                        self.name, e
+                    ),
+                }
+            }
+
+            if let Some(spool) = spool.as_mut().filter(|_| spooling) {
+                if retry_at.is_none_or(|at| Instant::now() >= at) {
+                    match self.drain(&mut conn, spool).await {
+                        Ok(done) => {
+                            if done {
+                                info!("Drained the spool for database {}", self.name);
+                            }
+                            spooling = !done;
+                            retry_at = None;
+                            backoff = RETRY_MIN;
+                        }
+                        Err(_) => {
+                            retry_at = Some(Instant::now() + backoff);
+                            backoff = (backoff * 2).min(RETRY_MAX);
+                        }
+                    }
+                }
+
+                if spooling {
+                    match spool.append(batch.iter().map(|r| r.spooled())) {
+                        Ok(n) => {
+                            self.count("lordlogger.rows_spooled", n);
+                            self.release(&mut batch);
+                        }
+                        // Keeps the batch in memory, as if there were no spool.
+                        Err(e) => {
+                            error!(
+                                "Failed to spool rows for database {} to {}. Error: {}",
+                                self.name,
+                                spool.path().display(),
+                                e
+                            );
+                            time::sleep(backoff).await;
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            let mut span = Span::start("sink.write");
+            span.attr("target", self.name.as_str());
+            span.attr("rows", batch.len());
+            let result = self.write_within(&mut conn, &batch, None).await;
+            if let Err(e) = &result {
+                span.fail(&e.to_string());
+            }
+            span.end();
+
+            let err = match result {
+                Ok(()) => {
+                    self.written(&batch);
+                    self.release(&mut batch);
+                    backoff = RETRY_MIN;
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            if !self.failed(&mut conn, &err, batch.len()) {
+                self.release(&mut batch);
+            } else if spool.is_some() {
+                spooling = true;
+                retry_at = Some(Instant::now() + backoff);
+                backoff = (backoff * 2).min(RETRY_MAX);
+            } else if (closing || self.health.stopping.load(Ordering::Relaxed))
+                && backoff > RETRY_MIN
+            {
+                // Retried once already; without a spool there's nowhere to
+                // keep the rows while waiting out the outage.
+                warn!(
+                    "Database {} is away, dropped its last {} rows on stopping",
+                    self.name,
+                    batch.len()
+                );
+                self.health
+                    .dropped
+                    .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                self.count("lordlogger.rows_dropped", batch.len());
+                self.release(&mut batch);
+                return;
+            } else {
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(RETRY_MAX);
+            }
+        }
+    }
+
+    fn timed_out(&self) -> Error {
+        format!(
+            "no reply from database {} within {}ms",
+            self.name,
+            self.batching.timeout.as_millis()
+        )
+        .into()
+    }
+
+    async fn connect<'a>(
+        &self,
+        conn: &'a mut Option<Connection>,
+    ) -> Result<&'a mut Connection, Error> {
+        if let Some(conn) = conn {
+            return Ok(conn);
+        }
+        // The startup handshake is held to the timeout too, so a server that
+        // takes the connection and never answers can't stall the writer.
+        let config: Config = self.url.parse()?;
+        let (client, connection) = time::timeout(self.batching.timeout, config.connect(NoTls))
+            .await
+            .map_err(|_| self.timed_out())??;
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+
+        // Schema setup may build indexes on large tables, so it isn't held to
+        // the timeout.
+        let url = self.url.clone();
+        let setup = self.setup.clone();
+        let health = self.health.clone();
+        let (per_row, timeout) = (self.per_row, self.batching.timeout);
+        task::spawn_blocking(move || -> Result<(), Error> {
+            let _setting_up = health.setting_up.lock().unwrap();
+            if !(per_row && health.ready.load(Ordering::Relaxed)) {
+                let mut client = url
+                    .parse::<postgres::Config>()?
+                    .connect_timeout(timeout)
+                    .connect(postgres::NoTls)?;
+                setup(&mut client)?;
+                health.ready.store(true, Ordering::Relaxed);
+            }
+            Ok(())
+        })
+        .await??;
+        // Once for both of a target's writers.
+        if !self.health.connected.swap(true, Ordering::Relaxed) {
+            info!("Connected to database {}", self.name);
+        }
+        Ok(conn.insert(Connection {
+            client,
+            statements: HashMap::new(),
+            staging: HashMap::new(),
+            resumed: false,
+        }))
+    }
+
+    // The write held to the timeout once connected. One that runs over leaves
+    // the connection in doubt, so it's dropped and the rows are tried again
+    // or spooled as for a lost connection.
+    async fn write_within(
+        &self,
+        conn: &mut Option<Connection>,
+        batch: &[Arc<Row>],
+        ack: Option<Ack>,
+    ) -> Result<(), Error> {
+        self.connect(conn).await?;
+        let span = debug_span!("insert", sink = %self.name, rows = batch.len());
+        let writing = self.write(conn, batch, ack).instrument(span);
+        match time::timeout(self.batching.timeout, writing).await {
+            Ok(result) => result,
+            Err(_) => {
+                *conn = None;
+                Err(self.timed_out())
+            }
+        }
+    }
+
+    // `ack` is recorded in the same transaction as the rows.
+    async fn write(
+        &self,
+        conn: &mut Option<Connection>,
+        batch: &[Arc<Row>],
+        ack: Option<Ack>,
+    ) -> Result<(), Error> {
+        let Connection {
+            client,
+            statements,
+            staging,
+            ..
+        } = self.connect(conn).await?;
+
+        // Runs of rows for the same statement go out as multi-row INSERTs.
+        let tx = client.transaction().await?;
+        let mut i = 0;
+        while i < batch.len() {
+            let first = &batch[i];
+            let params = first.params.len();
+            let run = batch[i..]
+                .iter()
+                .take_while(|r| r.sql == first.sql && r.params.len() == params)
+                .count();
+
+            if self.copies(&first.table) {
+                let statement = prepare(statements, &tx, &first.sql, params, 1).await?;
+                if !staging.contains_key(&first.sql) {
+                    let mut table = Staging::new(&first.sql, &first.table, staging.len())
+                        .ok_or_else(|| format!("can't COPY `{}`", first.sql))?;
+                    table.create(&tx, &statement).await?;
+                    staging.insert(first.sql.clone(), table);
+                }
+                staging[&first.sql]
+                    .copy(&tx, &statement, &batch[i..i + run])
+                    .await?;
+                i += run;
+                continue;
+            }
+
+            let rows = chunk_rows(&first.sql, params, run);
+
+            let statement = prepare(statements, &tx, &first.sql, params, rows).await?;
+            let values: Vec<&(dyn ToSql + Sync)> =
+                batch[i..i + rows].iter().flat_map(|r| r.params()).collect();
+            tx.execute(&statement, &values).await?;
+            i += rows;
+        }
+        if let Some(ack) = ack {
+            tx.execute(ACK_SQL, &[&ack.generation, &self.name, &ack.seq])
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+// The URL a target writes to, and whether it's a mirror.
+pub fn mirrored(url: &str) -> (&str, bool) {
+    match url.strip_prefix(MIRROR) {
+        Some(url) => (url, true),
+        None => (url, false),
+    }
+}
+
+// The writer of a URL that isn't Postgres, None for Postgres.
+fn flat_sink(url: &str, writes: &Writes) -> Result<Option<Box<dyn FlatSink>>, Error> {
+    Ok(if csv::is_stdout(url) {
+        Some(Box::new(CsvStdout::new()?))
+    } else if let Some(dir) = csv::dir(url) {
+        Some(Box::new(CsvFiles::new(dir)?))
+    } else if let Some(path) = sqlite::path(url) {
+        Some(Box::new(SqliteFile::open(path)?))
+    } else if let Some(url) = influx::url(url) {
+        Some(Box::new(Influx::new(url, writes.influx_token.clone())?))
+    } else if let Some(dir) = parquet_files::dir(url) {
+        Some(Box::new(ParquetFiles::new(dir, writes.parquet_minutes)?))
+    } else if let Some(path) = jsonl::path(url) {
+        Some(Box::new(JsonLines::open(path)?))
+    } else if mqtt::is_mqtt(url) {
+        Some(Box::new(Mqtt::new(url)?))
+    } else if tail::is_tail(url) {
+        Some(Box::new(TailSink::new(writes.tail_seconds)))
+    } else {
+        None
+    })
+}
+
+// Whether a target URL is written by a FlatSink rather than as Postgres.
+pub fn is_flat(url: &str) -> bool {
+    let (url, _) = mirrored(url);
+    csv::dir(url).is_some()
+        || sqlite::path(url).is_some()
+        || influx::url(url).is_some()
+        || parquet_files::dir(url).is_some()
+        || jsonl::path(url).is_some()
+        || mqtt::is_mqtt(url)
+        || tail::is_tail(url)
+}
+
+// A target that takes rows as named columns rather than SQL, `csv:`,
+// `sqlite:`, `influx:`, `parquet:`, `jsonl:`, `mqtt://` or `tail:`. Composite columns come split out.
+pub trait FlatSink: Send {
+    fn write(&mut self, table: &str, layout: &Layout, params: &[Encoded]) -> Result<(), Error>;
+    fn flush(&mut self) -> Result<(), Error>;
+}
+
+// A flat target's writer. Rows are written as they come and flushed whenever
+// the queue runs dry.
+fn write_flat(
+    mut files: Box<dyn FlatSink>,
+    name: &str,
+    health: &Health,
+    mut rows: Receiver<Option<Arc<Row>>>,
+) {
+    let mut layouts: HashMap<String, Option<Layout>> = HashMap::new();
+    health.connected.store(true, Ordering::Relaxed);
+    let mut closing = false;
+    while !closing {
+        // There's no spool to spill to, so past the flush deadline what's
+        // queued is dropped.
+        if health.spill.load(Ordering::Relaxed) {
+            let left = std::iter::from_fn(|| rows.try_recv().ok().flatten()).count();
+            if left > 0 {
+                warn!(
+                    "Dropped the last {} rows for {} at the flush deadline",
+                    left, name
+                );
+                health.take(left);
+                health.dropped.fetch_add(left as u64, Ordering::Relaxed);
+            }
+            break;
+        }
+        let mut batch = match rows.blocking_recv() {
+            Some(Some(row)) => vec![row],
+            Some(None) | None => break,
+        };
+        while let Ok(row) = rows.try_recv() {
+            match row {
+                Some(row) => batch.push(row),
+                None => {
+                    closing = true;
+                    break;
+                }
+            }
+        }
+        let held = batch.len();
+        health.take(held);
+        health.held.fetch_add(held, Ordering::Relaxed);
+        let _span = debug_span!("insert", sink = %name, rows = held).entered();
+
+        let mut written = Vec::new();
+        for row in batch {
+            let layout = layouts.entry(row.sql.clone()).or_insert_with(|| {
+                let layout = csv::layout(&row.sql);
+                if layout.is_none() {
+                    warn!("Rows of {} can't be written to {}", row.table, name);
+                }
+                layout
+            });
+            let params: Vec<Encoded> = row.params.iter().map(|p| p.encode()).collect();
+            let result = match layout {
+                Some(layout) => files.write(&row.table, layout, &params),
+                None => Err("no columns".into()),
+            };
+            match result {
+                Ok(()) => written.push(row),
+                Err(e) => {
+                    if layout.is_some() {
+                        error!("Failed to write a row to {}. Error: {}", name, e);
+                        health.failures.fetch_add(1, Ordering::Relaxed);
+                        bus::publish(SinkError {
+                            sink: name.to_string(),
+                            error: e.to_string(),
+                            rows: 1,
+                            lost: false,
+                        });
+                    }
+                    health.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        if let Err(e) = files.flush() {
+            error!("Failed to flush {}. Error: {}", name, e);
+            health.failures.fetch_add(1, Ordering::Relaxed);
+            bus::publish(SinkError {
+                sink: name.to_string(),
+                error: e.to_string(),
+                rows: written.len(),
+                lost: false,
+            });
+        }
+
+        health
+            .written
+            .fetch_add(written.len() as u64, Ordering::Relaxed);
+        health.committed(&written);
+        telemetry::add(
+            "lordlogger.rows_written",
+            vec![("target", name.into())],
+            written.len() as u64,
+        );
+        health.held.fetch_sub(held, Ordering::Relaxed);
+    }
+}
+
+// The URL without its credentials, for logs.
+pub fn display_name(url: &str) -> String {
+    let (url, mirror) = mirrored(url);
+    let name = match url.rfind('@') {
+        Some(i) => &url[i + 1..],
+        None => url,
+    };
+    match mirror {
+        true => format!("{}{}", MIRROR, name),
+        false => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batching_comes_from_the_settings_file() {
+        let writes = Writes {
+            batch_rows: Some(100),
+            queue_rows: Some(2_000),
+            flush_deadline_s: Some(5),
+            ..Writes::default()
+        };
+        let batching = Batching::from_settings(&writes).unwrap();
+        assert_eq!(batching.rows, 100);
+        assert_eq!(batching.queue, 2_000);
+        assert_eq!(batching.flush_deadline, Duration::from_secs(5));
+
+        let empty = Writes {
+            batch_rows: Some(0),
+            ..Writes::default()
+        };
+        assert!(Batching::from_settings(&empty).is_err());
+    }
+
+    #[test]
+    fn splits_at_the_values_tuple() {
+        assert_eq!(
+            split_values("INSERT INTO t (a, b) VALUES ($1, ROW($2, $3));"),
+            Some(("INSERT INTO t (a, b) VALUES", "($1, ROW($2, $3))"))
+        );
+        assert_eq!(
+            split_values("insert into t (a) values\n    ($1)\n"),
+            Some(("insert into t (a) values", "($1)"))
+        );
+    }
+
+    #[test]
+    fn leaves_statements_that_cant_take_more_rows() {
+        assert_eq!(split_values("INSERT INTO t (a) SELECT $1"), None);
+        assert_eq!(split_values("INSERT INTO t (a) VALUES ($1), ($2)"), None);
+        assert_eq!(
+            split_values("INSERT INTO t (a) VALUES ($1) RETURNING id"),
+            None
+        );
+        assert_eq!(split_values("INSERT INTO t (a) VALUES ($1"), None);
+    }
+
+    #[test]
+    fn replaces_only_numbered_placeholders() {
+        let replaced = replace_placeholders("$1 + $10, $ x, $$, $", |n| format!(":{}", n));
+        assert_eq!(replaced, ":1 + :10, $ x, $$, $");
+        assert_eq!(shift_placeholders("ROW($1, $2)", 4), "ROW($5, $6)");
+    }
+
+    #[test]
+    fn extends_a_statement_to_several_rows() {
+        assert_eq!(
+            multi_row_sql("INSERT INTO t (a, b) VALUES ($1, ROW($2, $3));", 3, 2).as_deref(),
+            Some("INSERT INTO t (a, b) VALUES ($1, ROW($2, $3)), ($4, ROW($5, $6))")
+        );
+        assert_eq!(
+            multi_row_sql("INSERT INTO t (a) VALUES ($1)", 1, 1).as_deref(),
+            Some("INSERT INTO t (a) VALUES ($1)")
+        );
+        assert_eq!(multi_row_sql("INSERT INTO t (a) SELECT $1", 1, 2), None);
+    }
+}
@@ -0,0 +1,365 @@
+use crate::descriptors::{self, CommandDescriptor, DataDescriptor, FilterField};
+use crate::fanout::{FanOut, Param, Row};
+use crate::mip::{self, CommandPort};
+use crate::registry::Read;
+use crate::session::GpsTime;
+use crate::Error;
+use lordserial::{Field, Packet};
+use serde::{Deserialize, Serialize};
+
+// Filter command set (0x0D) fields.
+const RESET_FILTER: u8 = 0x01;
+const SET_INITIAL_HEADING: u8 = 0x03;
+const AUTO_INIT_CONTROL: u8 = 0x19;
+const INITIALIZATION_CONFIGURATION: u8 = 0x52;
+
+// Initial condition sources of 0x0D/0x52
+const INIT_AUTO: u8 = 0x00;
+const INIT_MANUAL_HEADING: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Alignment {
+    DualAntenna,
+    Kinematic,
+    Magnetometer,
+}
+
+impl Alignment {
+    fn bit(self) -> u8 {
+        match self {
+            Alignment::DualAntenna => 0x01,
+            Alignment::Kinematic => 0x02,
+            Alignment::Magnetometer => 0x04,
+        }
+    }
+}
+
+fn default_auto_init() -> bool {
+    true
+}
+
+fn default_alignment() -> Vec<Alignment> {
+    vec![Alignment::Kinematic, Alignment::Magnetometer]
+}
+
+// The `[filter]` table of the settings file:
+//
+//   [filter]
+//   auto_init = true                          # start once aiding is available
+//   alignment = ["kinematic", "magnetometer"] # heading sources when automatic
+//   initial_heading = 90.0                    # degrees true, skips alignment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterInit {
+    #[serde(default = "default_auto_init")]
+    pub auto_init: bool,
+    #[serde(default = "default_alignment")]
+    pub alignment: Vec<Alignment>,
+    pub initial_heading: Option<f32>,
+}
+
+impl FilterInit {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(heading) = self.initial_heading {
+            if !(heading.is_finite() && (-360.0..=360.0).contains(&heading)) {
+                return Err(format!("initial heading {} is not in degrees", heading).into());
+            }
+        } else if self.alignment.is_empty() {
+            return Err("the filter needs an alignment source or an initial heading".into());
+        }
+        Ok(())
+    }
+
+    fn initialization(&self) -> Vec<u8> {
+        let (source, heading) = match self.initial_heading {
+            Some(heading) => (INIT_MANUAL_HEADING, heading.to_radians()),
+            None => (INIT_AUTO, 0.0),
+        };
+        let alignment = self.alignment.iter().fold(0, |bits, a| bits | a.bit());
+
+        // wait for run command, source, alignment, heading, pitch, roll,
+        // position, velocity, then the LLH reference frame
+        let mut data = vec![mip::FUNCTION_APPLY, 0, source, alignment];
+        data.extend_from_slice(&heading.to_be_bytes());
+        for _ in 0..8 {
+            data.extend_from_slice(&0f32.to_be_bytes());
+        }
+        data.push(0x02);
+        data
+    }
+}
+
+pub fn configure(port: &mut CommandPort, init: &FilterInit) -> Result<(), Error> {
+    port.send(
+        CommandDescriptor::Filter,
+        INITIALIZATION_CONFIGURATION,
+        &init.initialization(),
+    )?;
+    port.send(
+        CommandDescriptor::Filter,
+        AUTO_INIT_CONTROL,
+        &[mip::FUNCTION_APPLY, init.auto_init as u8],
+    )
+}
+
+pub fn reset(port: &mut CommandPort) -> Result<(), Error> {
+    port.send(CommandDescriptor::Filter, RESET_FILTER, &[])
+}
+
+// Heading in degrees true, for a filter waiting on a manual heading.
+pub fn set_heading(port: &mut CommandPort, degrees: f32) -> Result<(), Error> {
+    port.send(
+        CommandDescriptor::Filter,
+        SET_INITIAL_HEADING,
+        &degrees.to_radians().to_be_bytes(),
+    )
+}
+
+pub const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS filter_status (
+        id BIGSERIAL PRIMARY KEY,
+        tow double precision NOT NULL,
+        week smallint NOT NULL,
+        state smallint NOT NULL,
+        dynamics_mode smallint NOT NULL,
+        status_flags smallint NOT NULL
+    );
+
+    COMMENT ON COLUMN filter_status.state IS
+        '0 startup, 1 initializing, 2 running, 3 running with errors. Source: Filter Status (0x82/0x10)';
+    COMMENT ON COLUMN filter_status.status_flags IS
+        'condition bitfield, meaning depends on state. Source: Filter Status (0x82/0x10)';
+
+    CREATE TABLE IF NOT EXISTS filter_uncertainty (
+        id BIGSERIAL PRIMARY KEY,
+        tow double precision NOT NULL,
+        week smallint NOT NULL,
+        position_north real,
+        position_east real,
+        position_down real,
+        velocity_north real,
+        velocity_east real,
+        velocity_down real,
+        roll real,
+        pitch real,
+        yaw real
+    );
+
+    COMMENT ON TABLE filter_uncertainty IS
+        '1-sigma estimates, NULL where the filter flagged them invalid';
+    COMMENT ON COLUMN filter_uncertainty.position_north IS
+        'm, NED frame. Source: Filter LLH Uncertainty (0x82/0x08)';
+    COMMENT ON COLUMN filter_uncertainty.velocity_north IS
+        'm/s, NED frame. Source: Filter NED Velocity Uncertainty (0x82/0x09)';
+    COMMENT ON COLUMN filter_uncertainty.roll IS
+        'rad. Source: Filter Euler Uncertainty (0x82/0x0A)';
+";
+
+// The uncertainty fields (0x82/0x08-0x0A): three floats then a valid flag.
+const UNCERTAINTY_FIELDS: [FilterField; 3] = [
+    FilterField::LlhUncertainty,
+    FilterField::NedVelocityUncertainty,
+    FilterField::EulerUncertainty,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterState {
+    Startup,
+    Initializing,
+    Running,
+    Degraded,
+    Unknown(u16),
+}
+
+impl FilterState {
+    fn from_raw(raw: u16) -> Self {
+        match raw {
+            0x00 => FilterState::Startup,
+            0x01 => FilterState::Initializing,
+            0x02 => FilterState::Running,
+            0x03 => FilterState::Degraded,
+            other => FilterState::Unknown(other),
+        }
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            FilterState::Startup => "startup".to_string(),
+            FilterState::Initializing => "initializing".to_string(),
+            FilterState::Running => "running".to_string(),
+            FilterState::Degraded => "degraded".to_string(),
+            FilterState::Unknown(raw) => format!("unknown (0x{:04X})", raw),
+        }
+    }
+}
+
+const INIT_FLAGS: &[(u16, &str)] = &[
+    (0x1000, "attitude not initialized"),
+    (0x2000, "position not initialized"),
+    (0x4000, "velocity not initialized"),
+];
+
+const RUNNING_FLAGS: &[(u16, &str)] = &[
+    (0x0001, "IMU unavailable"),
+    (0x0002, "GNSS unavailable"),
+    (0x0008, "matrix singularity"),
+    (0x0010, "position covariance high"),
+    (0x0020, "velocity covariance high"),
+    (0x0040, "attitude covariance high"),
+    (0x0080, "NaN in solution"),
+    (0x0100, "gyro bias estimate high"),
+    (0x0200, "accel bias estimate high"),
+    (0x0400, "gyro scale factor estimate high"),
+    (0x0800, "accel scale factor estimate high"),
+    (0x1000, "mag bias estimate high"),
+    (0x2000, "antenna offset correction high"),
+    (0x4000, "hard iron offset high"),
+    (0x8000, "soft iron correction high"),
+];
+
+// 0x82/0x10
+#[derive(Debug, Clone, Copy)]
+pub struct FilterStatus {
+    pub state: FilterState,
+    pub dynamics_mode: u16,
+    pub flags: u16,
+}
+
+impl FilterStatus {
+    pub fn extract(field: &Field) -> Result<Self, Error> {
+        Ok(FilterStatus {
+            state: FilterState::from_raw(field.read(0)?),
+            dynamics_mode: field.read(2)?,
+            flags: field.read(4)?,
+        })
+    }
+
+    pub fn from_packet(packet: &Packet) -> Result<Option<Self>, Error> {
+        if descriptors::data_set(packet) != Some(DataDescriptor::Filter) {
+            return Ok(None);
+        }
+        packet
+            .payload
+            .get_field(FilterField::FilterStatus.into())
+            .map(FilterStatus::extract)
+            .transpose()
+    }
+
+    // The flags set, named for the state they were reported in.
+    pub fn conditions(&self) -> Vec<&'static str> {
+        let table = match self.state {
+            FilterState::Startup | FilterState::Initializing => INIT_FLAGS,
+            _ => RUNNING_FLAGS,
+        };
+        table
+            .iter()
+            .filter(|(bit, _)| self.flags & bit != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+// Remembers the last filter state so only transitions become events.
+#[derive(Debug, Default)]
+pub struct StateTracker {
+    last: Option<FilterState>,
+}
+
+impl StateTracker {
+    // The event message when the state differs from the previous packet's.
+    pub fn update(&mut self, status: &FilterStatus) -> Option<String> {
+        let previous = self.last.replace(status.state);
+        if previous == Some(status.state) {
+            return None;
+        }
+
+        let message = serde_json::json!({
+            "from": previous.map(FilterState::name),
+            "to": status.state.name(),
+            "flags": format!("0x{:04X}", status.flags),
+            "conditions": status.conditions(),
+        });
+        Some(message.to_string())
+    }
+}
+
+// Writes the packet's filter status, if it has one.
+pub fn insert(out: &FanOut, packet: &Packet) -> Result<bool, Error> {
+    let (status, time) = match (
+        FilterStatus::from_packet(packet)?,
+        GpsTime::from_packet(packet)?,
+    ) {
+        (Some(status), Some(time)) => (status, time),
+        _ => return Ok(false),
+    };
+    let state = match status.state {
+        FilterState::Startup => 0,
+        FilterState::Initializing => 1,
+        FilterState::Running => 2,
+        FilterState::Degraded => 3,
+        FilterState::Unknown(raw) => raw as i16,
+    };
+
+    out.send(Row::timed(
+        "INSERT INTO filter_status (tow, week, state, dynamics_mode, status_flags)
+         VALUES ($1, $2, $3, $4, $5)",
+        vec![
+            Box::new(time.tow),
+            Box::new(time.week),
+            Box::new(state),
+            Box::new(status.dynamics_mode as i16),
+            Box::new(status.flags as i16),
+        ],
+        time,
+    ));
+
+    Ok(true)
+}
+
+fn uncertainty(field: &Field) -> Result<[Option<f32>; 3], Error> {
+    let valid = field.read::<u16>(12)? & 0x01 == 0x01;
+    let mut values = [None; 3];
+    for (i, value) in values.iter_mut().enumerate() {
+        *value = valid.then_some(field.read::<f32>(i * 4)?);
+    }
+    Ok(values)
+}
+
+// Writes the packet's position, velocity and attitude uncertainty, if it has
+// any of them.
+pub fn insert_uncertainty(out: &FanOut, packet: &Packet) -> Result<bool, Error> {
+    let time = match GpsTime::from_packet(packet)? {
+        Some(time) if descriptors::data_set(packet) == Some(DataDescriptor::Filter) => time,
+        _ => return Ok(false),
+    };
+
+    let mut params: Vec<Param> = vec![Box::new(time.tow), Box::new(time.week)];
+    let mut any = false;
+    for descriptor in UNCERTAINTY_FIELDS {
+        let values = match packet.payload.get_field(descriptor.into()) {
+            Some(field) => {
+                any = true;
+                uncertainty(field)?
+            }
+            None => [None; 3],
+        };
+        params.extend(values.iter().map(|v| Box::new(*v) as Param));
+    }
+    if !any {
+        return Ok(false);
+    }
+
+    out.send(Row::timed(
+        "INSERT INTO filter_uncertainty (
+            tow, week,
+            position_north, position_east, position_down,
+            velocity_north, velocity_east, velocity_down,
+            roll, pitch, yaw
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+        params,
+        time,
+    ));
+
+    Ok(true)
+}
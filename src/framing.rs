@@ -0,0 +1,371 @@
+// Bridges that wrap the device's MIP packets in frames of their own, like some
+// serial-to-CAN adapters, unwrapped before lordserial's parser sees the bytes.
+// A frame is described with LORDLOGGER_FRAMING, or `framing` in [device]:
+//
+//   LORDLOGGER_FRAMING="sync=aa55,length=u16le,skip=2,checksum=crc16,trailer=0d0a"
+//
+//   sync      hex bytes each frame starts with, required
+//   length    u8, u16be or u16le right after the sync, the payload's length
+//   skip      bytes between the length and the payload, say a CAN id, 0 by
+//             default
+//   checksum  none, sum8, xor8, crc16 (CCITT, big-endian) or fletcher16, of
+//             the bytes from the length to the end of the payload, right after
+//             the payload. none by default
+//   trailer   hex bytes each frame ends with, none by default
+//
+// Only payloads of frames that check out reach the parser, so a bridge
+// corrupting a frame costs that frame rather than desyncing the MIP stream.
+// Commands for the device go out wrapped the same way, with the skipped bytes
+// zero. Framings this can't describe are a Framing of the embedding program's
+// own, handed to the logger with LoggerBuilder::framing().
+use crate::mip;
+use crate::packet_source::PacketSource;
+use crate::Error;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+pub const FRAMING_ENV: &str = "LORDLOGGER_FRAMING";
+
+// Longer than any MIP packet, so a garbled length can't stall on a frame that
+// will never finish.
+const MAX_PAYLOAD: usize = 1024;
+const CHUNK: usize = 1024;
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+pub trait Framing: Send {
+    // Takes bytes as they come from the bridge and appends the payloads of
+    // the whole frames among them to `out`, keeping a partial frame for
+    // next time.
+    fn unwrap(&mut self, bytes: &[u8], out: &mut Vec<u8>);
+
+    // Bytes for the device as the bridge expects them.
+    fn wrap(&mut self, bytes: &[u8]) -> Vec<u8> {
+        bytes.to_vec()
+    }
+}
+
+// Makes a fresh Framing for each handle on the device, so partial frames
+// aren't shared between them.
+#[derive(Clone)]
+pub struct FramingHandle(pub Arc<dyn Fn() -> Box<dyn Framing> + Send + Sync>);
+
+impl FramingHandle {
+    pub fn new<F: Framing + 'static>(make: impl Fn() -> F + Send + Sync + 'static) -> Self {
+        FramingHandle(Arc::new(move || Box::new(make())))
+    }
+
+    pub fn make(&self) -> Box<dyn Framing> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for FramingHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("FramingHandle")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Length {
+    U8,
+    U16Be,
+    U16Le,
+}
+
+impl Length {
+    fn size(self) -> usize {
+        match self {
+            Length::U8 => 1,
+            Length::U16Be | Length::U16Le => 2,
+        }
+    }
+
+    fn read(self, bytes: &[u8]) -> usize {
+        match self {
+            Length::U8 => bytes[0] as usize,
+            Length::U16Be => u16::from_be_bytes([bytes[0], bytes[1]]) as usize,
+            Length::U16Le => u16::from_le_bytes([bytes[0], bytes[1]]) as usize,
+        }
+    }
+
+    fn write(self, len: usize) -> Vec<u8> {
+        match self {
+            Length::U8 => vec![len as u8],
+            Length::U16Be => (len as u16).to_be_bytes().to_vec(),
+            Length::U16Le => (len as u16).to_le_bytes().to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    None,
+    Sum8,
+    Xor8,
+    Crc16,
+    Fletcher16,
+}
+
+impl Checksum {
+    fn size(self) -> usize {
+        match self {
+            Checksum::None => 0,
+            Checksum::Sum8 | Checksum::Xor8 => 1,
+            Checksum::Crc16 | Checksum::Fletcher16 => 2,
+        }
+    }
+
+    fn of(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Checksum::None => Vec::new(),
+            Checksum::Sum8 => vec![bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))],
+            Checksum::Xor8 => vec![bytes.iter().fold(0u8, |sum, b| sum ^ b)],
+            Checksum::Crc16 => {
+                let mut crc: u16 = 0xFFFF;
+                for b in bytes {
+                    crc ^= (*b as u16) << 8;
+                    for _ in 0..8 {
+                        crc = if crc & 0x8000 != 0 {
+                            (crc << 1) ^ 0x1021
+                        } else {
+                            crc << 1
+                        };
+                    }
+                }
+                crc.to_be_bytes().to_vec()
+            }
+            // The same as MIP's own.
+            Checksum::Fletcher16 => mip::checksum(bytes).to_vec(),
+        }
+    }
+}
+
+// A frame as LORDLOGGER_FRAMING describes it.
+#[derive(Debug, Clone)]
+pub struct FrameSpec {
+    pub sync: Vec<u8>,
+    pub length: Length,
+    pub skip: usize,
+    pub checksum: Checksum,
+    pub trailer: Vec<u8>,
+}
+
+fn hex(text: &str) -> Result<Vec<u8>, Error> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return Err(format!("`{}` is not whole hex bytes", text).into());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&text[i..i + 2], 16)
+                .map_err(|_| format!("`{}` is not hex", text).into())
+        })
+        .collect()
+}
+
+impl FrameSpec {
+    pub fn parse(spec: &str) -> Result<Self, Error> {
+        let mut frame = FrameSpec {
+            sync: Vec::new(),
+            length: Length::U8,
+            skip: 0,
+            checksum: Checksum::None,
+            trailer: Vec::new(),
+        };
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("`{}` is not key=value", part))?;
+            match (key.trim(), value.trim()) {
+                ("sync", value) => frame.sync = hex(value)?,
+                ("length", "u8") => frame.length = Length::U8,
+                ("length", "u16be") => frame.length = Length::U16Be,
+                ("length", "u16le") => frame.length = Length::U16Le,
+                ("skip", value) => frame.skip = value.parse()?,
+                ("checksum", "none") => frame.checksum = Checksum::None,
+                ("checksum", "sum8") => frame.checksum = Checksum::Sum8,
+                ("checksum", "xor8") => frame.checksum = Checksum::Xor8,
+                ("checksum", "crc16") => frame.checksum = Checksum::Crc16,
+                ("checksum", "fletcher16") => frame.checksum = Checksum::Fletcher16,
+                ("trailer", value) => frame.trailer = hex(value)?,
+                _ => return Err(format!("unknown framing setting `{}`", part).into()),
+            }
+        }
+        if frame.sync.is_empty() {
+            return Err("framing needs sync bytes to find frames by".into());
+        }
+        Ok(frame)
+    }
+
+    fn header(&self) -> usize {
+        self.sync.len() + self.length.size() + self.skip
+    }
+}
+
+// Frames as a FrameSpec describes them.
+pub struct Frames {
+    spec: FrameSpec,
+    buffer: Vec<u8>,
+    rejected: u64,
+    reported: Instant,
+}
+
+impl Frames {
+    pub fn new(spec: FrameSpec) -> Self {
+        Frames {
+            spec,
+            buffer: Vec::new(),
+            rejected: 0,
+            reported: Instant::now(),
+        }
+    }
+
+    // The payload of the frame at the front of the buffer, taken off it, or
+    // Some(None) for one that failed its checks. None when the frame isn't all
+    // there yet.
+    fn take_frame(&mut self) -> Option<Option<Vec<u8>>> {
+        let spec = &self.spec;
+        let start = self
+            .buffer
+            .windows(spec.sync.len())
+            .position(|w| w == spec.sync.as_slice());
+        match start {
+            Some(start) => {
+                self.buffer.drain(..start);
+            }
+            None => {
+                // Keep what could be the start of the sync.
+                let keep = spec.sync.len() - 1;
+                let drop = self.buffer.len().saturating_sub(keep);
+                self.buffer.drain(..drop);
+                return None;
+            }
+        }
+        if self.buffer.len() < spec.header() {
+            return None;
+        }
+        let len = spec.length.read(&self.buffer[spec.sync.len()..]);
+        let body = spec.header() + len;
+        let total = body + spec.checksum.size() + spec.trailer.len();
+        if len <= MAX_PAYLOAD && self.buffer.len() < total {
+            return None;
+        }
+        let valid = len <= MAX_PAYLOAD
+            && spec.checksum.of(&self.buffer[spec.sync.len()..body])
+                == self.buffer[body..body + spec.checksum.size()]
+            && self.buffer[total - spec.trailer.len()..total] == spec.trailer[..];
+        if !valid {
+            // Look for the next sync from the byte after this one.
+            self.buffer.drain(..1);
+            self.rejected += 1;
+            return Some(None);
+        }
+        let payload = self.buffer[spec.header()..body].to_vec();
+        self.buffer.drain(..total);
+        Some(Some(payload))
+    }
+}
+
+impl Framing for Frames {
+    fn unwrap(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        self.buffer.extend_from_slice(bytes);
+        while let Some(payload) = self.take_frame() {
+            if let Some(payload) = payload {
+                out.extend_from_slice(&payload);
+            }
+        }
+        if self.rejected > 0 && self.reported.elapsed() >= REPORT_INTERVAL {
+            warn!(
+                "Dropped {} bridge frames that failed their checks",
+                self.rejected
+            );
+            self.rejected = 0;
+            self.reported = Instant::now();
+        }
+    }
+
+    fn wrap(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let spec = &self.spec;
+        let mut frame = spec.sync.clone();
+        frame.extend(spec.length.write(bytes.len()));
+        frame.extend(std::iter::repeat_n(0, spec.skip));
+        frame.extend_from_slice(bytes);
+        let sum = spec.checksum.of(&frame[spec.sync.len()..]);
+        frame.extend(sum);
+        frame.extend_from_slice(&spec.trailer);
+        frame
+    }
+}
+
+// The framing a spec like LORDLOGGER_FRAMING's describes.
+pub fn from_spec(spec: &str) -> Result<FramingHandle, Error> {
+    let spec = FrameSpec::parse(spec).map_err(|e| format!("framing `{}`: {}", spec, e))?;
+    Ok(FramingHandle::new(move || Frames::new(spec.clone())))
+}
+
+// A source whose bytes come wrapped, read as the bare MIP stream.
+pub struct Unframed {
+    source: Box<dyn PacketSource>,
+    handle: FramingHandle,
+    framing: Box<dyn Framing>,
+    chunk: Vec<u8>,
+    pending: Vec<u8>,
+    taken: usize,
+}
+
+impl Unframed {
+    pub fn new(source: Box<dyn PacketSource>, handle: FramingHandle) -> Self {
+        Unframed {
+            source,
+            framing: handle.make(),
+            handle,
+            chunk: vec![0; CHUNK],
+            pending: Vec::new(),
+            taken: 0,
+        }
+    }
+}
+
+impl PacketSource for Unframed {
+    fn name(&self) -> String {
+        self.source.name()
+    }
+
+    // Reads until a frame is whole, each read of the source with the full
+    // timeout.
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        while self.taken == self.pending.len() {
+            self.pending.clear();
+            self.taken = 0;
+            let n = self.source.read(&mut self.chunk, timeout)?;
+            self.framing.unwrap(&self.chunk[..n], &mut self.pending);
+        }
+        let n = buf.len().min(self.pending.len() - self.taken);
+        buf[..n].copy_from_slice(&self.pending[self.taken..self.taken + n]);
+        self.taken += n;
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let frame = self.framing.wrap(buf);
+        let mut written = 0;
+        while written < frame.len() {
+            written += self.source.write(&frame[written..])?;
+        }
+        Ok(buf.len())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn PacketSource>> {
+        Ok(Box::new(Unframed::new(
+            self.source.try_clone()?,
+            self.handle.clone(),
+        )))
+    }
+
+    fn answers(&self) -> bool {
+        self.source.answers()
+    }
+}
@@ -0,0 +1,185 @@
+// Positions from a local gpsd, for platforms with a GNSS receiver of their own
+// next to the sensor. Each fix is logged to gpsd_position with the same time
+// columns as the sensor's tables, so the two can be compared by time without
+// another logging tool.
+use crate::fanout::{FanOut, Row};
+use crate::session::GpsTime;
+use crate::Error;
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// gpsd's address, e.g. localhost:2947. Unset leaves gpsd alone.
+pub const GPSD_ENV: &str = "LORDLOGGER_GPSD";
+
+// gpsd reports at least once a second while it has a receiver, so this long
+// without a line means the connection is gone.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY: Duration = Duration::from_secs(5);
+
+const WATCH: &str = "?WATCH={\"enable\":true,\"json\":true};\n";
+
+pub const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS gpsd_position (
+        id BIGSERIAL PRIMARY KEY,
+        device text,
+        mode smallint NOT NULL,
+        latitude double precision NOT NULL,
+        longitude double precision NOT NULL,
+        altitude_hae double precision,
+        altitude_msl double precision,
+        speed real,
+        track real,
+        climb real,
+        eph real,
+        epv real,
+        tow double precision NOT NULL,
+        week smallint NOT NULL
+    );
+
+    COMMENT ON TABLE gpsd_position IS
+        'Fixes from a separate receiver through gpsd, timed like the sensor''s tables';
+    COMMENT ON COLUMN gpsd_position.device IS 'receiver path as gpsd names it';
+    COMMENT ON COLUMN gpsd_position.mode IS '2 for a 2D fix, 3 for 3D';
+    COMMENT ON COLUMN gpsd_position.altitude_hae IS 'm above the WGS84 ellipsoid';
+    COMMENT ON COLUMN gpsd_position.altitude_msl IS 'm above mean sea level';
+    COMMENT ON COLUMN gpsd_position.speed IS 'm/s over ground';
+    COMMENT ON COLUMN gpsd_position.track IS 'deg true, course over ground';
+    COMMENT ON COLUMN gpsd_position.climb IS 'm/s, positive up';
+    COMMENT ON COLUMN gpsd_position.eph IS 'm, estimated horizontal error';
+    COMMENT ON COLUMN gpsd_position.epv IS 'm, estimated vertical error';
+    COMMENT ON COLUMN gpsd_position.tow IS 's, receiver GPS time of week';
+";
+
+// Days from 1970-01-01 to a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Unix seconds of a UTC time as gpsd writes it, e.g. 2024-05-01T12:00:00.250Z.
+fn unix_seconds(time: &str) -> Option<f64> {
+    let (date, clock) = time.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.split('-').map(|p| p.parse::<i64>());
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut clock = clock.split(':');
+    let hours: i64 = clock.next()?.parse().ok()?;
+    let minutes: i64 = clock.next()?.parse().ok()?;
+    let seconds: f64 = clock.next()?.parse().ok()?;
+    let days = days_from_civil(year, month, day);
+    Some((days * 86_400 + hours * 3600 + minutes * 60) as f64 + seconds)
+}
+
+#[derive(Debug, Clone)]
+pub struct Fix {
+    pub device: Option<String>,
+    pub mode: i16,
+    pub time: GpsTime,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude_hae: Option<f64>,
+    pub altitude_msl: Option<f64>,
+    pub speed: Option<f32>,
+    pub track: Option<f32>,
+    pub climb: Option<f32>,
+    pub eph: Option<f32>,
+    pub epv: Option<f32>,
+}
+
+impl Fix {
+    // A TPV report with a 2D or 3D fix, None for anything else gpsd sends.
+    pub fn parse(line: &str) -> Option<Self> {
+        let report: Value = serde_json::from_str(line).ok()?;
+        if report["class"] != "TPV" {
+            return None;
+        }
+        let mode = report["mode"].as_i64()?;
+        if mode < 2 {
+            return None;
+        }
+        let float = |key: &str| report[key].as_f64();
+        let real = |key: &str| report[key].as_f64().map(|v| v as f32);
+
+        Some(Fix {
+            device: report["device"].as_str().map(str::to_string),
+            mode: mode as i16,
+            time: GpsTime::from_unix_seconds(unix_seconds(report["time"].as_str()?)?),
+            latitude: float("lat")?,
+            longitude: float("lon")?,
+            // Older gpsd only has alt, which is MSL.
+            altitude_hae: float("altHAE"),
+            altitude_msl: float("altMSL").or_else(|| float("alt")),
+            speed: real("speed"),
+            track: real("track"),
+            climb: real("climb"),
+            eph: real("eph"),
+            epv: real("epv"),
+        })
+    }
+
+    pub fn insert(&self, out: &FanOut) {
+        out.send(Row::timed(
+            "INSERT INTO gpsd_position (
+                device, mode, latitude, longitude, altitude_hae, altitude_msl,
+                speed, track, climb, eph, epv, tow, week
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+            vec![
+                Box::new(self.device.clone()),
+                Box::new(self.mode),
+                Box::new(self.latitude),
+                Box::new(self.longitude),
+                Box::new(self.altitude_hae),
+                Box::new(self.altitude_msl),
+                Box::new(self.speed),
+                Box::new(self.track),
+                Box::new(self.climb),
+                Box::new(self.eph),
+                Box::new(self.epv),
+                Box::new(self.time.tow),
+                Box::new(self.time.week),
+            ],
+            self.time,
+        ));
+    }
+}
+
+// Logs fixes until the connection drops. Returns how many.
+fn watch(address: &str, out: &FanOut) -> Result<u64, Error> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.write_all(WATCH.as_bytes())?;
+    info!("Watching gpsd at {}", address);
+
+    let mut fixes = 0;
+    for line in BufReader::new(stream).lines() {
+        if let Some(fix) = Fix::parse(&line?) {
+            fix.insert(out);
+            fixes += 1;
+        }
+    }
+    Ok(fixes)
+}
+
+// Starts logging the fixes of gpsd at `address`, when there is one,
+// reconnecting whenever gpsd goes away.
+pub fn start(out: FanOut, address: Option<&str>) {
+    let address = match address {
+        Some(address) => address.to_string(),
+        None => return,
+    };
+
+    thread::spawn(move || loop {
+        match watch(&address, &out) {
+            Ok(fixes) => warn!("gpsd at {} closed after {} fixes", address, fixes),
+            Err(e) => warn!("Lost gpsd at {}. Error: {}", address, e),
+        }
+        thread::sleep(RETRY);
+    });
+}
@@ -0,0 +1,210 @@
+use crate::session::GPS_TIME_SQL;
+use crate::Error;
+use serde_json::{json, Value};
+use tracing::info;
+
+pub const GRAFANA_URL_ENV: &str = "GRAFANA_URL";
+pub const GRAFANA_TOKEN_ENV: &str = "GRAFANA_TOKEN";
+pub const GRAFANA_DATASOURCE_ENV: &str = "GRAFANA_DATASOURCE";
+
+struct Panel {
+    title: &'static str,
+    kind: &'static str,
+    format: &'static str,
+    sql: String,
+}
+
+impl Panel {
+    fn to_json(&self, datasource: &str, index: usize) -> Value {
+        json!({
+            "id": index + 1,
+            "title": self.title,
+            "type": self.kind,
+            "datasource": { "type": "postgres", "uid": datasource },
+            "gridPos": { "h": 9, "w": 12, "x": (index % 2) * 12, "y": (index / 2) * 9 },
+            "targets": [{
+                "refId": "A",
+                "format": self.format,
+                "rawQuery": true,
+                "editorMode": "code",
+                "rawSql": self.sql,
+            }],
+        })
+    }
+}
+
+fn timeseries(title: &'static str, table: &str, columns: &str) -> Panel {
+    Panel {
+        title,
+        kind: "timeseries",
+        format: "time_series",
+        sql: format!(
+            "SELECT {time} AS time, {columns} FROM {table} \
+             WHERE $__timeFilter({time}) ORDER BY 1",
+            time = GPS_TIME_SQL,
+            columns = columns,
+            table = table
+        ),
+    }
+}
+
+fn dashboard(uid: &str, title: &str, datasource: &str, panels: Vec<Panel>) -> Value {
+    let panels: Vec<Value> = panels
+        .iter()
+        .enumerate()
+        .map(|(i, p)| p.to_json(datasource, i))
+        .collect();
+
+    json!({
+        "uid": uid,
+        "title": title,
+        "tags": ["lordlogger"],
+        "time": { "from": "now-1h", "to": "now" },
+        "refresh": "10s",
+        "schemaVersion": 36,
+        "panels": panels,
+    })
+}
+
+fn dashboards(datasource: &str) -> Vec<Value> {
+    vec![
+        dashboard(
+            "lordlogger-attitude",
+            "LORD Attitude",
+            datasource,
+            vec![
+                timeseries(
+                    "Euler angles (rad)",
+                    "imu_data",
+                    "(euler_angles).x AS roll, (euler_angles).y AS pitch, (euler_angles).z AS yaw",
+                ),
+                timeseries(
+                    "Heading (rad)",
+                    "imu_data",
+                    "heading_magnetic, heading_true",
+                ),
+                timeseries(
+                    "Angular rate (rad/s)",
+                    "imu_data",
+                    "(gyro).x AS x, (gyro).y AS y, (gyro).z AS z",
+                ),
+                timeseries(
+                    "Acceleration (g)",
+                    "imu_data",
+                    "(accel).x AS x, (accel).y AS y, (accel).z AS z",
+                ),
+            ],
+        ),
+        dashboard(
+            "lordlogger-position",
+            "LORD Position",
+            datasource,
+            vec![
+                Panel {
+                    title: "Track",
+                    kind: "geomap",
+                    format: "table",
+                    sql: format!(
+                        "SELECT {time} AS time, latitude, longitude FROM gnss_data \
+                         WHERE $__timeFilter({time}) ORDER BY 1",
+                        time = GPS_TIME_SQL
+                    ),
+                },
+                timeseries("Altitude (m)", "gnss_data", "ellipsoid_alt, msl_alt"),
+                timeseries(
+                    "Accuracy (m)",
+                    "gnss_data",
+                    "horizontal_accuracy, vertical_accuracy",
+                ),
+                timeseries("Satellites / fix type", "gnss_data", "svs, fix_type"),
+                timeseries(
+                    "Filter velocity (m/s)",
+                    "ekf_data",
+                    "velocity_north, velocity_east, velocity_down",
+                ),
+            ],
+        ),
+        dashboard(
+            "lordlogger-rates",
+            "LORD Rates",
+            datasource,
+            vec![
+                Panel {
+                    title: "IMU rows per second",
+                    kind: "timeseries",
+                    format: "time_series",
+                    sql: format!(
+                        "SELECT $__timeGroupAlias({time}, 1s), count(*) AS imu FROM imu_data \
+                         WHERE $__timeFilter({time}) GROUP BY 1 ORDER BY 1",
+                        time = GPS_TIME_SQL
+                    ),
+                },
+                Panel {
+                    title: "GNSS rows per second",
+                    kind: "timeseries",
+                    format: "time_series",
+                    sql: format!(
+                        "SELECT $__timeGroupAlias({time}, 1s), count(*) AS gnss FROM gnss_data \
+                         WHERE $__timeFilter({time}) GROUP BY 1 ORDER BY 1",
+                        time = GPS_TIME_SQL
+                    ),
+                },
+            ],
+        ),
+        dashboard(
+            "lordlogger-health",
+            "LORD Health",
+            datasource,
+            vec![
+                Panel {
+                    title: "Events",
+                    kind: "table",
+                    format: "table",
+                    sql: "SELECT created_at AS time, session_id, kind, message FROM events \
+                          WHERE $__timeFilter(created_at) ORDER BY 1 DESC"
+                        .to_string(),
+                },
+                Panel {
+                    title: "Sessions",
+                    kind: "table",
+                    format: "table",
+                    sql: "SELECT id, vehicle_id, port, device_serial, version, started_at, ended_at
+                          FROM sessions ORDER BY id DESC LIMIT 50"
+                        .to_string(),
+                },
+                timeseries("DOP", "gnss_data", "gdop, pdop, hdop, vdop"),
+            ],
+        ),
+    ]
+}
+
+fn env(name: &str) -> Result<String, Error> {
+    std::env::var(name).map_err(|_| format!("{} is not set", name).into())
+}
+
+pub fn provision() -> Result<(), Error> {
+    let url = env(GRAFANA_URL_ENV)?;
+    let token = env(GRAFANA_TOKEN_ENV)?;
+    let datasource = env(GRAFANA_DATASOURCE_ENV)?;
+
+    let endpoint = format!("{}/api/dashboards/db", url.trim_end_matches('/'));
+
+    for dashboard in dashboards(&datasource) {
+        let title = dashboard["title"].as_str().unwrap_or_default().to_string();
+
+        let response = ureq::post(&endpoint)
+            .set("Authorization", &format!("Bearer {}", token))
+            .send_json(json!({ "dashboard": dashboard, "overwrite": true }))
+            .map_err(|e| format!("failed to provision {}: {}", title, e))?;
+
+        let body: Value = response.into_json()?;
+        info!(
+            "Provisioned {} at {}{}",
+            title,
+            url.trim_end_matches('/'),
+            body["url"].as_str().unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
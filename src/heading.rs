@@ -0,0 +1,98 @@
+use crate::config::Data;
+use crate::session::GpsTime;
+use crate::wmm::Wmm;
+use crate::Error;
+use std::f64::consts::PI;
+
+pub const HEADING_REF_ENV: &str = "LORDLOGGER_HEADING_REF";
+pub const WMM_COF_ENV: &str = "LORDLOGGER_WMM_COF";
+
+const SECONDS_PER_YEAR: f64 = 365.2425 * 86_400.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reference {
+    Magnetic,
+    True,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Position {
+    pub lat: f64,
+    pub lon: f64,
+    pub height: f64,
+}
+
+// Turns the device-reported heading into both a magnetic and a true heading,
+// whichever reference the device is configured to report in.
+#[derive(Debug)]
+pub struct HeadingResolver {
+    pub reference: Reference,
+    model: Option<Wmm>,
+    position: Option<Position>,
+}
+
+fn wrap(angle: f64) -> f64 {
+    let wrapped = (angle + PI).rem_euclid(2.0 * PI) - PI;
+    if wrapped == -PI {
+        PI
+    } else {
+        wrapped
+    }
+}
+
+// Decimal year is only used for secular variation, so the mean Gregorian
+// year length is plenty accurate.
+fn decimal_year(time: GpsTime) -> f64 {
+    1970.0 + time.unix_seconds() / SECONDS_PER_YEAR
+}
+
+impl HeadingResolver {
+    pub fn from_settings(data: &Data) -> Result<Self, Error> {
+        let reference = match data.heading_ref.as_deref() {
+            None | Some("magnetic") => Reference::Magnetic,
+            Some("true") => Reference::True,
+            Some(other) => {
+                return Err(format!(
+                    "the heading reference must be `magnetic` or `true`, got `{}`",
+                    other
+                )
+                .into())
+            }
+        };
+
+        let model = match &data.wmm_cof {
+            Some(path) => Some(Wmm::load(path).map_err(|e| format!("{}: {}", path, e))?),
+            None => None,
+        };
+
+        Ok(HeadingResolver {
+            reference,
+            model,
+            position: None,
+        })
+    }
+
+    pub fn update_position(&mut self, position: Position) {
+        self.position = Some(position);
+    }
+
+    pub fn declination(&self, time: GpsTime) -> Option<f64> {
+        let model = self.model.as_ref()?;
+        let p = self.position?;
+        model.declination(p.lat, p.lon, p.height, decimal_year(time))
+    }
+
+    // Returns (magnetic, true) heading in radians. The side that needs the
+    // declination is None until a position fix and a model are available.
+    pub fn resolve(&self, heading: f32, time: GpsTime) -> (Option<f32>, Option<f32>) {
+        let heading = heading as f64;
+        let declination = self.declination(time);
+
+        let (magnetic, true_heading) = match self.reference {
+            Reference::Magnetic => (Some(heading), declination.map(|d| wrap(heading + d))),
+            Reference::True => (declination.map(|d| wrap(heading - d)), Some(heading)),
+        };
+
+        (magnetic.map(|h| h as f32), true_heading.map(|h| h as f32))
+    }
+}
@@ -0,0 +1,209 @@
+// An InfluxDB v2 target, so the data lands in the telemetry stack next to
+// everything else. Its URL is the server's with `influx:` in front and the org
+// and bucket as parameters:
+//
+//   influx:http://influx:8086?org=lab&bucket=lord&rig=north
+//
+// Each table is a measurement and each numeric, boolean or text column a field,
+// composite ones split out as in the CSV files. Rows are tagged with their
+// session_id, vehicle_id and device, the serial port unless the URL gives one,
+// plus any other URL parameters, e.g. rig=north above. They're timestamped
+// with their `time`.
+use crate::csv::Layout;
+use crate::fanout::{FlatSink, TargetConfig};
+use crate::spool::Encoded;
+use crate::vehicle;
+use crate::Error;
+use std::time::Duration;
+
+pub const SCHEME: &str = "influx:";
+// An API token allowed to write to the bucket.
+pub const TOKEN_ENV: &str = "LORDLOGGER_INFLUX_TOKEN";
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+// Lines kept for the next try while the server is unreachable; past this the
+// oldest are dropped.
+const MAX_PENDING: usize = 100_000;
+
+const TAG_COLUMNS: [&str; 2] = ["session_id", vehicle::COLUMN];
+
+// The server URL an `influx:` URL names, None for any other URL.
+pub fn url(url: &str) -> Option<&str> {
+    url.strip_prefix(SCHEME)
+}
+
+// Tags an `influx:` target's rows with the device unless it names its own.
+pub fn tag_device(target: &mut TargetConfig, device: &str) {
+    let query = match url(&target.url) {
+        Some(server) => server.split_once('?').map(|(_, query)| query),
+        None => return,
+    };
+    match query {
+        Some(query) if query.split('&').any(|p| p.starts_with("device=")) => (),
+        Some(_) => target.url = format!("{}&device={}", target.url, device),
+        None => target.url = format!("{}?device={}", target.url, device),
+    }
+}
+
+// Commas, equals signs and spaces are escaped in measurement names, tag keys
+// and tag values alike.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn field(value: &Encoded) -> Option<String> {
+    Some(match value {
+        Encoded::Bool(v) => v.to_string(),
+        Encoded::I16(v) => format!("{}i", v),
+        Encoded::I32(v) => format!("{}i", v),
+        Encoded::I64(v) => format!("{}i", v),
+        // Line protocol has no NaN or infinity.
+        Encoded::F32(v) => Some(f32::from_bits(*v))
+            .filter(|v| v.is_finite())?
+            .to_string(),
+        Encoded::F64(v) => Some(f64::from_bits(*v))
+            .filter(|v| v.is_finite())?
+            .to_string(),
+        Encoded::Text(v) => format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")),
+        _ => return None,
+    })
+}
+
+fn tag(value: &Encoded) -> Option<String> {
+    match value {
+        Encoded::I16(v) => Some(v.to_string()),
+        Encoded::I32(v) => Some(v.to_string()),
+        Encoded::I64(v) => Some(v.to_string()),
+        Encoded::Text(v) if !v.is_empty() => Some(escape(v)),
+        _ => None,
+    }
+}
+
+pub struct Influx {
+    write_url: String,
+    token: Option<String>,
+    tags: Vec<(String, String)>,
+    agent: ureq::Agent,
+    lines: Vec<String>,
+}
+
+impl Influx {
+    pub fn new(url: &str, token: Option<String>) -> Result<Self, Error> {
+        let (server, query) = url.split_once('?').unwrap_or((url, ""));
+        let (mut org, mut bucket, mut tags) = (None, None, Vec::new());
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| format!("influx parameter `{}` has no value", param))?;
+            match key {
+                "org" => org = Some(value),
+                "bucket" => bucket = Some(value),
+                _ => tags.push((escape(key), escape(value))),
+            }
+        }
+        let org = org.ok_or_else(|| format!("{}{} has no org", SCHEME, url))?;
+        let bucket = bucket.ok_or_else(|| format!("{}{} has no bucket", SCHEME, url))?;
+        tags.sort();
+
+        Ok(Influx {
+            write_url: format!(
+                "{}/api/v2/write?org={}&bucket={}&precision=ns",
+                server.trim_end_matches('/'),
+                org,
+                bucket
+            ),
+            token,
+            tags,
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+            lines: Vec::new(),
+        })
+    }
+}
+
+impl FlatSink for Influx {
+    fn write(&mut self, table: &str, layout: &Layout, params: &[Encoded]) -> Result<(), Error> {
+        let mut tags = self.tags.clone();
+        let mut fields = Vec::new();
+        let mut time = None;
+        for (column, i) in layout {
+            let value = match params.get(*i) {
+                Some(value) => value,
+                None => continue,
+            };
+            if column == "time" {
+                if let Encoded::Time(secs, nanos) = value {
+                    time = Some(*secs as u128 * 1_000_000_000 + *nanos as u128);
+                }
+            } else if TAG_COLUMNS.contains(&column.as_str()) {
+                if let Some(value) = tag(value) {
+                    tags.push((column.clone(), value));
+                }
+            } else if let Some(value) = field(value) {
+                fields.push((escape(column), value));
+            }
+        }
+        if fields.is_empty() {
+            return Err(format!("{} row has no fields", table).into());
+        }
+        // A column wins over a tag of the same name, e.g. gpsd's own device.
+        tags.retain(|(key, _)| fields.iter().all(|(field, _)| field != key));
+        tags.sort();
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+
+        let mut line = escape(table);
+        for (key, value) in &tags {
+            line.push_str(&format!(",{}={}", key, value));
+        }
+        line.push(' ');
+        line.push_str(&fields.join(","));
+        if let Some(time) = time {
+            line.push_str(&format!(" {}", time));
+        }
+        self.lines.push(line);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.lines.is_empty() {
+            return Ok(());
+        }
+        let mut request = self
+            .agent
+            .post(&self.write_url)
+            .set("Content-Type", "text/plain; charset=utf-8");
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Token {}", token));
+        }
+
+        match request.send_string(&self.lines.join("\n")) {
+            Ok(_) => {
+                self.lines.clear();
+                Ok(())
+            }
+            // The server read the lines and refused them, so sending them
+            // again won't help.
+            Err(ureq::Error::Status(status, response)) => {
+                self.lines.clear();
+                Err(format!(
+                    "InfluxDB refused the write ({}): {}",
+                    status,
+                    response.into_string().unwrap_or_default()
+                )
+                .into())
+            }
+            Err(e) => {
+                if self.lines.len() > MAX_PENDING {
+                    let excess = self.lines.len() - MAX_PENDING;
+                    self.lines.drain(..excess);
+                }
+                Err(e.into())
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use crate::descriptors::{self, DataDescriptor};
+use crate::fanout::{FanOut, Row};
+use crate::registry::{Shape, GNSS_CODED, GNSS_COLUMNS, IMU_FIELDS};
+use crate::session::GpsTime;
+use crate::shared::SharedData;
+use crate::Error;
+use lordserial::Packet;
+use serde_json::{Map, Value};
+
+pub const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS packets (
+        id BIGSERIAL PRIMARY KEY,
+        descriptor smallint NOT NULL,
+        time timestamptz,
+        tow double precision,
+        week smallint,
+        payload jsonb NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS packets_descriptor_time ON packets (descriptor, time);
+";
+
+fn payload(packet: &Packet) -> Result<Value, Error> {
+    let mut fields = Map::new();
+
+    match descriptors::data_set(packet) {
+        Some(DataDescriptor::Imu) => {
+            for spec in IMU_FIELDS {
+                if let Some(field) = packet.payload.get_field(spec.descriptor) {
+                    let values = spec.values(field)?;
+                    let value = match spec.shape {
+                        Shape::Scalar => Value::from(values[0]),
+                        _ => spec
+                            .channels()
+                            .into_iter()
+                            .zip(values)
+                            .map(|(name, v)| {
+                                let component = name.rsplit('.').next().unwrap_or(&name);
+                                (component.to_string(), Value::from(v))
+                            })
+                            .collect::<Map<String, Value>>()
+                            .into(),
+                    };
+                    fields.insert(spec.column.to_string(), value);
+                }
+            }
+        }
+        Some(DataDescriptor::Gnss) => {
+            for column in GNSS_COLUMNS {
+                if let Some(field) = packet.payload.get_field(column.descriptor) {
+                    if let Some(value) = column.value_f64(field)? {
+                        fields.insert(column.column.to_string(), value.into());
+                    }
+                }
+            }
+            for coded in GNSS_CODED {
+                if let Some(label) = coded.label(packet)? {
+                    fields.insert(coded.name_column.to_string(), label.into());
+                }
+            }
+        }
+        Some(DataDescriptor::Filter) | None => (),
+    }
+
+    for (column, value) in SharedData::from_packet(packet)?.values().iter() {
+        if let Some(value) = value {
+            fields.insert(column.to_string(), (*value).into());
+        }
+    }
+
+    Ok(Value::Object(fields))
+}
+
+// Stores the packet as one row with whatever named fields it carries, so a
+// changed device format never needs a schema change to be recorded.
+pub fn insert(out: &FanOut, packet: &Packet) -> Result<Option<GpsTime>, Error> {
+    let time = GpsTime::from_packet(packet)?;
+
+    out.send(Row::new(
+        "INSERT INTO packets (descriptor, time, tow, week, payload) VALUES ($1, $2, $3, $4, $5)",
+        vec![
+            Box::new(packet.header.descriptor as i16),
+            Box::new(time.map(GpsTime::to_system_time)),
+            Box::new(time.map(|t| t.tow)),
+            Box::new(time.map(|t| t.week)),
+            Box::new(payload(packet)?),
+        ],
+    ));
+
+    Ok(time)
+}
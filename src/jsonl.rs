@@ -0,0 +1,147 @@
+// JSON Lines for log pipelines like jq, Vector or Fluent Bit: a target whose
+// URL is `jsonl:<path>` appends each row it's sent to the file as one JSON
+// object, and `jsonl:-` writes them to stdout:
+//
+//   {"table":"imu_data","accel_x":0.01,"accel_y":-0.02,...,"tow":302418.25}
+//
+// Keys are the columns as in the CSV files, plus the table. Times are seconds
+// since the Unix epoch, JSON columns are embedded as they are, and binary ones
+// are hex. While rows go to stdout, everything the logger prints goes to
+// stderr instead, so stdout stays clean JSON.
+use crate::csv::{self, Layout};
+use crate::fanout::{FlatSink, Tables, TargetConfig};
+use crate::pipeline::Settings;
+use crate::spool::Encoded;
+use crate::Error;
+use serde_json::Value;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+pub const SCHEME: &str = "jsonl:";
+pub const STDOUT: &str = "-";
+
+static STDOUT_TAKEN: AtomicBool = AtomicBool::new(false);
+static STDOUT_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+// The file a `jsonl:` URL names, `-` for stdout, None for any other URL.
+pub fn path(url: &str) -> Option<&str> {
+    url.strip_prefix(SCHEME)
+}
+
+// The target `--json-lines` adds, every table.
+pub fn target(path: &str) -> TargetConfig {
+    TargetConfig {
+        url: format!("{}{}", SCHEME, path),
+        tables: Tables::All,
+    }
+}
+
+fn number(value: f64) -> Value {
+    serde_json::Number::from_f64(value).map_or(Value::Null, Value::Number)
+}
+
+fn value(encoded: &Encoded) -> Value {
+    match encoded {
+        Encoded::Null => Value::Null,
+        Encoded::Bool(v) => Value::Bool(*v),
+        Encoded::I16(v) => Value::from(*v),
+        Encoded::I32(v) => Value::from(*v),
+        Encoded::I64(v) => Value::from(*v),
+        // Through its shortest text, as in sqlite.rs.
+        Encoded::F32(v) => number(f32::from_bits(*v).to_string().parse().unwrap_or(f64::NAN)),
+        Encoded::F64(v) => number(f64::from_bits(*v)),
+        Encoded::Text(v) => Value::String(v.clone()),
+        Encoded::Time(secs, nanos) => number(*secs as f64 + *nanos as f64 * 1e-9),
+        Encoded::Json(v) => v.clone(),
+        Encoded::TextArray(v) => Value::from(v.clone()),
+        Encoded::F64Array(v) => {
+            Value::Array(v.iter().map(|v| number(f64::from_bits(*v))).collect())
+        }
+        Encoded::Bytes(v) => Value::String(v.iter().map(|b| format!("{:02x}", b)).collect()),
+    }
+}
+
+// Moves the process's own output to stderr, keeping the real stdout for the
+// `jsonl:-` or `csv:-` target.
+fn take_stdout() -> Result<(), Error> {
+    if STDOUT_TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    std::io::stdout().flush()?;
+    let fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if fd < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    *STDOUT_FILE.lock().unwrap() = Some(unsafe { File::from_raw_fd(fd) });
+    Ok(())
+}
+
+// Takes stdout before anything is printed when a run will write rows to it.
+pub fn claim_stdout(settings: &Settings) -> Result<(), Error> {
+    let mut urls = settings.sinks.iter().map(|t| t.url.as_str());
+    if settings.json_lines.as_deref() == Some(STDOUT)
+        || urls.any(|url| path(url) == Some(STDOUT) || csv::is_stdout(url))
+    {
+        take_stdout()?;
+    }
+    Ok(())
+}
+
+pub struct JsonLines {
+    out: BufWriter<File>,
+}
+
+// The real stdout, for the one target that writes its rows there.
+pub fn stdout() -> Result<File, Error> {
+    take_stdout()?;
+    Ok(STDOUT_FILE
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or("only one target can write to stdout")?)
+}
+
+impl JsonLines {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let file = if path == STDOUT {
+            stdout()?
+        } else {
+            let path = Path::new(path);
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                fs::create_dir_all(dir)?;
+            }
+            OpenOptions::new().create(true).append(true).open(path)?
+        };
+        Ok(JsonLines {
+            out: BufWriter::new(file),
+        })
+    }
+}
+
+// A row as a JSON object, without the newline. Written by hand to keep the
+// columns in order.
+pub fn object(table: &str, layout: &Layout, params: &[Encoded]) -> String {
+    let mut line = format!("{{\"table\":{}", Value::from(table));
+    for (column, i) in layout {
+        let value = params.get(*i).map_or(Value::Null, value);
+        line.push_str(&format!(",{}:{}", Value::from(column.as_str()), value));
+    }
+    line.push('}');
+    line
+}
+
+impl FlatSink for JsonLines {
+    fn write(&mut self, table: &str, layout: &Layout, params: &[Encoded]) -> Result<(), Error> {
+        writeln!(self.out, "{}", object(table, layout, params))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
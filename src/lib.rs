@@ -0,0 +1,95 @@
+// The acquisition pipeline as a library: `source` sets the device up,
+// `types` and `sinks` turn its packets into rows, and `pipeline` runs the
+// whole logger. The lordlogger binary is a command line over this.
+#![feature(never_type)]
+
+#[macro_use]
+extern crate postgres;
+#[macro_use]
+extern crate postgres_derive;
+
+pub mod adaptive;
+pub mod alert;
+pub mod archive;
+pub mod builder;
+pub mod bus;
+pub mod capture;
+#[cfg(feature = "changefeed")]
+pub mod changefeed;
+pub mod check;
+pub mod clock;
+pub mod command_log;
+pub mod config;
+pub mod control;
+pub mod csv;
+pub mod descriptors;
+pub mod device_status;
+pub mod diff;
+pub mod dump;
+pub mod ekf;
+pub mod environment;
+pub mod failure;
+pub mod fanout;
+pub mod filter;
+pub mod framing;
+pub mod gpsd;
+pub mod grafana;
+pub mod heading;
+pub mod influx;
+pub mod jsonb;
+pub mod jsonl;
+pub mod logging;
+pub mod maintenance;
+pub mod measurements;
+pub mod migrations;
+pub mod mip;
+pub mod mqtt;
+pub mod notify;
+pub mod odometer;
+pub mod packet_source;
+pub mod parquet_files;
+pub mod pipeline;
+pub mod preflight;
+pub mod quality;
+pub mod query;
+pub mod quota;
+pub mod rates;
+pub mod registry;
+pub mod replay;
+pub mod rollover;
+pub mod runtime;
+pub mod scheduler;
+pub mod schema;
+pub mod selection;
+pub mod session;
+pub mod shared;
+pub mod shutdown;
+pub mod sinks;
+pub mod smooth;
+pub mod source;
+pub mod spool;
+pub mod sqlite;
+pub mod stitch;
+pub mod tail;
+pub mod telemetry;
+pub mod timescale;
+pub mod types;
+pub mod udev;
+pub mod vehicle;
+pub mod watchdog;
+pub mod wmm;
+pub mod workers;
+
+use std::any::Any;
+
+pub type Error = Box<dyn std::error::Error + Sync + Send>;
+
+pub fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "panic while handling packet".to_string()
+    }
+}
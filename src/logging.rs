@@ -0,0 +1,34 @@
+// What the logger reports goes through tracing, to stderr, filtered with
+// RUST_LOG as usual and info and up when it's unset:
+//
+//   RUST_LOG=debug                               everything, with spans
+//   RUST_LOG=info,lordlogger::fanout=debug       one module in more detail
+//
+// Events are targeted at the module they come from. Each packet decoded runs
+// in a `packet` span naming its set, and each batch written to a target in an
+// `insert` span with the target as `sink` and the row count, both at debug.
+// LORDLOGGER_LOG_FORMAT=json writes an object per line instead of text, for
+// journald or a log shipper. A program embedding the logger installs its own
+// subscriber instead of calling init().
+use crate::Error;
+use std::io::IsTerminal;
+use tracing_subscriber::EnvFilter;
+
+pub const FORMAT_ENV: &str = "LORDLOGGER_LOG_FORMAT";
+
+pub fn init() -> Result<(), Error> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    match std::env::var(FORMAT_ENV).as_deref() {
+        Ok("json") => builder.json().try_init(),
+        Ok("text") | Err(_) => builder.try_init(),
+        // Text, so the error can still be reported.
+        Ok(other) => {
+            builder.try_init()?;
+            Err(format!("{} must be text or json, not `{}`", FORMAT_ENV, other).into())
+        }
+    }
+}
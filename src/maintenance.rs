@@ -0,0 +1,203 @@
+// The scheduled database upkeep: pruning old rows, hourly row counts and
+// spool compaction, on a thread and connection of its own so none of it holds
+// up the device loop.
+use crate::config;
+use crate::fanout::FanOut;
+use crate::scheduler::Scheduler;
+use crate::timescale;
+use crate::Error;
+use postgres::{Client, Config, NoTls};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// Days of data rows kept in the primary database. Unset keeps everything.
+pub const RETENTION_DAYS_ENV: &str = "LORDLOGGER_RETENTION_DAYS";
+
+pub const TASKS: [&str; 3] = ["retention", "spool_compaction", "rollups"];
+
+// Rows deleted per statement, so pruning never holds long locks.
+const PRUNE_BATCH: i64 = 10_000;
+// Longest sleep between checks, so a task isn't missed by much.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+pub const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS hourly_rows (
+        hour timestamptz NOT NULL,
+        table_name text NOT NULL,
+        rows bigint NOT NULL,
+        PRIMARY KEY (hour, table_name)
+    );
+
+    COMMENT ON TABLE hourly_rows IS
+        'Rows logged per table each hour, kept after retention prunes the rows themselves';
+";
+
+// Data tables and the column that says when each row was logged.
+fn dated_tables(c: &mut Client, tables: &[String]) -> Result<Vec<(String, &'static str)>, Error> {
+    let mut dated = Vec::new();
+    for table in tables {
+        let rows = c.query(
+            "SELECT column_name::text FROM information_schema.columns
+             WHERE table_schema = current_schema() AND table_name = $1
+               AND column_name IN ('time', 'received_at')",
+            &[table],
+        )?;
+        let columns: Vec<String> = rows.iter().map(|r| r.get(0)).collect();
+        if columns.iter().any(|c| c == "time") {
+            dated.push((table.clone(), "time"));
+        } else if columns.iter().any(|c| c == "received_at") {
+            dated.push((table.clone(), "received_at"));
+        }
+    }
+    Ok(dated)
+}
+
+// Hypertables lose whole chunks instead, since deleting by ctid can't tell
+// their chunks' rows apart. Returns the rows deleted and chunks dropped.
+fn prune(c: &mut Client, tables: &[(String, &str)], days: f64) -> Result<(u64, i64), Error> {
+    let mut deleted = 0;
+    let mut chunks = 0;
+    for (table, column) in tables {
+        if timescale::is_hypertable(c, table)? {
+            let dropped: i64 = c
+                .query_one(
+                    format!(
+                        "SELECT count(*) FROM drop_chunks('{}',
+                             older_than => now() - make_interval(secs => $1::float8 * 86400))",
+                        table
+                    )
+                    .as_str(),
+                    &[&days],
+                )?
+                .get(0);
+            chunks += dropped;
+            continue;
+        }
+        loop {
+            let n = c.execute(
+                format!(
+                    "DELETE FROM {table} WHERE ctid IN (
+                        SELECT ctid FROM {table}
+                        WHERE {column} < now() - make_interval(secs => $1::float8 * 86400)
+                        LIMIT $2
+                     )",
+                    table = table,
+                    column = column
+                )
+                .as_str(),
+                &[&days, &PRUNE_BATCH],
+            )?;
+            deleted += n;
+            if n < PRUNE_BATCH as u64 {
+                break;
+            }
+        }
+    }
+    Ok((deleted, chunks))
+}
+
+// Counts the current and previous hour again, since rows for them may still
+// be arriving.
+fn rollup(c: &mut Client, tables: &[(String, &str)]) -> Result<(), Error> {
+    for (table, column) in tables {
+        c.execute(
+            format!(
+                "INSERT INTO hourly_rows (hour, table_name, rows)
+                 SELECT date_trunc('hour', {column}), $1, count(*) FROM {table}
+                 WHERE {column} >= date_trunc('hour', now() - interval '1 hour')
+                 GROUP BY 1
+                 ON CONFLICT (hour, table_name) DO UPDATE SET rows = EXCLUDED.rows",
+                table = table,
+                column = column
+            )
+            .as_str(),
+            &[table],
+        )?;
+    }
+    Ok(())
+}
+
+struct Maintenance {
+    config: Config,
+    client: Option<Client>,
+    out: FanOut,
+    tables: Vec<String>,
+    retention_days: Option<f64>,
+}
+
+impl Maintenance {
+    fn client(&mut self) -> Result<&mut Client, Error> {
+        let client = match self.client.take() {
+            Some(c) if !c.is_closed() => c,
+            _ => self.config.connect(NoTls)?,
+        };
+        Ok(self.client.insert(client))
+    }
+
+    fn run(&mut self, task: &str) -> Result<(), Error> {
+        match task {
+            "spool_compaction" => self.out.compact_spools(),
+            "retention" => {
+                let days = match self.retention_days {
+                    Some(days) => days,
+                    None => return Ok(()),
+                };
+                let tables = self.tables.clone();
+                let c = self.client()?;
+                let dated = dated_tables(c, &tables)?;
+                let (deleted, chunks) = prune(c, &dated, days)?;
+                if deleted > 0 || chunks > 0 {
+                    info!(
+                        "Pruned {} rows and {} chunks older than {} days",
+                        deleted, chunks, days
+                    );
+                }
+            }
+            "rollups" => {
+                let tables = self.tables.clone();
+                let c = self.client()?;
+                let dated = dated_tables(c, &tables)?;
+                rollup(c, &dated)?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+// Starts the maintenance thread for the primary database, unless every task
+// is switched off. `tables` are the data tables retention and rollups cover.
+pub fn start(
+    config: Config,
+    out: FanOut,
+    tables: Vec<String>,
+    settings: &config::Maintenance,
+) -> Result<(), Error> {
+    let mut scheduler = Scheduler::from_settings(settings, &TASKS)?;
+    if scheduler.is_empty() {
+        return Ok(());
+    }
+    let retention_days = settings.retention_days;
+    if retention_days.is_some_and(|days| !(days.is_finite() && days > 0.0)) {
+        return Err("retention must be a positive number of days".into());
+    }
+
+    let mut maintenance = Maintenance {
+        config,
+        client: None,
+        out,
+        tables,
+        retention_days,
+    };
+    thread::spawn(move || loop {
+        thread::sleep(scheduler.until_next().unwrap_or(MAX_SLEEP).min(MAX_SLEEP));
+        for task in scheduler.due() {
+            if let Err(e) = maintenance.run(task) {
+                warn!("Scheduled {} failed. Error: {}", task, e);
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use crate::descriptors::{self, DataDescriptor};
+use crate::fanout::{FanOut, Row};
+use crate::registry::{GNSS_COLUMNS, IMU_FIELDS};
+use crate::session::GpsTime;
+use crate::shared::SharedData;
+use crate::Error;
+use lordserial::Packet;
+
+pub const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS measurements (
+        time timestamptz NOT NULL,
+        device text NOT NULL,
+        channel text NOT NULL,
+        value double precision NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS measurements_channel_time ON measurements (channel, time);
+
+    COMMENT ON COLUMN measurements.device IS
+        'Serial number of the sensor, else its model, USB serial number or port';
+";
+
+fn channels(packet: &Packet) -> Result<(Vec<String>, Vec<f64>), Error> {
+    let mut names = Vec::new();
+    let mut values = Vec::new();
+
+    match descriptors::data_set(packet) {
+        Some(DataDescriptor::Imu) => {
+            for spec in IMU_FIELDS {
+                if let Some(field) = packet.payload.get_field(spec.descriptor) {
+                    names.extend(spec.channels());
+                    values.extend(spec.values(field)?.into_iter().map(f64::from));
+                }
+            }
+        }
+        Some(DataDescriptor::Gnss) => {
+            for column in GNSS_COLUMNS {
+                if let Some(field) = packet.payload.get_field(column.descriptor) {
+                    if let Some(value) = column.value_f64(field)? {
+                        names.push(column.column.to_string());
+                        values.push(value);
+                    }
+                }
+            }
+        }
+        Some(DataDescriptor::Filter) | None => (),
+    }
+
+    for (column, value) in SharedData::from_packet(packet)?.values().iter() {
+        if let Some(value) = value {
+            names.push(column.to_string());
+            values.push(*value as f64);
+        }
+    }
+
+    Ok((names, values))
+}
+
+// Writes every value in the packet as its own row and returns the packet's
+// GPS time, if it has one.
+pub fn insert(out: &FanOut, device: &str, packet: &Packet) -> Result<Option<GpsTime>, Error> {
+    let time = match GpsTime::from_packet(packet)? {
+        Some(time) => time,
+        None => return Ok(None),
+    };
+
+    let (names, values) = channels(packet)?;
+    if names.is_empty() {
+        return Ok(Some(time));
+    }
+
+    out.send(Row::new(
+        "INSERT INTO measurements (time, device, channel, value)
+         SELECT $1, $2, unnest($3::text[]), unnest($4::double precision[])",
+        vec![
+            Box::new(time.to_system_time()),
+            Box::new(device.to_string()),
+            Box::new(names),
+            Box::new(values),
+        ],
+    ));
+
+    Ok(Some(time))
+}
@@ -0,0 +1,204 @@
+// Versioned schema changes. Each migration runs once per database, in order,
+// and is recorded in schema_migrations, so a deployment picks up new tables and
+// columns by itself when the logger is upgraded.
+//
+// Add a change as a new migration at the end; never edit one that has shipped,
+// since databases that already ran it won't run it again. The baseline is the
+// schema as it stood before migrations, written to be safe on databases that
+// already have some or all of it.
+use crate::command_log;
+use crate::device_status;
+use crate::ekf;
+use crate::filter;
+use crate::gpsd;
+use crate::maintenance;
+use crate::odometer;
+use crate::quality;
+use crate::registry;
+use crate::session;
+use crate::shared;
+use crate::smooth;
+use crate::spool;
+use crate::stitch;
+use crate::types;
+use crate::vehicle;
+use crate::Error;
+use postgres::Client;
+
+// Held while migrating, so loggers starting together don't migrate at once.
+const LOCK_KEY: i64 = 0x6c6f_7264_6d69_6772;
+
+const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS schema_migrations (
+        version integer PRIMARY KEY,
+        name text NOT NULL,
+        applied_at timestamptz NOT NULL DEFAULT now()
+    );
+";
+
+const BASE_TABLES: &str = "
+    CREATE TABLE IF NOT EXISTS sessions (
+        id SERIAL PRIMARY KEY,
+        started_at timestamptz NOT NULL DEFAULT now(),
+        ended_at timestamptz
+    );
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS previous_id integer REFERENCES sessions(id);
+
+    CREATE TABLE IF NOT EXISTS events (
+        id SERIAL PRIMARY KEY,
+        session_id integer NOT NULL REFERENCES sessions(id),
+        created_at timestamptz NOT NULL DEFAULT now(),
+        tow double precision,
+        week smallint,
+        kind text NOT NULL,
+        message text NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS imu_data (
+        id SERIAL PRIMARY KEY,
+        accel real3d NOT NULL,
+        gyro real3d NOT NULL,
+        mag real3d NOT NULL,
+        baro real NOT NULL,
+        delta_theta real3d NOT NULL,
+        delta_velocity real3d NOT NULL,
+        quat quaternion NOT NULL,
+        euler_angles real3d NOT NULL,
+        tow double precision NOT NULL,
+        week smallint NOT NULL
+    );
+
+    ALTER TABLE imu_data ADD COLUMN IF NOT EXISTS heading_magnetic real;
+    ALTER TABLE imu_data ADD COLUMN IF NOT EXISTS heading_true real;
+    ALTER TABLE imu_data ADD COLUMN IF NOT EXISTS raw_accel real3d;
+    ALTER TABLE imu_data ADD COLUMN IF NOT EXISTS raw_gyro real3d;
+    ALTER TABLE imu_data ADD COLUMN IF NOT EXISTS raw_mag real3d;
+    ALTER TABLE imu_data ADD COLUMN IF NOT EXISTS raw_baro real;
+
+    CREATE TABLE IF NOT EXISTS clock_bias (
+        id SERIAL PRIMARY KEY,
+        received_at timestamptz NOT NULL,
+        tow double precision NOT NULL,
+        week smallint NOT NULL,
+        offset_s double precision NOT NULL
+    );
+";
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    sql: fn() -> String,
+}
+
+fn baseline() -> String {
+    [
+        types::CREATE_SQL.to_string(),
+        BASE_TABLES.to_string(),
+        registry::gnss_create_sql(),
+        shared::create_sql("imu_data"),
+        shared::create_sql("gnss_data"),
+        stitch::CREATE_SQL.to_string(),
+        quality::CREATE_SQL.to_string(),
+        odometer::CREATE_SQL.to_string(),
+        filter::CREATE_SQL.to_string(),
+        ekf::CREATE_SQL.to_string(),
+        vehicle::CREATE_SQL.to_string(),
+        session::CREATE_SQL.to_string(),
+        device_status::CREATE_SQL.to_string(),
+        maintenance::CREATE_SQL.to_string(),
+        spool::CREATE_SQL.to_string(),
+    ]
+    .join("\n")
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "baseline",
+        sql: baseline,
+    },
+    Migration {
+        version: 2,
+        name: "command_log",
+        sql: || command_log::CREATE_SQL.to_string(),
+    },
+    Migration {
+        version: 3,
+        name: "gpsd_position",
+        sql: || gpsd::CREATE_SQL.to_string(),
+    },
+    Migration {
+        version: 4,
+        name: "session_key",
+        sql: || session::KEY_SQL.to_string(),
+    },
+    Migration {
+        version: 5,
+        name: "trajectory_smoothed",
+        sql: || smooth::CREATE_SQL.to_string(),
+    },
+    Migration {
+        version: 6,
+        name: "sensor_serial",
+        sql: || session::DEVICE_SERIAL_SQL.to_string(),
+    },
+    Migration {
+        version: 7,
+        name: "sensor_session_key",
+        sql: || session::SENSOR_KEY_SQL.to_string(),
+    },
+];
+
+pub fn latest() -> i32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+// The version a database is at, 0 before any migration.
+pub fn current(c: &mut Client) -> Result<i32, Error> {
+    c.batch_execute(CREATE_SQL)?;
+    let row = c.query_one(
+        "SELECT coalesce(max(version), 0) FROM schema_migrations",
+        &[],
+    )?;
+    Ok(row.get(0))
+}
+
+// Applies the migrations a database hasn't run, all in one transaction.
+// Returns the ones applied.
+pub fn run(c: &mut Client) -> Result<Vec<&'static Migration>, Error> {
+    c.batch_execute(CREATE_SQL)?;
+    let mut tx = c.transaction()?;
+    tx.execute("SELECT pg_advisory_xact_lock($1)", &[&LOCK_KEY])?;
+
+    let version: i32 = tx
+        .query_one(
+            "SELECT coalesce(max(version), 0) FROM schema_migrations",
+            &[],
+        )?
+        .get(0);
+    if version > latest() {
+        return Err(format!(
+            "database schema is at version {}, newer than the {} this build knows; upgrade lordlogger",
+            version,
+            latest()
+        )
+        .into());
+    }
+
+    let pending: Vec<&Migration> = MIGRATIONS.iter().filter(|m| m.version > version).collect();
+    for migration in &pending {
+        tx.batch_execute(&(migration.sql)()).map_err(|e| {
+            format!(
+                "migration {} ({}) failed: {}",
+                migration.version, migration.name, e
+            )
+        })?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+            &[&migration.version, &migration.name],
+        )?;
+    }
+    tx.commit()?;
+
+    Ok(pending)
+}
@@ -0,0 +1,470 @@
+use crate::descriptors::{self, CommandDescriptor, ACK_NACK};
+use crate::dump;
+use crate::packet_source::SourcePort;
+use crate::Error;
+use lordserial::Packet;
+use serde::Serialize;
+use serialport::SerialPort;
+use std::fmt;
+use std::io::{self, ErrorKind, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+// Raw MIP commands for settings lordserial has no call for. Replies arrive on
+// the data stream like any other packet, so the port the parser reads hands
+// what it reads to Replies while a command waits on its ACK or NACK.
+pub const SYNC: [u8; 2] = [0x75, 0x65];
+
+// How long a command waits for the device to answer it.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+// Bytes held while looking for a reply, past which the oldest are let go.
+const REPLY_BUFFER: usize = 4096;
+
+pub const FUNCTION_APPLY: u8 = 0x01;
+
+// 3DM message format and data stream enable
+pub const MESSAGE_FORMAT: u8 = 0x0F;
+pub const ENABLE_STREAM: u8 = 0x11;
+
+// Base get device information and the field its reply carries: the firmware
+// version, then five strings of 16 bytes padded with spaces.
+const GET_DEVICE_INFO: u8 = 0x03;
+const DEVICE_INFO: u8 = 0x81;
+const INFO_STRING: usize = 16;
+
+pub fn checksum(bytes: &[u8]) -> [u8; 2] {
+    let (mut a, mut b) = (0u8, 0u8);
+    for byte in bytes {
+        a = a.wrapping_add(*byte);
+        b = b.wrapping_add(a);
+    }
+    [a, b]
+}
+
+// One packet holding a single field.
+pub fn frame(set: CommandDescriptor, field: u8, data: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.len() > 253 {
+        return Err(format!("MIP field 0x{:02X} is too long", field).into());
+    }
+
+    let mut packet = SYNC.to_vec();
+    packet.push(set.into());
+    packet.push(data.len() as u8 + 2);
+    packet.push(data.len() as u8 + 2);
+    packet.push(field);
+    packet.extend_from_slice(data);
+    let sum = checksum(&packet);
+    packet.extend_from_slice(&sum);
+
+    Ok(packet)
+}
+
+// The fields of a MIP payload, each as its descriptor and data.
+pub fn fields(payload: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut fields = Vec::new();
+    let mut rest = payload;
+    while let [len, descriptor, ..] = *rest {
+        let len = len as usize;
+        if len < 2 || len > rest.len() {
+            break;
+        }
+        fields.push((descriptor, &rest[2..len]));
+        rest = &rest[len..];
+    }
+    fields
+}
+
+// The device turned a command down, with the error code from its NACK.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Nack {
+    pub set: u8,
+    pub field: u8,
+    pub code: u8,
+}
+
+impl fmt::Display for Nack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.code {
+            0x01 => "unknown command",
+            0x02 => "invalid checksum",
+            0x03 => "invalid parameter",
+            0x04 => "command failed",
+            0x05 => "command timed out",
+            _ => "unknown error",
+        };
+        write!(
+            f,
+            "device rejected {} field 0x{:02X}: {} (0x{:02X})",
+            descriptors::describe_set(self.set),
+            self.field,
+            reason,
+            self.code
+        )
+    }
+}
+
+impl std::error::Error for Nack {}
+
+// What a command's reply carried besides its ACK, each field's descriptor and
+// data, or the code of its NACK.
+type Reply = Result<Vec<(u8, Vec<u8>)>, u8>;
+
+struct Awaited {
+    set: u8,
+    field: u8,
+    bytes: Vec<u8>,
+    reply: Option<Reply>,
+}
+
+impl Awaited {
+    // Looks through what's been read for the reply, keeping a packet that's
+    // still coming in.
+    fn scan(&mut self) {
+        loop {
+            let start = match self.bytes.windows(2).position(|w| w == SYNC) {
+                Some(start) => start,
+                None => {
+                    let keep = usize::from(self.bytes.last() == Some(&SYNC[0]));
+                    self.bytes.drain(..self.bytes.len() - keep);
+                    return;
+                }
+            };
+            self.bytes.drain(..start);
+            let len = match self.bytes.get(3) {
+                Some(len) => *len as usize,
+                None => return,
+            };
+            if self.bytes.len() < 6 + len {
+                return;
+            }
+            let packet: Vec<u8> = self.bytes.drain(..6 + len).collect();
+            if checksum(&packet[..4 + len]) != packet[4 + len..] {
+                // Not a packet after all; look again past its sync bytes.
+                self.bytes.splice(..0, packet[2..].iter().copied());
+                continue;
+            }
+            if packet[2] != self.set {
+                continue;
+            }
+            let fields = fields(&packet[4..4 + len]);
+            let acked = fields
+                .iter()
+                .find(|(d, data)| *d == ACK_NACK && data.first() == Some(&self.field));
+            if let Some((_, ack)) = acked {
+                let code = ack.get(1).copied().unwrap_or(0);
+                self.reply = Some(match code {
+                    0 => Ok(fields
+                        .iter()
+                        .filter(|(d, _)| *d != ACK_NACK)
+                        .map(|(d, data)| (*d, data.to_vec()))
+                        .collect()),
+                    code => Err(code),
+                });
+                return;
+            }
+        }
+    }
+}
+
+// Shared by a port's handles: the parser's feeds it what it reads, and a
+// command port waits on it.
+#[derive(Clone, Default)]
+pub struct Replies(Arc<(Mutex<Option<Awaited>>, Condvar)>);
+
+impl Replies {
+    pub fn feed(&self, bytes: &[u8]) {
+        let (awaited, arrived) = &*self.0;
+        let mut awaited = awaited.lock().unwrap();
+        if let Some(awaited) = awaited.as_mut().filter(|a| a.reply.is_none()) {
+            awaited.bytes.extend_from_slice(bytes);
+            awaited.scan();
+            if awaited.reply.is_some() {
+                arrived.notify_all();
+            } else if awaited.bytes.len() > REPLY_BUFFER {
+                let over = awaited.bytes.len() - REPLY_BUFFER;
+                awaited.bytes.drain(..over);
+            }
+        }
+    }
+
+    fn expect(&self, set: u8, field: u8) {
+        *self.0 .0.lock().unwrap() = Some(Awaited {
+            set,
+            field,
+            bytes: Vec::new(),
+            reply: None,
+        });
+    }
+
+    fn wait(&self, timeout: Duration) -> Option<Reply> {
+        let (awaited, arrived) = &*self.0;
+        let awaited = awaited.lock().unwrap();
+        let (mut awaited, _) = arrived
+            .wait_timeout_while(awaited, timeout, |a| {
+                a.as_ref().is_some_and(|a| a.reply.is_none())
+            })
+            .unwrap();
+        awaited.take().and_then(|a| a.reply)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceInfo {
+    pub firmware: u16,
+    pub model: String,
+    pub model_number: String,
+    pub serial: String,
+    pub lot: String,
+    pub options: String,
+}
+
+impl DeviceInfo {
+    fn parse(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < 2 + 5 * INFO_STRING {
+            return Err(format!("device information is {} bytes, too short", data.len()).into());
+        }
+        let text = |n: usize| {
+            let start = 2 + n * INFO_STRING;
+            String::from_utf8_lossy(&data[start..start + INFO_STRING])
+                .trim()
+                .to_string()
+        };
+        Ok(DeviceInfo {
+            firmware: u16::from_be_bytes([data[0], data[1]]),
+            model: text(0),
+            model_number: text(1),
+            serial: text(2),
+            lot: text(3),
+            options: text(4),
+        })
+    }
+
+    // The reply as the device sends it, which a recording starts with so a
+    // replay knows what it came from.
+    pub fn frame(&self) -> Vec<u8> {
+        let mut data = self.firmware.to_be_bytes().to_vec();
+        for text in [
+            &self.model,
+            &self.model_number,
+            &self.serial,
+            &self.lot,
+            &self.options,
+        ]
+        .iter()
+        {
+            let mut field = format!("{:<width$}", text, width = INFO_STRING).into_bytes();
+            field.truncate(INFO_STRING);
+            data.extend_from_slice(&field);
+        }
+        frame(CommandDescriptor::Base, DEVICE_INFO, &data).expect("device information fits a field")
+    }
+
+    // A device information reply read back from a recording.
+    pub fn from_packet(packet: &Packet) -> Option<Self> {
+        if packet.header.descriptor != u8::from(CommandDescriptor::Base) {
+            return None;
+        }
+        let field = packet
+            .payload
+            .fields
+            .iter()
+            .find(|field| field.descriptor == DEVICE_INFO)?;
+        Self::parse(&dump::bytes(field)).ok()
+    }
+
+    // What the device goes by in the data: its serial number, or its model
+    // for one that reports none.
+    pub fn name(&self) -> Option<&str> {
+        [self.serial.as_str(), self.model.as_str()]
+            .iter()
+            .copied()
+            .find(|s| !s.is_empty())
+    }
+}
+
+// lordserial owns the port it reads, so raw commands go out on a second
+// handle to it. Sources with a device behind them wait for its reply; the
+// rest, a capture or stdin, have nobody to answer.
+pub struct CommandPort {
+    port: Box<dyn SerialPort>,
+    replies: Option<Replies>,
+}
+
+impl CommandPort {
+    pub fn new(serial: &SourcePort) -> Result<Self, Error> {
+        Ok(CommandPort {
+            port: serial.try_clone()?,
+            replies: serial.replies(),
+        })
+    }
+
+    pub fn send(&mut self, set: CommandDescriptor, field: u8, data: &[u8]) -> Result<(), Error> {
+        self.request(set, field, data).map(drop)
+    }
+
+    // Sends a command and returns the fields its reply carried besides the
+    // ACK, none for a source nobody answers. A NACK is a Nack error.
+    pub fn request(
+        &mut self,
+        set: CommandDescriptor,
+        field: u8,
+        data: &[u8],
+    ) -> Result<Vec<(u8, Vec<u8>)>, Error> {
+        let packet = frame(set, field, data)?;
+        let replies = match &self.replies {
+            Some(replies) => replies,
+            None => {
+                self.port.write_all(&packet)?;
+                self.port.flush()?;
+                return Ok(Vec::new());
+            }
+        };
+        replies.expect(set.into(), field);
+        self.port.write_all(&packet)?;
+        self.port.flush()?;
+        match replies.wait(REPLY_TIMEOUT) {
+            Some(Ok(fields)) => Ok(fields),
+            Some(Err(code)) => Err(Nack {
+                set: set.into(),
+                field,
+                code,
+            }
+            .into()),
+            None => Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "no reply from the device to {} field 0x{:02X}",
+                    descriptors::describe_set(set.into()),
+                    field
+                ),
+            )
+            .into()),
+        }
+    }
+
+    // What the device says it is, None for a source nobody answers.
+    pub fn device_info(&mut self) -> Result<Option<DeviceInfo>, Error> {
+        if self.replies.is_none() {
+            return Ok(None);
+        }
+        let fields = self.request(CommandDescriptor::Base, GET_DEVICE_INFO, &[])?;
+        match fields
+            .iter()
+            .find(|(descriptor, _)| *descriptor == DEVICE_INFO)
+        {
+            Some((_, data)) => DeviceInfo::parse(data).map(Some),
+            None => Err("the device information reply has no information field".into()),
+        }
+    }
+
+    // 3DM message format for a data set, the same shape lordserial sends for
+    // the IMU and GNSS sets.
+    pub fn set_format(&mut self, set: u8, fields: &[(u8, u16)]) -> Result<(), Error> {
+        let mut data = vec![FUNCTION_APPLY, set, fields.len() as u8];
+        for (descriptor, decimation) in fields {
+            data.push(*descriptor);
+            data.extend_from_slice(&decimation.to_be_bytes());
+        }
+        self.send(CommandDescriptor::ThreeDm, MESSAGE_FORMAT, &data)?;
+        self.send(
+            CommandDescriptor::ThreeDm,
+            ENABLE_STREAM,
+            &[FUNCTION_APPLY, set, 1],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(set: u8, fields: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for (descriptor, data) in fields {
+            payload.push(data.len() as u8 + 2);
+            payload.push(*descriptor);
+            payload.extend_from_slice(data);
+        }
+        let mut packet = SYNC.to_vec();
+        packet.push(set);
+        packet.push(payload.len() as u8);
+        packet.extend(payload);
+        let sum = checksum(&packet);
+        packet.extend_from_slice(&sum);
+        packet
+    }
+
+    fn awaiting(set: u8, field: u8) -> Replies {
+        let replies = Replies::default();
+        replies.expect(set, field);
+        replies
+    }
+
+    #[test]
+    fn finds_the_ack_among_other_packets() {
+        let replies = awaiting(0x0C, 0x0F);
+        let mut bytes = vec![0x00, 0x75];
+        bytes.extend(reply(0x80, &[(0x04, &[0; 12])]));
+        bytes.extend(reply(0x0C, &[(ACK_NACK, &[0x11, 0x00])]));
+        let ack = reply(0x0C, &[(ACK_NACK, &[0x0F, 0x00]), (0x81, &[1, 2])]);
+        let (head, tail) = ack.split_at(5);
+        replies.feed(&bytes);
+        replies.feed(head);
+        replies.feed(tail);
+        assert_eq!(
+            replies.wait(Duration::ZERO),
+            Some(Ok(vec![(0x81, vec![1, 2])]))
+        );
+    }
+
+    #[test]
+    fn reports_the_nack_code() {
+        let replies = awaiting(0x0D, 0x31);
+        replies.feed(&reply(0x0D, &[(ACK_NACK, &[0x31, 0x03])]));
+        assert_eq!(replies.wait(Duration::ZERO), Some(Err(0x03)));
+    }
+
+    #[test]
+    fn skips_a_corrupt_packet() {
+        let replies = awaiting(0x0C, 0x0F);
+        let mut corrupt = reply(0x0C, &[(ACK_NACK, &[0x0F, 0x00])]);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xFF;
+        replies.feed(&corrupt);
+        assert_eq!(replies.wait(Duration::ZERO), None);
+
+        let replies = awaiting(0x0C, 0x0F);
+        replies.feed(&corrupt);
+        replies.feed(&reply(0x0C, &[(ACK_NACK, &[0x0F, 0x00])]));
+        assert_eq!(replies.wait(Duration::ZERO), Some(Ok(Vec::new())));
+    }
+
+    #[test]
+    fn parses_device_information() {
+        let mut data = vec![0x04, 0x01];
+        for text in ["3DM-GX5-45", "6251-4220", "  6251.12345", "L1", ""] {
+            data.extend(format!("{:<16}", text).bytes());
+        }
+        let info = DeviceInfo::parse(&data).unwrap();
+        assert_eq!(info.firmware, 0x0401);
+        assert_eq!(info.model, "3DM-GX5-45");
+        assert_eq!(info.serial, "6251.12345");
+        assert_eq!(info.name(), Some("6251.12345"));
+        let framed = info.frame();
+        assert_eq!(
+            checksum(&framed[..framed.len() - 2]),
+            framed[framed.len() - 2..]
+        );
+        assert_eq!(
+            DeviceInfo::parse(&framed[6..framed.len() - 2]).unwrap(),
+            info
+        );
+
+        let unnamed = DeviceInfo {
+            serial: String::new(),
+            ..info
+        };
+        assert_eq!(unnamed.name(), Some("3DM-GX5-45"));
+        assert!(DeviceInfo::parse(&data[..40]).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use crate::archive;
+use crate::quality;
+use crate::rates::RateGroup;
+use crate::session::{Window, GPS_TIME_SQL};
+use crate::Error;
+use postgres::Client;
+use serde_json::{json, Map, Value};
+
+// POSTed a JSON summary whenever a session ends.
+pub const WEBHOOK_ENV: &str = "LORDLOGGER_WEBHOOK_URL";
+
+// What the running logger knows about its session that the database doesn't.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RunStats {
+    pub packets: u64,
+    pub decode_errors: u64,
+    pub missing_fields: u64,
+}
+
+fn gnss_quality(c: &mut Client, window: &Window) -> Result<Value, Error> {
+    let exists: bool = c
+        .query_one("SELECT to_regclass('gnss_data') IS NOT NULL", &[])?
+        .get(0);
+    if !exists {
+        return Ok(Value::Null);
+    }
+
+    // Fix types 0 and 1 are 3D and 2D fixes.
+    let row = c.query_one(
+        format!(
+            "SELECT count(*), count(*) FILTER (WHERE fix_type IN (0, 1)),
+                avg(hdop)::float8, max(horizontal_accuracy)::float8
+             FROM gnss_data WHERE {}",
+            window.filter(GPS_TIME_SQL)
+        )
+        .as_str(),
+        &[],
+    )?;
+    let fixes: i64 = row.get(0);
+    let with_fix: i64 = row.get(1);
+
+    Ok(json!({
+        "gnss_packets": fixes,
+        "fix_ratio": if fixes > 0 { with_fix as f64 / fixes as f64 } else { 0.0 },
+        "mean_hdop": row.get::<_, Option<f64>>(2),
+        "worst_horizontal_accuracy": row.get::<_, Option<f64>>(3),
+    }))
+}
+
+pub fn summary(
+    c: &mut Client,
+    session: i32,
+    stats: Option<RunStats>,
+    groups: &[RateGroup],
+) -> Result<Value, Error> {
+    let window = Window::load(c, session)?;
+
+    let duration: f64 = c
+        .query_one(
+            "SELECT extract(epoch FROM $2::text::timestamptz - $1::text::timestamptz)::float8",
+            &[&window.started_at, &window.until],
+        )?
+        .get(0);
+
+    let rows: Map<String, Value> = archive::row_counts(c, &window, groups)?
+        .into_iter()
+        .map(|(table, count)| (table, count.into()))
+        .collect();
+
+    let events: Map<String, Value> = c
+        .query(
+            "SELECT kind, count(*) FROM events WHERE session_id = $1 GROUP BY kind ORDER BY kind",
+            &[&session],
+        )?
+        .iter()
+        .map(|row| (row.get::<_, String>(0), row.get::<_, i64>(1).into()))
+        .collect();
+
+    let mut quality = json!({
+        "gnss": gnss_quality(c, &window)?,
+        "events": events,
+    });
+    if let Some(stats) = stats {
+        quality["packets"] = stats.packets.into();
+        quality["decode_errors"] = stats.decode_errors.into();
+        quality["missing_fields"] = stats.missing_fields.into();
+    }
+    quality["assessment"] = match quality::load(c, session)? {
+        Some(assessment) => assessment,
+        None => quality::assess(c, session, stats)?,
+    };
+
+    Ok(json!({
+        "event": "session_complete",
+        "session_id": session,
+        "vehicle_id": window.vehicle_id,
+        "port": window.port,
+        "device_serial": window.device_serial,
+        "version": window.version,
+        "started_at": window.started_at,
+        "ended_at": window.ended_at,
+        "duration_s": duration,
+        "rows": rows,
+        "quality": quality,
+    }))
+}
+
+// Returns false when no webhook is configured.
+pub fn send(summary: &Value, url: Option<&str>) -> Result<bool, Error> {
+    let url = match url {
+        Some(url) => url,
+        None => return Ok(false),
+    };
+
+    ureq::post(url)
+        .send_json(summary.clone())
+        .map_err(|e| format!("session webhook failed: {}", e))?;
+
+    Ok(true)
+}
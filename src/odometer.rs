@@ -0,0 +1,140 @@
+use crate::descriptors::{CommandDescriptor, FilterField};
+use crate::fanout::{FanOut, Row};
+use crate::mip::{self, CommandPort};
+use crate::registry::Read;
+use crate::session::GpsTime;
+use crate::Error;
+use lordserial::Packet;
+use serde::{Deserialize, Serialize};
+
+// 0x0D/0x43, the GQ7's wheel encoder input.
+const ODOMETER_SETTINGS: u8 = 0x43;
+const MODE_QUADRATURE: u8 = 0x02;
+
+pub const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS odometer_data (
+        id BIGSERIAL PRIMARY KEY,
+        tow double precision NOT NULL,
+        week smallint NOT NULL,
+        scale_factor_error real,
+        scale_factor_uncertainty real
+    );
+
+    COMMENT ON COLUMN odometer_data.scale_factor_error IS
+        'Filter estimate of the odometer scale error, unitless. Source: Filter (0x82/0x47)';
+    COMMENT ON COLUMN odometer_data.scale_factor_uncertainty IS
+        '1-sigma, unitless. Source: Filter (0x82/0x48)';
+";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Forward,
+    Reverse,
+}
+
+fn default_uncertainty() -> f32 {
+    0.01
+}
+
+fn default_decimation() -> u16 {
+    50
+}
+
+// The `[odometer]` table of the settings file:
+//
+//   [odometer]
+//   pulses_per_meter = 1250.0
+//   direction = "reverse"    # encoder counts down going forward
+//   uncertainty = 0.01       # 1-sigma, fraction of distance
+//   decimation = 50          # of the filter base rate, for odometer_data
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Odometer {
+    pub pulses_per_meter: f32,
+    #[serde(default)]
+    pub direction: Direction,
+    #[serde(default = "default_uncertainty")]
+    pub uncertainty: f32,
+    #[serde(default = "default_decimation")]
+    pub decimation: u16,
+}
+
+impl Odometer {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(self.pulses_per_meter.is_finite() && self.pulses_per_meter > 0.0) {
+            return Err("odometer pulses_per_meter must be positive".into());
+        }
+        if !(self.uncertainty.is_finite() && self.uncertainty >= 0.0) {
+            return Err("odometer uncertainty can't be negative".into());
+        }
+        if self.decimation == 0 {
+            return Err("odometer decimation must be at least 1".into());
+        }
+        Ok(())
+    }
+
+    // The device takes direction as the sign of the scale.
+    fn scaling(&self) -> f32 {
+        match self.direction {
+            Direction::Forward => self.pulses_per_meter,
+            Direction::Reverse => -self.pulses_per_meter,
+        }
+    }
+
+    // Filter fields for odometer_data.
+    pub fn format(&self) -> Vec<(u8, u16)> {
+        vec![
+            (
+                FilterField::OdometerScaleFactorError.into(),
+                self.decimation,
+            ),
+            (
+                FilterField::OdometerScaleFactorUncertainty.into(),
+                self.decimation,
+            ),
+            (FilterField::GpsTimestamp.into(), self.decimation),
+        ]
+    }
+}
+
+pub fn configure(port: &mut CommandPort, config: &Odometer) -> Result<(), Error> {
+    let mut data = vec![mip::FUNCTION_APPLY, MODE_QUADRATURE];
+    data.extend_from_slice(&config.scaling().to_be_bytes());
+    data.extend_from_slice(&config.uncertainty.to_be_bytes());
+    port.send(CommandDescriptor::Filter, ODOMETER_SETTINGS, &data)
+}
+
+// Writes the filter's odometer estimates, if the packet has them.
+pub fn insert(out: &FanOut, packet: &Packet) -> Result<bool, Error> {
+    let time = match GpsTime::from_packet(packet)? {
+        Some(time) => time,
+        None => return Ok(false),
+    };
+    let estimate = |field: FilterField| -> Result<Option<f32>, Error> {
+        match packet.payload.get_field(field.into()) {
+            Some(f) if f.read::<u16>(4)? & 0x01 == 0x01 => Ok(Some(f.read(0)?)),
+            _ => Ok(None),
+        }
+    };
+    let error = estimate(FilterField::OdometerScaleFactorError)?;
+    let uncertainty = estimate(FilterField::OdometerScaleFactorUncertainty)?;
+    if error.is_none() && uncertainty.is_none() {
+        return Ok(false);
+    }
+
+    out.send(Row::timed(
+        "INSERT INTO odometer_data (tow, week, scale_factor_error, scale_factor_uncertainty)
+         VALUES ($1, $2, $3, $4)",
+        vec![
+            Box::new(time.tow),
+            Box::new(time.week),
+            Box::new(error),
+            Box::new(uncertainty),
+        ],
+        time,
+    ));
+
+    Ok(true)
+}
@@ -0,0 +1,646 @@
+// Where the device's bytes come from. --port takes any of:
+//
+//   /dev/ttyACM0       a serial port, read through tokio-serial
+//   tcp://host:port    a ser2net or socat bridge to one, with commands sent
+//                      back over the same connection
+//   file:<path>        a capture from --record or a raw dump, in real time
+//   -                  raw MIP bytes on stdin, with commands dropped
+//
+// With LORDLOGGER_FRAMING, see framing.rs, any of them is unwrapped first.
+//
+// Each is a PacketSource, put behind the SerialPort lordserial reads from by
+// SourcePort, so the parser and the command port use any of them as they
+// would the device. A program embedding the logger can hand its own to
+// SourcePort, or canned bytes in a MemorySource to run the pipeline without
+// hardware.
+// Preflight checks, udev lookups and unplug detection are only for serial
+// ports.
+//
+// lordserial parses on a thread of its own and hands packets over through
+// get_data, which doesn't block. Its thread only asks the port for more bytes
+// once it has handed over the packets in those it had, so the SourcePort wakes
+// whoever waits on Packets then, and the loops await their packets rather
+// than poll for them.
+use crate::framing::{FramingHandle, Unframed};
+use crate::mip::Replies;
+use crate::replay::ReplayPort;
+use crate::runtime;
+use crate::Error;
+use lordserial::parser::Lord;
+use lordserial::Packet;
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::Notify;
+use tokio::time;
+use tokio_serial::SerialStream;
+use tracing::{info, warn};
+
+pub const TCP: &str = "tcp://";
+pub const FILE: &str = "file:";
+pub const STDIN: &str = "-";
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT: Duration = Duration::from_secs(1);
+
+pub trait PacketSource: Send {
+    fn name(&self) -> String;
+
+    // Up to buf.len() bytes, or a TimedOut error when none came within the
+    // timeout, as a serial port does.
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize>;
+
+    // Bytes for the device. Sources that can't reach one drop them.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    // Another handle on the same source, for sending commands while the
+    // parser reads.
+    fn try_clone(&self) -> io::Result<Box<dyn PacketSource>>;
+
+    // Whether the source has run out for good, as stdin does, after which
+    // decoding it stops. A device or bridge only ever goes quiet.
+    fn ended(&self) -> bool {
+        false
+    }
+
+    // Whether a device behind the source answers what's written to it, so
+    // commands wait for their replies.
+    fn answers(&self) -> bool {
+        false
+    }
+}
+
+// Whether --port names a serial port rather than one of the other sources.
+pub fn is_serial(port: &str) -> bool {
+    !(port.starts_with(TCP) || port.starts_with(FILE) || port == STDIN)
+}
+
+// The source --port names, as the port lordserial reads from, unwrapped with
+// the framing when the device is behind a bridge that frames its packets.
+pub fn open(port: &str, baud: u32, framing: Option<&FramingHandle>) -> Result<SourcePort, Error> {
+    let source: Box<dyn PacketSource> = if let Some(address) = port.strip_prefix(TCP) {
+        Box::new(TcpSource::connect(address)?)
+    } else if let Some(path) = port.strip_prefix(FILE) {
+        let replay = ReplayPort::open(Path::new(path), baud, true)?;
+        // A capture's packets were unwrapped when they were recorded.
+        if replay.is_capture() {
+            return Ok(SourcePort::new(Box::new(replay), baud));
+        }
+        Box::new(replay)
+    } else if port == STDIN {
+        Box::new(StdinSource::new())
+    } else {
+        Box::new(SerialSource::open(port, baud)?)
+    };
+    Ok(SourcePort::new(unframed(source, framing), baud))
+}
+
+// The source unwrapped with the framing, if there is one.
+pub fn unframed(
+    source: Box<dyn PacketSource>,
+    framing: Option<&FramingHandle>,
+) -> Box<dyn PacketSource> {
+    match framing {
+        Some(framing) => Box::new(Unframed::new(source, framing.clone())),
+        None => source,
+    }
+}
+
+// A serial port, read and written through tokio-serial on the runtime, see
+// runtime.rs. Handles share the port's halves, so a read waiting out its
+// timeout doesn't hold up a command being written.
+pub struct SerialSource {
+    name: String,
+    reader: Arc<tokio::sync::Mutex<ReadHalf<SerialStream>>>,
+    writer: Arc<tokio::sync::Mutex<WriteHalf<SerialStream>>>,
+}
+
+impl SerialSource {
+    pub fn open(path: &str, baud: u32) -> Result<Self, Error> {
+        // The stream registers with the runtime's reactor as it opens.
+        let _runtime = runtime::get().enter();
+        let stream = SerialStream::open(&tokio_serial::new(path, baud))?;
+        let (reader, writer) = tokio::io::split(stream);
+        Ok(SerialSource {
+            name: path.to_string(),
+            reader: Arc::new(tokio::sync::Mutex::new(reader)),
+            writer: Arc::new(tokio::sync::Mutex::new(writer)),
+        })
+    }
+}
+
+impl PacketSource for SerialSource {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        runtime::get().block_on(async {
+            let mut reader = self.reader.lock().await;
+            match time::timeout(timeout, reader.read(buf)).await {
+                Ok(read) => read,
+                Err(_) => Err(ErrorKind::TimedOut.into()),
+            }
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        runtime::get().block_on(async {
+            let mut writer = self.writer.lock().await;
+            writer.write_all(buf).await?;
+            writer.flush().await?;
+            Ok(buf.len())
+        })
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn PacketSource>> {
+        Ok(Box::new(SerialSource {
+            name: self.name.clone(),
+            reader: self.reader.clone(),
+            writer: self.writer.clone(),
+        }))
+    }
+
+    fn answers(&self) -> bool {
+        true
+    }
+}
+
+struct Connection {
+    address: String,
+    stream: Option<TcpStream>,
+    // Counts connections, so handles can tell theirs has been replaced.
+    generation: u64,
+    attempted: Option<Instant>,
+}
+
+impl Connection {
+    fn connect(&mut self) -> io::Result<()> {
+        self.attempted = Some(Instant::now());
+        let mut last = io::Error::new(ErrorKind::NotFound, "no address to connect to");
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    self.stream = Some(stream);
+                    self.generation += 1;
+                    return Ok(());
+                }
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+}
+
+// A serial bridge over TCP. A dropped connection is made again, a second
+// apart, so a restarted ser2net looks like a device that went quiet for a
+// while.
+pub struct TcpSource {
+    connection: Arc<Mutex<Connection>>,
+    stream: Option<(u64, TcpStream)>,
+}
+
+impl TcpSource {
+    pub fn connect(address: &str) -> Result<Self, Error> {
+        let mut connection = Connection {
+            address: address.to_string(),
+            stream: None,
+            generation: 0,
+            attempted: None,
+        };
+        connection
+            .connect()
+            .map_err(|e| format!("failed to connect to {}: {}", address, e))?;
+        Ok(TcpSource {
+            connection: Arc::new(Mutex::new(connection)),
+            stream: None,
+        })
+    }
+
+    // This handle's copy of the current connection, connecting again when
+    // it's been lost. TimedOut while there isn't one.
+    fn stream(&mut self) -> io::Result<(u64, &TcpStream)> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.stream.is_none() {
+            if connection
+                .attempted
+                .is_some_and(|t| t.elapsed() < RECONNECT)
+            {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            match connection.connect() {
+                Ok(()) => info!("Connected to {} again", connection.address),
+                Err(_) => return Err(ErrorKind::TimedOut.into()),
+            }
+        }
+        let generation = connection.generation;
+        if self.stream.as_ref().map(|(g, _)| *g) != Some(generation) {
+            let stream = connection.stream.as_ref().unwrap().try_clone()?;
+            self.stream = Some((generation, stream));
+        }
+        let (_, stream) = self.stream.as_ref().unwrap();
+        Ok((generation, stream))
+    }
+
+    fn lost(&mut self, generation: u64, reason: &str) {
+        self.stream = None;
+        let mut connection = self.connection.lock().unwrap();
+        if connection.generation == generation && connection.stream.is_some() {
+            warn!(
+                "Lost the connection to {} ({}), reconnecting",
+                connection.address, reason
+            );
+            connection.stream = None;
+        }
+    }
+}
+
+impl PacketSource for TcpSource {
+    fn name(&self) -> String {
+        format!("{}{}", TCP, self.connection.lock().unwrap().address)
+    }
+
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let (generation, mut stream) = match self.stream() {
+            Ok(stream) => stream,
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                thread::sleep(timeout);
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        match stream.read(buf) {
+            Ok(0) => {
+                self.lost(generation, "closed by the other end");
+                Err(ErrorKind::TimedOut.into())
+            }
+            Ok(n) => Ok(n),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Err(ErrorKind::TimedOut.into())
+            }
+            Err(e) => {
+                self.lost(generation, &e.to_string());
+                Err(ErrorKind::TimedOut.into())
+            }
+        }
+    }
+
+    // Commands while disconnected are dropped, the watchdog sends the setup
+    // again once the data is back.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (generation, mut stream) = match self.stream() {
+            Ok(stream) => stream,
+            Err(e) if e.kind() == ErrorKind::TimedOut => return Ok(buf.len()),
+            Err(e) => return Err(e),
+        };
+        match stream.write_all(buf) {
+            Ok(()) => Ok(buf.len()),
+            Err(e) => {
+                self.lost(generation, &e.to_string());
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn PacketSource>> {
+        Ok(Box::new(TcpSource {
+            connection: self.connection.clone(),
+            stream: None,
+        }))
+    }
+
+    fn answers(&self) -> bool {
+        true
+    }
+}
+
+// Bytes piped in, say `socat /dev/ttyACM0,raw - | lordlogger --port -`. The
+// read blocks until there are some, and stdin running out reads as a device
+// gone quiet. Clones share whether it has.
+#[derive(Clone, Default)]
+pub struct StdinSource {
+    ended: Arc<AtomicBool>,
+}
+
+impl StdinSource {
+    pub fn new() -> Self {
+        StdinSource::default()
+    }
+}
+
+impl PacketSource for StdinSource {
+    fn name(&self) -> String {
+        "stdin".to_string()
+    }
+
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        if !self.ended() {
+            match io::stdin().lock().read(buf)? {
+                0 => self.ended.store(true, Ordering::Relaxed),
+                n => return Ok(n),
+            }
+        }
+        thread::sleep(timeout);
+        Err(ErrorKind::TimedOut.into())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn PacketSource>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn ended(&self) -> bool {
+        self.ended.load(Ordering::Relaxed)
+    }
+}
+
+// Bytes held in memory, say a canned capture for running the pipeline in a
+// test. They're read out once and then the source has ended, as stdin does.
+// What's written for the device is kept. Clones share both.
+#[derive(Clone, Default)]
+pub struct MemorySource {
+    unread: Arc<Mutex<VecDeque<u8>>>,
+    written: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemorySource {
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        MemorySource {
+            unread: Arc::new(Mutex::new(bytes.into().into())),
+            written: Arc::default(),
+        }
+    }
+
+    // Everything written to the source so far, the commands a device would
+    // have been sent.
+    pub fn written(&self) -> Vec<u8> {
+        self.written.lock().unwrap().clone()
+    }
+}
+
+impl PacketSource for MemorySource {
+    fn name(&self) -> String {
+        "memory".to_string()
+    }
+
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let mut unread = self.unread.lock().unwrap();
+        if unread.is_empty() {
+            drop(unread);
+            thread::sleep(timeout);
+            return Err(ErrorKind::TimedOut.into());
+        }
+        let n = buf.len().min(unread.len());
+        for (to, from) in buf.iter_mut().zip(unread.drain(..n)) {
+            *to = from;
+        }
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn PacketSource>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn ended(&self) -> bool {
+        self.unread.lock().unwrap().is_empty()
+    }
+}
+
+// Any source as a serial port. The line settings are kept but mean nothing to
+// the source.
+pub struct SourcePort {
+    source: Box<dyn PacketSource>,
+    baud: u32,
+    timeout: Duration,
+    // What's read goes by here too, for commands waiting on their replies.
+    replies: Option<Replies>,
+    // Shared with the port's clones and the Packets reading it.
+    arrived: Arc<Notify>,
+    // Whether the last read brought bytes, whose packets the parser has
+    // handed over by the time it reads again.
+    parsing: bool,
+}
+
+impl SourcePort {
+    pub fn new(source: Box<dyn PacketSource>, baud: u32) -> Self {
+        SourcePort {
+            replies: source.answers().then(Replies::default),
+            source,
+            baud,
+            timeout: TIMEOUT,
+            arrived: Arc::default(),
+            parsing: false,
+        }
+    }
+
+    // Shared with the port's clones, None when nothing answers commands.
+    pub fn replies(&self) -> Option<Replies> {
+        self.replies.clone()
+    }
+}
+
+impl Read for SourcePort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Kept until someone waits when no one is yet, so it isn't lost.
+        if self.parsing {
+            self.arrived.notify_one();
+        }
+        self.parsing = false;
+        let n = self.source.read(buf, self.timeout)?;
+        if let Some(replies) = &self.replies {
+            replies.feed(&buf[..n]);
+        }
+        self.parsing = n > 0;
+        Ok(n)
+    }
+}
+
+impl Write for SourcePort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.source.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for SourcePort {
+    fn name(&self) -> Option<String> {
+        Some(self.source.name())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud: u32) -> serialport::Result<()> {
+        self.baud = baud;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(SourcePort {
+            source: self.source.try_clone()?,
+            baud: self.baud,
+            timeout: self.timeout,
+            replies: self.replies.clone(),
+            arrived: self.arrived.clone(),
+            parsing: false,
+        }))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
+
+// lordserial reading the port, with its packets awaited. Derefs to the Lord
+// for setting the device up.
+pub struct Packets {
+    lord: Lord,
+    arrived: Arc<Notify>,
+}
+
+impl Packets {
+    // Starts the parser on the port.
+    pub fn start(port: SourcePort) -> Self {
+        let arrived = port.arrived.clone();
+        let mut lord = Lord::new(Box::new(port));
+        lord.start();
+        Packets { lord, arrived }
+    }
+
+    // The next packet, or None when none comes within the timeout.
+    pub async fn next(&mut self, timeout: Duration) -> Option<Packet> {
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            if let Some(packet) = self.lord.get_data() {
+                return Some(packet);
+            }
+            if time::timeout_at(deadline, self.arrived.notified())
+                .await
+                .is_err()
+            {
+                return self.lord.get_data();
+            }
+        }
+    }
+
+    // next, for a loop on a thread of its own rather than a task.
+    pub fn wait(&mut self, timeout: Duration) -> Option<Packet> {
+        runtime::get().block_on(self.next(timeout))
+    }
+}
+
+impl Deref for Packets {
+    type Target = Lord;
+
+    fn deref(&self) -> &Lord {
+        &self.lord
+    }
+}
+
+impl DerefMut for Packets {
+    fn deref_mut(&mut self) -> &mut Lord {
+        &mut self.lord
+    }
+}
@@ -0,0 +1,306 @@
+// Parquet files for offline analysis with pandas or polars: a target whose URL
+// is `parquet:<dir>` writes each table it's sent to `<dir>/<table>/`, one file
+// per window of minutes, named after the window's start in Unix seconds:
+//
+//   imu_data/1714564800.parquet
+//   imu_data/1714565400.parquet
+//
+// so `pl.scan_parquet("<dir>/imu_data/*.parquet")` reads a whole recording.
+// Columns are as in the CSV files, with times as UTC timestamps, and JSON and
+// arrays as JSON text.
+//
+// A file is only readable once it's closed, so it's written as `.parquet.tmp`
+// and renamed at the end of its window or when the logger stops. A crash loses
+// the window it was in.
+use crate::csv::Layout;
+use crate::fanout::FlatSink;
+use crate::spool::Encoded;
+use crate::Error;
+use arrow_array::{
+    ArrayRef, BinaryArray, BooleanArray, Float32Array, Float64Array, Int16Array, Int32Array,
+    Int64Array, NullArray, RecordBatch, StringArray, TimestampNanosecondArray,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+pub const SCHEME: &str = "parquet:";
+// Minutes of data in each file, 10 by default. Windows start on multiples of
+// it, e.g. on the hour and every 10 minutes after.
+pub const MINUTES_ENV: &str = "LORDLOGGER_PARQUET_MINUTES";
+
+const DEFAULT_MINUTES: u64 = 10;
+// Rows held per table before they're written as a row group.
+const ROW_GROUP_ROWS: usize = 10_000;
+
+// The directory a `parquet:` URL names, None for any other URL.
+pub fn dir(url: &str) -> Option<&Path> {
+    url.strip_prefix(SCHEME).map(Path::new)
+}
+
+fn data_type(value: &Encoded) -> Option<DataType> {
+    Some(match value {
+        Encoded::Null => return None,
+        Encoded::Bool(_) => DataType::Boolean,
+        Encoded::I16(_) => DataType::Int16,
+        Encoded::I32(_) => DataType::Int32,
+        Encoded::I64(_) => DataType::Int64,
+        Encoded::F32(_) => DataType::Float32,
+        Encoded::F64(_) => DataType::Float64,
+        Encoded::Time(..) => DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())),
+        Encoded::Bytes(_) => DataType::Binary,
+        Encoded::Text(_) | Encoded::Json(_) | Encoded::TextArray(_) | Encoded::F64Array(_) => {
+            DataType::Utf8
+        }
+    })
+}
+
+fn text(value: &Encoded) -> Option<String> {
+    match value {
+        Encoded::Text(v) => Some(v.clone()),
+        Encoded::Json(v) => Some(v.to_string()),
+        Encoded::TextArray(v) => Some(serde_json::json!(v).to_string()),
+        Encoded::F64Array(v) => {
+            let values: Vec<f64> = v.iter().map(|v| f64::from_bits(*v)).collect();
+            Some(serde_json::json!(values).to_string())
+        }
+        _ => None,
+    }
+}
+
+// One column of a row group. Values that aren't of the column's type, which
+// the same statement never sends, are left null.
+fn array(data_type: &DataType, values: &[&Encoded]) -> ArrayRef {
+    macro_rules! collect {
+        ($array:ident, $pattern:pat => $value:expr) => {
+            Arc::new($array::from(
+                values
+                    .iter()
+                    .map(|v| match v {
+                        $pattern => Some($value),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>(),
+            ))
+        };
+    }
+    match data_type {
+        DataType::Boolean => collect!(BooleanArray, Encoded::Bool(v) => *v),
+        DataType::Int16 => collect!(Int16Array, Encoded::I16(v) => *v),
+        DataType::Int32 => collect!(Int32Array, Encoded::I32(v) => *v),
+        DataType::Int64 => collect!(Int64Array, Encoded::I64(v) => *v),
+        DataType::Float32 => collect!(Float32Array, Encoded::F32(v) => f32::from_bits(*v)),
+        DataType::Float64 => collect!(Float64Array, Encoded::F64(v) => f64::from_bits(*v)),
+        DataType::Timestamp(..) => {
+            let times: Vec<Option<i64>> = values
+                .iter()
+                .map(|v| match v {
+                    Encoded::Time(secs, nanos) => {
+                        Some(*secs as i64 * 1_000_000_000 + *nanos as i64)
+                    }
+                    _ => None,
+                })
+                .collect();
+            Arc::new(TimestampNanosecondArray::from(times).with_timezone("UTC"))
+        }
+        DataType::Binary => collect!(BinaryArray, Encoded::Bytes(v) => v.as_slice()),
+        DataType::Utf8 => {
+            let texts: Vec<Option<String>> = values.iter().map(|v| text(v)).collect();
+            Arc::new(StringArray::from(texts))
+        }
+        _ => Arc::new(NullArray::new(values.len())),
+    }
+}
+
+struct Open {
+    path: PathBuf,
+    schema: SchemaRef,
+    writer: ArrowWriter<File>,
+}
+
+impl Open {
+    fn close(self) -> Result<(), Error> {
+        self.writer.close()?;
+        fs::rename(&self.path, self.path.with_extension(""))?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Table {
+    columns: Vec<String>,
+    rows: Vec<Vec<Encoded>>,
+    file: Option<Open>,
+}
+
+// The tables' files for the current window. A table whose columns or column
+// types change, say a column that was all null gets values, starts a new file.
+pub struct ParquetFiles {
+    dir: PathBuf,
+    minutes: u64,
+    window: u64,
+    tables: HashMap<String, Table>,
+}
+
+fn window(minutes: u64) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    now - now % (minutes * 60)
+}
+
+impl ParquetFiles {
+    pub fn new(dir: &Path, minutes: Option<u64>) -> Result<Self, Error> {
+        let minutes = minutes.unwrap_or(DEFAULT_MINUTES);
+        if minutes == 0 {
+            return Err("parquet files must hold at least a minute each".into());
+        }
+        fs::create_dir_all(dir)?;
+        Ok(ParquetFiles {
+            dir: dir.to_path_buf(),
+            minutes,
+            window: window(minutes),
+            tables: HashMap::new(),
+        })
+    }
+
+    // The window's file for a table, `<start>.<n>.parquet` when an earlier
+    // run already wrote `<start>.parquet`.
+    fn open(&self, table: &str, schema: SchemaRef) -> Result<Open, Error> {
+        let dir = self.dir.join(table);
+        fs::create_dir_all(&dir)?;
+        for n in 0.. {
+            let name = match n {
+                0 => format!("{}.parquet.tmp", self.window),
+                n => format!("{}.{}.parquet.tmp", self.window, n),
+            };
+            let path = dir.join(name);
+            if path.exists() || path.with_extension("").exists() {
+                continue;
+            }
+            let properties = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build();
+            let writer =
+                ArrowWriter::try_new(File::create(&path)?, schema.clone(), Some(properties))?;
+            return Ok(Open {
+                path,
+                schema,
+                writer,
+            });
+        }
+        unreachable!()
+    }
+
+    // Writes a table's held rows as a row group.
+    fn write_rows(&mut self, name: &str) -> Result<(), Error> {
+        let table = match self.tables.get_mut(name) {
+            Some(table) if !table.rows.is_empty() => table,
+            _ => return Ok(()),
+        };
+        let rows = std::mem::take(&mut table.rows);
+        let mut fields = Vec::new();
+        let mut arrays = Vec::new();
+        for (i, column) in table.columns.iter().enumerate() {
+            let values: Vec<&Encoded> = rows.iter().map(|row| &row[i]).collect();
+            let data_type = values
+                .iter()
+                .find_map(|v| data_type(v))
+                .unwrap_or(DataType::Null);
+            arrays.push(array(&data_type, &values));
+            fields.push(Field::new(column, data_type, true));
+        }
+        let schema = Arc::new(Schema::new(fields));
+
+        if let Some(file) = table.file.take() {
+            if file.schema == schema {
+                table.file = Some(file);
+            } else {
+                file.close()?;
+            }
+        }
+        if table.file.is_none() {
+            let file = self.open(name, schema.clone())?;
+            self.tables.get_mut(name).unwrap().file = Some(file);
+        }
+        let writer = &mut self
+            .tables
+            .get_mut(name)
+            .unwrap()
+            .file
+            .as_mut()
+            .unwrap()
+            .writer;
+        writer.write(&RecordBatch::try_new(schema, arrays)?)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Writes what's held and closes every file.
+    fn close(&mut self) -> Result<(), Error> {
+        let names: Vec<String> = self.tables.keys().cloned().collect();
+        for name in names {
+            self.write_rows(&name)?;
+            if let Some(file) = self.tables.get_mut(&name).unwrap().file.take() {
+                file.close()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FlatSink for ParquetFiles {
+    fn write(&mut self, table: &str, layout: &Layout, params: &[Encoded]) -> Result<(), Error> {
+        let columns: Vec<String> = layout.iter().map(|(column, _)| column.clone()).collect();
+        if self
+            .tables
+            .get(table)
+            .is_some_and(|held| held.columns != columns)
+        {
+            self.write_rows(table)?;
+        }
+        let held = self.tables.entry(table.to_string()).or_default();
+        held.columns = columns;
+        held.rows.push(
+            layout
+                .iter()
+                .map(|(_, i)| params.get(*i).cloned().unwrap_or(Encoded::Null))
+                .collect(),
+        );
+        if held.rows.len() >= ROW_GROUP_ROWS {
+            self.write_rows(table)?;
+        }
+        Ok(())
+    }
+
+    // Rows are held until there are enough for a row group, since small ones
+    // make slow files; flushing only moves on to the next window's files.
+    fn flush(&mut self) -> Result<(), Error> {
+        let now = window(self.minutes);
+        if now != self.window {
+            self.close()?;
+            self.window = now;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ParquetFiles {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            error!(
+                "Failed to close the Parquet files in {}. Error: {}",
+                self.dir.display(),
+                e
+            );
+        }
+    }
+}
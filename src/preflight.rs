@@ -0,0 +1,149 @@
+use crate::failure::{Failure, FailureKind};
+use crate::packet_source;
+use postgres::{Client, NoTls};
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+pub const MIN_FREE_BYTES: u64 = 1024 * 1024 * 1024;
+// 2021-01-01T00:00:00Z, anything earlier means the host clock was never set.
+const MIN_SANE_TIME: u64 = 1_609_459_200;
+
+enum Outcome {
+    Pass,
+    Warn(String),
+    Fail(String),
+}
+
+fn check_device(port: &str) -> Outcome {
+    if Path::new(port).exists() {
+        Outcome::Pass
+    } else {
+        Outcome::Fail(format!(
+            "{} does not exist. Is the sensor plugged in and powered? \
+             Check `ls /dev/ttyACM* /dev/ttyUSB*` and `dmesg | tail` for the assigned port.",
+            port
+        ))
+    }
+}
+
+fn check_permissions(port: &str) -> Outcome {
+    match OpenOptions::new().read(true).write(true).open(port) {
+        Ok(_) => Outcome::Pass,
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Outcome::Fail(format!(
+            "no read/write access to {}. Add this user to the dialout group with \
+             `sudo usermod -aG dialout $USER` and log in again, or run \
+             `sudo lordlogger install-udev` for a stable /dev/lord0 writable by that group.",
+            port
+        )),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            Outcome::Warn(format!("skipped, {} is not present", port))
+        }
+        Err(e) => Outcome::Fail(format!("could not open {}: {}", port, e)),
+    }
+}
+
+fn check_database(db_url: &str) -> Outcome {
+    match Client::connect(db_url, NoTls) {
+        Ok(_) => Outcome::Pass,
+        Err(e) => Outcome::Fail(format!(
+            "could not connect ({}). Check that PostgreSQL is running \
+             (`systemctl status postgresql`), that the role and database in the URL exist, \
+             and that pg_hba.conf allows password logins from this host.",
+            e
+        )),
+    }
+}
+
+pub fn free_bytes(path: &str) -> std::io::Result<u64> {
+    let c_path = CString::new(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+fn check_disk(path: &str) -> Outcome {
+    match free_bytes(path) {
+        Ok(free) if free >= MIN_FREE_BYTES => Outcome::Pass,
+        Ok(free) => Outcome::Fail(format!(
+            "only {} MiB free on the filesystem holding {}. Free up space or prune old \
+             data before starting a long logging run.",
+            free / (1024 * 1024),
+            path
+        )),
+        Err(e) => Outcome::Warn(format!("could not query free space: {}", e)),
+    }
+}
+
+fn check_clock() -> Outcome {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+
+    if now.as_secs() >= MIN_SANE_TIME {
+        Outcome::Pass
+    } else {
+        Outcome::Warn(
+            "host clock looks unset, host-side timestamps will be wrong. \
+             Enable NTP with `sudo timedatectl set-ntp true` or set the time manually."
+                .to_string(),
+        )
+    }
+}
+
+// The database check is skipped when running without one.
+pub fn run(port: &str, db_url: Option<&str>) -> Result<(), Failure> {
+    let mut checks = Vec::new();
+    // A bridge, file or pipe is found out when it's opened.
+    if packet_source::is_serial(port) {
+        checks.push((
+            "serial device present",
+            FailureKind::Serial,
+            check_device(port),
+        ));
+        checks.push((
+            "serial permissions",
+            FailureKind::Serial,
+            check_permissions(port),
+        ));
+    }
+    if let Some(db_url) = db_url {
+        checks.push((
+            "database reachable",
+            FailureKind::Database,
+            check_database(db_url),
+        ));
+    }
+    checks.push(("disk space", FailureKind::Other, check_disk(".")));
+    checks.push(("clock sanity", FailureKind::Other, check_clock()));
+
+    let mut failed = 0;
+    let mut first_kind = None;
+    for (name, kind, outcome) in checks {
+        match outcome {
+            Outcome::Pass => info!("[ OK ] {}", name),
+            Outcome::Warn(hint) => warn!("[WARN] {}: {}", name, hint),
+            Outcome::Fail(hint) => {
+                failed += 1;
+                first_kind.get_or_insert(kind);
+                error!("[FAIL] {}: {}", name, hint);
+            }
+        }
+    }
+
+    if let Some(kind) = first_kind {
+        return Err(Failure::new(
+            kind,
+            format!("{} preflight check(s) failed", failed),
+        ));
+    }
+
+    Ok(())
+}
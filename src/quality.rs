@@ -0,0 +1,147 @@
+// One number per session for triage, 0 to 100, built from:
+//   coverage  share of the session's seconds that have data           (50%)
+//   fix       share of GNSS packets with a 3D, 2D or RTK fix          (30%)
+//   anomalies alerts, device resets, clock wander and decode errors,
+//             at ANOMALY_CEILING per hour or more scoring zero        (20%)
+// Gaps in the data count against coverage; they're reported on their own too.
+use crate::notify::RunStats;
+use crate::registry::{self, GNSS_CODED};
+use crate::session::{Window, GPS_TIME_SQL};
+use crate::stitch;
+use crate::Error;
+use postgres::Client;
+use serde_json::{json, Map, Value};
+
+pub const CREATE_SQL: &str = "
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS quality_score real;
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS quality jsonb;
+";
+
+// A pause in the data longer than this is a gap.
+const GAP_SECONDS: f64 = 1.0;
+const ANOMALY_CEILING: f64 = 10.0;
+const ANOMALY_EVENTS: [&str; 3] = ["alert", "device_reset", "clock"];
+
+fn data_seconds(c: &mut Client, window: &Window) -> Result<(i64, i64), Error> {
+    let (table, time) = match stitch::extent_table(c)? {
+        Some(source) => source,
+        None => return Ok((0, 0)),
+    };
+
+    let row = c.query_one(
+        format!(
+            "SELECT count(DISTINCT floor(extract(epoch FROM t)))::int8,
+                count(*) FILTER (WHERE gap > {gap})
+             FROM (SELECT t, extract(epoch FROM t - lag(t) OVER (ORDER BY t)) AS gap
+                   FROM (SELECT {time} AS t FROM {table} WHERE {filter}) times) gaps",
+            gap = GAP_SECONDS,
+            time = time,
+            table = table,
+            filter = window.filter(time)
+        )
+        .as_str(),
+        &[],
+    )?;
+
+    Ok((row.get(0), row.get(1)))
+}
+
+fn fix_types(c: &mut Client, window: &Window) -> Result<(Map<String, Value>, f64), Error> {
+    let exists: bool = c
+        .query_one("SELECT to_regclass('gnss_data') IS NOT NULL", &[])?
+        .get(0);
+    if !exists {
+        return Ok((Map::new(), 0.0));
+    }
+
+    let rows = c.query(
+        format!(
+            "SELECT fix_type, count(*) FROM gnss_data WHERE {} GROUP BY fix_type ORDER BY fix_type",
+            window.filter(GPS_TIME_SQL)
+        )
+        .as_str(),
+        &[],
+    )?;
+
+    let mut counts = Map::new();
+    let (mut total, mut fixed) = (0, 0);
+    for row in rows {
+        let fix_type: Option<i16> = row.get(0);
+        let count: i64 = row.get(1);
+        let label = fix_type
+            .and_then(|t| GNSS_CODED[0].labels.iter().find(|(code, _)| *code == t))
+            .map_or("unknown", |(_, label)| *label);
+
+        total += count;
+        if registry::is_fix(label) {
+            fixed += count;
+        }
+        counts.insert(label.to_string(), count.into());
+    }
+
+    let ratio = if total > 0 {
+        fixed as f64 / total as f64
+    } else {
+        0.0
+    };
+    Ok((counts, ratio))
+}
+
+pub fn assess(c: &mut Client, session: i32, stats: Option<RunStats>) -> Result<Value, Error> {
+    let window = Window::load(c, session)?;
+
+    let duration: f64 = c
+        .query_one(
+            "SELECT extract(epoch FROM $2::text::timestamptz - $1::text::timestamptz)::float8",
+            &[&window.started_at, &window.until],
+        )?
+        .get(0);
+    let (seconds, gaps) = data_seconds(c, &window)?;
+    let coverage = if duration > 0.0 {
+        (seconds as f64 / duration).min(1.0)
+    } else {
+        0.0
+    };
+
+    let (fix_types, fix_ratio) = fix_types(c, &window)?;
+
+    let events: i64 = c
+        .query_one(
+            "SELECT count(*) FROM events WHERE session_id = $1 AND kind = ANY($2)",
+            &[&session, &&ANOMALY_EVENTS[..]],
+        )?
+        .get(0);
+    let anomalies = events as u64 + stats.map_or(0, |s| s.decode_errors);
+    let hours = (duration / 3600.0).max(1.0 / 60.0);
+    let anomaly_rate = anomalies as f64 / hours;
+
+    let score = 100.0
+        * (0.5 * coverage
+            + 0.3 * fix_ratio
+            + 0.2 * (1.0 - (anomaly_rate / ANOMALY_CEILING).min(1.0)));
+
+    Ok(json!({
+        "score": (score * 10.0).round() / 10.0,
+        "coverage": coverage,
+        "gaps": gaps,
+        "fix_types": fix_types,
+        "fix_ratio": fix_ratio,
+        "anomalies": anomalies,
+        "anomalies_per_hour": anomaly_rate,
+    }))
+}
+
+pub fn store(c: &mut Client, session: i32, assessment: &Value) -> Result<(), Error> {
+    let score = assessment["score"].as_f64().map(|s| s as f32);
+    c.execute(
+        "UPDATE sessions SET quality_score = $2, quality = $3 WHERE id = $1",
+        &[&session, &score, assessment],
+    )?;
+    Ok(())
+}
+
+// The stored assessment, if the session was scored when it ended.
+pub fn load(c: &mut Client, session: i32) -> Result<Option<Value>, Error> {
+    let row = c.query_opt("SELECT quality FROM sessions WHERE id = $1", &[&session])?;
+    Ok(row.and_then(|row| row.get(0)))
+}
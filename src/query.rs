@@ -0,0 +1,166 @@
+// Read-side helpers mapping stored rows back onto the types the logger
+// writes, for tools that consume the data without hand-rolling SQL.
+#![allow(dead_code)]
+
+use crate::session::GpsTime;
+use crate::types::ImuData;
+use crate::Error;
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::types::ToSql;
+use postgres::{Client, Row, SimpleQueryMessage};
+
+const IMU_COLUMNS: &str = "accel, gyro, mag, baro, delta_theta, delta_velocity, quat, \
+                           euler_angles, tow, week, raw_accel, raw_gyro, raw_mag, raw_baro";
+
+#[derive(Debug, Clone)]
+pub struct Fix {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub ellipsoid_alt: f64,
+    pub horizontal_accuracy: f32,
+    pub vertical_accuracy: f32,
+    pub fix_type: i16,
+    pub svs: i16,
+    pub time: GpsTime,
+}
+
+fn imu_from_row(row: &Row) -> Result<ImuData, Error> {
+    Ok(ImuData {
+        accel: row.try_get("accel")?,
+        gyro: row.try_get("gyro")?,
+        mag: row.try_get("mag")?,
+        baro: row.try_get("baro")?,
+        delta_theta: row.try_get("delta_theta")?,
+        delta_velocity: row.try_get("delta_velocity")?,
+        quat: row.try_get("quat")?,
+        euler_angles: row.try_get("euler_angles")?,
+        tow: row.try_get("tow")?,
+        week: row.try_get("week")?,
+        raw_accel: row.try_get("raw_accel")?,
+        raw_gyro: row.try_get("raw_gyro")?,
+        raw_mag: row.try_get("raw_mag")?,
+        raw_baro: row.try_get("raw_baro")?,
+    })
+}
+
+// Streams IMU rows with GPS time in [start, end], in time order.
+pub fn query_imu_range<'a>(
+    c: &'a mut Client,
+    start: GpsTime,
+    end: GpsTime,
+) -> Result<impl Iterator<Item = Result<ImuData, Error>> + 'a, Error> {
+    let sql = format!(
+        "SELECT {} FROM imu_data \
+         WHERE (week, tow) >= ($1, $2) AND (week, tow) <= ($3, $4) \
+         ORDER BY week, tow",
+        IMU_COLUMNS
+    );
+    let params: [&(dyn ToSql + Sync); 4] = [&start.week, &start.tow, &end.week, &end.tow];
+
+    let rows = c.query_raw(sql.as_str(), params)?;
+
+    Ok(rows
+        .iterator()
+        .map(|row| row.map_err(Error::from).and_then(|row| imu_from_row(&row))))
+}
+
+pub fn latest_fix(c: &mut Client) -> Result<Option<Fix>, Error> {
+    let row = c.query_opt(
+        "SELECT latitude, longitude, ellipsoid_alt, horizontal_accuracy, vertical_accuracy, \
+                fix_type, svs, tow, week \
+         FROM gnss_data ORDER BY week DESC, tow DESC LIMIT 1",
+        &[],
+    )?;
+
+    Ok(match row {
+        Some(row) => Some(Fix {
+            latitude: row.try_get("latitude")?,
+            longitude: row.try_get("longitude")?,
+            ellipsoid_alt: row.try_get("ellipsoid_alt")?,
+            horizontal_accuracy: row.try_get("horizontal_accuracy")?,
+            vertical_accuracy: row.try_get("vertical_accuracy")?,
+            fix_type: row.try_get("fix_type")?,
+            svs: row.try_get("svs")?,
+            time: GpsTime {
+                tow: row.try_get("tow")?,
+                week: row.try_get("week")?,
+            },
+        }),
+        None => None,
+    })
+}
+
+// Runs ad hoc SQL read-only and lays each result out as a text table, for
+// checking data where psql isn't installed. NULLs print as empty cells, and a
+// query that returns nothing prints only its row count.
+pub fn run_text(c: &mut Client, sql: &str) -> Result<String, Error> {
+    let mut tx = c.build_transaction().read_only(true).start()?;
+    // The server's message is the useful part of a typo in hand-typed SQL.
+    let messages = tx.simple_query(sql).map_err(|e| match e.as_db_error() {
+        Some(db) => Error::from(format!("{}: {}", db.severity(), db.message())),
+        None => e.into(),
+    })?;
+    tx.commit()?;
+
+    let mut out = Vec::new();
+    let mut header: Option<Vec<String>> = None;
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for message in messages {
+        match message {
+            SimpleQueryMessage::Row(row) => {
+                if header.is_none() {
+                    header = Some(row.columns().iter().map(|c| c.name().to_string()).collect());
+                }
+                rows.push(
+                    (0..row.len())
+                        .map(|i| row.get(i).unwrap_or("").to_string())
+                        .collect(),
+                );
+            }
+            SimpleQueryMessage::CommandComplete(n) => {
+                out.push(match header.take() {
+                    Some(header) => table(&header, &rows),
+                    None => count(n as usize),
+                });
+                rows.clear();
+            }
+            _ => (),
+        }
+    }
+    Ok(out.join("\n\n"))
+}
+
+fn table(header: &[String], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: &[String]| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join(" | ")
+            .trim_end()
+            .to_string()
+    };
+    let mut lines = vec![
+        line(header),
+        widths
+            .iter()
+            .map(|w| "-".repeat(*w))
+            .collect::<Vec<_>>()
+            .join("-+-"),
+    ];
+    lines.extend(rows.iter().map(|row| line(row)));
+    lines.push(count(rows.len()));
+    lines.join("\n")
+}
+
+fn count(rows: usize) -> String {
+    format!("({} row{})", rows, if rows == 1 { "" } else { "s" })
+}
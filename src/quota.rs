@@ -0,0 +1,193 @@
+// Daily quotas on how much of a table is sent to the databases, so a runaway
+// rate in a config can't fill a shared one:
+//
+//   LORDLOGGER_QUOTAS="imu_data=20000000 rows,imu_data=2GB,filter_status=500MB"
+//
+// A table over any of its quotas for the UTC day is sampled, keeping one row in
+// LORDLOGGER_QUOTA_KEEP_EVERY (10 by default), until the day ends. Bytes are
+// those of the rows' values, short of what they take on disk with indexes and
+// tuple headers. Each table going over is recorded as a quota_exceeded event.
+use crate::config::Writes;
+use crate::fanout::Param;
+use crate::spool::Encoded;
+use crate::Error;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+pub const QUOTAS_ENV: &str = "LORDLOGGER_QUOTAS";
+pub const KEEP_EVERY_ENV: &str = "LORDLOGGER_QUOTA_KEEP_EVERY";
+
+const KEEP_EVERY: u64 = 10;
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Rows(u64),
+    Bytes(u64),
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Limit::Rows(rows) => write!(f, "{} rows", rows),
+            Limit::Bytes(bytes) => write!(f, "{} bytes", bytes),
+        }
+    }
+}
+
+// "20000000 rows", "500MB", "2GB" or a plain number of bytes.
+fn limit(text: &str) -> Result<Limit, Error> {
+    let text = text.trim();
+    if let Some(rows) = text.strip_suffix("rows") {
+        return Ok(Limit::Rows(rows.trim().parse()?));
+    }
+    let upper = text.to_ascii_uppercase();
+    let (number, scale) = [
+        ("KB", 1e3),
+        ("MB", 1e6),
+        ("GB", 1e9),
+        ("TB", 1e12),
+        ("B", 1.0),
+    ]
+    .iter()
+    .find_map(|(unit, scale)| upper.strip_suffix(unit).map(|n| (n, *scale)))
+    .unwrap_or((&upper, 1.0));
+    let bytes: f64 = number.trim().parse()?;
+    if !(bytes.is_finite() && bytes > 0.0) {
+        return Err(format!("`{}` is not a positive size", text).into());
+    }
+    Ok(Limit::Bytes((bytes * scale) as u64))
+}
+
+// What a value takes as sent.
+fn size(encoded: &Encoded) -> u64 {
+    match encoded {
+        Encoded::Null => 0,
+        Encoded::Bool(_) => 1,
+        Encoded::I16(_) => 2,
+        Encoded::I32(_) | Encoded::F32(_) => 4,
+        Encoded::I64(_) | Encoded::F64(_) | Encoded::Time(..) => 8,
+        Encoded::Text(v) => v.len() as u64,
+        Encoded::Json(v) => v.to_string().len() as u64,
+        Encoded::TextArray(v) => v.iter().map(|s| s.len() as u64).sum(),
+        Encoded::F64Array(v) => 8 * v.len() as u64,
+        Encoded::Bytes(v) => v.len() as u64,
+    }
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / DAY_SECS
+}
+
+#[derive(Default)]
+struct Usage {
+    day: u64,
+    rows: u64,
+    bytes: u64,
+    over: Option<Limit>,
+    // Rows offered while over, for keeping one in so many.
+    offered: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Exceeded {
+    pub table: String,
+    pub limit: Limit,
+    pub keep_every: u64,
+}
+
+#[derive(Default)]
+pub struct Quotas {
+    limits: HashMap<String, Vec<Limit>>,
+    keep_every: u64,
+    usage: Mutex<HashMap<String, Usage>>,
+    // Tables gone over since last asked, for their events.
+    exceeded: Mutex<Vec<Exceeded>>,
+}
+
+impl Quotas {
+    pub fn from_settings(writes: &Writes) -> Result<Self, Error> {
+        let mut limits: HashMap<String, Vec<Limit>> = HashMap::new();
+        for quota in writes.quotas.iter().flatten().map(|q| q.trim()) {
+            let (table, text) = quota
+                .split_once('=')
+                .ok_or_else(|| format!("quota `{}` is not table=limit", quota))?;
+            let limit = limit(text).map_err(|e| format!("quota `{}`: {}", quota, e))?;
+            limits
+                .entry(table.trim().to_string())
+                .or_default()
+                .push(limit);
+        }
+        let keep_every = writes.quota_keep_every.unwrap_or(KEEP_EVERY);
+        if keep_every == 0 {
+            return Err("a table over its quota must keep at least one row in so many".into());
+        }
+        Ok(Quotas {
+            limits,
+            keep_every,
+            ..Quotas::default()
+        })
+    }
+
+    // Counts the row against its table's quotas and says whether to send it.
+    pub fn keeps(&self, table: &str, params: &[Param]) -> bool {
+        let limits = match self.limits.get(table) {
+            Some(limits) => limits,
+            None => return true,
+        };
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(table.to_string()).or_default();
+        let day = today();
+        if usage.day != day {
+            if usage.over.is_some() {
+                info!("New day, writing every row of {} again", table);
+            }
+            *usage = Usage {
+                day,
+                ..Usage::default()
+            };
+        }
+
+        if usage.over.is_some() {
+            usage.offered += 1;
+            if !(usage.offered - 1).is_multiple_of(self.keep_every) {
+                return false;
+            }
+        }
+        usage.rows += 1;
+        if limits.iter().any(|l| matches!(l, Limit::Bytes(_))) {
+            usage.bytes += params.iter().map(|p| size(&p.encode())).sum::<u64>();
+        }
+
+        if usage.over.is_none() {
+            let over = limits.iter().find(|limit| match limit {
+                Limit::Rows(rows) => usage.rows > *rows,
+                Limit::Bytes(bytes) => usage.bytes > *bytes,
+            });
+            if let Some(limit) = over {
+                info!(
+                    "{} is over its daily quota of {}, keeping 1 in {} rows until the day ends",
+                    table, limit, self.keep_every
+                );
+                usage.over = Some(*limit);
+                self.exceeded.lock().unwrap().push(Exceeded {
+                    table: table.to_string(),
+                    limit: *limit,
+                    keep_every: self.keep_every,
+                });
+            }
+        }
+        true
+    }
+
+    // The tables gone over since the last call.
+    pub fn exceeded(&self) -> Vec<Exceeded> {
+        std::mem::take(&mut *self.exceeded.lock().unwrap())
+    }
+}
@@ -0,0 +1,265 @@
+use crate::config;
+use crate::descriptors::ImuField;
+use crate::fanout::{FanOut, Param, Row};
+use crate::registry::{self, FieldSpec};
+use crate::session::GpsTime;
+use crate::telemetry;
+use crate::Error;
+use lordserial::Packet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::warn;
+
+// Semicolon separated `table:decimation:field,field,...`, e.g.
+// `imu_fast:2:04,05,07,08,0A,0C;imu_slow:100:06,17`
+pub const IMU_RATE_GROUPS_ENV: &str = "LORDLOGGER_IMU_RATE_GROUPS";
+
+// A group's rows dropped for want of a timestamp are warned of on the first
+// and then every this many.
+const UNTIMED_WARN_EVERY: u64 = 1000;
+
+#[derive(Debug, Clone)]
+pub struct RateGroup {
+    pub table: String,
+    pub decimation: u16,
+    pub fields: Vec<&'static FieldSpec>,
+    // Rows dropped for a packet without the GPS timestamp.
+    untimed: Arc<AtomicU64>,
+}
+
+impl RateGroup {
+    fn new(table: String, decimation: u16, descriptors: &[u8]) -> Result<Self, Error> {
+        if table.is_empty() || !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid rate group table name `{}`", table).into());
+        }
+        if decimation == 0 {
+            return Err(format!("rate group `{}` has a decimation of 0", table).into());
+        }
+
+        let fields = descriptors
+            .iter()
+            .map(|&descriptor| {
+                registry::imu_field(descriptor)
+                    .ok_or_else(|| format!("unsupported IMU field 0x{:02X}", descriptor).into())
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(RateGroup {
+            table,
+            decimation,
+            fields,
+            untimed: Arc::default(),
+        })
+    }
+
+    pub fn create_sql(&self) -> String {
+        let columns: Vec<String> = self
+            .fields
+            .iter()
+            .map(|f| format!("{} {} NOT NULL", f.column, f.shape.sql_type()))
+            .collect();
+
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                id SERIAL PRIMARY KEY,
+                {},
+                tow double precision NOT NULL,
+                week smallint NOT NULL
+            );",
+            self.table,
+            columns.join(",\n                ")
+        )
+    }
+
+    fn insert_sql(&self) -> String {
+        let mut next = 1;
+        let mut values = Vec::new();
+        for f in &self.fields {
+            values.push(f.shape.placeholder(next));
+            next += f.shape.width();
+        }
+        values.push(format!("${}", next));
+        values.push(format!("${}", next + 1));
+
+        let columns: Vec<&str> = self.fields.iter().map(|f| f.column).collect();
+
+        format!(
+            "INSERT INTO {} ({}, tow, week) VALUES ({});",
+            self.table,
+            columns.join(", "),
+            values.join(", ")
+        )
+    }
+
+    // Writes the group's row if every one of its fields is in this packet.
+    // Packets at the base rate only carry the groups whose decimation is due.
+    pub fn insert(&self, out: &FanOut, packet: &Packet) -> Result<bool, Error> {
+        let mut params: Vec<Param> = Vec::new();
+
+        for spec in &self.fields {
+            let field = match packet.payload.get_field(spec.descriptor) {
+                Some(field) => field,
+                None => return Ok(false),
+            };
+            for value in spec.values(field)? {
+                params.push(Box::new(value));
+            }
+        }
+
+        let time = match GpsTime::from_packet(packet)? {
+            Some(time) => time,
+            None => {
+                self.dropped_untimed();
+                return Ok(false);
+            }
+        };
+        params.push(Box::new(time.tow));
+        params.push(Box::new(time.week));
+
+        out.send(Row::timed(self.insert_sql(), params, time));
+
+        Ok(true)
+    }
+
+    fn dropped_untimed(&self) {
+        telemetry::add(
+            "lordlogger.rate_group_untimed",
+            vec![("table", self.table.as_str().into())],
+            1,
+        );
+        let dropped = self.untimed.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped == 1 || dropped.is_multiple_of(UNTIMED_WARN_EVERY) {
+            warn!(
+                "Dropped {} rows of {} from packets without a GPS timestamp",
+                dropped, self.table
+            );
+        }
+    }
+}
+
+fn parse(spec: &str) -> Result<config::RateGroup, Error> {
+    let parts: Vec<&str> = spec.trim().split(':').collect();
+    if parts.len() != 3 {
+        return Err(format!("rate group `{}` is not table:decimation:fields", spec).into());
+    }
+
+    let table = parts[0].to_string();
+    let decimation = parts[1].parse::<u16>()?;
+
+    let fields = parts[2]
+        .split(',')
+        .map(|d| u8::from_str_radix(d.trim().trim_start_matches("0x"), 16))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(config::RateGroup {
+        table,
+        decimation,
+        fields,
+    })
+}
+
+// The groups LORDLOGGER_IMU_RATE_GROUPS spells out.
+pub fn parse_groups(spec: &str) -> Result<Vec<config::RateGroup>, Error> {
+    spec.split(';')
+        .filter(|s| !s.trim().is_empty())
+        .map(parse)
+        .collect()
+}
+
+pub fn from_settings(groups: &[config::RateGroup]) -> Result<Vec<RateGroup>, Error> {
+    groups
+        .iter()
+        .map(|g| RateGroup::new(g.table.clone(), g.decimation, &g.fields))
+        .collect()
+}
+
+// IMU message format for the groups. The GPS timestamp rides along at the
+// greatest common divisor of the decimations, so it's in every packet any
+// group's fields are, e.g. every 2nd sample for groups at 4 and 6.
+pub fn imu_format(groups: &[RateGroup]) -> Vec<(u8, u16)> {
+    let mut format: Vec<(u8, u16)> = groups
+        .iter()
+        .flat_map(|g| g.fields.iter().map(move |f| (f.descriptor, g.decimation)))
+        .collect();
+
+    if let Some(every) = groups.iter().map(|g| g.decimation).reduce(gcd) {
+        format.push((ImuField::GpsTimestamp.into(), every));
+    }
+
+    format
+}
+
+fn gcd(a: u16, b: u16) -> u16 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(spec: &str) -> Result<RateGroup, Error> {
+        let g = parse(spec)?;
+        RateGroup::new(g.table, g.decimation, &g.fields)
+    }
+
+    #[test]
+    fn parses_a_group() {
+        let group = group(" imu_fast:2:04, 0x05,0C ").unwrap();
+        assert_eq!(group.table, "imu_fast");
+        assert_eq!(group.decimation, 2);
+        let columns: Vec<&str> = group.fields.iter().map(|f| f.column).collect();
+        assert_eq!(columns, ["accel", "gyro", "euler_angles"]);
+    }
+
+    #[test]
+    fn timestamps_every_groups_packets() {
+        let groups = [
+            group("imu_fast:4:04").unwrap(),
+            group("imu_mid:6:05").unwrap(),
+            group("imu_slow:100:06").unwrap(),
+        ];
+        let format = imu_format(&groups);
+        assert_eq!(format.last(), Some(&(0x12, 2)));
+        assert_eq!(format.len(), 4);
+    }
+
+    #[test]
+    fn builds_groups_from_the_settings_file() {
+        let file: config::ConfigFile = toml::from_str(
+            "[[rate_groups]]\ntable = \"imu_fast\"\ndecimation = 2\nfields = [0x04, 0x05]\n",
+        )
+        .unwrap();
+        let groups = from_settings(&file.rate_groups).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].table, "imu_fast");
+        let columns: Vec<&str> = groups[0].fields.iter().map(|f| f.column).collect();
+        assert_eq!(columns, ["accel", "gyro"]);
+
+        let bad = config::RateGroup {
+            table: "imu_fast".to_string(),
+            decimation: 0,
+            fields: vec![0x04],
+        };
+        assert!(from_settings(&[bad]).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_groups() {
+        for spec in [
+            "imu_fast:2",
+            "imu_fast:2:04:05",
+            ":2:04",
+            "imu-fast:2:04",
+            "imu_fast:two:04",
+            "imu_fast:70000:04",
+            "imu_fast:0:04",
+            "imu_fast:2:zz",
+            "imu_fast:2:FF",
+        ] {
+            assert!(group(spec).is_err(), "{}", spec);
+        }
+    }
+}
@@ -0,0 +1,1164 @@
+use crate::config::ByteOrders;
+use crate::descriptors::{DataDescriptor, ImuField};
+use crate::fanout::Param;
+use crate::Error;
+use lordserial::{Field, Packet};
+use std::fmt;
+use std::sync::OnceLock;
+
+// How the device's multi-byte values arrive: "big" (MIP as sent, the
+// default), "little", "byte-swapped" (bytes swapped in each 16-bit word) or
+// "word-swapped" (16-bit words reversed), for captures through bridges that
+// reorder bytes in transit. Floats follow it unless given their own order.
+pub const BYTE_ORDER_ENV: &str = "LORDLOGGER_BYTE_ORDER";
+pub const FLOAT_ORDER_ENV: &str = "LORDLOGGER_FLOAT_ORDER";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ByteOrder {
+    #[default]
+    Big,
+    Little,
+    ByteSwapped,
+    WordSwapped,
+}
+
+impl ByteOrder {
+    fn parse(name: &str) -> Result<Self, Error> {
+        match name {
+            "big" => Ok(ByteOrder::Big),
+            "little" => Ok(ByteOrder::Little),
+            "byte-swapped" => Ok(ByteOrder::ByteSwapped),
+            "word-swapped" => Ok(ByteOrder::WordSwapped),
+            _ => Err(format!(
+                "unknown byte order `{}`, expected big, little, byte-swapped or word-swapped",
+                name
+            )
+            .into()),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ByteOrder::Big => "big",
+            ByteOrder::Little => "little",
+            ByteOrder::ByteSwapped => "byte-swapped",
+            ByteOrder::WordSwapped => "word-swapped",
+        }
+    }
+
+    // Puts bytes received in this order back into big-endian order.
+    fn to_big(self, bytes: &mut [u8]) {
+        match self {
+            ByteOrder::Big => (),
+            ByteOrder::Little => bytes.reverse(),
+            ByteOrder::ByteSwapped => bytes.chunks_mut(2).for_each(|w| w.reverse()),
+            ByteOrder::WordSwapped => {
+                bytes.reverse();
+                bytes.chunks_mut(2).for_each(|w| w.reverse());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Layout {
+    pub ints: ByteOrder,
+    pub floats: ByteOrder,
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ints and {} floats",
+            self.ints.name(),
+            self.floats.name()
+        )
+    }
+}
+
+static LAYOUT: OnceLock<Layout> = OnceLock::new();
+
+impl Layout {
+    pub fn from_settings(orders: &ByteOrders) -> Result<Self, Error> {
+        let ints = match &orders.byte_order {
+            Some(name) => ByteOrder::parse(name.trim())?,
+            None => ByteOrder::Big,
+        };
+        let floats = match &orders.float_order {
+            Some(name) => ByteOrder::parse(name.trim())?,
+            None => ints,
+        };
+        Ok(Layout { ints, floats })
+    }
+
+    // Used by every field read from here on. Modes that never call it read
+    // MIP as sent. It's one layout per process, so a run wanting another
+    // than the one already installed fails instead of misreading its data.
+    pub fn install(self) -> Result<(), Error> {
+        let installed = *LAYOUT.get_or_init(|| self);
+        if installed != self {
+            return Err(format!(
+                "fields are already read as {} in this process, not {}",
+                installed, self
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    pub fn current() -> Layout {
+        LAYOUT.get().copied().unwrap_or_default()
+    }
+}
+
+// Big-endian words at an offset into a field's data, taken as raw bits for
+// the layout to reorder.
+pub trait Words {
+    fn u8_at(&self, offset: usize) -> Result<u8, Error>;
+    fn u16_at(&self, offset: usize) -> Result<u16, Error>;
+    fn u32_at(&self, offset: usize) -> Result<u32, Error>;
+    fn u64_at(&self, offset: usize) -> Result<u64, Error>;
+
+    // Fills `out` with the bytes from `offset` on, 8 at a time.
+    fn bytes_at(&self, offset: usize, out: &mut [u8]) -> Result<(), Error> {
+        for (i, chunk) in out.chunks_mut(8).enumerate() {
+            let at = offset + i * 8;
+            match chunk.len() {
+                8 => chunk.copy_from_slice(&self.u64_at(at)?.to_be_bytes()),
+                4 => chunk.copy_from_slice(&self.u32_at(at)?.to_be_bytes()),
+                _ => {
+                    for (j, byte) in chunk.iter_mut().enumerate() {
+                        *byte = self.u8_at(at + j)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Words for Field {
+    fn u8_at(&self, offset: usize) -> Result<u8, Error> {
+        Ok(self.extract(offset)?)
+    }
+
+    fn u16_at(&self, offset: usize) -> Result<u16, Error> {
+        Ok(self.extract(offset)?)
+    }
+
+    fn u32_at(&self, offset: usize) -> Result<u32, Error> {
+        Ok(self.extract(offset)?)
+    }
+
+    fn u64_at(&self, offset: usize) -> Result<u64, Error> {
+        Ok(self.extract(offset)?)
+    }
+}
+
+// A value a field can hold, read through lordserial's big-endian extract as
+// raw bits and reordered for the layout.
+pub trait Word: Sized {
+    fn read<W: Words + ?Sized>(field: &W, offset: usize, layout: Layout) -> Result<Self, Error>;
+}
+
+macro_rules! word {
+    ($($ty:ty => $at:ident, $order:ident;)*) => {
+        $(impl Word for $ty {
+            fn read<W: Words + ?Sized>(field: &W, offset: usize, layout: Layout) -> Result<Self, Error> {
+                let mut bytes = field.$at(offset)?.to_be_bytes();
+                layout.$order.to_big(&mut bytes);
+                Ok(<$ty>::from_be_bytes(bytes))
+            }
+        })*
+    };
+}
+
+word! {
+    u8 => u8_at, ints;
+    i8 => u8_at, ints;
+    u16 => u16_at, ints;
+    i16 => u16_at, ints;
+    u32 => u32_at, ints;
+    i32 => u32_at, ints;
+    u64 => u64_at, ints;
+    i64 => u64_at, ints;
+    f32 => u32_at, floats;
+    f64 => u64_at, floats;
+}
+
+// Field reads that honor the configured layout, in place of `extract`.
+pub trait Read {
+    fn read<T: Word>(&self, offset: usize) -> Result<T, Error>;
+}
+
+impl Read for Field {
+    fn read<T: Word>(&self, offset: usize) -> Result<T, Error> {
+        T::read(self, offset, Layout::current())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    Scalar,
+    Vector3,
+    Quaternion,
+}
+
+impl Shape {
+    pub fn width(self) -> usize {
+        match self {
+            Shape::Scalar => 1,
+            Shape::Vector3 => 3,
+            Shape::Quaternion => 4,
+        }
+    }
+
+    pub fn sql_type(self) -> &'static str {
+        match self {
+            Shape::Scalar => "real",
+            Shape::Vector3 => "real3d",
+            Shape::Quaternion => "quaternion",
+        }
+    }
+
+    // SQL value expression for this shape, with placeholders starting at $first.
+    pub fn placeholder(self, first: usize) -> String {
+        let params: Vec<String> = (first..first + self.width())
+            .map(|i| format!("${}", i))
+            .collect();
+
+        match self {
+            Shape::Scalar => params[0].clone(),
+            _ => format!("ROW({})", params.join(", ")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FieldSpec {
+    pub descriptor: u8,
+    pub column: &'static str,
+    pub shape: Shape,
+    pub units: &'static str,
+    pub frame: &'static str,
+}
+
+const IMU: u8 = DataDescriptor::Imu as u8;
+const GNSS: u8 = DataDescriptor::Gnss as u8;
+
+const fn imu(
+    descriptor: u8,
+    column: &'static str,
+    shape: Shape,
+    units: &'static str,
+    frame: &'static str,
+) -> FieldSpec {
+    FieldSpec {
+        descriptor,
+        column,
+        shape,
+        units,
+        frame,
+    }
+}
+
+impl FieldSpec {
+    // Channel names for each component, matching the composite type's
+    // attribute names, e.g. accel.x or quat.q2.
+    pub fn channels(&self) -> Vec<String> {
+        let components: &[&str] = match self.shape {
+            Shape::Scalar => return vec![self.column.to_string()],
+            Shape::Vector3 => &["x", "y", "z"],
+            Shape::Quaternion => &["q0", "q1", "q2", "q3"],
+        };
+
+        components
+            .iter()
+            .map(|c| format!("{}.{}", self.column, c))
+            .collect()
+    }
+
+    pub fn values(&self, field: &Field) -> Result<Vec<f32>, Error> {
+        let mut values = vec![0.0; self.shape.width()];
+        extract_f32s(field, &mut values)?;
+        Ok(values)
+    }
+}
+
+// Fills `out` with consecutive floats from the start of the field. The
+// field's bytes are read once, two extracts for a vector or quaternion, and
+// converted in a single pass over the slice.
+pub fn extract_f32s(field: &Field, out: &mut [f32]) -> Result<(), Error> {
+    f32s(field, out, Layout::current())
+}
+
+// The widest float field, a quaternion.
+const MAX_FLOATS: usize = 4;
+
+fn f32s<W: Words + ?Sized>(field: &W, out: &mut [f32], layout: Layout) -> Result<(), Error> {
+    let mut buffer = [0; MAX_FLOATS * 4];
+    let bytes = buffer
+        .get_mut(..out.len() * 4)
+        .ok_or_else(|| format!("{} floats is more than a field holds", out.len()))?;
+    field.bytes_at(0, bytes)?;
+
+    let chunks = out.iter_mut().zip(bytes.chunks_exact(4));
+    match layout.floats {
+        ByteOrder::Big => {
+            for (value, b) in chunks {
+                *value = f32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+            }
+        }
+        ByteOrder::Little => {
+            for (value, b) in chunks {
+                *value = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+            }
+        }
+        order => {
+            for (value, b) in chunks {
+                let mut word = [b[0], b[1], b[2], b[3]];
+                order.to_big(&mut word);
+                *value = f32::from_be_bytes(word);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub const IMU_FIELDS: &[FieldSpec] = &[
+    imu(0x01, "raw_accel", Shape::Vector3, "ADC counts", "sensor"),
+    imu(0x02, "raw_gyro", Shape::Vector3, "ADC counts", "sensor"),
+    imu(0x03, "raw_mag", Shape::Vector3, "ADC counts", "sensor"),
+    imu(0x04, "accel", Shape::Vector3, "g", "sensor"),
+    imu(0x05, "gyro", Shape::Vector3, "rad/s", "sensor"),
+    imu(0x06, "mag", Shape::Vector3, "gauss", "sensor"),
+    imu(0x07, "delta_theta", Shape::Vector3, "rad", "sensor"),
+    imu(0x08, "delta_velocity", Shape::Vector3, "g*s", "sensor"),
+    imu(0x0A, "quat", Shape::Quaternion, "unitless", "NED to sensor"),
+    imu(
+        0x0C,
+        "euler_angles",
+        Shape::Vector3,
+        "rad (roll, pitch, yaw)",
+        "NED",
+    ),
+    imu(0x16, "raw_baro", Shape::Scalar, "ADC counts", ""),
+    imu(0x17, "baro", Shape::Scalar, "mbar", ""),
+];
+
+pub fn imu_field(descriptor: u8) -> Option<&'static FieldSpec> {
+    IMU_FIELDS.iter().find(|f| f.descriptor == descriptor)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scalar {
+    F64,
+    F32,
+    I16,
+    I8,
+}
+
+impl Scalar {
+    // Postgres has no one-byte integer, so I8 fields are stored as smallint.
+    pub fn sql_type(self) -> &'static str {
+        match self {
+            Scalar::F64 => "double precision",
+            Scalar::F32 => "real",
+            Scalar::I16 | Scalar::I8 => "smallint",
+        }
+    }
+}
+
+// Every GNSS field set ends in a valid-flags word with a bit per member.
+// Without a fix the device still sends the sets, with zeros or stale values in
+// the members it has cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Valid {
+    Flags,
+    Bit(u8),
+}
+
+#[derive(Debug)]
+pub struct GnssColumn {
+    pub column: &'static str,
+    pub descriptor: u8,
+    pub offset: usize,
+    pub kind: Scalar,
+    pub valid: Valid,
+    pub units: &'static str,
+}
+
+const fn gnss(
+    column: &'static str,
+    descriptor: u8,
+    offset: usize,
+    kind: Scalar,
+    valid: Valid,
+    units: &'static str,
+) -> GnssColumn {
+    GnssColumn {
+        column,
+        descriptor,
+        offset,
+        kind,
+        valid,
+        units,
+    }
+}
+
+impl GnssColumn {
+    pub fn is_valid(&self, field: &Field) -> Result<bool, Error> {
+        let bit = match self.valid {
+            Valid::Flags => return Ok(true),
+            Valid::Bit(bit) => bit,
+        };
+        let flags = GNSS_COLUMNS
+            .iter()
+            .find(|c| c.descriptor == self.descriptor && c.valid == Valid::Flags)
+            .ok_or_else(|| format!("no valid flags for {}", self.column))?;
+
+        Ok(field.read::<u16>(flags.offset)? & (1 << bit) != 0)
+    }
+
+    // None when the device marked the member invalid.
+    pub fn value_f64(&self, field: &Field) -> Result<Option<f64>, Error> {
+        if !self.is_valid(field)? {
+            return Ok(None);
+        }
+
+        Ok(Some(match self.kind {
+            Scalar::F64 => field.read::<f64>(self.offset)?,
+            Scalar::F32 => field.read::<f32>(self.offset)? as f64,
+            Scalar::I16 => field.read::<i16>(self.offset)? as f64,
+            Scalar::I8 => field.read::<i8>(self.offset)? as f64,
+        }))
+    }
+
+    // The value as the Rust type matching the column's sql_type, NULL when
+    // the device marked it invalid.
+    pub fn param(&self, field: &Field) -> Result<Param, Error> {
+        let valid = self.is_valid(field)?;
+
+        Ok(match self.kind {
+            Scalar::F64 => Box::new(valid.then_some(field.read::<f64>(self.offset)?)),
+            Scalar::F32 => Box::new(valid.then_some(field.read::<f32>(self.offset)?)),
+            Scalar::I16 => Box::new(valid.then_some(field.read::<i16>(self.offset)?)),
+            Scalar::I8 => Box::new(valid.then_some(field.read::<i8>(self.offset)? as i16)),
+        })
+    }
+}
+
+pub const GNSS_COLUMNS: &[GnssColumn] = &[
+    gnss(
+        "latitude",
+        0x03,
+        0,
+        Scalar::F64,
+        Valid::Bit(0),
+        "deg, WGS84",
+    ),
+    gnss(
+        "longitude",
+        0x03,
+        8,
+        Scalar::F64,
+        Valid::Bit(0),
+        "deg, WGS84",
+    ),
+    gnss(
+        "ellipsoid_alt",
+        0x03,
+        16,
+        Scalar::F64,
+        Valid::Bit(1),
+        "m, WGS84 ellipsoid",
+    ),
+    gnss("msl_alt", 0x03, 24, Scalar::F64, Valid::Bit(2), "m, MSL"),
+    gnss(
+        "horizontal_accuracy",
+        0x03,
+        32,
+        Scalar::F32,
+        Valid::Bit(3),
+        "m",
+    ),
+    gnss(
+        "vertical_accuracy",
+        0x03,
+        36,
+        Scalar::F32,
+        Valid::Bit(4),
+        "m",
+    ),
+    gnss(
+        "llh_flags",
+        0x03,
+        40,
+        Scalar::I16,
+        Valid::Flags,
+        "valid flags",
+    ),
+    gnss("ecefp_x", 0x04, 0, Scalar::F64, Valid::Bit(0), "m, ECEF"),
+    gnss("ecefp_y", 0x04, 8, Scalar::F64, Valid::Bit(0), "m, ECEF"),
+    gnss("ecefp_z", 0x04, 16, Scalar::F64, Valid::Bit(0), "m, ECEF"),
+    gnss("ecefp_accuracy", 0x04, 24, Scalar::F32, Valid::Bit(1), "m"),
+    gnss(
+        "ecefp_flags",
+        0x04,
+        28,
+        Scalar::I16,
+        Valid::Flags,
+        "valid flags",
+    ),
+    gnss("ned_north", 0x05, 0, Scalar::F32, Valid::Bit(0), "m/s, NED"),
+    gnss("ned_east", 0x05, 4, Scalar::F32, Valid::Bit(0), "m/s, NED"),
+    gnss("ned_down", 0x05, 8, Scalar::F32, Valid::Bit(0), "m/s, NED"),
+    gnss("ned_speed", 0x05, 12, Scalar::F32, Valid::Bit(1), "m/s"),
+    gnss(
+        "ned_ground_speed",
+        0x05,
+        16,
+        Scalar::F32,
+        Valid::Bit(2),
+        "m/s",
+    ),
+    gnss(
+        "ned_heading",
+        0x05,
+        20,
+        Scalar::F32,
+        Valid::Bit(3),
+        "deg, true",
+    ),
+    gnss(
+        "ned_speed_accuracy",
+        0x05,
+        24,
+        Scalar::F32,
+        Valid::Bit(4),
+        "m/s",
+    ),
+    gnss(
+        "ned_heading_accuracy",
+        0x05,
+        28,
+        Scalar::F32,
+        Valid::Bit(5),
+        "deg",
+    ),
+    gnss(
+        "ned_flags",
+        0x05,
+        32,
+        Scalar::I16,
+        Valid::Flags,
+        "valid flags",
+    ),
+    gnss("ecefv_x", 0x06, 0, Scalar::F32, Valid::Bit(0), "m/s, ECEF"),
+    gnss("ecefv_y", 0x06, 4, Scalar::F32, Valid::Bit(0), "m/s, ECEF"),
+    gnss("ecefv_z", 0x06, 8, Scalar::F32, Valid::Bit(0), "m/s, ECEF"),
+    gnss(
+        "ecefv_accuracy",
+        0x06,
+        12,
+        Scalar::F32,
+        Valid::Bit(1),
+        "m/s",
+    ),
+    gnss(
+        "ecefv_flags",
+        0x06,
+        16,
+        Scalar::I16,
+        Valid::Flags,
+        "valid flags",
+    ),
+    gnss("gdop", 0x07, 0, Scalar::F32, Valid::Bit(0), "unitless"),
+    gnss("pdop", 0x07, 4, Scalar::F32, Valid::Bit(1), "unitless"),
+    gnss("hdop", 0x07, 8, Scalar::F32, Valid::Bit(2), "unitless"),
+    gnss("vdop", 0x07, 12, Scalar::F32, Valid::Bit(3), "unitless"),
+    gnss("tdop", 0x07, 16, Scalar::F32, Valid::Bit(4), "unitless"),
+    gnss("ndop", 0x07, 20, Scalar::F32, Valid::Bit(5), "unitless"),
+    gnss("edop", 0x07, 24, Scalar::F32, Valid::Bit(6), "unitless"),
+    gnss(
+        "dop_flags",
+        0x07,
+        28,
+        Scalar::I16,
+        Valid::Flags,
+        "valid flags",
+    ),
+    gnss(
+        "tow",
+        0x09,
+        0,
+        Scalar::F64,
+        Valid::Bit(0),
+        "s, GPS time of week",
+    ),
+    gnss("week", 0x09, 8, Scalar::I16, Valid::Bit(1), "GPS week"),
+    gnss(
+        "time_flags",
+        0x09,
+        10,
+        Scalar::I16,
+        Valid::Flags,
+        "valid flags",
+    ),
+    gnss("fix_type", 0x0B, 0, Scalar::I8, Valid::Bit(0), "enum"),
+    gnss("svs", 0x0B, 1, Scalar::I8, Valid::Bit(1), "count"),
+    gnss("fix_flags", 0x0B, 2, Scalar::I16, Valid::Bit(2), "bitfield"),
+    gnss(
+        "fix_valid",
+        0x0B,
+        4,
+        Scalar::I16,
+        Valid::Flags,
+        "valid flags",
+    ),
+];
+
+// Named booleans decoded from the flag words. Generated by Postgres from the
+// stored flags, so they are never written and stay right for old rows.
+#[derive(Debug)]
+pub struct FlagBit {
+    pub column: &'static str,
+    pub flags: &'static str,
+    pub mask: i16,
+    pub meaning: &'static str,
+}
+
+const fn flag(
+    column: &'static str,
+    flags: &'static str,
+    mask: i16,
+    meaning: &'static str,
+) -> FlagBit {
+    FlagBit {
+        column,
+        flags,
+        mask,
+        meaning,
+    }
+}
+
+// Set when every bit in the mask is.
+pub const GNSS_FLAG_BITS: &[FlagBit] = &[
+    flag(
+        "lat_lon_valid",
+        "llh_flags",
+        0x01,
+        "latitude and longitude valid",
+    ),
+    flag(
+        "ellipsoid_alt_valid",
+        "llh_flags",
+        0x02,
+        "ellipsoid altitude valid",
+    ),
+    flag("msl_alt_valid", "llh_flags", 0x04, "MSL altitude valid"),
+    flag(
+        "horizontal_accuracy_valid",
+        "llh_flags",
+        0x08,
+        "horizontal accuracy valid",
+    ),
+    flag(
+        "vertical_accuracy_valid",
+        "llh_flags",
+        0x10,
+        "vertical accuracy valid",
+    ),
+    flag("ecefp_valid", "ecefp_flags", 0x01, "ECEF position valid"),
+    flag(
+        "ecefp_accuracy_valid",
+        "ecefp_flags",
+        0x02,
+        "ECEF position accuracy valid",
+    ),
+    flag(
+        "ned_velocity_valid",
+        "ned_flags",
+        0x01,
+        "NED velocity valid",
+    ),
+    flag("ned_speed_valid", "ned_flags", 0x02, "3D speed valid"),
+    flag(
+        "ned_ground_speed_valid",
+        "ned_flags",
+        0x04,
+        "ground speed valid",
+    ),
+    flag("ned_heading_valid", "ned_flags", 0x08, "heading valid"),
+    flag(
+        "ned_speed_accuracy_valid",
+        "ned_flags",
+        0x10,
+        "speed accuracy valid",
+    ),
+    flag(
+        "ned_heading_accuracy_valid",
+        "ned_flags",
+        0x20,
+        "heading accuracy valid",
+    ),
+    flag("ecefv_valid", "ecefv_flags", 0x01, "ECEF velocity valid"),
+    flag(
+        "ecefv_accuracy_valid",
+        "ecefv_flags",
+        0x02,
+        "ECEF velocity accuracy valid",
+    ),
+    flag("dop_valid", "dop_flags", 0x7F, "every DOP valid"),
+    flag("tow_valid", "time_flags", 0x01, "time of week valid"),
+    flag("week_valid", "time_flags", 0x02, "week number valid"),
+    flag(
+        "time_valid",
+        "time_flags",
+        0x03,
+        "time of week and week number valid",
+    ),
+    flag("fix_type_valid", "fix_valid", 0x01, "fix type valid"),
+    flag("svs_valid", "fix_valid", 0x02, "satellite count valid"),
+    flag("fix_flags_valid", "fix_valid", 0x04, "fix flags valid"),
+    flag("sbas_used", "fix_flags", 0x01, "SBAS corrections used"),
+    flag(
+        "dgnss_used",
+        "fix_flags",
+        0x02,
+        "differential corrections used",
+    ),
+];
+
+// A coded column's symbolic value, written next to the number as a Postgres
+// enum so queries can say fix_type_name = '3d' instead of fix_type = 0.
+#[derive(Debug)]
+pub struct Coded {
+    pub column: &'static str,
+    pub name_column: &'static str,
+    pub sql_type: &'static str,
+    pub labels: &'static [(i16, &'static str)],
+}
+
+impl Coded {
+    // CREATE TYPE has no IF NOT EXISTS. Labels added to the list later are
+    // appended to an existing type.
+    pub fn create_sql(&self, table: &str) -> String {
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(_, label)| format!("'{}'", label))
+            .collect();
+        let additions: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(_, label)| {
+                format!(
+                    "ALTER TYPE {} ADD VALUE IF NOT EXISTS '{}';",
+                    self.sql_type, label
+                )
+            })
+            .collect();
+
+        format!(
+            "DO $$ BEGIN
+                 CREATE TYPE {ty} AS ENUM ({labels});
+             EXCEPTION WHEN duplicate_object THEN NULL;
+             END $$;
+             {additions}
+             ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {name} {ty};",
+            ty = self.sql_type,
+            labels = labels.join(", "),
+            additions = additions.join("\n"),
+            table = table,
+            name = self.name_column
+        )
+    }
+
+    // The insert placeholder; the label is sent as text.
+    pub fn placeholder(&self, n: usize) -> String {
+        format!("${}::text::{}", n, self.sql_type)
+    }
+
+    // None for a missing or invalid value, or a code with no label.
+    pub fn label(&self, packet: &Packet) -> Result<Option<&'static str>, Error> {
+        let column = GNSS_COLUMNS
+            .iter()
+            .find(|c| c.column == self.column)
+            .ok_or_else(|| format!("no GNSS column {}", self.column))?;
+        let code = match packet.payload.get_field(column.descriptor) {
+            Some(field) => column.value_f64(field)?,
+            None => None,
+        };
+
+        Ok(code.and_then(|code| {
+            self.labels
+                .iter()
+                .find(|(c, _)| *c as f64 == code)
+                .map(|(_, label)| *label)
+        }))
+    }
+}
+
+pub const GNSS_CODED: &[Coded] = &[Coded {
+    column: "fix_type",
+    name_column: "fix_type_name",
+    sql_type: "gnss_fix_type",
+    labels: &[
+        (0, "3d"),
+        (1, "2d"),
+        (2, "time_only"),
+        (3, "none"),
+        (4, "invalid"),
+        (5, "rtk_float"),
+        (6, "rtk_fixed"),
+    ],
+}];
+
+// Fix types that give a position.
+pub fn is_fix(label: &str) -> bool {
+    matches!(label, "3d" | "2d" | "rtk_float" | "rtk_fixed")
+}
+
+// A position fix the device vouches for.
+pub fn solution_valid(packet: &Packet) -> Result<bool, Error> {
+    Ok(GNSS_CODED[0].label(packet)?.is_some_and(is_fix))
+}
+
+pub fn gnss_create_sql() -> String {
+    let columns: Vec<String> = GNSS_COLUMNS
+        .iter()
+        .map(|c| match c.valid {
+            Valid::Flags => format!("{} {} NOT NULL", c.column, c.kind.sql_type()),
+            Valid::Bit(_) => format!("{} {}", c.column, c.kind.sql_type()),
+        })
+        .collect();
+
+    // Tables from before invalid members were stored as NULL.
+    let relax: Vec<String> = GNSS_COLUMNS
+        .iter()
+        .filter(|c| c.valid != Valid::Flags)
+        .map(|c| {
+            format!(
+                "ALTER TABLE gnss_data ALTER COLUMN {} DROP NOT NULL;",
+                c.column
+            )
+        })
+        .collect();
+
+    let flag_bits: Vec<String> = GNSS_FLAG_BITS
+        .iter()
+        .map(|f| {
+            format!(
+                "ALTER TABLE gnss_data ADD COLUMN IF NOT EXISTS {} boolean
+                 GENERATED ALWAYS AS (({flags} & {mask}) = {mask}) STORED;",
+                f.column,
+                flags = f.flags,
+                mask = f.mask
+            )
+        })
+        .collect();
+
+    let coded: Vec<String> = GNSS_CODED
+        .iter()
+        .map(|c| c.create_sql("gnss_data"))
+        .collect();
+
+    format!(
+        "CREATE TABLE IF NOT EXISTS gnss_data (id SERIAL PRIMARY KEY, {}, solution_valid boolean);
+         ALTER TABLE gnss_data ADD COLUMN IF NOT EXISTS solution_valid boolean;
+         {}
+         {}
+         {}",
+        columns.join(", "),
+        relax.join("\n"),
+        flag_bits.join("\n"),
+        coded.join("\n")
+    )
+}
+
+pub fn gnss_insert_sql() -> String {
+    let mut columns: Vec<&str> = GNSS_COLUMNS.iter().map(|c| c.column).collect();
+    columns.push("solution_valid");
+    let mut params: Vec<String> = (1..=columns.len()).map(|i| format!("${}", i)).collect();
+    for coded in GNSS_CODED {
+        columns.push(coded.name_column);
+        params.push(coded.placeholder(columns.len()));
+    }
+    for column in crate::shared::column_names() {
+        columns.push(column);
+        params.push(format!("${}", columns.len()));
+    }
+
+    format!(
+        "INSERT INTO gnss_data ({}) VALUES ({})",
+        columns.join(", "),
+        params.join(", ")
+    )
+}
+
+// Text for COMMENT ON COLUMN: units, frame and the field the value comes from.
+pub fn describe(set: u8, descriptor: u8, units: &str, frame: &str) -> String {
+    let mut text = units.to_string();
+    if !frame.is_empty() {
+        text.push_str(&format!(", {} frame", frame));
+    }
+    format!(
+        "{}. Source: {}",
+        text,
+        crate::descriptors::describe_field(set, descriptor)
+    )
+}
+
+pub fn comment_sql(table: &str, column: &str, comment: &str) -> String {
+    format!(
+        "COMMENT ON COLUMN {}.{} IS '{}';",
+        table,
+        column,
+        comment.replace('\'', "''")
+    )
+}
+
+pub fn column_comments(table: &str, fields: &[&FieldSpec]) -> Vec<String> {
+    let mut statements: Vec<String> = fields
+        .iter()
+        .map(|f| {
+            let text = describe(IMU, f.descriptor, f.units, f.frame);
+            comment_sql(table, f.column, &text)
+        })
+        .collect();
+
+    statements.push(comment_sql(
+        table,
+        "tow",
+        &describe(
+            IMU,
+            ImuField::GpsTimestamp.into(),
+            "s, GPS time of week",
+            "",
+        ),
+    ));
+    statements.push(comment_sql(
+        table,
+        "week",
+        &describe(IMU, ImuField::GpsTimestamp.into(), "GPS week", ""),
+    ));
+    statements
+}
+
+pub fn gnss_comments() -> Vec<String> {
+    let mut statements: Vec<String> = GNSS_COLUMNS
+        .iter()
+        .map(|c| {
+            comment_sql(
+                "gnss_data",
+                c.column,
+                &describe(GNSS, c.descriptor, c.units, ""),
+            )
+        })
+        .collect();
+
+    statements.push(comment_sql(
+        "gnss_data",
+        "solution_valid",
+        "true when fix_type is a valid 3D, 2D or RTK fix. Members the device flagged invalid are NULL",
+    ));
+    statements.extend(GNSS_CODED.iter().map(|c| {
+        let codes: Vec<String> = c
+            .labels
+            .iter()
+            .map(|(code, label)| format!("{} = {}", code, label))
+            .collect();
+        comment_sql(
+            "gnss_data",
+            c.name_column,
+            &format!("{} as {}: {}", c.column, c.sql_type, codes.join(", ")),
+        )
+    }));
+    statements.extend(GNSS_FLAG_BITS.iter().map(|f| {
+        comment_sql(
+            "gnss_data",
+            f.column,
+            &format!("{}, from {} & {:#04x}", f.meaning, f.flags, f.mask),
+        )
+    }));
+    statements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    impl Words for [u8] {
+        fn u8_at(&self, offset: usize) -> Result<u8, Error> {
+            let bytes = self.get(offset..offset + 1).ok_or("past the end")?;
+            Ok(u8::from_be_bytes(bytes.try_into()?))
+        }
+
+        fn u16_at(&self, offset: usize) -> Result<u16, Error> {
+            let bytes = self.get(offset..offset + 2).ok_or("past the end")?;
+            Ok(u16::from_be_bytes(bytes.try_into()?))
+        }
+
+        fn u32_at(&self, offset: usize) -> Result<u32, Error> {
+            let bytes = self.get(offset..offset + 4).ok_or("past the end")?;
+            Ok(u32::from_be_bytes(bytes.try_into()?))
+        }
+
+        fn u64_at(&self, offset: usize) -> Result<u64, Error> {
+            let bytes = self.get(offset..offset + 8).ok_or("past the end")?;
+            Ok(u64::from_be_bytes(bytes.try_into()?))
+        }
+    }
+
+    fn to_big(order: ByteOrder, bytes: &[u8]) -> Vec<u8> {
+        let mut bytes = bytes.to_vec();
+        order.to_big(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn big_is_left_alone() {
+        assert_eq!(to_big(ByteOrder::Big, &[1, 2]), [1, 2]);
+        assert_eq!(to_big(ByteOrder::Big, &[1, 2, 3, 4]), [1, 2, 3, 4]);
+        assert_eq!(
+            to_big(ByteOrder::Big, &[1, 2, 3, 4, 5, 6, 7, 8]),
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+    }
+
+    #[test]
+    fn little_is_reversed() {
+        assert_eq!(to_big(ByteOrder::Little, &[2, 1]), [1, 2]);
+        assert_eq!(to_big(ByteOrder::Little, &[4, 3, 2, 1]), [1, 2, 3, 4]);
+        assert_eq!(
+            to_big(ByteOrder::Little, &[8, 7, 6, 5, 4, 3, 2, 1]),
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+    }
+
+    #[test]
+    fn byte_swapped_swaps_each_word() {
+        assert_eq!(to_big(ByteOrder::ByteSwapped, &[2, 1]), [1, 2]);
+        assert_eq!(to_big(ByteOrder::ByteSwapped, &[2, 1, 4, 3]), [1, 2, 3, 4]);
+        assert_eq!(
+            to_big(ByteOrder::ByteSwapped, &[2, 1, 4, 3, 6, 5, 8, 7]),
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+    }
+
+    #[test]
+    fn word_swapped_reverses_the_words() {
+        assert_eq!(to_big(ByteOrder::WordSwapped, &[1, 2]), [1, 2]);
+        assert_eq!(to_big(ByteOrder::WordSwapped, &[3, 4, 1, 2]), [1, 2, 3, 4]);
+        assert_eq!(
+            to_big(ByteOrder::WordSwapped, &[7, 8, 5, 6, 3, 4, 1, 2]),
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+    }
+
+    #[test]
+    fn signed_ints_follow_the_int_order() {
+        let layout = Layout {
+            ints: ByteOrder::Little,
+            floats: ByteOrder::Big,
+        };
+        let data = [0xFE];
+        assert_eq!(i8::read(&data[..], 0, layout).unwrap(), -2);
+        let data = (-300i16).to_le_bytes();
+        assert_eq!(i16::read(&data[..], 0, layout).unwrap(), -300);
+        let data = (-123_456i32).to_le_bytes();
+        assert_eq!(i32::read(&data[..], 0, layout).unwrap(), -123_456);
+        let data = (-9_876_543_210i64).to_le_bytes();
+        assert_eq!(i64::read(&data[..], 0, layout).unwrap(), -9_876_543_210);
+    }
+
+    #[test]
+    fn floats_follow_their_own_order() {
+        let layout = Layout {
+            ints: ByteOrder::Little,
+            floats: ByteOrder::Big,
+        };
+        let data = (-1.5f32).to_be_bytes();
+        assert_eq!(f32::read(&data[..], 0, layout).unwrap(), -1.5);
+        let data = 2.25f64.to_be_bytes();
+        assert_eq!(f64::read(&data[..], 0, layout).unwrap(), 2.25);
+
+        let layout = Layout {
+            ints: ByteOrder::Big,
+            floats: ByteOrder::WordSwapped,
+        };
+        let mut data = (-1.5f32).to_be_bytes();
+        data.rotate_left(2);
+        assert_eq!(f32::read(&data[..], 0, layout).unwrap(), -1.5);
+        let data = [0, 0, 0, 0, 0, 0, 0x40, 0x02];
+        assert_eq!(f64::read(&data[..], 0, layout).unwrap(), 2.25);
+        let data = 513u16.to_be_bytes();
+        assert_eq!(u16::read(&data[..], 0, layout).unwrap(), 513);
+    }
+
+    #[test]
+    fn floats_are_read_in_one_pass() {
+        let floats = [1.0f32, -2.5, 3.25, 0.125];
+        for shape in [Shape::Scalar, Shape::Vector3, Shape::Quaternion] {
+            let expected = &floats[..shape.width()];
+            for order in [
+                ByteOrder::Big,
+                ByteOrder::Little,
+                ByteOrder::ByteSwapped,
+                ByteOrder::WordSwapped,
+            ] {
+                let layout = Layout {
+                    ints: ByteOrder::Big,
+                    floats: order,
+                };
+                let data: Vec<u8> = expected
+                    .iter()
+                    .flat_map(|f| {
+                        let mut word = f.to_be_bytes();
+                        // Each order is its own inverse on a 4-byte word.
+                        order.to_big(&mut word);
+                        word
+                    })
+                    .collect();
+
+                let mut read = vec![0.0; shape.width()];
+                f32s(&data[..], &mut read, layout).unwrap();
+                let mut each = vec![0.0; shape.width()];
+                for (i, value) in each.iter_mut().enumerate() {
+                    *value = f32::read(&data[..], i * 4, layout).unwrap();
+                }
+
+                assert_eq!(read, expected, "{:?} {:?}", shape, order);
+                assert_eq!(each, expected, "{:?} {:?}", shape, order);
+            }
+        }
+    }
+
+    #[test]
+    fn bytes_are_read_in_words() {
+        let data: Vec<u8> = (0..16).collect();
+        for len in [1, 3, 4, 8, 12, 16] {
+            let mut out = vec![0; len];
+            data[..].bytes_at(0, &mut out).unwrap();
+            assert_eq!(out, &data[..len]);
+        }
+        let mut out = [0; 4];
+        assert!(data[..].bytes_at(14, &mut out).is_err());
+    }
+
+    #[test]
+    fn a_second_layout_is_refused() {
+        // Other tests decode with the default, so that's the one installed.
+        Layout::default().install().unwrap();
+        Layout::default().install().unwrap();
+        let swapped = Layout {
+            ints: ByteOrder::ByteSwapped,
+            floats: ByteOrder::Little,
+        };
+        assert!(swapped.install().is_err());
+        assert_eq!(Layout::current(), Layout::default());
+    }
+}
@@ -0,0 +1,154 @@
+// Old recordings back through the logger, say after a schema change:
+// `lordlogger replay <file>` reads a capture from --record, or a raw dump of
+// the serial port's bytes like `cat /dev/ttyACM0 > dump.bin`, through
+// lordserial's parser and the same decoder and targets as a live run, into a
+// session of its own.
+//
+// The file stands in for the serial port, so the parser reads it as it would
+// the device. It goes as fast as it can unless asked for real time, which
+// paces a capture by when its packets arrived and a raw dump by the baud rate.
+use crate::capture::Reader;
+use crate::packet_source::PacketSource;
+use crate::Error;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const CHUNK: usize = 4096;
+
+enum Source {
+    Capture(Reader),
+    Raw(File),
+}
+
+struct Inner {
+    source: Source,
+    pending: Vec<u8>,
+    taken: usize,
+    // Bits per second, None for as fast as it goes.
+    baud: Option<u32>,
+    realtime: bool,
+    // The wall clock at the first packet and when it arrived.
+    start: Option<(Instant, SystemTime)>,
+    bytes: u64,
+    finished: bool,
+}
+
+impl Inner {
+    // Holds up the next chunk until it's due in real time.
+    fn pace(&mut self, arrived: Option<SystemTime>, len: usize) {
+        if !self.realtime {
+            return;
+        }
+        let due = match (arrived, self.baud) {
+            (Some(arrived), _) => {
+                let (wall, first) = *self.start.get_or_insert((Instant::now(), arrived));
+                wall + arrived.duration_since(first).unwrap_or_default()
+            }
+            // Ten bits a byte on the wire with the start and stop bits.
+            (None, Some(baud)) => {
+                let (wall, _) = *self
+                    .start
+                    .get_or_insert((Instant::now(), SystemTime::now()));
+                wall + Duration::from_secs_f64(self.bytes as f64 * 10.0 / baud as f64)
+            }
+            (None, None) => return,
+        };
+        self.bytes += len as u64;
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+    }
+
+    fn refill(&mut self) -> io::Result<()> {
+        let other = |e: Error| io::Error::other(e.to_string());
+        self.taken = 0;
+        match &mut self.source {
+            Source::Capture(reader) => match reader.next_packet().map_err(other)? {
+                Some((arrived, packet)) => {
+                    let len = packet.len();
+                    self.pending = packet;
+                    self.pace(Some(arrived), len);
+                }
+                None => self.finished = true,
+            },
+            Source::Raw(file) => {
+                self.pending.resize(CHUNK, 0);
+                let n = file.read(&mut self.pending)?;
+                self.pending.truncate(n);
+                self.finished = n == 0;
+                self.pace(None, n);
+            }
+        }
+        Ok(())
+    }
+}
+
+// A recording read as the device. Clones share the recording, and commands
+// for the device go nowhere.
+#[derive(Clone)]
+pub struct ReplayPort {
+    name: String,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ReplayPort {
+    pub fn open(path: &Path, baud: u32, realtime: bool) -> Result<Self, Error> {
+        let (source, baud) = match Reader::open(path)? {
+            Some(reader) => (Source::Capture(reader), None),
+            None => (Source::Raw(File::open(path)?), Some(baud)),
+        };
+        Ok(ReplayPort {
+            name: path.display().to_string(),
+            inner: Arc::new(Mutex::new(Inner {
+                source,
+                pending: Vec::new(),
+                taken: 0,
+                baud,
+                realtime,
+                start: None,
+                bytes: 0,
+                finished: false,
+            })),
+        })
+    }
+
+    pub fn is_capture(&self) -> bool {
+        matches!(self.inner.lock().unwrap().source, Source::Capture(_))
+    }
+
+    // Whether every byte of the recording has been read.
+    pub fn finished(&self) -> bool {
+        self.inner.lock().unwrap().finished
+    }
+}
+
+impl PacketSource for ReplayPort {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    // Times out at the end like an idle port, which is what the parser
+    // expects of one.
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        while inner.taken == inner.pending.len() && !inner.finished {
+            inner.refill()?;
+        }
+        if inner.taken == inner.pending.len() {
+            drop(inner);
+            thread::sleep(timeout);
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let n = buf.len().min(inner.pending.len() - inner.taken);
+        let taken = inner.taken;
+        buf[..n].copy_from_slice(&inner.pending[taken..taken + n]);
+        inner.taken += n;
+        Ok(n)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn PacketSource>> {
+        Ok(Box::new(self.clone()))
+    }
+}
@@ -0,0 +1,85 @@
+use crate::config;
+use crate::session::GpsTime;
+use crate::Error;
+use std::time::{Duration, Instant};
+
+// Comma separated rules, any of which ends the session and starts the next:
+// a duration such as `6h`, `90m` or `3600s`, a packet count `packets=N`, or
+// `utc-day` to roll over when GPS time crosses UTC midnight.
+pub const ROLLOVER_ENV: &str = "LORDLOGGER_ROLLOVER";
+
+const SECONDS_PER_DAY: f64 = 86_400.0;
+
+#[derive(Debug, Default)]
+pub struct Rollover {
+    max_duration: Option<Duration>,
+    max_packets: Option<u64>,
+    utc_day: bool,
+    started: Option<Instant>,
+    day: Option<i64>,
+}
+
+fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let unit_len = s.chars().last().map_or(0, char::len_utf8);
+    let (n, unit) = s.split_at(s.len() - unit_len);
+    let n: u64 = n.parse()?;
+    match unit {
+        "s" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        "h" => Ok(Duration::from_secs(n * 3600)),
+        _ => Err(format!("unknown unit `{}`", unit).into()),
+    }
+}
+
+impl Rollover {
+    pub fn from_settings(session: &config::Session) -> Result<Self, Error> {
+        let mut rollover = Rollover::default();
+        for rule in session.rollover.iter().flatten().map(|r| r.trim()) {
+            let parsed = match rule.split_once('=') {
+                Some(("packets", n)) => n
+                    .parse()
+                    .map(|n| rollover.max_packets = Some(n))
+                    .map_err(Error::from),
+                None if rule == "utc-day" => {
+                    rollover.utc_day = true;
+                    Ok(())
+                }
+                None => parse_duration(rule).map(|d| rollover.max_duration = Some(d)),
+                Some(_) => Err("unknown rule".into()),
+            };
+            parsed.map_err(|e| format!("bad rollover rule `{}`: {}", rule, e))?;
+        }
+
+        Ok(rollover)
+    }
+
+    // Called with the session's packet count and latest GPS time. Returns why
+    // the session should roll over, if it should.
+    pub fn check(&mut self, packets: u64, gps_time: Option<GpsTime>) -> Option<&'static str> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+
+        if self.max_duration.is_some_and(|d| started.elapsed() >= d) {
+            return Some("duration");
+        }
+        if self.max_packets.is_some_and(|n| packets >= n) {
+            return Some("packets");
+        }
+        if let (true, Some(time)) = (self.utc_day, gps_time) {
+            let day = (time.unix_seconds() / SECONDS_PER_DAY).floor() as i64;
+            if self
+                .day
+                .replace(day)
+                .is_some_and(|previous| previous != day)
+            {
+                return Some("utc_day");
+            }
+        }
+
+        None
+    }
+
+    // Restarts the duration for the next session; the current day carries over.
+    pub fn reset(&mut self) {
+        self.started = Some(Instant::now());
+    }
+}
@@ -0,0 +1,20 @@
+// The tokio runtime the logger's I/O runs on: each target's writer is a task
+// here, writing to Postgres with tokio-postgres, and a serial port is read
+// through tokio-serial. Started on first use and shared by every run in the
+// process. The acquisition loops stay threads of their own, awaiting their
+// packets here through block_on, see Packets in packet_source.rs, so they
+// must not be started from a task on it.
+use std::sync::OnceLock;
+use tokio::runtime::{Builder, Runtime};
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+pub fn get() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("lordlogger-io")
+            .build()
+            .expect("failed to start the tokio runtime")
+    })
+}
@@ -0,0 +1,120 @@
+// Periodic maintenance run by the logger itself instead of by cron on each
+// deployment. Every task runs on its own interval, spread by a random jitter so
+// a fleet started together doesn't hit its databases all at once.
+use crate::check::parse_duration;
+use crate::config::Maintenance;
+use crate::Error;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+// Comma separated `task=interval` overrides, e.g. `retention=6h,bit=off`.
+pub const SCHEDULE_ENV: &str = "LORDLOGGER_SCHEDULE";
+// Fraction of each interval a run may move early or late by, 0.1 by default.
+pub const SCHEDULE_JITTER_ENV: &str = "LORDLOGGER_SCHEDULE_JITTER";
+
+const DEFAULT_JITTER: f64 = 0.1;
+
+// Every task and its interval when not overridden, None for off. The built-in
+// test interrupts the data stream on some models, so it's only run on request.
+const TASKS: [(&str, Option<Duration>); 4] = [
+    ("retention", Some(Duration::from_secs(3600))),
+    ("spool_compaction", Some(Duration::from_secs(600))),
+    ("rollups", Some(Duration::from_secs(300))),
+    ("bit", None),
+];
+
+struct Task {
+    name: &'static str,
+    interval: Duration,
+    next: Instant,
+}
+
+pub struct Scheduler {
+    tasks: Vec<Task>,
+    jitter: f64,
+}
+
+// Uniform in [-1, 1).
+fn random() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+}
+
+fn intervals(overrides: &[String]) -> Result<Vec<(&'static str, Option<Duration>)>, Error> {
+    let mut tasks = TASKS.to_vec();
+    for rule in overrides.iter().map(|r| r.trim()) {
+        let (name, interval) = rule
+            .split_once('=')
+            .ok_or_else(|| format!("schedule `{}` is not task=interval", rule))?;
+        let task = tasks
+            .iter_mut()
+            .find(|(task, _)| *task == name.trim())
+            .ok_or_else(|| format!("schedule: unknown task `{}`", name))?;
+        task.1 = match interval.trim() {
+            "off" => None,
+            interval => Some(
+                parse_duration(interval).map_err(|e| format!("bad schedule `{}`: {}", rule, e))?,
+            ),
+        };
+    }
+
+    Ok(tasks)
+}
+
+impl Scheduler {
+    // A scheduler for the named tasks that are switched on. The first run of
+    // each waits a full interval.
+    pub fn from_settings(maintenance: &Maintenance, names: &[&str]) -> Result<Self, Error> {
+        let jitter = maintenance.jitter.unwrap_or(DEFAULT_JITTER);
+        if !(0.0..1.0).contains(&jitter) {
+            return Err("the schedule jitter must be at least 0 and below 1".into());
+        }
+
+        let mut scheduler = Scheduler {
+            tasks: Vec::new(),
+            jitter,
+        };
+        let overrides = maintenance.schedule.as_deref().unwrap_or_default();
+        for (name, interval) in intervals(overrides)? {
+            if let (true, Some(interval)) = (names.contains(&name), interval) {
+                let next = scheduler.after(interval);
+                scheduler.tasks.push(Task {
+                    name,
+                    interval,
+                    next,
+                });
+            }
+        }
+
+        Ok(scheduler)
+    }
+
+    fn after(&self, interval: Duration) -> Instant {
+        Instant::now() + interval.mul_f64(1.0 + self.jitter * random())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    // The tasks whose time has come, each scheduled again for its next run.
+    pub fn due(&mut self) -> Vec<&'static str> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for i in 0..self.tasks.len() {
+            if self.tasks[i].next <= now {
+                self.tasks[i].next = self.after(self.tasks[i].interval);
+                due.push(self.tasks[i].name);
+            }
+        }
+        due
+    }
+
+    pub fn until_next(&self) -> Option<Duration> {
+        self.tasks
+            .iter()
+            .map(|t| t.next.saturating_duration_since(Instant::now()))
+            .min()
+    }
+}
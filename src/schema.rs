@@ -0,0 +1,29 @@
+use crate::config::Data;
+use crate::Error;
+
+pub const SCHEMA_ENV: &str = "LORDLOGGER_SCHEMA";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaMode {
+    // One row per packet in imu_data / gnss_data.
+    Wide,
+    // One row per value in measurements.
+    Long,
+    // One row per packet in packets, fields in a JSONB payload.
+    Json,
+}
+
+impl SchemaMode {
+    pub fn from_settings(data: &Data) -> Result<Self, Error> {
+        match data.schema.as_deref() {
+            None | Some("wide") => Ok(SchemaMode::Wide),
+            Some("long") => Ok(SchemaMode::Long),
+            Some("json") => Ok(SchemaMode::Json),
+            Some(other) => Err(format!(
+                "the schema must be `wide`, `long` or `json`, got `{}`",
+                other
+            )
+            .into()),
+        }
+    }
+}
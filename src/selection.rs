@@ -0,0 +1,169 @@
+use crate::config::Data;
+use crate::descriptors::{self, DataDescriptor, ImuField};
+use crate::schema::SchemaMode;
+use crate::Error;
+use lordserial::Packet;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+// Comma separated selectors, `80` for a whole descriptor set or `80/04` for
+// one field of it. Ignored fields are never requested from the device and
+// ignored sets are dropped on arrival; a required field missing from a packet
+// or a required set going quiet stops the logger.
+pub const IGNORE_ENV: &str = "LORDLOGGER_IGNORE";
+pub const REQUIRE_ENV: &str = "LORDLOGGER_REQUIRE";
+
+const REQUIRED_SET_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Fields the wide schema can do without.
+const OPTIONAL_WIDE_IMU: [ImuField; 4] = [
+    ImuField::RawAccel,
+    ImuField::RawGyro,
+    ImuField::RawMag,
+    ImuField::RawPressure,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selector {
+    Set(u8),
+    Field(u8, u8),
+}
+
+impl Selector {
+    fn parse(s: &str) -> Result<Self, Error> {
+        let hex = |h: &str| u8::from_str_radix(h.trim().trim_start_matches("0x"), 16);
+        match s.split_once('/') {
+            Some((set, field)) => Ok(Selector::Field(hex(set)?, hex(field)?)),
+            None => Ok(Selector::Set(hex(s)?)),
+        }
+    }
+
+    fn describe(self) -> String {
+        match self {
+            Selector::Set(set) => descriptors::describe_set(set),
+            Selector::Field(set, field) => descriptors::describe_field(set, field),
+        }
+    }
+}
+
+fn parse_list(name: &str, list: &Option<Vec<String>>) -> Result<Vec<Selector>, Error> {
+    list.iter()
+        .flatten()
+        .map(|s| {
+            Selector::parse(s).map_err(|e| format!("{}: bad selector `{}`: {}", name, s, e).into())
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct Selection {
+    ignore: Vec<Selector>,
+    require: Vec<Selector>,
+    last_seen: HashMap<u8, Instant>,
+    started: Instant,
+}
+
+impl Selection {
+    pub fn from_settings(data: &Data) -> Result<Self, Error> {
+        let selection = Selection {
+            ignore: parse_list("ignore", &data.ignore)?,
+            require: parse_list("require", &data.require)?,
+            last_seen: HashMap::new(),
+            started: Instant::now(),
+        };
+
+        for required in &selection.require {
+            let set = match *required {
+                Selector::Set(set) | Selector::Field(set, _) => set,
+            };
+            if selection.ignore.contains(required) || selection.ignore.contains(&Selector::Set(set))
+            {
+                return Err(format!("{} is both ignored and required", required.describe()).into());
+            }
+        }
+
+        Ok(selection)
+    }
+
+    // The wide tables need every field they have a column for, so only the
+    // optional raw IMU fields can be ignored there.
+    pub fn validate(&self, schema: SchemaMode, rate_groups: bool) -> Result<(), Error> {
+        if schema != SchemaMode::Wide {
+            return Ok(());
+        }
+
+        for selector in &self.ignore {
+            let needed = match *selector {
+                Selector::Field(set, field) => match DataDescriptor::try_from(set) {
+                    Ok(DataDescriptor::Imu) => {
+                        !rate_groups && !OPTIONAL_WIDE_IMU.iter().any(|f| u8::from(*f) == field)
+                    }
+                    Ok(DataDescriptor::Gnss) => true,
+                    Ok(DataDescriptor::Filter) | Err(_) => false,
+                },
+                _ => false,
+            };
+            if needed {
+                return Err(format!(
+                    "the wide schema needs {}, ignore it with the long or json schema instead",
+                    selector.describe()
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn ignores_set(&self, set: u8) -> bool {
+        self.ignore.contains(&Selector::Set(set))
+    }
+
+    // The message format to request from the device for a set.
+    pub fn format(&self, set: u8, fields: Vec<(u8, u16)>) -> Vec<(u8, u16)> {
+        if self.ignores_set(set) {
+            return Vec::new();
+        }
+
+        fields
+            .into_iter()
+            .filter(|(field, _)| !self.ignore.contains(&Selector::Field(set, *field)))
+            .collect()
+    }
+
+    pub fn check_packet(&mut self, packet: &Packet) -> Result<(), Error> {
+        let set = packet.header.descriptor;
+        self.last_seen.insert(set, Instant::now());
+
+        for selector in &self.require {
+            if let Selector::Field(s, field) = *selector {
+                if s == set && packet.payload.get_field(field).is_none() {
+                    return Err(
+                        format!("required {} missing from packet", selector.describe()).into(),
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn check_sets(&self) -> Result<(), Error> {
+        for selector in &self.require {
+            if let Selector::Set(set) = *selector {
+                let since = self.last_seen.get(&set).unwrap_or(&self.started);
+                if since.elapsed() > REQUIRED_SET_TIMEOUT {
+                    return Err(format!(
+                        "no required {} packets for {:?}",
+                        selector.describe(),
+                        REQUIRED_SET_TIMEOUT
+                    )
+                    .into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+use crate::descriptors::{self, DataDescriptor, FilterField, GnssField, ImuField};
+use crate::registry::{self, Read};
+use crate::shared;
+use crate::Error;
+use lordserial::Packet;
+use postgres::{Client, Config, NoTls};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
+
+// Unix time of the GPS epoch, 1980-01-06T00:00:00Z.
+const GPS_EPOCH_UNIX: f64 = 315_964_800.0;
+const GPS_LEAP_SECONDS: f64 = 18.0;
+const SECONDS_PER_WEEK: f64 = 604_800.0;
+
+const EVENT_QUEUE: usize = 1024;
+
+// Row time from GPS week/tow, since the data tables don't carry a timestamp.
+pub const GPS_TIME_SQL: &str = "to_timestamp(315964800 + week * 604800 + tow - 18)";
+
+#[derive(Debug, Clone, Copy)]
+pub struct GpsTime {
+    pub tow: f64,
+    pub week: i16,
+}
+
+impl GpsTime {
+    pub fn unix_seconds(self) -> f64 {
+        GPS_EPOCH_UNIX + self.week as f64 * SECONDS_PER_WEEK + self.tow - GPS_LEAP_SECONDS
+    }
+
+    pub fn from_unix_seconds(seconds: f64) -> Self {
+        let gps = seconds - GPS_EPOCH_UNIX + GPS_LEAP_SECONDS;
+        let week = (gps / SECONDS_PER_WEEK).floor();
+        GpsTime {
+            tow: gps - week * SECONDS_PER_WEEK,
+            week: week as i16,
+        }
+    }
+
+    // GPS time carried by IMU (0x80/0x12), GNSS (0x81/0x09) and filter (0x82/0x11)
+    // packets, or by
+    // the shared timestamp newer devices put in any set.
+    pub fn from_packet(packet: &Packet) -> Result<Option<Self>, Error> {
+        let descriptor = match descriptors::data_set(packet) {
+            Some(DataDescriptor::Imu) => ImuField::GpsTimestamp.into(),
+            Some(DataDescriptor::Gnss) => GnssField::GpsTime.into(),
+            Some(DataDescriptor::Filter) => FilterField::GpsTimestamp.into(),
+            None => return shared::gps_time(packet),
+        };
+
+        match packet.payload.get_field(descriptor) {
+            Some(field) => Ok(Some(GpsTime {
+                tow: field.read(0)?,
+                week: field.read(8)?,
+            })),
+            None => shared::gps_time(packet),
+        }
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs_f64(self.unix_seconds().max(0.0))
+    }
+}
+
+// The span of host time a session covers, as Postgres timestamp text. A
+// session that never recorded its end runs until the next one started. The
+// run it came from rides along, unset on sessions from before it was kept.
+#[derive(Debug, Clone)]
+pub struct Window {
+    pub started_at: String,
+    pub ended_at: Option<String>,
+    pub until: String,
+    pub vehicle_id: Option<String>,
+    pub port: Option<String>,
+    pub device_serial: Option<String>,
+    pub config_hash: Option<String>,
+    pub version: Option<String>,
+    pub environment: Option<serde_json::Value>,
+    pub session_key: Option<String>,
+}
+
+impl Window {
+    pub fn load(c: &mut Client, session: i32) -> Result<Self, Error> {
+        let row = c
+            .query_opt(
+                "SELECT started_at::text, ended_at::text,
+                    coalesce(ended_at, (SELECT min(n.started_at) FROM sessions n WHERE n.id > s.id), now())::text,
+                    vehicle_id, port, device_serial, config_hash, version,
+                    environment, session_key
+                 FROM sessions s WHERE id = $1",
+                &[&session],
+            )?
+            .ok_or_else(|| format!("no session {}", session))?;
+
+        Ok(Window {
+            started_at: row.get(0),
+            ended_at: row.get(1),
+            until: row.get(2),
+            vehicle_id: row.get(3),
+            port: row.get(4),
+            device_serial: row.get(5),
+            config_hash: row.get(6),
+            version: row.get(7),
+            environment: row.get(8),
+            session_key: row.get(9),
+        })
+    }
+
+    // SQL condition selecting rows whose `time` expression falls in the window.
+    pub fn filter(&self, time: &str) -> String {
+        let quote = |s: &str| format!("'{}'", s.replace('\'', "''"));
+        format!(
+            "{time} >= {start}::timestamptz AND {time} < {end}::timestamptz",
+            time = time,
+            start = quote(&self.started_at),
+            end = quote(&self.until)
+        )
+    }
+}
+
+pub const CREATE_SQL: &str = "
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS port text;
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS device_serial text;
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS config_hash text;
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS version text;
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS environment jsonb;
+
+    COMMENT ON COLUMN sessions.device_serial IS 'USB serial number of the sensor, when it has one';
+    COMMENT ON COLUMN sessions.config_hash IS
+        'sha256 of the config and device events, equal for runs set up the same way';
+    COMMENT ON COLUMN sessions.version IS 'lordlogger version, with the git commit when built from one';
+    COMMENT ON COLUMN sessions.environment IS
+        'build, library, host OS, kernel and serial driver versions at start';
+";
+
+// Sessions from before it held the USB adapter's serial number, left as they
+// are.
+pub const DEVICE_SERIAL_SQL: &str = "
+    COMMENT ON COLUMN sessions.device_serial IS
+        'serial number the sensor reports for itself, e.g. 6251.12345, when it answers';
+";
+
+pub const SENSOR_KEY_SQL: &str = "
+    COMMENT ON COLUMN sessions.session_key IS
+        'serial number the sensor reports and first GPS epoch, e.g. 6251.12345@2312:302418.000';
+";
+
+// A session's key is the serial number the sensor reports and its first GPS
+// epoch, which depend on neither the database nor the port or file the data
+// came in on, so loading the same capture again finds the session it made the
+// first time instead of making another. A session whose sensor never said
+// what it is goes unkeyed.
+pub const KEY_SQL: &str = "
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS session_key text;
+    CREATE UNIQUE INDEX IF NOT EXISTS sessions_session_key_idx ON sessions (session_key);
+
+    COMMENT ON COLUMN sessions.session_key IS
+        'device serial, or port without one, and first GPS epoch, e.g. 6251.12345@2312:302418.000';
+";
+
+pub fn key(device: &str, first: GpsTime) -> String {
+    format!("{}@{}:{:.3}", device, first.week, first.tow)
+}
+
+// Extra targets don't hold the sessions table's rows, so data rows name their
+// session without a foreign key.
+pub fn create_sql(table: &str) -> String {
+    format!(
+        "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS session_id integer;
+         CREATE INDEX IF NOT EXISTS {table}_session_id_idx ON {table} (session_id);",
+        table = table
+    )
+}
+
+pub fn comment(table: &str) -> String {
+    registry::comment_sql(
+        table,
+        "session_id",
+        "sessions.id of the primary database for the run that logged the row",
+    )
+}
+
+pub fn version() -> &'static str {
+    option_env!("LORDLOGGER_GIT_VERSION").unwrap_or(env!("CARGO_PKG_VERSION"))
+}
+
+// What a run records about itself when its session starts.
+#[derive(Debug, Clone, Default)]
+pub struct RunInfo {
+    pub vehicle_id: Option<String>,
+    pub port: String,
+    // As the sensor reports it, not the USB adapter's.
+    pub device_serial: Option<String>,
+    pub config_hash: String,
+    pub environment: serde_json::Value,
+}
+
+#[derive(Debug)]
+pub struct Session {
+    pub id: i32,
+    pub gps_time: Option<GpsTime>,
+    pub first_gps_time: Option<GpsTime>,
+    pub device_serial: Option<String>,
+    // Whether the key has been looked at, given or not.
+    pub keyed: bool,
+    // The session this run started as before it turned out to repeat `id`,
+    // and the GPS time up to which `id` already has the data.
+    pub merged_from: Option<i32>,
+    pub held_until: Option<GpsTime>,
+}
+
+impl Session {
+    pub fn start(c: &mut Client, run: &RunInfo) -> Result<Self, Error> {
+        let row = c.query_one(
+            "INSERT INTO sessions (vehicle_id, port, device_serial, config_hash, version, environment)
+             VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+            &[
+                &run.vehicle_id,
+                &run.port,
+                &run.device_serial,
+                &run.config_hash,
+                &version(),
+                &run.environment,
+            ],
+        )?;
+
+        Ok(Session {
+            id: row.get(0),
+            gps_time: None,
+            first_gps_time: None,
+            device_serial: run.device_serial.clone(),
+            keyed: false,
+            merged_from: None,
+            held_until: None,
+        })
+    }
+
+    // The next session after a rollover, linked back to the one it continues
+    // and from the same run.
+    pub fn start_after(c: &mut Client, previous: &Session) -> Result<Self, Error> {
+        let row = c.query_one(
+            "INSERT INTO sessions (
+                previous_id, vehicle_id, port, device_serial, config_hash, version, environment
+             )
+             SELECT id, vehicle_id, port, device_serial, config_hash, version, environment
+             FROM sessions WHERE id = $1 RETURNING id",
+            &[&previous.id],
+        )?;
+
+        Ok(Session {
+            id: row.get(0),
+            gps_time: previous.gps_time,
+            first_gps_time: None,
+            device_serial: previous.device_serial.clone(),
+            keyed: false,
+            merged_from: None,
+            held_until: None,
+        })
+    }
+
+    pub fn track(&mut self, time: GpsTime) {
+        self.gps_time = Some(time);
+        self.first_gps_time.get_or_insert(time);
+    }
+
+    pub fn key_pending(&self) -> bool {
+        !self.keyed && self.first_gps_time.is_some() && self.device_serial.is_some()
+    }
+
+    // For a session started without a device to ask, once its data says
+    // which sensor it came from.
+    pub fn identify(&mut self, c: &mut Client, serial: &str) -> Result<(), Error> {
+        if self.device_serial.is_some() || serial.is_empty() {
+            return Ok(());
+        }
+        c.execute(
+            "UPDATE sessions SET device_serial = $2 WHERE id = $1",
+            &[&self.id, &serial],
+        )?;
+        self.device_serial = Some(serial.to_string());
+        Ok(())
+    }
+
+    // Keys the session by its sensor and first GPS epoch. Returns the session
+    // that already has the key when this one repeats a capture, leaving this
+    // one without.
+    pub fn claim_key(&mut self, c: &mut Client) -> Result<Option<i32>, Error> {
+        let (device, first) = match (&self.device_serial, self.first_gps_time) {
+            (Some(device), Some(first)) => (device, first),
+            _ => return Ok(None),
+        };
+        let key = key(device, first);
+
+        let existing = c.query_opt(
+            "SELECT id FROM sessions WHERE session_key = $1 AND id <> $2",
+            &[&key, &self.id],
+        )?;
+        if existing.is_none() {
+            c.execute(
+                "UPDATE sessions SET session_key = $2 WHERE id = $1",
+                &[&self.id, &key],
+            )?;
+        }
+        self.keyed = true;
+        Ok(existing.map(|row| row.get(0)))
+    }
+
+    // Carries on as `existing`, the session this one repeats: what's been
+    // written under this one so far moves there, and from here on only data
+    // past what `existing` already holds is wanted, see holds().
+    pub fn merge_into(&mut self, c: &mut Client, existing: i32) -> Result<(), Error> {
+        self.held_until = held_until(c, existing)?;
+        move_rows(c, self.id, existing)?;
+        self.merged_from = Some(self.id);
+        self.id = existing;
+        Ok(())
+    }
+
+    // Whether the packet last tracked is one the session merged into already
+    // has the data of.
+    pub fn holds(&self) -> bool {
+        match (self.held_until, self.gps_time) {
+            (Some(until), Some(now)) => now.unix_seconds() <= until.unix_seconds(),
+            _ => false,
+        }
+    }
+
+    // Once everything's written, moves the rows still naming the session
+    // merged away and drops it.
+    pub fn finish_merge(&mut self, c: &mut Client) -> Result<(), Error> {
+        let merged = match self.merged_from {
+            Some(merged) => merged,
+            None => return Ok(()),
+        };
+        move_rows(c, merged, self.id)?;
+        c.execute("DELETE FROM sessions WHERE id = $1", &[&merged])?;
+        self.merged_from = None;
+        Ok(())
+    }
+
+    pub fn end(&self, c: &mut Client) -> Result<(), Error> {
+        c.execute(
+            "UPDATE sessions SET ended_at = now() WHERE id = $1",
+            &[&self.id],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_event(&self, c: &mut Client, kind: &str, message: &str) -> Result<(), Error> {
+        let tow = self.gps_time.map(|t| t.tow);
+        let week = self.gps_time.map(|t| t.week);
+
+        c.execute(
+            "INSERT INTO events (session_id, tow, week, kind, message) VALUES ($1, $2, $3, $4, $5)",
+            &[&self.id, &tow, &week, &kind, &message],
+        )?;
+
+        Ok(())
+    }
+}
+
+struct Event {
+    session: i32,
+    created_at: SystemTime,
+    gps_time: Option<GpsTime>,
+    kind: String,
+    message: String,
+}
+
+// Records events from the acquisition thread on a connection of their own, so
+// a slow or stalled database never holds up reading the device. An event that
+// doesn't fit in the queue or can't be written is logged and dropped.
+pub struct EventQueue {
+    events: Option<SyncSender<Event>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+fn write_events(config: Config, events: Receiver<Event>) {
+    let mut client: Option<Client> = None;
+    for event in events {
+        let result = match &mut client {
+            Some(c) if !c.is_closed() => Ok(c),
+            _ => config.connect(NoTls).map(|c| client.insert(c)),
+        }
+        .and_then(|c| {
+            c.execute(
+                "INSERT INTO events (session_id, created_at, tow, week, kind, message)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+                &[
+                    &event.session,
+                    &event.created_at,
+                    &event.gps_time.map(|t| t.tow),
+                    &event.gps_time.map(|t| t.week),
+                    &event.kind,
+                    &event.message,
+                ],
+            )
+        });
+
+        if let Err(e) = result {
+            error!("Failed to record {} event. Error: {}", event.kind, e);
+        }
+    }
+}
+
+impl EventQueue {
+    pub fn start(config: Config) -> Self {
+        let (events, queued) = mpsc::sync_channel(EVENT_QUEUE);
+        let writer = thread::spawn(move || write_events(config, queued));
+
+        EventQueue {
+            events: Some(events),
+            writer: Some(writer),
+        }
+    }
+
+    pub fn send(&self, session: &Session, kind: &str, message: &str) {
+        let event = Event {
+            session: session.id,
+            created_at: SystemTime::now(),
+            gps_time: session.gps_time,
+            kind: kind.to_string(),
+            message: message.to_string(),
+        };
+        let queued = match &self.events {
+            Some(events) => events.try_send(event).is_ok(),
+            None => false,
+        };
+        if !queued {
+            warn!("Event queue full, dropped {} event", kind);
+        }
+    }
+
+    // Waits for the queued events to be written. Nothing can be sent after.
+    pub fn drain(&mut self) {
+        self.events.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+// Every table naming sessions by id, views aside: the data tables, events and
+// command log among them.
+fn session_tables(c: &mut Client, column: &str) -> Result<Vec<String>, Error> {
+    let rows = c.query(
+        "SELECT table_name::text FROM information_schema.columns
+         JOIN information_schema.tables USING (table_schema, table_name)
+         WHERE table_schema = current_schema() AND column_name = $1
+           AND table_type = 'BASE TABLE' AND table_name <> 'sessions'
+         ORDER BY table_name",
+        &[&column],
+    )?;
+    Ok(rows.iter().map(|r| r.get(0)).collect())
+}
+
+fn move_rows(c: &mut Client, from: i32, to: i32) -> Result<(), Error> {
+    let tables = session_tables(c, "session_id")?;
+    let mut tx = c.transaction()?;
+    for table in tables {
+        tx.execute(
+            format!("UPDATE {} SET session_id = $2 WHERE session_id = $1", table).as_str(),
+            &[&from, &to],
+        )?;
+    }
+    tx.execute(
+        "UPDATE sessions SET previous_id = $2 WHERE previous_id = $1",
+        &[&from, &to],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+// The latest GPS time a session has rows at. Events don't count, so packets
+// that were dropped the first time are decoded when the capture comes again.
+fn held_until(c: &mut Client, session: i32) -> Result<Option<GpsTime>, Error> {
+    let mut latest: Option<f64> = None;
+    let timed: Vec<String> = session_tables(c, "gps_time")?;
+    for table in session_tables(c, "session_id")?
+        .into_iter()
+        .filter(|t| timed.contains(t))
+    {
+        let max: Option<f64> = c
+            .query_one(
+                format!(
+                    "SELECT extract(epoch FROM max(gps_time))::float8 FROM {} WHERE session_id = $1",
+                    table
+                )
+                .as_str(),
+                &[&session],
+            )?
+            .get(0);
+        latest = match (latest, max) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    Ok(latest.map(GpsTime::from_unix_seconds))
+}
+
+// Takes a session-level advisory lock keyed on the device, held for as long as
+// this connection lives. Returns false if another logger already holds it.
+pub fn lock_device(c: &mut Client, device: &str) -> Result<bool, Error> {
+    let key = format!("lordlogger:{}", device);
+    let row = c.query_one("SELECT pg_try_advisory_lock(hashtext($1))", &[&key])?;
+    Ok(row.get(0))
+}
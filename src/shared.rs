@@ -0,0 +1,134 @@
+use crate::descriptors::{DataDescriptor, ImuField};
+use crate::fanout::Param;
+use crate::registry::{self, Read};
+use crate::session::GpsTime;
+use crate::Error;
+use lordserial::Packet;
+
+// Shared data fields the CV7 and 3DM-GQ7 can add to any data descriptor set,
+// so every stream carries its own time instead of relying on 0x80/0x12.
+pub const EVENT_SOURCE: u8 = 0xD0;
+pub const TICKS: u8 = 0xD1;
+pub const DELTA_TICKS: u8 = 0xD2;
+pub const GPS_TIMESTAMP: u8 = 0xD3;
+pub const DELTA_TIME: u8 = 0xD4;
+pub const REFERENCE_TIMESTAMP: u8 = 0xD5;
+pub const DELTA_REFERENCE_TIME: u8 = 0xD6;
+
+// (column, shared field, comment units)
+const COLUMNS: &[(&str, u8, &str)] = &[
+    (
+        "reference_time_ns",
+        REFERENCE_TIMESTAMP,
+        "ns, device reference time",
+    ),
+    (
+        "delta_reference_ns",
+        DELTA_REFERENCE_TIME,
+        "ns since the previous packet of this set",
+    ),
+    ("ticks", TICKS, "device ticks"),
+    (
+        "delta_ticks",
+        DELTA_TICKS,
+        "device ticks since the previous packet of this set",
+    ),
+];
+
+// Whether a configured field satisfies a field the wide schema needs. The
+// shared GPS timestamp stands in for the IMU set's own.
+pub fn covers(configured: u8, needed: u8) -> bool {
+    configured == needed
+        || (configured == GPS_TIMESTAMP && needed == u8::from(ImuField::GpsTimestamp))
+}
+
+pub fn gps_time(packet: &Packet) -> Result<Option<GpsTime>, Error> {
+    match packet.payload.get_field(GPS_TIMESTAMP) {
+        Some(field) => Ok(Some(GpsTime {
+            tow: field.read(0)?,
+            week: field.read(8)?,
+        })),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SharedData {
+    pub reference_time_ns: Option<i64>,
+    pub delta_reference_ns: Option<i64>,
+    pub ticks: Option<i64>,
+    pub delta_ticks: Option<i64>,
+}
+
+impl SharedData {
+    pub fn from_packet(packet: &Packet) -> Result<Self, Error> {
+        let nanos = |descriptor| {
+            packet
+                .payload
+                .get_field(descriptor)
+                .map(|f| f.read::<u64>(0).map(|v| v as i64))
+                .transpose()
+        };
+        let ticks = |descriptor| {
+            packet
+                .payload
+                .get_field(descriptor)
+                .map(|f| f.read::<u32>(0).map(i64::from))
+                .transpose()
+        };
+
+        Ok(SharedData {
+            reference_time_ns: nanos(REFERENCE_TIMESTAMP)?,
+            delta_reference_ns: nanos(DELTA_REFERENCE_TIME)?,
+            ticks: ticks(TICKS)?,
+            delta_ticks: ticks(DELTA_TICKS)?,
+        })
+    }
+
+    pub fn values(&self) -> [(&'static str, Option<i64>); 4] {
+        [
+            (COLUMNS[0].0, self.reference_time_ns),
+            (COLUMNS[1].0, self.delta_reference_ns),
+            (COLUMNS[2].0, self.ticks),
+            (COLUMNS[3].0, self.delta_ticks),
+        ]
+    }
+
+    // Parameters in `column_names()` order.
+    pub fn params(&self) -> Vec<Param> {
+        self.values()
+            .iter()
+            .map(|(_, v)| Box::new(*v) as Param)
+            .collect()
+    }
+}
+
+pub fn column_names() -> impl Iterator<Item = &'static str> {
+    COLUMNS.iter().map(|(column, _, _)| *column)
+}
+
+pub fn create_sql(table: &str) -> String {
+    COLUMNS
+        .iter()
+        .map(|(column, _, _)| {
+            format!(
+                "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} bigint;",
+                table, column
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn comments(table: &str, set: DataDescriptor) -> Vec<String> {
+    COLUMNS
+        .iter()
+        .map(|(column, descriptor, units)| {
+            registry::comment_sql(
+                table,
+                column,
+                &registry::describe(set.into(), *descriptor, units, ""),
+            )
+        })
+        .collect()
+}
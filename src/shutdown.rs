@@ -0,0 +1,33 @@
+// SIGINT and SIGTERM ask the logger to stop at its next turn of the loop, so
+// queued rows are written and the session closed instead of being cut off. A
+// second signal isn't caught, for when stopping cleanly hangs.
+use crate::Error;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+static REQUESTED: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn request(signal: libc::c_int) {
+    REQUESTED.store(signal, Ordering::SeqCst);
+}
+
+pub fn install() -> Result<(), Error> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = request as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESETHAND;
+        if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+// The name of the signal that asked to stop, once one has.
+pub fn requested() -> Option<&'static str> {
+    match REQUESTED.load(Ordering::SeqCst) {
+        0 => None,
+        libc::SIGINT => Some("SIGINT"),
+        libc::SIGTERM => Some("SIGTERM"),
+        _ => Some("signal"),
+    }
+}
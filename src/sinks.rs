@@ -0,0 +1,341 @@
+use crate::bus::{self, PacketDecoded, SinkError};
+use crate::clock::{self, ClockSources};
+use crate::descriptors::{self, DataDescriptor};
+use crate::ekf;
+use crate::fanout::{FanOut, Row};
+use crate::filter;
+use crate::heading::HeadingResolver;
+use crate::jsonb;
+use crate::measurements;
+use crate::migrations;
+use crate::odometer;
+use crate::rates::RateGroup;
+use crate::registry;
+use crate::schema::SchemaMode;
+use crate::session::{self, GpsTime};
+use crate::shared::{self, SharedData};
+use crate::timescale::Timescale;
+use crate::types::{field, GnssData, ImuData};
+use crate::vehicle::{self, Vehicle};
+use crate::Error;
+use lordserial::Packet;
+use postgres::Client;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tracing::{debug_span, trace, warn};
+
+// Tables whose rows carry the time columns, the session and the vehicle.
+pub fn timed_tables(rate_groups: &[RateGroup]) -> Vec<String> {
+    let mut timed: Vec<String> = [
+        "imu_data",
+        "gnss_data",
+        "filter_status",
+        "filter_uncertainty",
+        "ekf_data",
+        "odometer_data",
+        "gpsd_position",
+    ]
+    .iter()
+    .map(|t| t.to_string())
+    .collect();
+    timed.extend(rate_groups.iter().map(|g| g.table.clone()));
+    timed
+}
+
+pub fn setup_psql(
+    c: &mut Client,
+    schema: SchemaMode,
+    rate_groups: &[RateGroup],
+    clocks: &ClockSources,
+    vehicle: &Vehicle,
+    timescale: Option<&Timescale>,
+) -> Result<(), Error> {
+    migrations::run(c)?;
+
+    // The rest depends on the run's settings rather than the build, so it's
+    // brought up to date on every connect instead of migrated.
+    match schema {
+        SchemaMode::Wide => (),
+        SchemaMode::Long => c.batch_execute(measurements::CREATE_SQL)?,
+        SchemaMode::Json => c.batch_execute(jsonb::CREATE_SQL)?,
+    }
+
+    for group in rate_groups {
+        c.batch_execute(&group.create_sql())?;
+    }
+
+    let timed = timed_tables(rate_groups);
+    for table in &timed {
+        c.batch_execute(&clock::create_sql(table))?;
+        c.batch_execute(&session::create_sql(table))?;
+        if vehicle.rows {
+            c.batch_execute(&vehicle::create_sql(table))?;
+        }
+    }
+    if let Some(timescale) = timescale.filter(|_| schema == SchemaMode::Wide) {
+        timescale.setup(c)?;
+    }
+
+    let imu_fields: Vec<_> = registry::IMU_FIELDS.iter().collect();
+    let mut comments = registry::column_comments("imu_data", &imu_fields);
+    comments.push(registry::comment_sql(
+        "imu_data",
+        "heading_magnetic",
+        "rad, heading from magnetic north, derived from euler_angles yaw",
+    ));
+    comments.push(registry::comment_sql(
+        "imu_data",
+        "heading_true",
+        "rad, heading from true north, derived from euler_angles yaw and WMM declination",
+    ));
+    comments.extend(registry::gnss_comments());
+    comments.extend(shared::comments("imu_data", DataDescriptor::Imu));
+    comments.extend(shared::comments("gnss_data", DataDescriptor::Gnss));
+    comments.push(registry::comment_sql(
+        "clock_bias",
+        "offset_s",
+        "s, host clock minus device GPS time at receipt, including transport latency",
+    ));
+    for group in rate_groups {
+        comments.extend(registry::column_comments(&group.table, &group.fields));
+    }
+    for table in &timed {
+        comments.extend(clock::comments(table, clocks.source(table)));
+        comments.push(session::comment(table));
+        if vehicle.rows {
+            comments.push(vehicle::comment(table));
+        }
+    }
+    c.batch_execute(&comments.join("\n"))?;
+
+    Ok(())
+}
+
+// Decoded IMU and GNSS data for a program embedding the logger, say to feed a
+// controller, alongside the rows for the targets. Given every packet decoded
+// with the wide schema and default formats. Called on the decoding thread, so
+// anything slow should be handed off; an error is printed and the rows are
+// written regardless.
+pub trait Sink: Send {
+    fn write_imu(&mut self, data: &ImuData) -> Result<(), Error>;
+    fn write_gnss(&mut self, data: &GnssData) -> Result<(), Error>;
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct SinkHandle(pub Arc<Mutex<dyn Sink>>);
+
+impl SinkHandle {
+    pub fn new<S: Sink + 'static>(sink: S) -> Self {
+        SinkHandle(Arc::new(Mutex::new(sink)))
+    }
+}
+
+impl fmt::Debug for SinkHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SinkHandle")
+    }
+}
+
+// Turns packets into rows. Holds no per-stream state of its own, so it can be
+// shared by the decode workers.
+pub struct Decoder {
+    pub out: FanOut,
+    pub heading: Arc<Mutex<HeadingResolver>>,
+    pub rate_groups: Vec<RateGroup>,
+    pub schema: SchemaMode,
+    pub device: String,
+    pub sinks: Vec<SinkHandle>,
+}
+
+impl Decoder {
+    fn to_sinks(&self, write: impl Fn(&mut dyn Sink) -> Result<(), Error>) {
+        for sink in &self.sinks {
+            if let Err(e) = write(&mut *sink.0.lock().unwrap()) {
+                warn!("Sink failed. Error: {}", e);
+                bus::publish(SinkError {
+                    sink: "custom".to_string(),
+                    error: e.to_string(),
+                    rows: 1,
+                    lost: false,
+                });
+            }
+        }
+    }
+
+    // Flushes the sinks, for when the run stops.
+    pub fn flush(&self) {
+        self.to_sinks(|sink| sink.flush());
+    }
+
+    pub fn decode(&self, packet: &Packet) -> Result<(), Error> {
+        let _span = debug_span!(
+            "packet",
+            set = %descriptors::describe_set(packet.header.descriptor)
+        )
+        .entered();
+        trace!("decoding");
+        if self.schema != SchemaMode::Wide {
+            match self.schema {
+                SchemaMode::Long => measurements::insert(&self.out, &self.device, packet)?,
+                _ => jsonb::insert(&self.out, packet)?,
+            };
+            self.decoded(packet);
+            return Ok(());
+        }
+
+        match descriptors::data_set(packet) {
+            Some(DataDescriptor::Imu) if !self.rate_groups.is_empty() => {
+                for group in &self.rate_groups {
+                    group.insert(&self.out, packet)?;
+                }
+            }
+            Some(DataDescriptor::Imu) => {
+                let data = ImuData::new(packet)?;
+                let shared = SharedData::from_packet(packet)?;
+                let gps_time = GpsTime {
+                    tow: data.tow,
+                    week: data.week,
+                };
+                let (heading_magnetic, heading_true) = self
+                    .heading
+                    .lock()
+                    .unwrap()
+                    .resolve(data.euler_angles.z, gps_time);
+                self.out.send(Row::timed(
+                    "
+                INSERT INTO imu_data (
+                    accel,
+                    gyro,
+                    mag,
+                    baro,
+                    delta_theta,
+                    delta_velocity,
+                    quat,
+                    euler_angles,
+                    tow,
+                    week,
+                    heading_magnetic,
+                    heading_true,
+                    raw_accel,
+                    raw_gyro,
+                    raw_mag,
+                    raw_baro,
+                    reference_time_ns,
+                    delta_reference_ns,
+                    ticks,
+                    delta_ticks
+                ) VALUES (
+                    ROW($1, $2, $3),
+                    ROW($4, $5, $6),
+                    ROW($7, $8, $9),
+                    $10,
+                    ROW($11, $12, $13),
+                    ROW($14, $15, $16),
+                    ROW($17, $18, $19, $20),
+                    ROW($21, $22, $23),
+                    $24,
+                    $25,
+                    $26,
+                    $27,
+                    CASE WHEN $28::real IS NULL THEN NULL ELSE ROW($28, $29, $30)::real3d END,
+                    CASE WHEN $31::real IS NULL THEN NULL ELSE ROW($31, $32, $33)::real3d END,
+                    CASE WHEN $34::real IS NULL THEN NULL ELSE ROW($34, $35, $36)::real3d END,
+                    $37,
+                    $38,
+                    $39,
+                    $40,
+                    $41
+                );
+            ",
+                    vec![
+                        Box::new(data.accel.x),
+                        Box::new(data.accel.y),
+                        Box::new(data.accel.z),
+                        Box::new(data.gyro.x),
+                        Box::new(data.gyro.y),
+                        Box::new(data.gyro.z),
+                        Box::new(data.mag.x),
+                        Box::new(data.mag.y),
+                        Box::new(data.mag.z),
+                        Box::new(data.baro),
+                        Box::new(data.delta_theta.x),
+                        Box::new(data.delta_theta.y),
+                        Box::new(data.delta_theta.z),
+                        Box::new(data.delta_velocity.x),
+                        Box::new(data.delta_velocity.y),
+                        Box::new(data.delta_velocity.z),
+                        Box::new(data.quat.q0),
+                        Box::new(data.quat.q1),
+                        Box::new(data.quat.q2),
+                        Box::new(data.quat.q3),
+                        Box::new(data.euler_angles.x),
+                        Box::new(data.euler_angles.y),
+                        Box::new(data.euler_angles.z),
+                        Box::new(data.tow),
+                        Box::new(data.week),
+                        Box::new(heading_magnetic),
+                        Box::new(heading_true),
+                        Box::new(data.raw_accel.as_ref().map(|v| v.x)),
+                        Box::new(data.raw_accel.as_ref().map(|v| v.y)),
+                        Box::new(data.raw_accel.as_ref().map(|v| v.z)),
+                        Box::new(data.raw_gyro.as_ref().map(|v| v.x)),
+                        Box::new(data.raw_gyro.as_ref().map(|v| v.y)),
+                        Box::new(data.raw_gyro.as_ref().map(|v| v.z)),
+                        Box::new(data.raw_mag.as_ref().map(|v| v.x)),
+                        Box::new(data.raw_mag.as_ref().map(|v| v.y)),
+                        Box::new(data.raw_mag.as_ref().map(|v| v.z)),
+                        Box::new(data.raw_baro),
+                        Box::new(shared.reference_time_ns),
+                        Box::new(shared.delta_reference_ns),
+                        Box::new(shared.ticks),
+                        Box::new(shared.delta_ticks),
+                    ],
+                    gps_time,
+                ));
+                self.to_sinks(|sink| sink.write_imu(&data));
+            }
+            Some(DataDescriptor::Gnss) => {
+                let mut params = registry::GNSS_COLUMNS
+                    .iter()
+                    .map(|column| column.param(field(packet, column.descriptor)?))
+                    .collect::<Result<Vec<_>, _>>()?;
+                params.push(Box::new(registry::solution_valid(packet)?));
+                for coded in registry::GNSS_CODED {
+                    params.push(Box::new(coded.label(packet)?));
+                }
+                params.extend(SharedData::from_packet(packet)?.params());
+                let row = match GpsTime::from_packet(packet)? {
+                    Some(time) => Row::timed(registry::gnss_insert_sql(), params, time),
+                    None => Row::new(registry::gnss_insert_sql(), params),
+                };
+                self.out.send(row);
+                if !self.sinks.is_empty() {
+                    let data = GnssData::new(packet)?;
+                    self.to_sinks(|sink| sink.write_gnss(&data));
+                }
+            }
+            Some(DataDescriptor::Filter) => {
+                filter::insert(&self.out, packet)?;
+                ekf::insert(&self.out, packet)?;
+                filter::insert_uncertainty(&self.out, packet)?;
+                odometer::insert(&self.out, packet)?;
+            }
+            None => (),
+        }
+
+        self.decoded(packet);
+        Ok(())
+    }
+
+    fn decoded(&self, packet: &Packet) {
+        if bus::listening::<PacketDecoded>() {
+            bus::publish(PacketDecoded {
+                device: self.device.clone(),
+                packet: packet.clone(),
+            });
+        }
+    }
+}
@@ -0,0 +1,470 @@
+// Post-processed trajectory for a finished session: a Kalman filter runs
+// forward over the session's IMU and GNSS data and a Rauch-Tung-Striebel pass
+// runs back over it, so every epoch's position is estimated from the fixes
+// after it as well as before. Gaps in GNSS are bridged by the IMU from both
+// ends instead of drifting off one.
+//
+// Each NED axis has its own position, velocity and accelerometer bias state,
+// driven by the specific force rotated into NED with the sensor's own roll,
+// pitch and yaw, and corrected by the GNSS position and NED velocity, weighted
+// by their reported accuracy. Positions are worked in metres north, east and
+// down of the first fix at its latitude's scale; the distortion away from it
+// is small next to GNSS noise over a few tens of kilometres.
+use crate::Error;
+use postgres::Client;
+use std::io::Write;
+
+pub const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS trajectory_smoothed (
+        id BIGSERIAL PRIMARY KEY,
+        session_id integer NOT NULL REFERENCES sessions(id),
+        tow double precision NOT NULL,
+        week smallint NOT NULL,
+        latitude double precision NOT NULL,
+        longitude double precision NOT NULL,
+        height double precision NOT NULL,
+        velocity_north real NOT NULL,
+        velocity_east real NOT NULL,
+        velocity_down real NOT NULL,
+        sigma_north real NOT NULL,
+        sigma_east real NOT NULL,
+        sigma_down real NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS trajectory_smoothed_session_id_idx
+        ON trajectory_smoothed (session_id);
+
+    COMMENT ON TABLE trajectory_smoothed IS
+        'Forward-backward smoothed IMU and GNSS trajectory, written by lordlogger smooth';
+    COMMENT ON COLUMN trajectory_smoothed.latitude IS 'deg, WGS84';
+    COMMENT ON COLUMN trajectory_smoothed.height IS 'm above the WGS84 ellipsoid';
+    COMMENT ON COLUMN trajectory_smoothed.velocity_north IS 'm/s, NED frame';
+    COMMENT ON COLUMN trajectory_smoothed.sigma_north IS 'm, 1-sigma position estimate';
+";
+
+pub const DEFAULT_HZ: f64 = 10.0;
+
+const GRAVITY: f64 = 9.80665;
+const WGS84_A: f64 = 6_378_137.0;
+const WGS84_E2: f64 = 6.694_379_990_14e-3;
+
+// Acceleration noise, (m/s^2)^2/Hz. Generous, since attitude errors from the
+// sensor's own filter leak gravity into the horizontal.
+const ACCEL_NOISE: f64 = 0.25;
+// Accelerometer bias random walk, (m/s^2)^2/s.
+const BIAS_WALK: f64 = 1e-6;
+const BIAS_SIGMA: f64 = 0.1;
+// For fixes that don't report their accuracy, m and m/s.
+const HORIZONTAL_SIGMA: f64 = 5.0;
+const VERTICAL_SIGMA: f64 = 10.0;
+const VELOCITY_SIGMA: f64 = 0.5;
+// Longer than this without an IMU sample, acceleration is taken as zero.
+const IMU_TIMEOUT: f64 = 1.0;
+
+const GNSS_SQL: &str = "
+    SELECT week::float8 * 604800 + tow, latitude, longitude, ellipsoid_alt,
+        CASE WHEN horizontal_accuracy_valid THEN horizontal_accuracy::float8 END,
+        CASE WHEN vertical_accuracy_valid THEN vertical_accuracy::float8 END,
+        CASE WHEN ned_velocity_valid THEN ned_north::float8 END,
+        CASE WHEN ned_velocity_valid THEN ned_east::float8 END,
+        CASE WHEN ned_velocity_valid THEN ned_down::float8 END,
+        CASE WHEN ned_speed_accuracy_valid THEN ned_speed_accuracy::float8 END
+    FROM gnss_data
+    -- 3D, RTK float and RTK fixed
+    WHERE session_id = $1 AND time_valid AND lat_lon_valid AND ellipsoid_alt_valid
+        AND fix_type IN (0, 5, 6)
+    ORDER BY week, tow";
+
+const IMU_SQL: &str = "
+    SELECT week::float8 * 604800 + tow,
+        (accel).x::float8, (accel).y::float8, (accel).z::float8,
+        (euler_angles).x::float8, (euler_angles).y::float8, (euler_angles).z::float8
+    FROM imu_data WHERE session_id = $1
+    ORDER BY week, tow";
+
+type Vec3 = [f64; 3];
+type Mat3 = [[f64; 3]; 3];
+
+fn mul(a: &Mat3, b: &Mat3) -> Mat3 {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+fn transpose(a: &Mat3) -> Mat3 {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = a[j][i];
+        }
+    }
+    m
+}
+
+fn apply(a: &Mat3, v: &Vec3) -> Vec3 {
+    let mut out = [0.0; 3];
+    for (i, value) in out.iter_mut().enumerate() {
+        *value = (0..3).map(|k| a[i][k] * v[k]).sum();
+    }
+    out
+}
+
+fn combine(a: &Mat3, b: &Mat3, sign: f64) -> Mat3 {
+    let mut m = *a;
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value += sign * b[i][j];
+        }
+    }
+    m
+}
+
+fn inverse(a: &Mat3) -> Option<Mat3> {
+    let cofactor = |i: usize, j: usize| {
+        let (r0, r1) = ((i + 1) % 3, (i + 2) % 3);
+        let (c0, c1) = ((j + 1) % 3, (j + 2) % 3);
+        a[r0][c0] * a[r1][c1] - a[r0][c1] * a[r1][c0]
+    };
+    let det: f64 = (0..3).map(|j| a[0][j] * cofactor(0, j)).sum();
+    if det.abs() < 1e-300 {
+        return None;
+    }
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = cofactor(j, i) / det;
+        }
+    }
+    Some(m)
+}
+
+fn transition(dt: f64) -> Mat3 {
+    [[1.0, dt, -dt * dt / 2.0], [0.0, 1.0, -dt], [0.0, 0.0, 1.0]]
+}
+
+// Position, velocity and accelerometer bias along one axis.
+#[derive(Debug, Clone, Copy)]
+struct Axis {
+    x: Vec3,
+    p: Mat3,
+}
+
+impl Axis {
+    fn new(position: f64, position_sigma: f64, velocity: f64, velocity_sigma: f64) -> Self {
+        Axis {
+            x: [position, velocity, 0.0],
+            p: [
+                [position_sigma.powi(2), 0.0, 0.0],
+                [0.0, velocity_sigma.powi(2), 0.0],
+                [0.0, 0.0, BIAS_SIGMA.powi(2)],
+            ],
+        }
+    }
+
+    fn predict(&self, accel: f64, dt: f64) -> Axis {
+        let f = transition(dt);
+        let mut x = apply(&f, &self.x);
+        x[0] += accel * dt * dt / 2.0;
+        x[1] += accel * dt;
+        let mut p = mul(&mul(&f, &self.p), &transpose(&f));
+        p[0][0] += ACCEL_NOISE * dt.powi(3) / 3.0;
+        p[0][1] += ACCEL_NOISE * dt.powi(2) / 2.0;
+        p[1][0] += ACCEL_NOISE * dt.powi(2) / 2.0;
+        p[1][1] += ACCEL_NOISE * dt;
+        p[2][2] += BIAS_WALK * dt;
+        Axis { x, p }
+    }
+
+    // Corrects state `i`, 0 for position and 1 for velocity, with a measurement.
+    fn update(&mut self, i: usize, z: f64, sigma: f64) {
+        let s = self.p[i][i] + sigma * sigma;
+        let gain: Vec3 = [self.p[0][i] / s, self.p[1][i] / s, self.p[2][i] / s];
+        let innovation = z - self.x[i];
+        let row = self.p[i];
+        for (k, gain) in gain.iter().enumerate() {
+            self.x[k] += gain * innovation;
+            for (j, value) in row.iter().enumerate() {
+                self.p[k][j] -= gain * value;
+            }
+        }
+        self.p = combine(&self.p, &transpose(&self.p), 1.0);
+        for row in self.p.iter_mut() {
+            for value in row.iter_mut() {
+                *value /= 2.0;
+            }
+        }
+    }
+}
+
+// Metres north, east and down of the first fix.
+struct Plane {
+    lat: f64,
+    lon: f64,
+    height: f64,
+    meridian: f64,
+    normal: f64,
+}
+
+impl Plane {
+    fn new(lat: f64, lon: f64, height: f64) -> Self {
+        let w = 1.0 - WGS84_E2 * lat.to_radians().sin().powi(2);
+        Plane {
+            lat,
+            lon,
+            height,
+            meridian: WGS84_A * (1.0 - WGS84_E2) / w.powf(1.5) + height,
+            normal: (WGS84_A / w.sqrt() + height) * lat.to_radians().cos(),
+        }
+    }
+
+    fn ned(&self, lat: f64, lon: f64, height: f64) -> Vec3 {
+        [
+            (lat - self.lat).to_radians() * self.meridian,
+            (lon - self.lon).to_radians() * self.normal,
+            self.height - height,
+        ]
+    }
+
+    fn llh(&self, ned: &Vec3) -> Vec3 {
+        [
+            self.lat + (ned[0] / self.meridian).to_degrees(),
+            self.lon + (ned[1] / self.normal).to_degrees(),
+            self.height - ned[2],
+        ]
+    }
+}
+
+struct Fix {
+    t: f64,
+    ned: Vec3,
+    horizontal: f64,
+    vertical: f64,
+    velocity: Option<Vec3>,
+    speed_sigma: f64,
+}
+
+// Acceleration in NED, m/s^2, from specific force in g and roll pitch yaw.
+fn acceleration(force: &Vec3, euler: &Vec3) -> Vec3 {
+    let (sr, cr) = euler[0].sin_cos();
+    let (sp, cp) = euler[1].sin_cos();
+    let (sy, cy) = euler[2].sin_cos();
+    let body_to_ned = [
+        [cp * cy, sr * sp * cy - cr * sy, cr * sp * cy + sr * sy],
+        [cp * sy, sr * sp * sy + cr * cy, cr * sp * sy - sr * cy],
+        [-sp, sr * cp, cr * cp],
+    ];
+    let f = apply(&body_to_ned, force);
+    [f[0] * GRAVITY, f[1] * GRAVITY, f[2] * GRAVITY + GRAVITY]
+}
+
+struct Step {
+    t: f64,
+    dt: f64,
+    predicted: [Axis; 3],
+    filtered: [Axis; 3],
+}
+
+#[derive(Debug, Clone)]
+pub struct Smoothed {
+    pub rows: usize,
+    pub fixes: usize,
+    pub imu_samples: usize,
+    pub seconds: f64,
+}
+
+fn load_fixes(c: &mut Client, session: i32) -> Result<(Plane, Vec<Fix>), Error> {
+    let rows = c.query(GNSS_SQL, &[&session])?;
+    let first = rows
+        .first()
+        .ok_or_else(|| format!("session {} has no 3D GNSS fixes", session))?;
+    let plane = Plane::new(first.get(1), first.get(2), first.get(3));
+
+    let fixes = rows
+        .iter()
+        .map(|row| {
+            let velocity = match (row.get(6), row.get(7), row.get(8)) {
+                (Some(n), Some(e), Some(d)) => Some([n, e, d]),
+                _ => None,
+            };
+            Fix {
+                t: row.get(0),
+                ned: plane.ned(row.get(1), row.get(2), row.get(3)),
+                horizontal: row.get::<_, Option<f64>>(4).unwrap_or(HORIZONTAL_SIGMA),
+                vertical: row.get::<_, Option<f64>>(5).unwrap_or(VERTICAL_SIGMA),
+                velocity,
+                speed_sigma: row.get::<_, Option<f64>>(9).unwrap_or(VELOCITY_SIGMA),
+            }
+        })
+        .collect();
+    Ok((plane, fixes))
+}
+
+fn forward(fixes: &[Fix], imu: &[(f64, Vec3)]) -> Vec<Step> {
+    let first = &fixes[0];
+    let velocity = first.velocity.unwrap_or([0.0; 3]);
+    let velocity_sigma = first.velocity.map_or(10.0, |_| first.speed_sigma);
+    let sigmas = [first.horizontal, first.horizontal, first.vertical];
+    let mut state =
+        [0, 1, 2].map(|k| Axis::new(first.ned[k], sigmas[k], velocity[k], velocity_sigma));
+
+    let mut steps = vec![Step {
+        t: first.t,
+        dt: 0.0,
+        predicted: state,
+        filtered: state,
+    }];
+    let mut i = imu.partition_point(|(t, _)| *t <= first.t);
+    let (mut accel, mut accel_at) = match i {
+        0 => ([0.0; 3], f64::NEG_INFINITY),
+        i => (imu[i - 1].1, imu[i - 1].0),
+    };
+    let mut t = first.t;
+    let mut next_fix = 1;
+
+    while next_fix < fixes.len() {
+        let fix_t = fixes[next_fix].t;
+        let imu_t = imu.get(i).map_or(f64::INFINITY, |(t, _)| *t);
+        let next = fix_t.min(imu_t);
+        let dt = next - t;
+        let held = if next - accel_at > IMU_TIMEOUT {
+            [0.0; 3]
+        } else {
+            accel
+        };
+        let predicted = [0, 1, 2].map(|k| state[k].predict(held[k], dt));
+        state = predicted;
+        t = next;
+
+        while i < imu.len() && imu[i].0 <= t {
+            accel = imu[i].1;
+            accel_at = imu[i].0;
+            i += 1;
+        }
+        if fix_t <= t {
+            let fix = &fixes[next_fix];
+            let sigmas = [fix.horizontal, fix.horizontal, fix.vertical];
+            for (k, axis) in state.iter_mut().enumerate() {
+                axis.update(0, fix.ned[k], sigmas[k]);
+                if let Some(velocity) = fix.velocity {
+                    axis.update(1, velocity[k], fix.speed_sigma);
+                }
+            }
+            next_fix += 1;
+        }
+        steps.push(Step {
+            t,
+            dt,
+            predicted,
+            filtered: state,
+        });
+    }
+    steps
+}
+
+// The smoothed states, newest first, at the steps `keep` picks.
+fn backward(steps: &[Step], keep: &[bool]) -> Vec<(f64, [Axis; 3])> {
+    let last = steps.len() - 1;
+    let mut smoothed = steps[last].filtered;
+    let mut out = vec![(steps[last].t, smoothed)];
+    for k in (0..last).rev() {
+        let next = &steps[k + 1];
+        for (axis, current) in smoothed.iter_mut().enumerate() {
+            let filtered = &steps[k].filtered[axis];
+            let predicted = &next.predicted[axis];
+            let gain = match inverse(&predicted.p) {
+                Some(inverse) => mul(
+                    &mul(&filtered.p, &transpose(&transition(next.dt))),
+                    &inverse,
+                ),
+                None => [[0.0; 3]; 3],
+            };
+            let dx = apply(&gain, &[0, 1, 2].map(|i| current.x[i] - predicted.x[i]));
+            let dp = mul(
+                &mul(&gain, &combine(&current.p, &predicted.p, -1.0)),
+                &transpose(&gain),
+            );
+            *current = Axis {
+                x: [0, 1, 2].map(|i| filtered.x[i] + dx[i]),
+                p: combine(&filtered.p, &dp, 1.0),
+            };
+        }
+        if keep[k] {
+            out.push((steps[k].t, smoothed));
+        }
+    }
+    out
+}
+
+// Smooths a session and replaces its rows in trajectory_smoothed, one every
+// 1/hz seconds.
+pub fn smooth(c: &mut Client, session: i32, hz: f64) -> Result<Smoothed, Error> {
+    if !(hz.is_finite() && hz > 0.0) {
+        return Err(format!("can't write rows at {} Hz", hz).into());
+    }
+    let (plane, fixes) = load_fixes(c, session)?;
+    let imu: Vec<(f64, Vec3)> = c
+        .query(IMU_SQL, &[&session])?
+        .iter()
+        .map(|row| {
+            let force = [row.get(1), row.get(2), row.get(3)];
+            let euler = [row.get(4), row.get(5), row.get(6)];
+            (row.get(0), acceleration(&force, &euler))
+        })
+        .collect();
+    if imu.is_empty() {
+        return Err(format!("session {} has no IMU data", session).into());
+    }
+
+    let steps = forward(&fixes, &imu);
+    let keep: Vec<bool> = steps
+        .iter()
+        .enumerate()
+        .map(|(k, step)| k == 0 || (step.t * hz).floor() != (steps[k - 1].t * hz).floor())
+        .collect();
+    let mut rows = backward(&steps, &keep);
+    rows.reverse();
+
+    let mut tx = c.transaction()?;
+    tx.execute(
+        "DELETE FROM trajectory_smoothed WHERE session_id = $1",
+        &[&session],
+    )?;
+    let mut copy = tx.copy_in(
+        "COPY trajectory_smoothed (
+            session_id, tow, week, latitude, longitude, height,
+            velocity_north, velocity_east, velocity_down, sigma_north, sigma_east, sigma_down
+         ) FROM STDIN (FORMAT csv)",
+    )?;
+    for (t, axes) in &rows {
+        let week = (t / 604_800.0).floor();
+        let llh = plane.llh(&[axes[0].x[0], axes[1].x[0], axes[2].x[0]]);
+        writeln!(
+            copy,
+            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            session,
+            t - week * 604_800.0,
+            week,
+            llh[0],
+            llh[1],
+            llh[2],
+            axes[0].x[1],
+            axes[1].x[1],
+            axes[2].x[1],
+            axes[0].p[0][0].max(0.0).sqrt(),
+            axes[1].p[0][0].max(0.0).sqrt(),
+            axes[2].p[0][0].max(0.0).sqrt()
+        )?;
+    }
+    copy.finish()?;
+    tx.commit()?;
+
+    Ok(Smoothed {
+        rows: rows.len(),
+        fixes: fixes.len(),
+        imu_samples: imu.len(),
+        seconds: steps[steps.len() - 1].t - steps[0].t,
+    })
+}
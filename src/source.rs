@@ -0,0 +1,129 @@
+use crate::descriptors::{DataDescriptor, FilterField, GnssField, ImuField};
+use crate::ekf;
+use crate::filter;
+use crate::mip::CommandPort;
+use crate::odometer;
+use crate::pipeline::Settings;
+use crate::selection::Selection;
+use crate::Error;
+use lordserial::parser::Lord;
+
+pub const RAW_IMU_ENV: &str = "LORDLOGGER_RAW_IMU";
+
+pub fn default_imu_format(raw_imu: bool) -> Vec<(u8, u16)> {
+    let mut imu_fields = vec![
+        ImuField::ScaledAccel,
+        ImuField::ScaledGyro,
+        ImuField::ScaledMag,
+        ImuField::ScaledPressure,
+        ImuField::DeltaTheta,
+        ImuField::DeltaVelocity,
+        ImuField::Quaternion,
+        ImuField::EulerAngles,
+        ImuField::GpsTimestamp,
+    ];
+
+    // Raw ADC quantities, unscaled and uncompensated, for calibration work.
+    if raw_imu {
+        imu_fields.extend(vec![
+            ImuField::RawAccel,
+            ImuField::RawGyro,
+            ImuField::RawMag,
+            ImuField::RawPressure,
+        ]);
+    }
+
+    imu_fields.into_iter().map(|f| (f.into(), 50)).collect()
+}
+
+pub fn default_gnss_format() -> Vec<(u8, u16)> {
+    vec![
+        GnssField::LlhPosition,
+        GnssField::EcefPosition,
+        GnssField::NedVelocity,
+        GnssField::EcefVelocity,
+        GnssField::Dop,
+        GnssField::GpsTime,
+        GnssField::FixInfo,
+    ]
+    .into_iter()
+    .map(|f| (f.into(), 4))
+    .collect()
+}
+
+// The filter's solution, status, uncertainty and time, plus whatever fields
+// the odometer logs.
+pub fn filter_format(settings: &Settings) -> Vec<(u8, u16)> {
+    let mut fields: Vec<(u8, u16)> = ekf::FIELDS
+        .iter()
+        .copied()
+        .chain(vec![
+            FilterField::FilterStatus,
+            FilterField::LlhUncertainty,
+            FilterField::NedVelocityUncertainty,
+            FilterField::EulerUncertainty,
+            FilterField::GpsTimestamp,
+        ])
+        .map(|f| (f.into(), 50))
+        .collect();
+
+    if let Some(odometer) = &settings.odometer {
+        for (descriptor, decimation) in odometer.format() {
+            match fields.iter_mut().find(|(d, _)| *d == descriptor) {
+                Some(field) => field.1 = field.1.min(decimation),
+                None => fields.push((descriptor, decimation)),
+            }
+        }
+    }
+
+    fields
+}
+
+pub fn setup_lord(
+    lord: &mut Lord,
+    imu_fields: Vec<(u8, u16)>,
+    gnss_fields: Vec<(u8, u16)>,
+    selection: &Selection,
+    port: &mut CommandPort,
+    settings: &Settings,
+) -> Result<(), Error> {
+    lord.set_imu_format(
+        0x01,
+        selection.format(DataDescriptor::Imu.into(), imu_fields),
+    )?;
+    lord.set_gnss_format(
+        0x01,
+        selection.format(DataDescriptor::Gnss.into(), gnss_fields),
+    )?;
+    if let Some(odometer) = &settings.odometer {
+        odometer::configure(port, odometer)?;
+    }
+    if let Some(init) = &settings.filter {
+        filter::configure(port, init)?;
+    }
+
+    set_filter_format(
+        port,
+        selection.format(DataDescriptor::Filter.into(), filter_format(settings)),
+    )
+}
+
+// The filter set's counterpart to Lord's IMU and GNSS formats, sent as a
+// command since lordserial has none for it. Leaves the set off when empty.
+pub fn set_filter_format(port: &mut CommandPort, fields: Vec<(u8, u16)>) -> Result<(), Error> {
+    if fields.is_empty() {
+        return Ok(());
+    }
+    port.set_format(DataDescriptor::Filter.into(), &fields)
+}
+
+// Settings the device would only reject once it's being set up.
+pub fn check_settings(settings: &Settings) -> Result<(), Error> {
+    if let Some(odometer) = &settings.odometer {
+        odometer.validate()?;
+    }
+    if let Some(init) = &settings.filter {
+        init.validate()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,403 @@
+// Rows a target couldn't write, kept on disk until its database is back.
+//
+// The spool is an append-only file of JSON lines, one row each, read back in
+// the order it was written. Only the read position is kept in memory; the
+// file is emptied once every row in it has been committed. Each batch drained
+// records its last row in the target's spool_acks, so a restart resumes where
+// the database left off instead of writing rows twice.
+use crate::Error;
+use bytes::BytesMut;
+use postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+// Directory for each target's spool file. Unset keeps rows in memory only.
+pub const SPOOL_DIR_ENV: &str = "LORDLOGGER_SPOOL_DIR";
+
+// A row parameter as stored in the spool, written back with the same wire
+// encoding as the value it came from. Floats are kept as their bits, since
+// JSON has no NaN.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Encoded {
+    Null,
+    Bool(bool),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+    Text(String),
+    Time(u64, u32),
+    Json(serde_json::Value),
+    TextArray(Vec<String>),
+    F64Array(Vec<u64>),
+    Bytes(Vec<u8>),
+}
+
+impl ToSql for Encoded {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        match self {
+            Encoded::Null => Ok(IsNull::Yes),
+            Encoded::Bool(v) => v.to_sql(ty, out),
+            Encoded::I16(v) => v.to_sql(ty, out),
+            Encoded::I32(v) => v.to_sql(ty, out),
+            Encoded::I64(v) => v.to_sql(ty, out),
+            Encoded::F32(v) => f32::from_bits(*v).to_sql(ty, out),
+            Encoded::F64(v) => f64::from_bits(*v).to_sql(ty, out),
+            Encoded::Text(v) => v.to_sql(ty, out),
+            Encoded::Time(secs, nanos) => {
+                (UNIX_EPOCH + Duration::new(*secs, *nanos)).to_sql(ty, out)
+            }
+            Encoded::Json(v) => v.to_sql(ty, out),
+            Encoded::TextArray(v) => v.to_sql(ty, out),
+            Encoded::F64Array(v) => v
+                .iter()
+                .map(|v| f64::from_bits(*v))
+                .collect::<Vec<_>>()
+                .to_sql(ty, out),
+            Encoded::Bytes(v) => v.to_sql(ty, out),
+        }
+    }
+
+    // Whatever the original value was sent as, so any type it was accepted
+    // for.
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+// A value that can be both sent and spooled.
+pub trait Value: ToSql + Send + Sync {
+    fn encode(&self) -> Encoded;
+    fn as_sql(&self) -> &(dyn ToSql + Sync);
+}
+
+macro_rules! value {
+    ($($ty:ty => |$v:ident| $encoded:expr,)*) => {
+        $(impl Value for $ty {
+            fn encode(&self) -> Encoded {
+                let $v = self;
+                $encoded
+            }
+
+            fn as_sql(&self) -> &(dyn ToSql + Sync) {
+                self
+            }
+        })*
+    };
+}
+
+value! {
+    bool => |v| Encoded::Bool(*v),
+    i16 => |v| Encoded::I16(*v),
+    i32 => |v| Encoded::I32(*v),
+    i64 => |v| Encoded::I64(*v),
+    f32 => |v| Encoded::F32(v.to_bits()),
+    f64 => |v| Encoded::F64(v.to_bits()),
+    String => |v| Encoded::Text(v.clone()),
+    &'static str => |v| Encoded::Text(v.to_string()),
+    SystemTime => |v| {
+        let since = v.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Encoded::Time(since.as_secs(), since.subsec_nanos())
+    },
+    serde_json::Value => |v| Encoded::Json(v.clone()),
+    Vec<String> => |v| Encoded::TextArray(v.clone()),
+    Vec<f64> => |v| Encoded::F64Array(v.iter().map(|v| v.to_bits()).collect()),
+    Vec<u8> => |v| Encoded::Bytes(v.clone()),
+    Encoded => |v| v.clone(),
+}
+
+impl<T: Value> Value for Option<T>
+where
+    Option<T>: ToSql,
+{
+    fn encode(&self) -> Encoded {
+        self.as_ref().map_or(Encoded::Null, Value::encode)
+    }
+
+    fn as_sql(&self) -> &(dyn ToSql + Sync) {
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Spooled {
+    pub sql: String,
+    pub params: Vec<Encoded>,
+    // When the row's packet arrived, for the lag once it's drained. Missing
+    // from rows spooled by older builds.
+    #[serde(default)]
+    pub received: Option<SystemTime>,
+}
+
+// Where reading the spool has got to: a byte offset into the file, and the
+// sequence number of the last row before it.
+#[derive(Debug, Clone, Copy)]
+pub struct Position {
+    offset: u64,
+    seq: u64,
+}
+
+// The last row of a spool a database has committed. It's written in the same
+// transaction as the rows, so after a crash the database says exactly where
+// to resume.
+#[derive(Debug, Clone, Copy)]
+pub struct Ack {
+    pub generation: i64,
+    pub seq: i64,
+}
+
+pub const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS spool_acks (
+        generation bigint PRIMARY KEY,
+        sink text NOT NULL,
+        seq bigint NOT NULL,
+        acked_at timestamptz NOT NULL DEFAULT now()
+    );
+
+    COMMENT ON TABLE spool_acks IS
+        'Last spooled row each lordlogger spool file has committed here, by the file''s generation';
+";
+
+pub const ACK_SQL: &str = "
+    INSERT INTO spool_acks (generation, sink, seq) VALUES ($1, $2, $3)
+    ON CONFLICT (generation) DO UPDATE SET seq = EXCLUDED.seq, acked_at = now()
+";
+
+// First line of a spool file: its generation, random and new each time the
+// file starts over, and the sequence number of the row after it. Files from
+// before acks have none and are drained without them.
+const HEADER: &str = "#spool";
+
+pub struct Spool {
+    path: PathBuf,
+    file: File,
+    generation: Option<i64>,
+    // Rows up to here are committed to the database.
+    read: Position,
+}
+
+// "host:5432/lord" as a file name.
+fn file_name(target: &str) -> String {
+    let name: String = target
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}.spool", name)
+}
+
+fn random_generation() -> i64 {
+    (RandomState::new().build_hasher().finish() >> 1) as i64
+}
+
+fn header(generation: i64, first_seq: u64) -> String {
+    format!("{} {} {}\n", HEADER, generation, first_seq)
+}
+
+// The generation and first sequence number of a header line.
+fn parse_header(line: &str) -> Option<(i64, u64)> {
+    let mut parts = line.strip_prefix(HEADER)?.split_whitespace();
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+impl Spool {
+    // Rows left over from an earlier run are kept, to be drained first.
+    pub fn open(dir: &Path, target: &str) -> Result<Self, Error> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(file_name(target));
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+
+        // A line cut short by a crash mid-append would swallow the next row.
+        let mut complete = 0;
+        let mut first = None;
+        let mut reader = BufReader::new(&file);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let n = reader.read_until(b'\n', &mut line)?;
+            if n == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            if complete == 0 {
+                first = Some((String::from_utf8_lossy(&line).into_owned(), n as u64));
+            }
+            complete += n as u64;
+        }
+        file.set_len(complete)?;
+
+        let (generation, read) = match first.and_then(|(l, n)| Some((parse_header(&l)?, n))) {
+            Some(((generation, first_seq), n)) => (
+                Some(generation),
+                Position {
+                    offset: n,
+                    seq: first_seq - 1,
+                },
+            ),
+            None => (None, Position { offset: 0, seq: 0 }),
+        };
+
+        Ok(Spool {
+            path,
+            file,
+            generation,
+            read,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn generation(&self) -> Option<i64> {
+        self.generation
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.file.metadata()?.len() <= self.read.offset)
+    }
+
+    // Synced before returning, so a row is never both dropped from memory and
+    // missing from disk.
+    pub fn append(&mut self, rows: impl Iterator<Item = Spooled>) -> Result<usize, Error> {
+        let mut text = String::new();
+        if self.file.metadata()?.len() == 0 {
+            let generation = random_generation();
+            text.push_str(&header(generation, 1));
+            self.generation = Some(generation);
+            self.read = Position {
+                offset: text.len() as u64,
+                seq: 0,
+            };
+        }
+
+        let mut count = 0;
+        for row in rows {
+            text.push_str(&serde_json::to_string(&row)?);
+            text.push('\n');
+            count += 1;
+        }
+        self.file.write_all(text.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(count)
+    }
+
+    // Up to `max` of the oldest uncommitted rows, and where the next read
+    // starts once they're committed.
+    pub fn peek(&mut self, max: usize) -> Result<(Vec<Spooled>, Position), Error> {
+        let mut reader = BufReader::new(&self.file);
+        reader.seek(SeekFrom::Start(self.read.offset))?;
+
+        let mut rows = Vec::new();
+        let mut next = self.read;
+        let mut line = String::new();
+        while rows.len() < max {
+            line.clear();
+            let n = reader.read_line(&mut line)?;
+            if n == 0 {
+                break;
+            }
+            next.offset += n as u64;
+            next.seq += 1;
+            match serde_json::from_str(&line) {
+                Ok(row) => rows.push(row),
+                Err(e) => warn!(
+                    "Skipped an unreadable row in {}. Error: {}",
+                    self.path.display(),
+                    e
+                ),
+            }
+        }
+        Ok((rows, next))
+    }
+
+    // The ack to commit along with the rows up to `next`, when the file has a
+    // generation to key it by.
+    pub fn ack(&self, next: Position) -> Option<Ack> {
+        Some(Ack {
+            generation: self.generation?,
+            seq: next.seq as i64,
+        })
+    }
+
+    // Moves past rows the database already acknowledged, which a crash
+    // between its commit and ours left unmarked here. Returns how many.
+    pub fn skip_acked(&mut self, seq: i64) -> Result<u64, Error> {
+        let behind = (seq.max(0) as u64).saturating_sub(self.read.seq);
+        if behind == 0 {
+            return Ok(0);
+        }
+        let mut reader = BufReader::new(&self.file);
+        reader.seek(SeekFrom::Start(self.read.offset))?;
+
+        let mut next = self.read;
+        let mut line = Vec::new();
+        while next.seq < seq as u64 {
+            line.clear();
+            let n = reader.read_until(b'\n', &mut line)?;
+            if n == 0 {
+                break;
+            }
+            next.offset += n as u64;
+            next.seq += 1;
+        }
+        let skipped = next.seq - self.read.seq;
+        self.commit(next)?;
+        Ok(skipped)
+    }
+
+    // Rewrites the file without the rows already committed, so a spool
+    // drained in part gives back their disk. Returns the bytes freed.
+    pub fn compact(&mut self) -> Result<u64, Error> {
+        let header = match self.generation {
+            Some(generation) => header(generation, self.read.seq + 1),
+            None => String::new(),
+        };
+        let freed = self.read.offset.saturating_sub(header.len() as u64);
+        if freed == 0 {
+            return Ok(0);
+        }
+
+        let partial = self.path.with_extension("spool.compacting");
+        let mut out = File::create(&partial)?;
+        out.write_all(header.as_bytes())?;
+        let mut rest = &self.file;
+        rest.seek(SeekFrom::Start(self.read.offset))?;
+        io::copy(&mut rest, &mut out)?;
+        out.sync_all()?;
+        fs::rename(&partial, &self.path)?;
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.read.offset = header.len() as u64;
+        Ok(freed)
+    }
+
+    // Emptied once everything is committed, to start a new generation.
+    pub fn commit(&mut self, next: Position) -> Result<(), Error> {
+        self.read = next;
+        if self.is_empty()? {
+            self.file.set_len(0)?;
+            self.generation = None;
+            self.read = Position { offset: 0, seq: 0 };
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+// A local SQLite file for vehicles without a Postgres server: a target whose
+// URL is `sqlite:<path>` writes each table it's sent to a table of the same
+// name in that file, creating it on first use.
+//
+// Columns are the Postgres table's, with composite ones split out the way the
+// CSV files have them, e.g. accel_x, accel_y, accel_z. Times are seconds since
+// the Unix epoch, booleans 1 and 0, and JSON and arrays JSON text.
+use crate::csv::Layout;
+use crate::fanout::FlatSink;
+use crate::spool::Encoded;
+use crate::Error;
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+pub const SCHEME: &str = "sqlite:";
+
+// The file a `sqlite:` URL names, None for any other URL.
+pub fn path(url: &str) -> Option<&Path> {
+    url.strip_prefix(SCHEME).map(Path::new)
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn value(encoded: &Encoded) -> Value {
+    match encoded {
+        Encoded::Null => Value::Null,
+        Encoded::Bool(v) => Value::Integer(*v as i64),
+        Encoded::I16(v) => Value::Integer(*v as i64),
+        Encoded::I32(v) => Value::Integer(*v as i64),
+        Encoded::I64(v) => Value::Integer(*v),
+        // Through its shortest text, so 0.1f32 reads back as 0.1 and not 0.100000001.
+        Encoded::F32(v) => Value::Real(f32::from_bits(*v).to_string().parse().unwrap_or(f64::NAN)),
+        Encoded::F64(v) => Value::Real(f64::from_bits(*v)),
+        Encoded::Text(v) => Value::Text(v.clone()),
+        Encoded::Time(secs, nanos) => Value::Real(*secs as f64 + *nanos as f64 * 1e-9),
+        Encoded::Json(v) => Value::Text(v.to_string()),
+        Encoded::TextArray(v) => Value::Text(serde_json::json!(v).to_string()),
+        Encoded::F64Array(v) => {
+            let values: Vec<f64> = v.iter().map(|v| f64::from_bits(*v)).collect();
+            Value::Text(serde_json::json!(values).to_string())
+        }
+        Encoded::Bytes(v) => Value::Blob(v.clone()),
+    }
+}
+
+// A new column's type, from the first value written to it.
+fn affinity(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "",
+        Value::Integer(_) => " INTEGER",
+        Value::Real(_) => " REAL",
+        Value::Text(_) => " TEXT",
+        Value::Blob(_) => " BLOB",
+    }
+}
+
+// Rows are written in a transaction that's committed on flush, once per batch.
+pub struct SqliteFile {
+    conn: Connection,
+    // Each table's columns, as far as they're known.
+    columns: HashMap<String, HashSet<String>>,
+}
+
+impl SqliteFile {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let conn = Connection::open(path)?;
+        // WAL lets the file be read while it's being logged to.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        Ok(SqliteFile {
+            conn,
+            columns: HashMap::new(),
+        })
+    }
+
+    fn add_columns(&mut self, table: &str, layout: &Layout, values: &[Value]) -> Result<(), Error> {
+        if !self.columns.contains_key(table) {
+            self.conn.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY)",
+                quote(table)
+            ))?;
+            let mut info = self
+                .conn
+                .prepare(&format!("PRAGMA table_info({})", quote(table)))?;
+            let existing = info
+                .query_map([], |row| row.get::<_, String>(1))?
+                .collect::<Result<HashSet<String>, _>>()?;
+            self.columns.insert(table.to_string(), existing);
+        }
+
+        let known = self.columns.get_mut(table).unwrap();
+        for ((column, _), value) in layout.iter().zip(values) {
+            if !known.contains(column) {
+                self.conn.execute_batch(&format!(
+                    "ALTER TABLE {} ADD COLUMN {}{}",
+                    quote(table),
+                    quote(column),
+                    affinity(value)
+                ))?;
+                known.insert(column.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FlatSink for SqliteFile {
+    fn write(&mut self, table: &str, layout: &Layout, params: &[Encoded]) -> Result<(), Error> {
+        let values: Vec<Value> = layout
+            .iter()
+            .map(|(_, i)| params.get(*i).map_or(Value::Null, value))
+            .collect();
+        if self.conn.is_autocommit() {
+            self.conn.execute_batch("BEGIN")?;
+        }
+        self.add_columns(table, layout, &values)?;
+
+        let columns: Vec<String> = layout.iter().map(|(column, _)| quote(column)).collect();
+        let placeholders: Vec<String> = (1..=columns.len()).map(|n| format!("?{}", n)).collect();
+        let mut insert = self.conn.prepare_cached(&format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quote(table),
+            columns.join(", "),
+            placeholders.join(", ")
+        ))?;
+        insert.execute(rusqlite::params_from_iter(values))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("COMMIT")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+// Joins sessions that a logger restart split apart. Consecutive sessions from
+// the same device whose data picks up within a gap of where the previous one
+// left off in GPS time are mapped to one mission, the id of the first session
+// in the chain. Nothing is rewritten; the mapping lives in session_aliases.
+use crate::session::GPS_TIME_SQL;
+use crate::Error;
+use postgres::Client;
+
+pub const DEFAULT_GAP_SECONDS: f64 = 60.0;
+
+pub const CREATE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS session_aliases (
+        session_id integer PRIMARY KEY REFERENCES sessions(id),
+        mission_id integer NOT NULL REFERENCES sessions(id)
+    );
+
+    CREATE OR REPLACE VIEW session_missions AS
+        SELECT s.id AS session_id, coalesce(a.mission_id, s.id) AS mission_id
+        FROM sessions s LEFT JOIN session_aliases a ON a.session_id = s.id;
+";
+
+// Tables to take a session's GPS extent from, in order of preference.
+const EXTENT_TABLES: [(&str, &str); 4] = [
+    ("gnss_data", GPS_TIME_SQL),
+    ("imu_data", GPS_TIME_SQL),
+    ("measurements", "time"),
+    ("packets", "time"),
+];
+
+#[derive(Debug)]
+struct SessionSpan {
+    id: i32,
+    device: Option<String>,
+    // First and last data time, Unix seconds.
+    extent: Option<(f64, f64)>,
+}
+
+#[derive(Debug)]
+pub struct Stitch {
+    pub session_id: i32,
+    pub mission_id: i32,
+    pub gap: f64,
+}
+
+pub fn extent_table(c: &mut Client) -> Result<Option<(&'static str, &'static str)>, Error> {
+    for (table, time) in EXTENT_TABLES.iter() {
+        let exists: bool = c
+            .query_one("SELECT to_regclass($1) IS NOT NULL", &[table])?
+            .get(0);
+        if exists {
+            return Ok(Some((table, time)));
+        }
+    }
+    Ok(None)
+}
+
+fn sessions(c: &mut Client) -> Result<Vec<SessionSpan>, Error> {
+    let source = extent_table(c)?;
+
+    let rows = c.query(
+        "SELECT s.id, s.started_at::text,
+            coalesce(s.ended_at, (SELECT min(n.started_at) FROM sessions n WHERE n.id > s.id), now())::text,
+            (SELECT e.message::json->>'port' FROM events e
+             WHERE e.session_id = s.id AND e.kind = 'device' ORDER BY e.id LIMIT 1)
+         FROM sessions s ORDER BY s.id",
+        &[],
+    )?;
+
+    let mut spans = Vec::new();
+    for row in rows {
+        let started_at: String = row.get(1);
+        let until: String = row.get(2);
+
+        let extent = match source {
+            Some((table, time)) => {
+                let row = c.query_one(
+                    format!(
+                        "SELECT extract(epoch FROM min({time}))::float8, extract(epoch FROM max({time}))::float8
+                         FROM {table} WHERE {time} >= $1::text::timestamptz AND {time} < $2::text::timestamptz",
+                        time = time,
+                        table = table
+                    )
+                    .as_str(),
+                    &[&started_at, &until],
+                )?;
+                match (row.get::<_, Option<f64>>(0), row.get::<_, Option<f64>>(1)) {
+                    (Some(first), Some(last)) => Some((first, last)),
+                    _ => None,
+                }
+            }
+            None => None,
+        };
+
+        spans.push(SessionSpan {
+            id: row.get(0),
+            device: row.get(3),
+            extent,
+        });
+    }
+
+    Ok(spans)
+}
+
+// Finds the chains without writing anything.
+pub fn plan(c: &mut Client, max_gap: f64) -> Result<Vec<Stitch>, Error> {
+    let spans = sessions(c)?;
+    let mut stitches = Vec::new();
+    let mut mission = None;
+
+    for pair in spans.windows(2) {
+        let (prev, next) = (&pair[0], &pair[1]);
+
+        let gap = match (prev.extent, next.extent) {
+            (Some((_, last)), Some((first, _))) if prev.device == next.device => first - last,
+            _ => {
+                mission = None;
+                continue;
+            }
+        };
+
+        if (0.0..=max_gap).contains(&gap) {
+            let mission_id = *mission.get_or_insert(prev.id);
+            stitches.push(Stitch {
+                session_id: next.id,
+                mission_id,
+                gap,
+            });
+        } else {
+            mission = None;
+        }
+    }
+
+    Ok(stitches)
+}
+
+pub fn apply(c: &mut Client, stitches: &[Stitch]) -> Result<(), Error> {
+    let mut tx = c.transaction()?;
+    for stitch in stitches {
+        tx.execute(
+            "INSERT INTO session_aliases (session_id, mission_id) VALUES ($1, $2)
+             ON CONFLICT (session_id) DO UPDATE SET mission_id = EXCLUDED.mission_id",
+            &[&stitch.session_id, &stitch.mission_id],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
@@ -0,0 +1,293 @@
+// The last few seconds of rows, kept in memory for consumers on the same
+// machine that can't wait for a database round trip. Each table is a stream
+// whose rows are numbered from 1; asking for a stream's rows since a number
+// returns the ones after it that are still held:
+//
+//   GET /tail/imu_data?since=1200&wait=100
+//   {"stream":"imu_data","next":1231,"missed":0,"rows":[{...},...]}
+//
+// `next` is the number to ask since next time, `missed` how many rows after
+// `since` had already aged out, and `wait` how long in ms to wait for a row
+// when there's nothing new, so a consumer can long-poll instead of spinning.
+// GET /tail lists the streams with their latest number. Rows are the JSON
+// objects of jsonl.rs.
+//
+// LORDLOGGER_TAIL, or `tail` in [services], serves this over HTTP; a program
+// embedding the logger can call enable() before running it and read with
+// tail() and wait() instead.
+use crate::csv::Layout;
+use crate::fanout::{FlatSink, Tables, TargetConfig};
+use crate::jsonl;
+use crate::spool::Encoded;
+use crate::Error;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Address to serve the tail API on, e.g. 127.0.0.1:8081. Unset serves nothing.
+pub const TAIL_ENV: &str = "LORDLOGGER_TAIL";
+// Seconds of rows held per stream, 10 by default.
+pub const SECONDS_ENV: &str = "LORDLOGGER_TAIL_SECONDS";
+pub const SCHEME: &str = "tail:";
+
+const DEFAULT_SECONDS: u64 = 10;
+// Held per stream whatever their age, so a fast stream can't take all memory.
+const MAX_ROWS: usize = 100_000;
+const MAX_WAIT: Duration = Duration::from_secs(10);
+
+static BUFFER: OnceLock<Buffer> = OnceLock::new();
+
+#[derive(Default)]
+struct Stream {
+    latest: u64,
+    rows: VecDeque<(u64, Instant, Arc<str>)>,
+}
+
+struct Buffer {
+    keep: Duration,
+    streams: Mutex<HashMap<String, Stream>>,
+    pushed: Condvar,
+}
+
+#[derive(Debug, Clone)]
+pub struct Tail {
+    pub next: u64,
+    pub missed: u64,
+    pub rows: Vec<Value>,
+}
+
+// The rows of a stream after `since`, as JSON text.
+struct Raw {
+    next: u64,
+    missed: u64,
+    rows: Vec<Arc<str>>,
+}
+
+impl Stream {
+    fn since(&self, since: u64) -> Raw {
+        let oldest = self
+            .rows
+            .front()
+            .map_or(self.latest + 1, |(seq, _, _)| *seq);
+        let skip = since.saturating_sub(oldest - 1) as usize;
+        Raw {
+            next: self.latest,
+            missed: oldest.saturating_sub(since + 1),
+            rows: self
+                .rows
+                .iter()
+                .skip(skip)
+                .map(|(_, _, row)| row.clone())
+                .collect(),
+        }
+    }
+}
+
+impl Buffer {
+    fn push(&self, stream: &str, row: String) {
+        let now = Instant::now();
+        let mut streams = self.streams.lock().unwrap();
+        let stream = streams.entry(stream.to_string()).or_default();
+        stream.latest += 1;
+        stream.rows.push_back((stream.latest, now, row.into()));
+        while stream.rows.len() > MAX_ROWS
+            || stream
+                .rows
+                .front()
+                .is_some_and(|(_, at, _)| now.duration_since(*at) > self.keep)
+        {
+            stream.rows.pop_front();
+        }
+        drop(streams);
+        self.pushed.notify_all();
+    }
+
+    fn since(&self, stream: &str, since: u64, timeout: Duration) -> Raw {
+        let deadline = Instant::now() + timeout.min(MAX_WAIT);
+        let mut streams = self.streams.lock().unwrap();
+        loop {
+            let latest = streams.get(stream).map_or(0, |s| s.latest);
+            let now = Instant::now();
+            if latest > since || now >= deadline {
+                break;
+            }
+            streams = self.pushed.wait_timeout(streams, deadline - now).unwrap().0;
+        }
+        match streams.get(stream) {
+            Some(stream) => stream.since(since),
+            None => Raw {
+                next: since,
+                missed: 0,
+                rows: Vec::new(),
+            },
+        }
+    }
+}
+
+// Starts holding rows, `seconds` of them per stream or 10. Does nothing the
+// second time.
+pub fn enable(seconds: Option<u64>) {
+    BUFFER.get_or_init(|| Buffer {
+        keep: Duration::from_secs(seconds.unwrap_or(DEFAULT_SECONDS)),
+        streams: Mutex::new(HashMap::new()),
+        pushed: Condvar::new(),
+    });
+}
+
+pub fn enabled() -> bool {
+    BUFFER.get().is_some()
+}
+
+fn parse(raw: Raw) -> Tail {
+    Tail {
+        next: raw.next,
+        missed: raw.missed,
+        rows: raw
+            .rows
+            .iter()
+            .filter_map(|row| serde_json::from_str(row).ok())
+            .collect(),
+    }
+}
+
+// A stream's rows after `since`, 0 for all that are held.
+pub fn tail(stream: &str, since: u64) -> Result<Tail, Error> {
+    wait(stream, since, Duration::ZERO)
+}
+
+// As tail(), but waits up to `timeout` for a row when there are none yet.
+pub fn wait(stream: &str, since: u64, timeout: Duration) -> Result<Tail, Error> {
+    let buffer = BUFFER.get().ok_or("the tail buffer isn't enabled")?;
+    Ok(parse(buffer.since(stream, since, timeout)))
+}
+
+// The target that fills the buffer.
+pub fn target() -> TargetConfig {
+    TargetConfig {
+        url: SCHEME.to_string(),
+        tables: Tables::All,
+    }
+}
+
+pub fn is_tail(url: &str) -> bool {
+    url == SCHEME
+}
+
+pub struct TailSink {
+    buffer: &'static Buffer,
+}
+
+impl TailSink {
+    pub fn new(seconds: Option<u64>) -> Self {
+        enable(seconds);
+        TailSink {
+            buffer: BUFFER.get().unwrap(),
+        }
+    }
+}
+
+impl FlatSink for TailSink {
+    fn write(&mut self, table: &str, layout: &Layout, params: &[Encoded]) -> Result<(), Error> {
+        self.buffer
+            .push(table, jsonl::object(table, layout, params));
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<(), Error> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+fn serve(mut stream: TcpStream, buffer: &Buffer) -> Result<(), Error> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let target = match request.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", target, _] => target,
+        _ => return respond(&mut stream, "405 Method Not Allowed", "{}"),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (mut since, mut timeout) = (0, Duration::ZERO);
+    for param in query.split('&') {
+        match param.split_once('=') {
+            Some(("since", v)) => since = v.parse().unwrap_or(0),
+            Some(("wait", v)) => timeout = Duration::from_millis(v.parse().unwrap_or(0)),
+            _ => (),
+        }
+    }
+
+    if path == "/tail" {
+        let streams: HashMap<String, u64> = buffer
+            .streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, s)| (name.clone(), s.latest))
+            .collect();
+        return respond(&mut stream, "200 OK", &json!(streams).to_string());
+    }
+    let name = match path.strip_prefix("/tail/") {
+        Some(name) if !name.is_empty() => name,
+        _ => return respond(&mut stream, "404 Not Found", "{}"),
+    };
+
+    let raw = buffer.since(name, since, timeout);
+    let body = format!(
+        "{{\"stream\":{},\"next\":{},\"missed\":{},\"rows\":[{}]}}",
+        Value::from(name),
+        raw.next,
+        raw.missed,
+        raw.rows.join(",")
+    );
+    respond(&mut stream, "200 OK", &body)
+}
+
+// Enables the buffer and serves it when there's an address to serve it on.
+pub fn start(address: Option<&str>, seconds: Option<u64>) -> Result<(), Error> {
+    let address = match address {
+        Some(address) => address.to_string(),
+        None => return Ok(()),
+    };
+    enable(seconds);
+    let listener = TcpListener::bind(&address)?;
+    info!("Serving the tail API on {}", address);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Tail connection failed. Error: {}", e);
+                    continue;
+                }
+            };
+            thread::spawn(move || {
+                if let Err(e) = serve(stream, BUFFER.get().unwrap()) {
+                    warn!("Tail request failed. Error: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
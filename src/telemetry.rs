@@ -0,0 +1,352 @@
+// Spans, counters and gauges from the acquisition, decode and sink stages, exported
+// over OTLP/HTTP with the JSON encoding. Enabled by an endpoint in
+// [telemetry] or the standard OTEL_EXPORTER_OTLP_ENDPOINT variable, e.g.
+// http://collector:4318; without one every call here is a no-op.
+use crate::config;
+use crate::vehicle::Vehicle;
+use crate::Error;
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+pub const ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+pub const SERVICE_NAME_ENV: &str = "OTEL_SERVICE_NAME";
+
+// Per-packet spans are sampled; the sinks' batch spans are all kept.
+pub const PACKET_SAMPLE_EVERY: u64 = 100;
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_PENDING_SPANS: usize = 10_000;
+
+static TELEMETRY: OnceLock<Telemetry> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AttrValue {
+    Str(String),
+    Int(i64),
+}
+
+impl From<&str> for AttrValue {
+    fn from(v: &str) -> Self {
+        AttrValue::Str(v.to_string())
+    }
+}
+
+impl From<String> for AttrValue {
+    fn from(v: String) -> Self {
+        AttrValue::Str(v)
+    }
+}
+
+impl From<u64> for AttrValue {
+    fn from(v: u64) -> Self {
+        AttrValue::Int(v as i64)
+    }
+}
+
+impl From<usize> for AttrValue {
+    fn from(v: usize) -> Self {
+        AttrValue::Int(v as i64)
+    }
+}
+
+type Attrs = Vec<(&'static str, AttrValue)>;
+
+// Read at each export, for values that change without anything happening,
+// like how far behind a stalled writer is.
+type Gauge = Box<dyn Fn() -> Vec<(Attrs, f64)> + Send + Sync>;
+
+fn attrs_json(attrs: &[(&'static str, AttrValue)]) -> Value {
+    attrs
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                AttrValue::Str(s) => json!({ "stringValue": s }),
+                AttrValue::Int(i) => json!({ "intValue": i.to_string() }),
+            };
+            json!({ "key": key, "value": value })
+        })
+        .collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+        .to_string()
+}
+
+#[derive(Debug)]
+struct SpanRecord {
+    name: &'static str,
+    trace_id: String,
+    span_id: String,
+    parent_id: Option<String>,
+    start: SystemTime,
+    end: SystemTime,
+    attrs: Attrs,
+    error: Option<String>,
+}
+
+impl SpanRecord {
+    fn to_json(&self) -> Value {
+        let mut span = json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "name": self.name,
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end),
+            "attributes": attrs_json(&self.attrs),
+            "status": match &self.error {
+                Some(message) => json!({ "code": 2, "message": message }),
+                None => json!({ "code": 1 }),
+            },
+        });
+        if let Some(parent) = &self.parent_id {
+            span["parentSpanId"] = json!(parent);
+        }
+        span
+    }
+}
+
+struct Telemetry {
+    endpoint: String,
+    service: String,
+    vehicle: Option<String>,
+    started: SystemTime,
+    ids: RandomState,
+    next_id: AtomicU64,
+    spans: Mutex<Vec<SpanRecord>>,
+    counters: Mutex<HashMap<(&'static str, Attrs), u64>>,
+    gauges: Mutex<Vec<(&'static str, Gauge)>>,
+}
+
+impl Telemetry {
+    fn random_hex(&self, words: usize) -> String {
+        (0..words)
+            .map(|_| {
+                let mut hasher = self.ids.build_hasher();
+                hasher.write_u64(self.next_id.fetch_add(1, Ordering::Relaxed));
+                format!("{:016x}", hasher.finish())
+            })
+            .collect()
+    }
+
+    fn resource(&self) -> Value {
+        let mut attrs = vec![("service.name", self.service.as_str().into())];
+        if let Some(vehicle) = &self.vehicle {
+            attrs.push(("vehicle.id", vehicle.as_str().into()));
+        }
+        json!({ "attributes": attrs_json(&attrs) })
+    }
+
+    fn post(&self, path: &str, body: Value) -> Result<(), Error> {
+        ureq::post(&format!("{}{}", self.endpoint, path))
+            .send_json(body)
+            .map_err(|e| format!("OTLP export to {} failed: {}", path, e))?;
+        Ok(())
+    }
+
+    fn export(&self) -> Result<(), Error> {
+        let spans: Vec<SpanRecord> = std::mem::take(&mut *self.spans.lock().unwrap());
+        if !spans.is_empty() {
+            let spans: Vec<Value> = spans.iter().map(SpanRecord::to_json).collect();
+            self.post(
+                "/v1/traces",
+                json!({ "resourceSpans": [{
+                    "resource": self.resource(),
+                    "scopeSpans": [{ "scope": { "name": "lordlogger" }, "spans": spans }],
+                }]}),
+            )?;
+        }
+
+        let now = unix_nanos(SystemTime::now());
+        let start = unix_nanos(self.started);
+        let mut sums: HashMap<&'static str, Vec<Value>> = HashMap::new();
+        for ((name, attrs), value) in self.counters.lock().unwrap().iter() {
+            sums.entry(name).or_default().push(json!({
+                "attributes": attrs_json(attrs),
+                "startTimeUnixNano": start,
+                "timeUnixNano": now,
+                "asInt": value.to_string(),
+            }));
+        }
+        let mut metrics: Vec<Value> = sums
+            .into_iter()
+            .map(|(name, points)| {
+                json!({
+                    "name": name,
+                    "sum": {
+                        "dataPoints": points,
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                    },
+                })
+            })
+            .collect();
+        for (name, gauge) in self.gauges.lock().unwrap().iter() {
+            let points: Vec<Value> = gauge()
+                .iter()
+                .map(|(attrs, value)| {
+                    json!({
+                        "attributes": attrs_json(attrs),
+                        "timeUnixNano": now,
+                        "asDouble": value,
+                    })
+                })
+                .collect();
+            if !points.is_empty() {
+                metrics.push(json!({ "name": name, "gauge": { "dataPoints": points } }));
+            }
+        }
+        if !metrics.is_empty() {
+            self.post(
+                "/v1/metrics",
+                json!({ "resourceMetrics": [{
+                    "resource": self.resource(),
+                    "scopeMetrics": [{ "scope": { "name": "lordlogger" }, "metrics": metrics }],
+                }]}),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn init(vehicle: &Vehicle, settings: &config::Telemetry) -> Result<(), Error> {
+    let endpoint = match &settings.endpoint {
+        Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+        None => return Ok(()),
+    };
+
+    let telemetry = Telemetry {
+        endpoint,
+        service: settings
+            .service_name
+            .clone()
+            .unwrap_or_else(|| "lordlogger".to_string()),
+        vehicle: vehicle.id.clone(),
+        started: SystemTime::now(),
+        ids: RandomState::new(),
+        next_id: AtomicU64::new(0),
+        spans: Mutex::new(Vec::new()),
+        counters: Mutex::new(HashMap::new()),
+        gauges: Mutex::new(Vec::new()),
+    };
+    if TELEMETRY.set(telemetry).is_err() {
+        return Err("telemetry already initialized".into());
+    }
+
+    thread::spawn(|| loop {
+        thread::sleep(EXPORT_INTERVAL);
+        if let Some(telemetry) = TELEMETRY.get() {
+            if let Err(e) = telemetry.export() {
+                warn!("{}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+pub fn add(counter: &'static str, attrs: Attrs, value: u64) {
+    if let Some(telemetry) = TELEMETRY.get() {
+        *telemetry
+            .counters
+            .lock()
+            .unwrap()
+            .entry((counter, attrs))
+            .or_insert(0) += value;
+    }
+}
+
+// Registers a gauge whose points are read by `read` at each export.
+pub fn gauge(name: &'static str, read: impl Fn() -> Vec<(Attrs, f64)> + Send + Sync + 'static) {
+    if let Some(telemetry) = TELEMETRY.get() {
+        telemetry
+            .gauges
+            .lock()
+            .unwrap()
+            .push((name, Box::new(read)));
+    }
+}
+
+// An open span, recorded when ended. Dropping it without ending discards it.
+pub struct Span {
+    record: Option<SpanRecord>,
+}
+
+impl Span {
+    // A span that records nothing, for unsampled work.
+    pub fn none() -> Self {
+        Span { record: None }
+    }
+
+    pub fn start(name: &'static str) -> Self {
+        Self::start_at(name, SystemTime::now())
+    }
+
+    pub fn start_at(name: &'static str, start: SystemTime) -> Self {
+        let record = TELEMETRY.get().map(|telemetry| SpanRecord {
+            name,
+            trace_id: telemetry.random_hex(2),
+            span_id: telemetry.random_hex(1),
+            parent_id: None,
+            start,
+            end: start,
+            attrs: Vec::new(),
+            error: None,
+        });
+        Span { record }
+    }
+
+    pub fn child_at(&self, name: &'static str, start: SystemTime) -> Self {
+        let record = match (&self.record, TELEMETRY.get()) {
+            (Some(parent), Some(telemetry)) => Some(SpanRecord {
+                name,
+                trace_id: parent.trace_id.clone(),
+                span_id: telemetry.random_hex(1),
+                parent_id: Some(parent.span_id.clone()),
+                start,
+                end: start,
+                attrs: Vec::new(),
+                error: None,
+            }),
+            _ => None,
+        };
+        Span { record }
+    }
+
+    pub fn child(&self, name: &'static str) -> Self {
+        self.child_at(name, SystemTime::now())
+    }
+
+    pub fn attr<V: Into<AttrValue>>(&mut self, key: &'static str, value: V) {
+        if let Some(record) = &mut self.record {
+            record.attrs.push((key, value.into()));
+        }
+    }
+
+    pub fn fail(&mut self, message: &str) {
+        if let Some(record) = &mut self.record {
+            record.error = Some(message.to_string());
+        }
+    }
+
+    pub fn end(self) {
+        if let (Some(mut record), Some(telemetry)) = (self.record, TELEMETRY.get()) {
+            record.end = SystemTime::now();
+            let mut spans = telemetry.spans.lock().unwrap();
+            if spans.len() < MAX_PENDING_SPANS {
+                spans.push(record);
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+// Opt-in TimescaleDB hypertables for the high-rate tables, partitioned on the
+// row time so months of IMU data stay quick to query and cheap to prune.
+// Existing tables are converted in place, their rows moved into chunks.
+use crate::check::parse_duration;
+use crate::config;
+use crate::Error;
+use postgres::Client;
+use std::time::Duration;
+use tracing::info;
+
+// "1" makes imu_data and gnss_data hypertables. Needs the timescaledb
+// extension installed on the server.
+pub const TIMESCALE_ENV: &str = "LORDLOGGER_TIMESCALE";
+// Time span of each chunk, e.g. 6h. One day by default.
+pub const CHUNK_ENV: &str = "LORDLOGGER_TIMESCALE_CHUNK";
+// Compresses chunks once they're this old, e.g. 7d. Unset leaves them as is.
+pub const COMPRESS_AFTER_ENV: &str = "LORDLOGGER_TIMESCALE_COMPRESS_AFTER";
+
+pub const TABLES: [&str; 2] = ["imu_data", "gnss_data"];
+
+const DEFAULT_CHUNK: Duration = Duration::from_secs(86_400);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timescale {
+    pub chunk: Duration,
+    pub compress_after: Option<Duration>,
+}
+
+fn duration(name: &str, value: &Option<String>) -> Result<Option<Duration>, Error> {
+    match value {
+        Some(value) => Ok(Some(
+            parse_duration(value).map_err(|e| format!("timescale {}: {}", name, e))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+// Whether a table is a hypertable, false on servers without TimescaleDB.
+pub fn is_hypertable(c: &mut Client, table: &str) -> Result<bool, Error> {
+    let installed: bool = c
+        .query_one(
+            "SELECT to_regclass('timescaledb_information.hypertables') IS NOT NULL",
+            &[],
+        )?
+        .get(0);
+    if !installed {
+        return Ok(false);
+    }
+    let row = c.query_opt(
+        "SELECT 1 FROM timescaledb_information.hypertables
+         WHERE hypertable_schema = current_schema() AND hypertable_name = $1",
+        &[&table],
+    )?;
+    Ok(row.is_some())
+}
+
+impl Timescale {
+    pub fn from_settings(settings: &config::Timescale) -> Result<Option<Self>, Error> {
+        if settings.enabled != Some(true) {
+            return Ok(None);
+        }
+        let chunk = duration("chunk", &settings.chunk)?.unwrap_or(DEFAULT_CHUNK);
+        if chunk.is_zero() {
+            return Err("the timescale chunk must be more than 0".into());
+        }
+        Ok(Some(Timescale {
+            chunk,
+            compress_after: duration("compress_after", &settings.compress_after)?,
+        }))
+    }
+
+    // Run after the time columns exist. Does nothing to a table that's
+    // already a hypertable, so it's safe on every connect.
+    pub fn setup(&self, c: &mut Client) -> Result<(), Error> {
+        c.batch_execute("CREATE EXTENSION IF NOT EXISTS timescaledb")
+            .map_err(|e| {
+                let reason = e
+                    .as_db_error()
+                    .map_or(e.to_string(), |e| e.message().to_string());
+                format!(
+                    "{} is set but TimescaleDB isn't available: {}",
+                    TIMESCALE_ENV, reason
+                )
+            })?;
+
+        for table in &TABLES {
+            if !is_hypertable(c, table)? {
+                self.convert(c, table)?;
+            }
+            if let Some(after) = self.compress_after {
+                self.compress(c, table, after)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn convert(&self, c: &mut Client, table: &str) -> Result<(), Error> {
+        let mut tx = c.transaction()?;
+        // The partition column can't be NULL, and every unique index has to
+        // include it. Rows from before the time columns get their GPS time,
+        // leap seconds and all.
+        tx.batch_execute(&format!(
+            "UPDATE {table} SET time = coalesce(
+                 gps_time,
+                 host_time,
+                 timestamptz '1980-01-06 00:00:00+00' + make_interval(weeks => week, secs => tow)
+             )
+             WHERE time IS NULL;
+             ALTER TABLE {table} ALTER COLUMN time SET NOT NULL;
+             ALTER TABLE {table} DROP CONSTRAINT IF EXISTS {table}_pkey;
+             ALTER TABLE {table} ADD PRIMARY KEY (id, time);",
+            table = table
+        ))?;
+        tx.execute(
+            format!(
+                "SELECT create_hypertable('{}', 'time',
+                     chunk_time_interval => make_interval(secs => $1::float8),
+                     migrate_data => true)",
+                table
+            )
+            .as_str(),
+            &[&self.chunk.as_secs_f64()],
+        )?;
+        tx.commit()?;
+        info!("Made {} a TimescaleDB hypertable", table);
+        Ok(())
+    }
+
+    // Chunks are compressed per session, newest rows first, which is how
+    // they're read back.
+    fn compress(&self, c: &mut Client, table: &str, after: Duration) -> Result<(), Error> {
+        let enabled: bool = c
+            .query_one(
+                "SELECT compression_enabled FROM timescaledb_information.hypertables
+                 WHERE hypertable_schema = current_schema() AND hypertable_name = $1",
+                &[&table],
+            )?
+            .get(0);
+        if !enabled {
+            c.batch_execute(&format!(
+                "ALTER TABLE {} SET (
+                     timescaledb.compress,
+                     timescaledb.compress_segmentby = 'session_id',
+                     timescaledb.compress_orderby = 'time DESC'
+                 )",
+                table
+            ))?;
+        }
+        c.execute(
+            format!(
+                "SELECT add_compression_policy('{}', make_interval(secs => $1::float8),
+                     if_not_exists => true)",
+                table
+            )
+            .as_str(),
+            &[&after.as_secs_f64()],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+use crate::descriptors::{self, GnssField, ImuField};
+use crate::registry::{self, Read};
+use crate::session::GpsTime;
+use crate::Error;
+use lordserial::{Field, Packet};
+use std::fmt;
+
+// Postgres has no CREATE TYPE IF NOT EXISTS, so an existing type is caught
+// instead.
+pub const CREATE_SQL: &str = "
+    DO $$ BEGIN
+        CREATE TYPE real3d AS (x real, y real, z real);
+    EXCEPTION WHEN duplicate_object THEN NULL;
+    END $$;
+
+    DO $$ BEGIN
+        CREATE TYPE quaternion AS (q0 real, q1 real, q2 real, q3 real);
+    EXCEPTION WHEN duplicate_object THEN NULL;
+    END $$;
+";
+
+#[derive(Debug, FromSql)]
+#[postgres(name = "real3d")]
+pub struct Vector3f {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Vector3f {
+    pub fn extract(field: &Field) -> Result<Self, Error> {
+        let mut v = [0.0; 3];
+        registry::extract_f32s(field, &mut v)?;
+        Ok(Self {
+            x: v[0],
+            y: v[1],
+            z: v[2],
+        })
+    }
+}
+
+#[derive(Debug, FromSql)]
+#[postgres(name = "quaternion")]
+pub struct Quaternion {
+    pub q0: f32,
+    pub q1: f32,
+    pub q2: f32,
+    pub q3: f32,
+}
+
+impl Quaternion {
+    pub fn extract(field: &Field) -> Result<Self, Error> {
+        let mut q = [0.0; 4];
+        registry::extract_f32s(field, &mut q)?;
+        Ok(Self {
+            q0: q[0],
+            q1: q[1],
+            q2: q[2],
+            q3: q[3],
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ImuData {
+    pub accel: Vector3f,
+    pub gyro: Vector3f,
+    pub mag: Vector3f,
+    pub baro: f32,
+    pub delta_theta: Vector3f,
+    pub delta_velocity: Vector3f,
+    pub quat: Quaternion,
+    pub euler_angles: Vector3f,
+    pub tow: f64,
+    pub week: i16,
+    pub raw_accel: Option<Vector3f>,
+    pub raw_gyro: Option<Vector3f>,
+    pub raw_mag: Option<Vector3f>,
+    pub raw_baro: Option<f32>,
+}
+
+// A field the decoder needs that the packet didn't carry.
+#[derive(Debug, Clone, Copy)]
+pub struct MissingField {
+    pub set: u8,
+    pub field: u8,
+}
+
+impl fmt::Display for MissingField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "missing {}",
+            descriptors::describe_field(self.set, self.field)
+        )
+    }
+}
+
+impl std::error::Error for MissingField {}
+
+pub fn field(packet: &Packet, descriptor: u8) -> Result<&Field, MissingField> {
+    packet.payload.get_field(descriptor).ok_or(MissingField {
+        set: packet.header.descriptor,
+        field: descriptor,
+    })
+}
+
+impl ImuData {
+    pub fn new(packet: &Packet) -> Result<Self, Error> {
+        let time = GpsTime::from_packet(packet)?.ok_or(MissingField {
+            set: packet.header.descriptor,
+            field: ImuField::GpsTimestamp.into(),
+        })?;
+
+        Ok(ImuData {
+            accel: Vector3f::extract(field(packet, ImuField::ScaledAccel.into())?)?,
+            gyro: Vector3f::extract(field(packet, ImuField::ScaledGyro.into())?)?,
+            mag: Vector3f::extract(field(packet, ImuField::ScaledMag.into())?)?,
+            baro: field(packet, ImuField::ScaledPressure.into())?.read::<f32>(0)?,
+            delta_theta: Vector3f::extract(field(packet, ImuField::DeltaTheta.into())?)?,
+            delta_velocity: Vector3f::extract(field(packet, ImuField::DeltaVelocity.into())?)?,
+            quat: Quaternion::extract(field(packet, ImuField::Quaternion.into())?)?,
+            euler_angles: Vector3f::extract(field(packet, ImuField::EulerAngles.into())?)?,
+            tow: time.tow,
+            week: time.week,
+            raw_accel: packet
+                .payload
+                .get_field(ImuField::RawAccel.into())
+                .map(Vector3f::extract)
+                .transpose()?,
+            raw_gyro: packet
+                .payload
+                .get_field(ImuField::RawGyro.into())
+                .map(Vector3f::extract)
+                .transpose()?,
+            raw_mag: packet
+                .payload
+                .get_field(ImuField::RawMag.into())
+                .map(Vector3f::extract)
+                .transpose()?,
+            raw_baro: packet
+                .payload
+                .get_field(ImuField::RawPressure.into())
+                .map(|f| f.read::<f32>(0))
+                .transpose()?,
+        })
+    }
+}
+
+// 0x81/0x03
+#[derive(Debug, Clone)]
+pub struct LlhPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub ellipsoid_alt: f64,
+    pub msl_alt: f64,
+    pub horizontal_accuracy: f32,
+    pub vertical_accuracy: f32,
+    pub flags: u16,
+}
+
+impl LlhPosition {
+    pub fn extract(field: &Field) -> Result<Self, Error> {
+        Ok(Self {
+            latitude: field.read(0)?,
+            longitude: field.read(8)?,
+            ellipsoid_alt: field.read(16)?,
+            msl_alt: field.read(24)?,
+            horizontal_accuracy: field.read(32)?,
+            vertical_accuracy: field.read(36)?,
+            flags: field.read(40)?,
+        })
+    }
+
+    // Lat/lon (bit 0) and ellipsoid height (bit 1) are both valid.
+    pub fn has_position(&self) -> bool {
+        self.flags & 0x03 == 0x03
+    }
+}
+
+// 0x81/0x04
+#[derive(Debug, Clone)]
+pub struct EcefPosition {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub accuracy: f32,
+    pub flags: u16,
+}
+
+impl EcefPosition {
+    pub fn extract(field: &Field) -> Result<Self, Error> {
+        Ok(Self {
+            x: field.read(0)?,
+            y: field.read(8)?,
+            z: field.read(16)?,
+            accuracy: field.read(24)?,
+            flags: field.read(28)?,
+        })
+    }
+}
+
+// 0x81/0x05
+#[derive(Debug, Clone)]
+pub struct NedVelocity {
+    pub north: f32,
+    pub east: f32,
+    pub down: f32,
+    pub speed: f32,
+    pub ground_speed: f32,
+    pub heading: f32,
+    pub speed_accuracy: f32,
+    pub heading_accuracy: f32,
+    pub flags: u16,
+}
+
+impl NedVelocity {
+    pub fn extract(field: &Field) -> Result<Self, Error> {
+        Ok(Self {
+            north: field.read(0)?,
+            east: field.read(4)?,
+            down: field.read(8)?,
+            speed: field.read(12)?,
+            ground_speed: field.read(16)?,
+            heading: field.read(20)?,
+            speed_accuracy: field.read(24)?,
+            heading_accuracy: field.read(28)?,
+            flags: field.read(32)?,
+        })
+    }
+}
+
+// 0x81/0x07
+#[derive(Debug, Clone)]
+pub struct Dop {
+    pub gdop: f32,
+    pub pdop: f32,
+    pub hdop: f32,
+    pub vdop: f32,
+    pub tdop: f32,
+    pub ndop: f32,
+    pub edop: f32,
+    pub flags: u16,
+}
+
+impl Dop {
+    pub fn extract(field: &Field) -> Result<Self, Error> {
+        Ok(Self {
+            gdop: field.read(0)?,
+            pdop: field.read(4)?,
+            hdop: field.read(8)?,
+            vdop: field.read(12)?,
+            tdop: field.read(16)?,
+            ndop: field.read(20)?,
+            edop: field.read(24)?,
+            flags: field.read(28)?,
+        })
+    }
+}
+
+// 0x81/0x09, named apart from session::GpsTime which only carries the time.
+#[derive(Debug, Clone, Copy)]
+pub struct GnssTime {
+    pub tow: f64,
+    pub week: i16,
+    pub flags: u16,
+}
+
+impl GnssTime {
+    pub fn extract(field: &Field) -> Result<Self, Error> {
+        Ok(Self {
+            tow: field.read(0)?,
+            week: field.read(8)?,
+            flags: field.read(10)?,
+        })
+    }
+
+    pub fn gps_time(&self) -> GpsTime {
+        GpsTime {
+            tow: self.tow,
+            week: self.week,
+        }
+    }
+}
+
+// 0x81/0x0B
+#[derive(Debug, Clone, Copy)]
+pub struct FixInfo {
+    pub fix_type: u8,
+    pub svs: u8,
+    pub fix_flags: u16,
+    pub flags: u16,
+}
+
+impl FixInfo {
+    pub fn extract(field: &Field) -> Result<Self, Error> {
+        Ok(Self {
+            fix_type: field.read(0)?,
+            svs: field.read(1)?,
+            fix_flags: field.read(2)?,
+            flags: field.read(4)?,
+        })
+    }
+
+    // 0x00 3D, 0x01 2D, 0x05 RTK float, 0x06 RTK fixed
+    pub fn has_fix(&self) -> bool {
+        matches!(self.fix_type, 0x00 | 0x01 | 0x05 | 0x06)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GnssData {
+    pub llh: LlhPosition,
+    pub ecef_position: EcefPosition,
+    pub ned_velocity: NedVelocity,
+    pub dop: Dop,
+    pub time: GnssTime,
+    pub fix: FixInfo,
+}
+
+impl GnssData {
+    pub fn new(packet: &Packet) -> Result<Self, Error> {
+        Ok(GnssData {
+            llh: LlhPosition::extract(field(packet, GnssField::LlhPosition.into())?)?,
+            ecef_position: EcefPosition::extract(field(packet, GnssField::EcefPosition.into())?)?,
+            ned_velocity: NedVelocity::extract(field(packet, GnssField::NedVelocity.into())?)?,
+            dop: Dop::extract(field(packet, GnssField::Dop.into())?)?,
+            time: GnssTime::extract(field(packet, GnssField::GpsTime.into())?)?,
+            fix: FixInfo::extract(field(packet, GnssField::FixInfo.into())?)?,
+        })
+    }
+}
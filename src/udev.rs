@@ -0,0 +1,115 @@
+use crate::Error;
+use serialport::{SerialPortType, UsbPortInfo};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+
+pub const RULES_PATH: &str = "/etc/udev/rules.d/99-lordlogger.rules";
+pub const DEFAULT_SYMLINK: &str = "lord0";
+pub const DEFAULT_GROUP: &str = "dialout";
+
+// MicroStrain's USB vendor id.
+const LORD_VID: u16 = 0x199B;
+
+// The USB sensor behind `port`, or the only LORD device plugged in when `port`
+// isn't there.
+pub fn find(port: &str) -> Result<(String, UsbPortInfo), Error> {
+    let wanted = fs::canonicalize(port).ok();
+    let usb: Vec<(String, UsbPortInfo)> = serialport::available_ports()?
+        .into_iter()
+        .filter_map(|p| match p.port_type {
+            SerialPortType::UsbPort(info) => Some((p.port_name, info)),
+            _ => None,
+        })
+        .collect();
+
+    if let Some(found) = usb
+        .iter()
+        .find(|(name, _)| wanted.is_some() && fs::canonicalize(name).ok() == wanted)
+    {
+        return Ok(found.clone());
+    }
+
+    let mut lord = usb.into_iter().filter(|(_, info)| info.vid == LORD_VID);
+    match (lord.next(), lord.next()) {
+        (Some(found), None) => Ok(found),
+        (Some(_), Some(_)) => Err(format!(
+            "{} is not a USB serial port and several LORD devices are plugged in, \
+             pick one with --port",
+            port
+        )
+        .into()),
+        (None, _) => Err(format!(
+            "no LORD USB device found. Is the sensor plugged in? Check `lsusb` for vendor {:04x}.",
+            LORD_VID
+        )
+        .into()),
+    }
+}
+
+// Matches the device by vendor, product and, when it has one, serial number,
+// so the symlink follows that one sensor whichever port it lands on.
+pub fn rule(info: &UsbPortInfo, symlink: &str, group: &str) -> String {
+    let mut matches = vec![
+        "SUBSYSTEM==\"tty\"".to_string(),
+        format!("ATTRS{{idVendor}}==\"{:04x}\"", info.vid),
+        format!("ATTRS{{idProduct}}==\"{:04x}\"", info.pid),
+    ];
+    if let Some(serial) = &info.serial_number {
+        matches.push(format!("ATTRS{{serial}}==\"{}\"", serial));
+    }
+
+    format!(
+        "# Written by lordlogger install-udev\n{}, SYMLINK+=\"{}\", GROUP=\"{}\", MODE=\"0660\"\n",
+        matches.join(", "),
+        symlink,
+        group
+    )
+}
+
+pub fn install(rule: &str, path: &Path) -> Result<(), Error> {
+    if let Err(e) = fs::write(path, rule) {
+        return Err(match e.kind() {
+            ErrorKind::PermissionDenied => format!(
+                "no permission to write {}. Run again with sudo.",
+                path.display()
+            )
+            .into(),
+            _ => e.into(),
+        });
+    }
+
+    for args in [
+        &["control", "--reload-rules"][..],
+        &["trigger", "--subsystem-match=tty"][..],
+    ] {
+        let status = Command::new("udevadm").args(args).status()?;
+        if !status.success() {
+            return Err(format!("udevadm {} failed ({})", args.join(" "), status).into());
+        }
+    }
+
+    Ok(())
+}
+
+// Where an unplugged sensor is now: back at `port`, or wherever a device with
+// the same USB identity enumerated.
+pub fn locate(port: &str, usb: Option<&UsbPortInfo>) -> Option<String> {
+    if Path::new(port).exists() {
+        return Some(port.to_string());
+    }
+    let usb = usb?;
+    serialport::available_ports()
+        .ok()?
+        .into_iter()
+        .find(|p| match &p.port_type {
+            SerialPortType::UsbPort(info) => {
+                info.vid == usb.vid
+                    && info.pid == usb.pid
+                    && info.serial_number == usb.serial_number
+            }
+            _ => false,
+        })
+        .map(|p| p.port_name)
+}
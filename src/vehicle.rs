@@ -0,0 +1,60 @@
+// Which vehicle of a fleet this logger rides on, so one central database can
+// hold them all. Sessions always carry it; data rows only when asked, since
+// the session already says which vehicle a time window belongs to.
+use crate::config;
+use crate::registry;
+use crate::Error;
+
+pub const VEHICLE_ID_ENV: &str = "LORDLOGGER_VEHICLE_ID";
+// "1" also stamps every timed data row with the vehicle id.
+pub const VEHICLE_ROWS_ENV: &str = "LORDLOGGER_VEHICLE_ROWS";
+
+pub const COLUMN: &str = "vehicle_id";
+
+pub const CREATE_SQL: &str = "
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS vehicle_id text;
+    CREATE INDEX IF NOT EXISTS sessions_vehicle_id_idx ON sessions (vehicle_id);
+";
+
+#[derive(Debug, Clone, Default)]
+pub struct Vehicle {
+    pub id: Option<String>,
+    pub rows: bool,
+}
+
+impl Vehicle {
+    pub fn from_settings(vehicle: &config::Vehicle) -> Result<Self, Error> {
+        let id = vehicle
+            .id
+            .as_deref()
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(str::to_string);
+        let rows = vehicle.rows.unwrap_or(false);
+        if rows && id.is_none() {
+            return Err("vehicle rows need a vehicle id".into());
+        }
+        Ok(Vehicle { id, rows })
+    }
+
+    // The id to stamp data rows with, if they get one.
+    pub fn row_id(&self) -> Option<&str> {
+        self.id.as_deref().filter(|_| self.rows)
+    }
+}
+
+pub fn create_sql(table: &str) -> String {
+    format!(
+        "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS vehicle_id text;
+         CREATE INDEX IF NOT EXISTS {table}_vehicle_id_idx ON {table} (vehicle_id, time);",
+        table = table
+    )
+}
+
+pub fn comment(table: &str) -> String {
+    registry::comment_sql(
+        table,
+        COLUMN,
+        &format!("fleet vehicle that logged the row, from {}", VEHICLE_ID_ENV),
+    )
+}
@@ -0,0 +1,82 @@
+use crate::config;
+use std::time::{Duration, Instant};
+
+// Seconds without data before the logger stands by, default 30.
+pub const STANDBY_ENV: &str = "LORDLOGGER_STANDBY_SECS";
+
+// A LORD device that browns out comes back streaming nothing, having lost the
+// message formats it was given. This long without a packet, the formats are
+// sent again, and again every time this passes until data returns.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+// In standby the device is likely off, so it's pinged less often.
+const STANDBY_PING: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resumed {
+    AfterSetup,
+    FromStandby,
+}
+
+pub struct Watchdog {
+    last_packet: Instant,
+    last_setup: Option<Instant>,
+    standby_after: Duration,
+    standby: bool,
+}
+
+impl Watchdog {
+    pub fn from_settings(session: &config::Session) -> Self {
+        Watchdog {
+            last_packet: Instant::now(),
+            last_setup: None,
+            standby_after: Duration::from_secs(session.standby_secs.unwrap_or(30)),
+            standby: false,
+        }
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.last_packet.elapsed()
+    }
+
+    pub fn in_standby(&self) -> bool {
+        self.standby
+    }
+
+    // True when the device should be set up again. Counts as an attempt.
+    pub fn setup_due(&mut self) -> bool {
+        let interval = if self.standby {
+            STANDBY_PING
+        } else {
+            IDLE_TIMEOUT
+        };
+        let due =
+            self.idle() >= IDLE_TIMEOUT && self.last_setup.is_none_or(|t| t.elapsed() >= interval);
+        if due {
+            self.last_setup = Some(Instant::now());
+        }
+        due
+    }
+
+    // True once, when the quiet spell has gone on long enough to stand by.
+    pub fn standby_due(&mut self) -> bool {
+        if self.standby || self.idle() < self.standby_after {
+            return false;
+        }
+        self.standby = true;
+        true
+    }
+
+    // What the first packet after a quiet spell ends, if anything.
+    pub fn packet_received(&mut self) -> Option<Resumed> {
+        self.last_packet = Instant::now();
+        let set_up = self.last_setup.take().is_some();
+
+        if std::mem::take(&mut self.standby) {
+            Some(Resumed::FromStandby)
+        } else if set_up {
+            Some(Resumed::AfterSetup)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::Error;
+use std::fs;
+
+const MAX_DEGREE: usize = 12;
+// WGS-84 ellipsoid
+const WGS84_A: f64 = 6378.137;
+const WGS84_F: f64 = 1.0 / 298.257_223_563;
+// Geomagnetic reference radius, km
+const REFERENCE_RADIUS: f64 = 6371.2;
+
+type Coefficients = [[f64; MAX_DEGREE + 1]; MAX_DEGREE + 1];
+
+// World Magnetic Model loaded from the NOAA-distributed WMM.COF file.
+#[derive(Debug, Clone)]
+pub struct Wmm {
+    pub epoch: f64,
+    g: Coefficients,
+    h: Coefficients,
+    g_dot: Coefficients,
+    h_dot: Coefficients,
+}
+
+impl Wmm {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(cof: &str) -> Result<Self, Error> {
+        let mut lines = cof.lines();
+        let epoch = lines
+            .next()
+            .and_then(|l| l.split_whitespace().next())
+            .ok_or("WMM coefficient file is empty")?
+            .parse::<f64>()?;
+
+        let zero = [[0.0; MAX_DEGREE + 1]; MAX_DEGREE + 1];
+        let mut wmm = Wmm {
+            epoch,
+            g: zero,
+            h: zero,
+            g_dot: zero,
+            h_dot: zero,
+        };
+
+        for line in lines {
+            if line.trim_start().starts_with("9999") {
+                break;
+            }
+
+            let cols: Vec<&str> = line.split_whitespace().collect();
+            if cols.len() < 6 {
+                continue;
+            }
+
+            let n: usize = cols[0].parse()?;
+            let m: usize = cols[1].parse()?;
+            if n > MAX_DEGREE || m > n {
+                return Err(format!("bad WMM coefficient degree/order {} {}", n, m).into());
+            }
+
+            wmm.g[n][m] = cols[2].parse()?;
+            wmm.h[n][m] = cols[3].parse()?;
+            wmm.g_dot[n][m] = cols[4].parse()?;
+            wmm.h_dot[n][m] = cols[5].parse()?;
+        }
+
+        Ok(wmm)
+    }
+
+    // Magnetic declination in radians, positive east, at geodetic latitude and
+    // longitude (degrees), height above the ellipsoid (m) and decimal year.
+    // Returns None at the geographic poles where declination is undefined.
+    pub fn declination(&self, lat: f64, lon: f64, height: f64, year: f64) -> Option<f64> {
+        let phi = lat.to_radians();
+        let lambda = lon.to_radians();
+        let height = height / 1000.0;
+        let dt = year - self.epoch;
+
+        // Geodetic to geocentric spherical coordinates.
+        let e2 = WGS84_F * (2.0 - WGS84_F);
+        let rc = WGS84_A / (1.0 - e2 * phi.sin().powi(2)).sqrt();
+        let p = (rc + height) * phi.cos();
+        let z = (rc * (1.0 - e2) + height) * phi.sin();
+        let r = (p * p + z * z).sqrt();
+        let phi_c = (z / r).asin();
+
+        let cos_theta = phi_c.sin();
+        let sin_theta = phi_c.cos();
+        if sin_theta.abs() < 1e-10 {
+            return None;
+        }
+
+        // Gauss-normalized associated Legendre functions and their theta
+        // derivatives, with the Schmidt to Gauss conversion factors.
+        let mut pnm = [[0.0; MAX_DEGREE + 1]; MAX_DEGREE + 1];
+        let mut dpnm = [[0.0; MAX_DEGREE + 1]; MAX_DEGREE + 1];
+        let mut schmidt = [[0.0; MAX_DEGREE + 1]; MAX_DEGREE + 1];
+        pnm[0][0] = 1.0;
+        schmidt[0][0] = 1.0;
+
+        for n in 1..=MAX_DEGREE {
+            let nf = n as f64;
+            schmidt[n][0] = schmidt[n - 1][0] * (2.0 * nf - 1.0) / nf;
+
+            for m in 0..=n {
+                let mf = m as f64;
+
+                if m > 0 {
+                    let delta = if m == 1 { 2.0 } else { 1.0 };
+                    schmidt[n][m] =
+                        schmidt[n][m - 1] * ((nf - mf + 1.0) * delta / (nf + mf)).sqrt();
+                }
+
+                if m == n {
+                    pnm[n][m] = sin_theta * pnm[n - 1][m - 1];
+                    dpnm[n][m] = sin_theta * dpnm[n - 1][m - 1] + cos_theta * pnm[n - 1][m - 1];
+                } else {
+                    let k = if n > 1 {
+                        ((nf - 1.0).powi(2) - mf * mf) / ((2.0 * nf - 1.0) * (2.0 * nf - 3.0))
+                    } else {
+                        0.0
+                    };
+                    let (p2, dp2) = if n > 1 {
+                        (pnm[n - 2][m], dpnm[n - 2][m])
+                    } else {
+                        (0.0, 0.0)
+                    };
+
+                    pnm[n][m] = cos_theta * pnm[n - 1][m] - k * p2;
+                    dpnm[n][m] = cos_theta * dpnm[n - 1][m] - sin_theta * pnm[n - 1][m] - k * dp2;
+                }
+            }
+        }
+
+        // Field components in the geocentric north/east/down frame.
+        let (mut x, mut y, mut zc) = (0.0, 0.0, 0.0);
+        for n in 1..=MAX_DEGREE {
+            let ratio = (REFERENCE_RADIUS / r).powi(n as i32 + 2);
+
+            for m in 0..=n {
+                let g = schmidt[n][m] * (self.g[n][m] + dt * self.g_dot[n][m]);
+                let h = schmidt[n][m] * (self.h[n][m] + dt * self.h_dot[n][m]);
+                let (sin_ml, cos_ml) = (m as f64 * lambda).sin_cos();
+
+                x += ratio * (g * cos_ml + h * sin_ml) * dpnm[n][m];
+                y += ratio * m as f64 * (g * sin_ml - h * cos_ml) * pnm[n][m] / sin_theta;
+                zc -= ratio * (n as f64 + 1.0) * (g * cos_ml + h * sin_ml) * pnm[n][m];
+            }
+        }
+
+        // Rotate north into the geodetic frame; east is unchanged.
+        let psi = phi_c - phi;
+        let north = x * psi.cos() - zc * psi.sin();
+
+        Some(y.atan2(north))
+    }
+}
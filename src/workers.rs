@@ -0,0 +1,135 @@
+use crate::clock::{self, Stamp};
+use crate::config::Data;
+use crate::types::MissingField;
+use crate::Error;
+use lordserial::Packet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TryIter};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+// Number of decode worker threads; unset or 0 decodes on the acquisition thread.
+pub const DECODE_WORKERS_ENV: &str = "LORDLOGGER_DECODE_WORKERS";
+
+const SHARD_QUEUE: usize = 1024;
+
+pub type Decode = Arc<dyn Fn(&Packet) -> Result<(), Error> + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    MissingField,
+    Malformed,
+    Panic,
+}
+
+impl Reason {
+    pub fn name(self) -> &'static str {
+        match self {
+            Reason::MissingField => "missing_field",
+            Reason::Malformed => "malformed",
+            Reason::Panic => "panic",
+        }
+    }
+}
+
+// A failed decode, reported back to the acquisition thread.
+#[derive(Debug)]
+pub struct DecodeError {
+    pub descriptor: u8,
+    pub reason: Reason,
+    pub message: String,
+}
+
+// Why a decode caught with catch_unwind failed, None if it didn't.
+pub fn failure(result: thread::Result<Result<(), Error>>) -> Option<(Reason, String)> {
+    match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) if e.downcast_ref::<MissingField>().is_some() => {
+            Some((Reason::MissingField, e.to_string()))
+        }
+        Ok(Err(e)) => Some((Reason::Malformed, e.to_string())),
+        Err(payload) => Some((Reason::Panic, crate::panic_message(&payload))),
+    }
+}
+
+// Decodes on a pool of threads. Every (device, descriptor) stream is pinned to
+// one worker, so a stream's packets are decoded in the order they arrived.
+pub struct WorkerPool {
+    shards: Vec<SyncSender<(Packet, Stamp)>>,
+    errors: Receiver<DecodeError>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+fn work(packets: Receiver<(Packet, Stamp)>, decode: Decode, errors: Sender<DecodeError>) {
+    for (packet, received) in packets {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            clock::received(received, || decode(&packet))
+        }));
+        let (reason, message) = match failure(result) {
+            Some(failure) => failure,
+            None => continue,
+        };
+
+        let error = DecodeError {
+            descriptor: packet.header.descriptor,
+            reason,
+            message,
+        };
+        if errors.send(error).is_err() {
+            return;
+        }
+    }
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, decode: Decode) -> Self {
+        let (error_tx, errors) = mpsc::channel();
+
+        let (shards, workers) = (0..workers)
+            .map(|_| {
+                let (shard, packets) = mpsc::sync_channel(SHARD_QUEUE);
+                let decode = decode.clone();
+                let errors = error_tx.clone();
+                (shard, thread::spawn(move || work(packets, decode, errors)))
+            })
+            .unzip();
+
+        WorkerPool {
+            shards,
+            errors,
+            workers,
+        }
+    }
+
+    // Blocks when the stream's worker is SHARD_QUEUE packets behind, so a
+    // saturated pool slows acquisition instead of growing without bound.
+    pub fn dispatch(&self, device: &str, packet: Packet, received: Stamp) -> Result<(), Error> {
+        let mut hasher = DefaultHasher::new();
+        (device, packet.header.descriptor).hash(&mut hasher);
+        let shard = hasher.finish() as usize % self.shards.len();
+
+        self.shards[shard]
+            .send((packet, received))
+            .map_err(|_| "decode worker exited".into())
+    }
+
+    pub fn errors(&self) -> TryIter<'_, DecodeError> {
+        self.errors.try_iter()
+    }
+
+    // Decodes every packet already dispatched, then stops the workers.
+    // Returns the errors not yet collected.
+    pub fn finish(self) -> Vec<DecodeError> {
+        drop(self.shards);
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+        self.errors.try_iter().collect()
+    }
+}
+
+pub fn from_settings(data: &Data) -> usize {
+    data.decode_workers.unwrap_or(0)
+}